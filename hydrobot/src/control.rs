@@ -0,0 +1,171 @@
+//! Control socket of the running daemon.
+use actix::prelude::*;
+use std::io::{self, BufRead, BufReader, ErrorKind, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+use crate::scheduler::SchedulerActor;
+
+/// In the store directory, whoever holds the store lock serves it
+pub const CONTROL_SOCKET: &str = "hydrobot.sock";
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A command line request carried out by the running daemon, replies what to tell the user or why it was refused
+#[derive(Debug, Clone, PartialEq, Message)]
+#[rtype(result = "Result<String, String>")]
+pub enum ControlRequest {
+    AcknowledgeLockout,
+    ApplyProfile {
+        name: String,
+    },
+}
+
+impl ControlRequest {
+    fn parse(line: &str) -> Option<Self> {
+        match line {
+            "lockout ack" => Some(ControlRequest::AcknowledgeLockout),
+            _ => line.strip_prefix("profile use ").map(|name| ControlRequest::ApplyProfile { name: name.to_string() }),
+        }
+    }
+
+    fn to_line(&self) -> String {
+        match self {
+            ControlRequest::AcknowledgeLockout => "lockout ack".to_string(),
+            ControlRequest::ApplyProfile { name } => format!("profile use {}", name),
+        }
+    }
+}
+
+impl Handler<ControlRequest> for SchedulerActor {
+    type Result = Result<String, String>;
+
+    fn handle(&mut self, msg: ControlRequest, _ctx: &mut Self::Context) -> Self::Result {
+        match msg {
+            ControlRequest::AcknowledgeLockout => match self.acknowledge_lockout() {
+                Ok(Some(lockout)) => Ok(format!("Lockout acknowledged: {}", lockout.reason)),
+                Ok(None) => Ok("No lockout".to_string()),
+                Err(e) => Err(e.to_string()),
+            },
+            ControlRequest::ApplyProfile { name } => self.apply_profile(&name)
+                .map(|profile| format!("Profile {} applied, {} settings changed", name, profile.values.len()))
+                .map_err(|e| e.to_string()),
+        }
+    }
+}
+
+/// Serves the requests until dropped, the socket file goes with it
+pub struct ControlSocket {
+    path: PathBuf,
+}
+
+impl ControlSocket {
+    /// Replaces the socket a crashed daemon left, `store` must be locked by the caller
+    pub fn bind<T: AsRef<Path>>(store: T, scheduler: Addr<SchedulerActor>) -> io::Result<Self> {
+        let path = store.as_ref().join(CONTROL_SOCKET);
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path)?;
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let scheduler = scheduler.clone();
+                        thread::spawn(move || if let Err(e) = serve(stream, &scheduler) {
+                            warn!("Control request failed: {}", e);
+                        });
+                    },
+                    Err(e) => warn!("Failed to accept a control connection: {}", e),
+                }
+            }
+        });
+        Ok(Self { path })
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// One request per connection, answered by `OK <message>` or `ERR <message>`
+fn serve(mut stream: UnixStream, scheduler: &Addr<SchedulerActor>) -> io::Result<()> {
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let reply = match ControlRequest::parse(line.trim_end()) {
+        Some(request) => futures::executor::block_on(scheduler.send(request)).unwrap_or_else(|e| Err(e.to_string())),
+        None => Err(format!("Unknown request `{}`", line.trim_end())),
+    };
+    match reply {
+        Ok(msg) => writeln!(stream, "OK {}", msg),
+        Err(msg) => writeln!(stream, "ERR {}", msg),
+    }
+}
+
+/// Send `request` to the daemon owning `store`, `None` when no daemon listens
+pub fn request<T: AsRef<Path>>(store: T, request: &ControlRequest) -> io::Result<Option<Result<String, String>>> {
+    let mut stream = match UnixStream::connect(store.as_ref().join(CONTROL_SOCKET)) {
+        Ok(stream) => stream,
+        Err(e) if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::ConnectionRefused) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    writeln!(stream, "{}", request.to_line())?;
+    let mut reply = String::new();
+    BufReader::new(stream).read_line(&mut reply)?;
+    let reply = reply.trim_end();
+    if let Some(msg) = reply.strip_prefix("OK ") {
+        Ok(Some(Ok(msg.to_string())))
+    } else if let Some(msg) = reply.strip_prefix("ERR ") {
+        Ok(Some(Err(msg.to_string())))
+    } else {
+        Err(io::Error::new(ErrorKind::InvalidData, format!("Unexpected reply `{}`", reply)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduler::testing::*;
+    use crate::simulator::VirtualBoard;
+    use crate::store::testing::temporary_path;
+    use crate::store::{Setting, SettingValue};
+    use futures::channel::oneshot;
+
+    /// From another thread, the blocking client would stall the actor otherwise
+    async fn ask(dir: PathBuf, req: ControlRequest) -> Option<Result<String, String>> {
+        let (tx, rx) = oneshot::channel();
+        thread::spawn(move || tx.send(request(dir, &req).unwrap()));
+        rx.await.unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn requests_to_the_daemon() {
+        let bench = Bench::start("control", VirtualBoard::default(), |_| {});
+        let dir = temporary_path("control-socket");
+        std::fs::create_dir_all(&dir).unwrap();
+        assert_eq!(ask(dir.clone(), ControlRequest::AcknowledgeLockout).await, None);
+
+        let socket = ControlSocket::bind(&dir, bench.scheduler.clone()).unwrap();
+        assert!(bench.valve_closed().await);
+        bench.inspect(|actor, _| actor.lock_out("Test".to_string())).await;
+        assert_eq!(ask(dir.clone(), ControlRequest::AcknowledgeLockout).await, Some(Ok("Lockout acknowledged: Test".to_string())));
+        assert_eq!(bench.store.lockout(), None);
+        assert_eq!(ask(dir.clone(), ControlRequest::AcknowledgeLockout).await, Some(Ok("No lockout".to_string())));
+
+        let profile = ControlRequest::ApplyProfile { name: "lettuce".to_string() };
+        assert_eq!(ask(dir.clone(), profile.clone()).await, Some(Err("No profile named `lettuce`".to_string())));
+        bench.store.set(Setting::Tds1Thresh, SettingValue::Float(700.0)).unwrap();
+        bench.store.save_profile("lettuce").unwrap();
+        bench.store.set(Setting::Tds1Thresh, SettingValue::Float(600.0)).unwrap();
+        assert!(matches!(ask(dir.clone(), profile).await, Some(Ok(msg)) if msg.starts_with("Profile lettuce applied")));
+        assert_eq!(bench.store.get(Setting::Tds1Thresh), SettingValue::Float(700.0));
+        assert!(bench.journal().iter().any(|line| line == "Profile lettuce applied"));
+
+        drop(socket);
+        assert_eq!(ask(dir.clone(), ControlRequest::AcknowledgeLockout).await, None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
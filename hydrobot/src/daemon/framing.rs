@@ -0,0 +1,131 @@
+//! Framed variant of the line protocol.
+use std::fmt::{self, Display, Formatter};
+
+/// First protocol version understanding `V1 ON`
+pub const FRAMED_PROTOCOL_VERSION: u32 = 2;
+
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+pub fn encode(seq: u8, payload: &str) -> String {
+    let frame = format!("{}:{}", seq, payload);
+    let crc = crc16(frame.as_bytes());
+    format!("{}*{:04X}", frame, crc)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FrameError {
+    Malformed,
+    Checksum,
+}
+
+pub fn decode(line: &str) -> Result<(u8, &str), FrameError> {
+    let line = line.trim_end();
+    let star = line.rfind('*').ok_or(FrameError::Malformed)?;
+    let (frame, crc) = (&line[..star], &line[star + 1..]);
+    let colon = frame.find(':').ok_or(FrameError::Malformed)?;
+    let seq = frame[..colon].parse().map_err(|_| FrameError::Malformed)?;
+    let crc = u16::from_str_radix(crc, 16).map_err(|_| FrameError::Malformed)?;
+    if crc16(frame.as_bytes()) != crc {
+        return Err(FrameError::Checksum);
+    }
+    Ok((seq, &frame[colon + 1..]))
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FrameStats {
+    /// Valid frames received
+    pub received: u64,
+    /// Frames dropped because of a bad checksum or syntax
+    pub corrupted: u64,
+    /// Frames dropped because their sequence id was already seen
+    pub duplicated: u64,
+    /// Frames missing in the board sequence
+    pub lost: u64,
+    /// Host frames the board reported as corrupted (`ERR CRC`)
+    pub rejected: u64,
+}
+
+impl Display for FrameStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{} frames, {} corrupted, {} duplicated, {} lost, {} rejected by the board", self.received, self.corrupted, self.duplicated, self.lost, self.rejected)
+    }
+}
+
+/// Check the frames coming from the board
+#[derive(Debug, Default)]
+pub struct FrameDecoder {
+    last_seq: Option<u8>,
+}
+
+impl FrameDecoder {
+    /// Payload of `line` or `None` when the frame must be dropped
+    pub fn accept<'a>(&mut self, line: &'a str, stats: &mut FrameStats) -> Option<&'a str> {
+        match decode(line) {
+            Ok((seq, _)) if self.last_seq == Some(seq) => {
+                warn!("Duplicated frame dropped: {:?}", line);
+                stats.duplicated += 1;
+                None
+            },
+            Ok((seq, payload)) => {
+                if let Some(last_seq) = self.last_seq {
+                    stats.lost += seq.wrapping_sub(last_seq).wrapping_sub(1) as u64;
+                }
+                self.last_seq = Some(seq);
+                stats.received += 1;
+                Some(payload)
+            },
+            Err(e) => {
+                warn!("Corrupted frame dropped ({:?}): {:?}", e, line);
+                stats.corrupted += 1;
+                None
+            },
+        }
+    }
+}
+
+/// Frame the host commands
+#[derive(Debug, Default)]
+pub struct FrameEncoder {
+    seq: u8,
+}
+
+impl FrameEncoder {
+    pub fn encode(&mut self, payload: &str) -> String {
+        let frame = encode(self.seq, payload);
+        self.seq = self.seq.wrapping_add(1);
+        frame
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc16_ccitt_false() {
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+    }
+
+    #[test]
+    fn decode_rejects_corrupted_and_duplicated_frames() {
+        let mut stats = FrameStats::default();
+        let mut decoder = FrameDecoder::default();
+        let frame = encode(7, "OK G1 STATUS 18573");
+        assert_eq!(decode(&frame), Ok((7, "OK G1 STATUS 18573")));
+        assert_eq!(decoder.accept(&frame, &mut stats), Some("OK G1 STATUS 18573"));
+        assert_eq!(decoder.accept(&frame, &mut stats), None);
+        assert_eq!(decoder.accept(&frame.replace("18573", "18575"), &mut stats), None);
+        assert_eq!(decoder.accept("OK G1 STATUS 18573", &mut stats), None);
+        assert_eq!(decoder.accept(&encode(10, "OK M0"), &mut stats), Some("OK M0"));
+        assert_eq!(stats, FrameStats { received: 2, corrupted: 2, duplicated: 1, lost: 2, rejected: 0 });
+    }
+}
@@ -0,0 +1,1039 @@
+#![allow(non_local_definitions)]
+use actix::prelude::*;
+use serialport::{SerialPortSettings, SerialPortType, UsbPortInfo};
+use std::thread;
+use std::sync::{Arc, Mutex, atomic::{AtomicU64, Ordering}};
+use std::collections::VecDeque;
+use std::future::Future;
+use futures::channel::oneshot;
+use std::io::{Write, BufRead, BufReader, ErrorKind};
+use std::time::{Duration, Instant};
+use std::{fmt, fmt::{Formatter, Display}};
+use crate::scheduler::*;
+pub mod framing;
+pub mod session;
+pub mod transport;
+pub mod state;
+pub use state::*;
+use framing::*;
+pub use transport::{Transport, Endpoint, PortUrl};
+pub use framing::FrameStats;
+use session::{Direction, SessionRecorder};
+
+pub struct SerialDaemon {
+    reader: BufReader<Box<dyn Transport>>,
+    link: Arc<Mutex<Link>>,
+    pending: Arc<Mutex<VecDeque<PendingRequest>>>,
+    parser: ResponseParser,
+    /// Set once the board switched to framed replies
+    decoder: Option<FrameDecoder>,
+    stats: Arc<Mutex<FrameStats>>,
+    recorder: Option<SessionRecorder>,
+    config: PortConfig,
+    sceduler: Recipient<SchedulerRequest>,
+}
+
+bitflags! {
+    pub struct Status: u32 {
+        const NONE                          = 0;
+        const TDS_CONNECTED                 = ((1 << 0));
+        const PH_CONNECTED                  = ((1 << 2));
+        const TEMPERATURE_CONNECTED         = ((1 << 3));
+        const OSMOS_SWITCH_OPENED           = ((1 << 4));
+        const OSMOS_SWITCH_OPENING          = ((1 << 5));
+        const OSMOS_SWITCH_CLOSING          = ((1 << 6));
+        const OSMOS_SWITCH_CLOSED           = ((1 << 7));
+        const PERISTALIC_PUMP_ON            = ((1 << 8));
+        const PERISTALIC_PUMP_REV           = ((1 << 9));
+        const BRONCHUS_STANDBY_FULL         = ((1 << 10));
+        const BRONCHUS_STANDBY_SAMPLING     = ((1 << 11));
+        const BRONCHUS_WAIT_FULL            = ((1 << 12));
+        const BRONCHUS_WAIT_EMPTY           = ((1 << 13));
+        const BREATHING                     = ((1 << 14));
+    }
+}
+
+/// Where to find the board and how to talk to it
+#[derive(Debug, Clone)]
+pub struct PortConfig {
+    /// Explicit board location, the serial port is looked up by USB vid/pid when `None`
+    pub endpoint: Option<Endpoint>,
+    pub vid: u16,
+    pub pid: u16,
+    pub settings: SerialPortSettings,
+    /// Negotiate the framed protocol when the firmware supports it
+    pub framing: bool,
+}
+
+impl Default for PortConfig {
+    /// CH340 bridge of the Mega2560 clone running at `Serial.begin(9600)`
+    fn default() -> Self {
+        Self {
+            endpoint: None,
+            vid: 0x1a86,
+            pid: 0x7523,
+            settings: SerialPortSettings {
+                timeout: Duration::from_secs(10),
+                ..SerialPortSettings::default()
+            },
+            framing: true,
+        }
+    }
+}
+
+impl PortConfig {
+    pub fn matches(&self, port_type: &SerialPortType) -> bool {
+        matches!(port_type, SerialPortType::UsbPort(UsbPortInfo { vid, pid, .. }) if *vid == self.vid && *pid == self.pid)
+    }
+
+    /// Take the endpoint and the serial settings of an URL
+    pub fn set_url(&mut self, url: &PortUrl) {
+        self.endpoint = Some(url.endpoint.clone());
+        url.apply(&mut self.settings);
+    }
+
+    /// Path of the board serial port, either the explicit one or the first port matching vid/pid
+    pub fn find(&self) -> serialport::Result<Option<String>> {
+        match self.endpoint.as_ref() {
+            Some(Endpoint::Serial(path)) => return Ok(Some(path.clone())),
+            Some(Endpoint::Tcp(_)) => return Ok(None),
+            None => {},
+        }
+        Ok(serialport::available_ports()?
+            .into_iter()
+            .find(|port| self.matches(&port.port_type))
+            .map(|port| port.port_name))
+    }
+
+    pub fn open(&self) -> std::io::Result<Box<dyn Transport>> {
+        if let Some(Endpoint::Tcp(addr)) = self.endpoint.as_ref() {
+            return transport::connect_tcp(addr, self.settings.timeout);
+        }
+        match self.find()? {
+            Some(path) => Ok(Box::new(serialport::open_with_settings(&path, &self.settings)?)),
+            None => Err(std::io::Error::new(std::io::ErrorKind::NotFound, format!("No port matching {:04x}:{:04x}", self.vid, self.pid))),
+        }
+    }
+}
+
+/// State of the link with the board, reported to the scheduler on every change
+#[derive(Debug, Clone)]
+pub enum LinkState {
+    Connected(String),
+    Disconnected(String),
+    Reconnecting {
+        attempt: u32,
+        delay: Duration,
+    },
+}
+
+impl Display for LinkState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            LinkState::Connected(port) => write!(f, "connected ({})", port),
+            LinkState::Disconnected(reason) => write!(f, "disconnected ({})", reason),
+            LinkState::Reconnecting { attempt, delay } => write!(f, "reconnecting (attempt {} in {}s)", attempt, delay.as_secs()),
+        }
+    }
+}
+
+const RECONNECT_MIN_DELAY: Duration = Duration::from_secs(1);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(30);
+const HANDSHAKE_ATTEMPTS: u32 = 3;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Host side of the link, shared by the read loop and the handle
+struct Link {
+    port: Option<Box<dyn Transport>>,
+    /// Set once the board supports framing, commands are framed from then on
+    encoder: Option<FrameEncoder>,
+    recorder: Option<SessionRecorder>,
+}
+
+impl Link {
+    fn write_line(&mut self, line: &str) -> std::io::Result<()> {
+        let port = self.port.as_mut().ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotConnected, "Board disconnected"))?;
+        let line = match self.encoder.as_mut() {
+            Some(encoder) => encoder.encode(line),
+            None => line.to_string(),
+        };
+        if let Some(recorder) = self.recorder.as_ref() {
+            recorder.record(Direction::Tx, &line);
+        }
+        writeln!(port, "{}", line)
+    }
+}
+
+impl SerialDaemon {
+    /// Start the read loop on `port`, unsolicited results and link changes go to `sceduler`.
+    /// The link traffic is written to `recorder` when set
+    pub fn new(port: Box<dyn Transport>, config: PortConfig, recorder: Option<SessionRecorder>, sceduler: Recipient<SchedulerRequest>) -> SerialDaemonHandle {
+        let tty = port.try_clone().expect("Duplex not usported on the tty");
+        let name = port.name();
+        let link = Arc::new(Mutex::new(Link { port: Some(port), encoder: None, recorder: recorder.clone() }));
+        let pending = Arc::new(Mutex::new(VecDeque::new()));
+        let stats = Arc::new(Mutex::new(FrameStats::default()));
+        let mut daemon = SerialDaemon {
+            reader: BufReader::new(tty),
+            link: link.clone(),
+            pending: pending.clone(),
+            parser: ResponseParser::default(),
+            decoder: None,
+            stats: stats.clone(),
+            recorder,
+            config,
+            sceduler,
+        };
+        let read_loop = thread::spawn(move || daemon.run());
+        SerialDaemonHandle {
+            _read_loop: read_loop,
+            name,
+            link,
+            pending,
+            stats,
+            next_id: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    fn run(&mut self) {
+        let name = self.reader.get_ref().name();
+        self.record(Direction::Event, &format!("connected on {}", name));
+        self.negotiate();
+        loop {
+            let reason = self.read_loop();
+            error!("Board disconnected: {}", reason);
+            self.record(Direction::Event, &format!("disconnected: {}", reason));
+            self.link.lock().unwrap().port.take();
+            for request in self.pending.lock().unwrap().drain(..) {
+                let _ = request.reply.send(Err(SerialError::Disconnected));
+            }
+            let _ = self.sceduler.do_send(SchedulerRequest::Link { state: LinkState::Disconnected(reason) });
+            let name = self.reconnect();
+            info!("Board reconnected on {}", name);
+            self.record(Direction::Event, &format!("reconnected on {}", name));
+            self.negotiate();
+            let _ = self.sceduler.do_send(SchedulerRequest::Link { state: LinkState::Connected(name) });
+        }
+    }
+
+    /// Forward the board output to the scheduler until the link breaks
+    fn read_loop(&mut self) -> String {
+        // Kept across timeouts, a line may come in several reads
+        let mut line = String::new();
+        loop {
+            match self.reader.read_line(&mut line) {
+                Ok(0) => return "end of stream".to_string(),
+                Ok(_) => {
+                    if let Some((result, success)) = self.decode(&line) {
+                        self.dispatch(result, success);
+                    }
+                    line.clear();
+                }
+                // A board with nothing to say, ie: during a long dwell. A TCP socket reports it as `WouldBlock`
+                Err(e) if matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock) => {},
+                Err(e) => return e.to_string(),
+            }
+        }
+    }
+
+    /// Unframe and parse a line sent by the board
+    fn decode(&mut self, line: &str) -> Option<(SerialCommandResult, bool)> {
+        self.record(Direction::Rx, line);
+        let payload = match self.decoder.as_mut() {
+            Some(decoder) => decoder.accept(line, &mut self.stats.lock().unwrap())?,
+            None => line,
+        };
+        match self.parser.parse(payload) {
+            None if !self.parser.is_pending() => {
+                warn!("Failed to parse `{:?}`", line);
+                None
+            },
+            result => result,
+        }
+    }
+
+    fn record(&self, direction: Direction, line: &str) {
+        if let Some(recorder) = self.recorder.as_ref() {
+            recorder.record(direction, line);
+        }
+    }
+
+    /// Switch to the framed protocol when the firmware supports it, the link stays in plain text otherwise.
+    /// An unframed `V0` always brings the board back to plain text so this is safe on a board left framed.
+    fn negotiate(&mut self) {
+        self.decoder = None;
+        self.link.lock().unwrap().encoder = None;
+        if !self.config.framing {
+            return;
+        }
+        match self.exchange(SerialCommand::V0) {
+            Some(SerialCommandResult::V0 { version: Some(version) }) if version >= FRAMED_PROTOCOL_VERSION => {},
+            Some(result) => return info!("Framing not supported by the board (`{}`), using plain text", result),
+            None => return warn!("No reply to V0, using plain text"),
+        }
+        // The firmware accepts frames in both modes, its replies are framed once `OK V1 ON` is sent
+        self.link.lock().unwrap().encoder = Some(FrameEncoder::default());
+        match self.exchange(SerialCommand::V1 { on: true }) {
+            Some(SerialCommandResult::V1 { on: Some(true) }) => {
+                info!("Framed protocol enabled");
+                self.decoder = Some(FrameDecoder::default());
+            },
+            _ => {
+                warn!("Failed to enable framing, using plain text");
+                let mut link = self.link.lock().unwrap();
+                link.encoder = None;
+                let _ = link.write_line(&SerialCommand::V0.to_string());
+            },
+        }
+    }
+
+    /// Send `cmd` from the read loop and wait for its reply, other lines are dispatched meanwhile
+    fn exchange(&mut self, cmd: SerialCommand) -> Option<SerialCommandResult> {
+        if let Err(e) = self.reader.get_mut().set_timeout(HANDSHAKE_TIMEOUT) {
+            warn!("Failed to set the handshake timeout: {}", e);
+            return None;
+        }
+        let mut reply = None;
+        for _ in 0..HANDSHAKE_ATTEMPTS {
+            reply = self.exchange_once(cmd);
+            if reply.is_some() {
+                break;
+            }
+        }
+        let _ = self.reader.get_mut().set_timeout(self.config.settings.timeout);
+        reply
+    }
+
+    fn exchange_once(&mut self, cmd: SerialCommand) -> Option<SerialCommandResult> {
+        if let Err(e) = self.link.lock().unwrap().write_line(&cmd.to_string()) {
+            warn!("Failed to send {}: {}", cmd, e);
+            return None;
+        }
+        let deadline = Instant::now() + HANDSHAKE_TIMEOUT;
+        while Instant::now() < deadline {
+            let mut line = String::new();
+            match self.reader.read_line(&mut line) {
+                Ok(0) | Err(_) => return None,
+                Ok(_) => match self.decode(&line) {
+                    Some((result, _)) if cmd.is_answered_by(&result) => return Some(result),
+                    Some((result, success)) => self.dispatch(result, success),
+                    None => {},
+                },
+            }
+        }
+        None
+    }
+
+    /// Resolve the oldest request answered by `result`, unsolicited results go to the scheduler
+    fn dispatch(&mut self, result: SerialCommandResult, success: bool) {
+        if let SerialCommandResult::Error { error: BoardError::Checksum, .. } = result {
+            self.stats.lock().unwrap().rejected += 1;
+        }
+        let request = {
+            let mut pending = self.pending.lock().unwrap();
+            let mut candidates = pending.iter().map(|request| request.command.is_answered_by(&result));
+            // The firmware rejects a command as soon as it reads it while some successes are
+            // only reported once the hardware is done, so errors belong to the latest request.
+            let idx = if success { candidates.position(|e| e) } else { candidates.rposition(|e| e) };
+            idx.and_then(|idx| pending.remove(idx))
+        };
+        match request {
+            Some(request) => {
+                let _ = request.reply.send(if success { Ok(result) } else { Err(SerialError::Board(result)) });
+            },
+            None => {
+                let _ = self.sceduler.do_send(SchedulerRequest::Serial {result, success});
+            },
+        }
+    }
+
+    /// Re-scan for the board with an exponential backoff until it can be opened again
+    fn reconnect(&mut self) -> String {
+        let mut delay = RECONNECT_MIN_DELAY;
+        let mut attempt = 1;
+        loop {
+            let _ = self.sceduler.do_send(SchedulerRequest::Link { state: LinkState::Reconnecting { attempt, delay } });
+            thread::sleep(delay);
+            match self.config.open().and_then(|port| Ok((port.try_clone()?, port))) {
+                Ok((tty, port)) => {
+                    let name = port.name();
+                    self.reader = BufReader::new(tty);
+                    self.parser = ResponseParser::default();
+                    self.link.lock().unwrap().port.replace(port);
+                    return name;
+                },
+                Err(e) => warn!("Reconnection attempt {} failed: {}", attempt, e),
+            }
+            delay = (delay * 2).min(RECONNECT_MAX_DELAY);
+            attempt += 1;
+        }
+    }
+}
+
+pub struct SerialDaemonHandle {
+    link: Arc<Mutex<Link>>,
+    pending: Arc<Mutex<VecDeque<PendingRequest>>>,
+    stats: Arc<Mutex<FrameStats>>,
+    next_id: Arc<AtomicU64>,
+    name: String,
+    _read_loop: thread::JoinHandle<()>,
+}
+
+pub type SerialResult<T> = Result<T, SerialError>;
+
+#[derive(Debug, Fail)]
+pub enum SerialError {
+    #[fail(display = "Board disconnected")]
+    Disconnected,
+    #[fail(display = "No reply from the board after {:?}", _0)]
+    Timeout(Duration),
+    #[fail(display = "Board replied `{}`", _0)]
+    Board(SerialCommandResult),
+    #[fail(display = "{}", _0)]
+    Io(std::io::Error),
+}
+
+/// Command waiting for its reply
+struct PendingRequest {
+    id: u64,
+    command: SerialCommand,
+    reply: oneshot::Sender<SerialResult<SerialCommandResult>>,
+}
+
+/// Error reported by the firmware with an `ERR` line
+#[derive(Debug, Clone, PartialEq)]
+pub enum BoardError {
+    /// `ERR S0 BUSY`, the valve is still moving
+    Busy,
+    /// `ERR <CMD> BAD_REQUEST`
+    BadRequest,
+    /// `ERR OVERFLOW`, the command didn't fit into the 64 bytes command buffer
+    Overflow,
+    /// `ERR CAL`, no calibration found in the EEPROM on boot, defaults were loaded
+    Calibration,
+    /// `ERR UNKNOW` followed by the rejected command
+    Unknown,
+    /// `PROCESS ERROR EMPTY COMMAND`
+    EmptyCommand,
+    /// `ERR CRC`, a framed command was corrupted (or an unframed one was sent in framed mode)
+    Checksum,
+    Other(String),
+}
+
+impl Display for BoardError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            BoardError::Busy => write!(f, "BUSY"),
+            BoardError::BadRequest => write!(f, "BAD_REQUEST"),
+            BoardError::Overflow => write!(f, "OVERFLOW"),
+            BoardError::Calibration => write!(f, "CAL"),
+            BoardError::Unknown => write!(f, "UNKNOW"),
+            BoardError::EmptyCommand => write!(f, "EMPTY COMMAND"),
+            BoardError::Checksum => write!(f, "CRC"),
+            BoardError::Other(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PeristalticPumpMode {
+    On,
+    Off,
+    Rev,
+}
+
+impl Display for PeristalticPumpMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            PeristalticPumpMode::On => write!(f, "ON"),
+            PeristalticPumpMode::Off => write!(f, "OFF"),
+            PeristalticPumpMode::Rev => write!(f, "REV"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BronchusMode {
+    Fill,
+    Empty,
+}
+
+impl Display for BronchusMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            BronchusMode::Fill => write!(f, "FILL"),
+            BronchusMode::Empty => write!(f, "EMPTY"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SerialCommandResult {
+    G0 {
+        tds_1: Option<f64>,
+        ph_1: Option<f64>,
+    },
+    G1 {
+        tds_1: Option<f64>,
+        ph_1: Option<f64>,
+        t_1: Option<f64>,
+        status: Option<Status>,
+    },
+    M0,
+    M1 {
+        tds_1: Option<(i64, i64)>,
+    },
+    M2 {
+        tds_1: Option<(i64, i64)>,
+    },
+    S0 {
+        on: Option<bool>,
+    },
+    S1 {
+        mode: Option<PeristalticPumpMode>,
+    },
+    S2 {
+        mode: Option<BronchusMode>,
+    },
+    V0 {
+        version: Option<u32>,
+    },
+    V1 {
+        on: Option<bool>,
+    },
+    /// Any `ERR` line, `command` is the rejected command when the firmware tells it
+    Error {
+        command: Option<String>,
+        error: BoardError,
+    },
+    Unknown {
+        raw: String,
+    }
+}
+
+impl SerialCommandResult {
+    fn from_string(val: &str) -> Option<(SerialCommandResult, bool)> {
+        info!("Receive {:?}", val);
+        let mut parts = val.split(' ').map(|e| e.trim().to_uppercase()).filter(|e| !e.is_empty());
+        let success = match parts.next()?.as_str() {
+            "OK" => true,
+            "ERR" => false,
+            "PROCESS" => return Some((SerialCommandResult::Error { command: None, error: BoardError::EmptyCommand }, false)),
+            _ => return None,
+        };
+        let command = parts.next()?;
+        if !success {
+            let (command, error) = match (command.as_str(), parts.next()) {
+                ("OVERFLOW", _) => (None, BoardError::Overflow),
+                ("CAL", _) => (None, BoardError::Calibration),
+                ("UNKNOW", _) => (None, BoardError::Unknown),
+                ("CRC", _) => (None, BoardError::Checksum),
+                (_, Some(reason)) if reason == "BUSY" => (Some(command), BoardError::Busy),
+                (_, Some(reason)) if reason == "BAD_REQUEST" => (Some(command), BoardError::BadRequest),
+                (_, reason) => (Some(command), BoardError::Other(reason.unwrap_or_default())),
+            };
+            return Some((SerialCommandResult::Error { command, error }, false));
+        }
+        match command.as_str() {
+            "M0" => Some((SerialCommandResult::M0, success)),
+            "M1" | "M2" => {
+                let mut tds_1 = None;
+                while let Some(part) = parts.next() {
+                    match part.as_str() {
+                        "TDS1" => {
+                            tds_1 = Some((parts.next()?.parse().ok()?, parts.next()?.parse().ok()?));
+                        },
+                        _ => None?
+                    }
+                }
+                if command == "M1" {
+                    Some((SerialCommandResult::M1 { tds_1 }, success))
+                } else {
+                    Some((SerialCommandResult::M2 { tds_1 }, success))
+                }
+            },
+            "S0" => {
+                let on: Option<bool> = parts.next().map(|e| e.eq("ON"));
+                Some((SerialCommandResult::S0 { on }, success))
+            },
+            "S1" => {
+                let mode = match parts.next().as_deref() {
+                    Some("ON") => Some(PeristalticPumpMode::On),
+                    Some("OFF") => Some(PeristalticPumpMode::Off),
+                    Some("REV") => Some(PeristalticPumpMode::Rev),
+                    _ => None,
+                };
+                Some((SerialCommandResult::S1 { mode }, success))
+            },
+            "S2" => {
+                let mode = match parts.next().as_deref() {
+                    Some("FILL") => Some(BronchusMode::Fill),
+                    Some("EMPTY") => Some(BronchusMode::Empty),
+                    _ => None,
+                };
+                Some((SerialCommandResult::S2 { mode }, success))
+            },
+            "V0" => {
+                let version = parts.next().and_then(|e| e.parse().ok());
+                Some((SerialCommandResult::V0 { version }, success))
+            },
+            "V1" => {
+                let on: Option<bool> = parts.next().map(|e| e.eq("ON"));
+                Some((SerialCommandResult::V1 { on }, success))
+            },
+            "G0" | "G1" => {
+                let mut tds_1: Option<f64> = None;
+                let mut t_1: Option<f64> = None;
+                let mut ph_1: Option<f64> = None;
+                let mut status: Option<Status> = None;
+                while let Some(part) = parts.next() {
+                    match part.as_str() {
+                        "TDS1" => {
+                            tds_1 = Some(parts.next()?.parse().ok()?);
+                        },
+                        "PH1" => {
+                            ph_1 = Some(parts.next()?.parse().ok()?);
+                        },
+                        "T1" => {
+                            t_1 = Some(parts.next()?.parse().ok()?);
+                        },
+                        "STATUS" => {
+                            let raw_status: u32 = parts.next()?.parse().ok()?;
+                            status = Status::from_bits(raw_status);
+                        },
+                        _ => None?
+                    }
+                }
+                if command == "G0" {
+                    Some((SerialCommandResult::G0{ tds_1, ph_1 }, success))
+                } else {
+                    Some((SerialCommandResult::G1{ tds_1, ph_1, t_1, status }, success))
+                }
+            },
+            _ => {
+                warn!("Unknown command: {:?}", command);
+                Some((SerialCommandResult::Unknown { raw: val.trim().to_string() }, success))
+            }
+        }
+    }
+}
+
+/// Formats results the way the firmware prints them
+impl Display for SerialCommandResult {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        fn calibration(f: &mut Formatter<'_>, tds_1: &Option<(i64, i64)>) -> fmt::Result {
+            match tds_1 {
+                Some((low, high)) => write!(f, " TDS1 {} {}", low, high),
+                None => Ok(()),
+            }
+        }
+        match self {
+            SerialCommandResult::G0 { tds_1, ph_1 } => {
+                write!(f, "OK G0")?;
+                if let Some(tds_1) = tds_1 { write!(f, " TDS1 {}", tds_1)?; }
+                if let Some(ph_1) = ph_1 { write!(f, " PH1 {}", ph_1)?; }
+                Ok(())
+            },
+            SerialCommandResult::G1 { tds_1, ph_1, t_1, status } => {
+                write!(f, "OK G1")?;
+                if let Some(tds_1) = tds_1 { write!(f, " TDS1 {:.2}", tds_1)?; }
+                if let Some(ph_1) = ph_1 { write!(f, " PH1 {:.2}", ph_1)?; }
+                if let Some(t_1) = t_1 { write!(f, " T1 {:.2}", t_1)?; }
+                if let Some(status) = status { write!(f, " STATUS {}", status.bits())?; }
+                Ok(())
+            },
+            SerialCommandResult::M0 => write!(f, "OK M0"),
+            SerialCommandResult::M1 { tds_1 } => {
+                write!(f, "OK M1 ")?;
+                calibration(f, tds_1)
+            },
+            SerialCommandResult::M2 { tds_1 } => {
+                write!(f, "OK M2 ")?;
+                calibration(f, tds_1)
+            },
+            SerialCommandResult::S0 { on: Some(on) } => write!(f, "OK S0 {}", if *on { "ON" } else { "OFF" }),
+            SerialCommandResult::S0 { on: None } => write!(f, "OK S0"),
+            SerialCommandResult::S1 { mode: Some(mode) } => write!(f, "OK S1 {}", mode),
+            SerialCommandResult::S1 { mode: None } => write!(f, "OK S1"),
+            SerialCommandResult::S2 { mode: Some(mode) } => write!(f, "OK S2 {}", mode),
+            SerialCommandResult::S2 { mode: None } => write!(f, "OK S2"),
+            SerialCommandResult::V0 { version: Some(version) } => write!(f, "OK V0 {}", version),
+            SerialCommandResult::V0 { version: None } => write!(f, "OK V0"),
+            SerialCommandResult::V1 { on: Some(on) } => write!(f, "OK V1 {}", if *on { "ON" } else { "OFF" }),
+            SerialCommandResult::V1 { on: None } => write!(f, "OK V1"),
+            SerialCommandResult::Error { error: BoardError::EmptyCommand, .. } => write!(f, "PROCESS ERROR EMPTY COMMAND"),
+            SerialCommandResult::Error { command: Some(command), error: BoardError::Unknown } => write!(f, "ERR UNKNOW\r\n{}", command),
+            SerialCommandResult::Error { command: Some(command), error } => write!(f, "ERR {} {}", command, error),
+            SerialCommandResult::Error { command: None, error } => write!(f, "ERR {}", error),
+            SerialCommandResult::Unknown { raw } => write!(f, "{}", raw),
+        }
+    }
+}
+
+/// Turns the board output into results, `ERR UNKNOW` is followed by a line echoing the rejected command
+#[derive(Default)]
+pub struct ResponseParser {
+    unknown_command: bool,
+}
+
+impl ResponseParser {
+    /// Tell if the parser waits for the next line to complete a result
+    pub fn is_pending(&self) -> bool {
+        self.unknown_command
+    }
+
+    pub fn parse(&mut self, line: &str) -> Option<(SerialCommandResult, bool)> {
+        if self.unknown_command {
+            self.unknown_command = false;
+            let command = Some(line.trim().to_uppercase());
+            return Some((SerialCommandResult::Error { command, error: BoardError::Unknown }, false));
+        }
+        match SerialCommandResult::from_string(line) {
+            Some((SerialCommandResult::Error { error: BoardError::Unknown, .. }, _)) => {
+                self.unknown_command = true;
+                None
+            },
+            result => result,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SerialCommand {
+    /// Get raw sensore values
+    G0,
+    /// Get filtred sensore values
+    G1,
+    /// Reset the calibration to the firmware defaults
+    M0,
+    /// Set the TDS probe calibration
+    M1 {
+        tds_1: (i64, i64),
+    },
+    /// Read the calibration
+    M2,
+    /// Osmosis water valve
+    S0 {
+        on: bool,
+    },
+    /// Peristaltic pump (pH Down)
+    S1 {
+        mode: PeristalticPumpMode,
+    },
+    /// Bronchus pumps
+    S2 {
+        mode: BronchusMode,
+    },
+    /// Protocol version, resets the link to plain text
+    V0,
+    /// Framed protocol
+    V1 {
+        on: bool,
+    },
+}
+
+impl Display for SerialCommand {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            SerialCommand::G0 => write!(f, "G0"),
+            SerialCommand::G1 => write!(f, "G1"),
+            SerialCommand::M0 => write!(f, "M0"),
+            SerialCommand::M1 { tds_1: (low, high) } => write!(f, "M1 TDS1 {} {}", low, high),
+            SerialCommand::M2 => write!(f, "M2"),
+            SerialCommand::S0 { on} => write!(f, "S0 {}", if *on {"ON"} else {"OFF"}),
+            SerialCommand::S1 { mode } => write!(f, "S1 {}", mode),
+            SerialCommand::S2 { mode } => write!(f, "S2 {}", mode),
+            SerialCommand::V0 => write!(f, "V0"),
+            SerialCommand::V1 { on } => write!(f, "V1 {}", if *on {"ON"} else {"OFF"}),
+        }
+    }
+}
+
+#[derive(Debug, Fail)]
+#[fail(display = "Invalid command: `{}`", _0)]
+pub struct ParseCommandError(String);
+
+impl std::str::FromStr for SerialCommand {
+    type Err = ParseCommandError;
+
+    fn from_str(val: &str) -> Result<Self, Self::Err> {
+        let invalid = || ParseCommandError(val.to_string());
+        let parts: Vec<String> = val.split_whitespace().map(|e| e.to_uppercase()).collect();
+        let parts: Vec<&str> = parts.iter().map(|e| e.as_str()).collect();
+        match parts.as_slice() {
+            ["G0"] => Ok(SerialCommand::G0),
+            ["G1"] => Ok(SerialCommand::G1),
+            ["M0"] => Ok(SerialCommand::M0),
+            ["M1", "TDS1", low, high] => Ok(SerialCommand::M1 {
+                tds_1: (low.parse().map_err(|_| invalid())?, high.parse().map_err(|_| invalid())?),
+            }),
+            ["M2"] => Ok(SerialCommand::M2),
+            ["S0", "ON"] => Ok(SerialCommand::S0 { on: true }),
+            ["S0", "OFF"] => Ok(SerialCommand::S0 { on: false }),
+            ["S1", "ON"] => Ok(SerialCommand::S1 { mode: PeristalticPumpMode::On }),
+            ["S1", "OFF"] => Ok(SerialCommand::S1 { mode: PeristalticPumpMode::Off }),
+            ["S1", "REV"] => Ok(SerialCommand::S1 { mode: PeristalticPumpMode::Rev }),
+            ["S2", "FILL"] => Ok(SerialCommand::S2 { mode: BronchusMode::Fill }),
+            ["S2", "EMPTY"] => Ok(SerialCommand::S2 { mode: BronchusMode::Empty }),
+            ["V0"] => Ok(SerialCommand::V0),
+            ["V1", "ON"] => Ok(SerialCommand::V1 { on: true }),
+            ["V1", "OFF"] => Ok(SerialCommand::V1 { on: false }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl SerialCommand {
+    /// Command keyword as echoed back by the firmware
+    pub fn name(&self) -> &'static str {
+        match self {
+            SerialCommand::G0 => "G0",
+            SerialCommand::G1 => "G1",
+            SerialCommand::M0 => "M0",
+            SerialCommand::M1 { .. } => "M1",
+            SerialCommand::M2 => "M2",
+            SerialCommand::S0 { .. } => "S0",
+            SerialCommand::S1 { .. } => "S1",
+            SerialCommand::S2 { .. } => "S2",
+            SerialCommand::V0 => "V0",
+            SerialCommand::V1 { .. } => "V1",
+        }
+    }
+
+    /// Tell if a line sent by the board is the reply to this command
+    pub fn is_answered_by(&self, result: &SerialCommandResult) -> bool {
+        match (self, result) {
+            (_, SerialCommandResult::Error { command: Some(command), .. }) => command == self.name(),
+            (SerialCommand::G0, SerialCommandResult::G0 { .. }) => true,
+            (SerialCommand::G1, SerialCommandResult::G1 { .. }) => true,
+            (SerialCommand::M0, SerialCommandResult::M0) => true,
+            (SerialCommand::M1 { .. }, SerialCommandResult::M1 { .. }) => true,
+            (SerialCommand::M2, SerialCommandResult::M2 { .. }) => true,
+            // The valve also reports its state by itself (ie: `OK S0 OFF` on boot)
+            (SerialCommand::S0 { on }, SerialCommandResult::S0 { on: reply }) => *reply == Some(*on),
+            (SerialCommand::S1 { mode }, SerialCommandResult::S1 { mode: reply }) => *reply == Some(*mode),
+            (SerialCommand::S2 { mode }, SerialCommandResult::S2 { mode: reply }) => *reply == Some(*mode),
+            (SerialCommand::V0, SerialCommandResult::V0 { .. }) => true,
+            (SerialCommand::V1 { on }, SerialCommandResult::V1 { on: reply }) => *reply == Some(*on),
+            _ => false,
+        }
+    }
+
+    /// Maximum time the firmware takes to answer
+    pub fn timeout(&self) -> Duration {
+        match self {
+            // The valve stepper replies once it has finished to move
+            SerialCommand::S0 { .. } => Duration::from_secs(10),
+            SerialCommand::S2 { .. } => Duration::from_millis(500),
+            _ => Duration::from_secs(2),
+        }
+    }
+
+    /// Result assumed when the firmware stays silent, `S2` only reports errors
+    pub fn implicit_reply(&self) -> Option<SerialCommandResult> {
+        match self {
+            SerialCommand::S2 { mode } => Some(SerialCommandResult::S2 { mode: Some(*mode) }),
+            _ => None,
+        }
+    }
+}
+
+impl SerialDaemonHandle {
+    /// Send a command and wait for its reply using the command default timeout
+    pub fn request(&mut self, cmd: SerialCommand) -> impl Future<Output = SerialResult<SerialCommandResult>> {
+        self.request_with_timeout(cmd, cmd.timeout())
+    }
+
+    /// Send a command and resolve with its reply, a board error or a timeout.
+    /// The reply is not forwarded to the scheduler as a `SchedulerRequest::Serial`.
+    pub fn request_with_timeout(&mut self, cmd: SerialCommand, timeout: Duration) -> impl Future<Output = SerialResult<SerialCommandResult>> {
+        let (reply, rx) = oneshot::channel();
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let pending = self.pending.clone();
+        pending.lock().unwrap().push_back(PendingRequest { id, command: cmd, reply });
+        let sent = self.send(cmd);
+        async move {
+            if let Err(e) = sent {
+                pending.lock().unwrap().retain(|request| request.id != id);
+                return Err(SerialError::Io(e));
+            }
+            match actix_rt::time::timeout(timeout, rx).await {
+                Ok(Ok(result)) => result,
+                Ok(Err(_)) => Err(SerialError::Disconnected),
+                Err(_) => {
+                    pending.lock().unwrap().retain(|request| request.id != id);
+                    cmd.implicit_reply().ok_or(SerialError::Timeout(timeout))
+                },
+            }
+        }
+    }
+
+    /// Stop waiting for the reply to `cmd`, its request resolves with `SerialError::Disconnected`
+    pub fn forget(&mut self, cmd: SerialCommand) {
+        let mut pending = self.pending.lock().unwrap();
+        if let Some(idx) = pending.iter().position(|request| request.command == cmd) {
+            pending.remove(idx);
+        }
+    }
+
+    /// Fire and forget, the reply will be forwarded to the scheduler
+    pub fn send(&mut self, cmd: SerialCommand) -> std::io::Result<()> {
+        self.link.lock().unwrap().write_line(&cmd.to_string())
+    }
+
+    /// Tell if the framed protocol is in use
+    pub fn is_framed(&self) -> bool {
+        self.link.lock().unwrap().encoder.is_some()
+    }
+
+    /// Integrity counters of the framed protocol since the start
+    pub fn frame_stats(&self) -> FrameStats {
+        *self.stats.lock().unwrap()
+    }
+
+    /// Name of the port the board was first connected on
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::VirtualBoard;
+
+    /// Output captured from the firmware, one entry per reply
+    const CAPTURED: &[&str] = &[
+        "OK G0 TDS1 512 PH1 498\r\n",
+        "OK G1 TDS1 513.05 PH1 6.79 T1 21.50 STATUS 18573\r\n",
+        "OK G1 STATUS 18501\r\n",
+        "OK M0\r\n",
+        "OK M1  TDS1 538 127\r\n",
+        "OK M2  TDS1 538 127\r\n",
+        "OK S0 ON\r\n",
+        "OK S0 OFF\r\n",
+        "OK S1 ON\r\n",
+        "OK S1 OFF\r\n",
+        "OK S1 REV\r\n",
+        "ERR S0 BUSY\r\n",
+        "ERR S0 BAD_REQUEST\r\n",
+        "ERR S1 BAD_REQUEST\r\n",
+        "ERR S2 BAD_REQUEST\r\n",
+        "ERR OVERFLOW\r\n",
+        "ERR CAL\r\n",
+        "ERR UNKNOW\r\nX9\r\n",
+        "PROCESS ERROR EMPTY COMMAND\r\n",
+        "OK V0 2\r\n",
+        "OK V1 ON\r\n",
+        "ERR CRC\r\n",
+    ];
+
+    fn parse_all(output: &str) -> Vec<(SerialCommandResult, bool)> {
+        let mut parser = ResponseParser::default();
+        output.lines().filter_map(|line| parser.parse(line)).collect()
+    }
+
+    fn commands() -> Vec<SerialCommand> {
+        vec![
+            SerialCommand::G0,
+            SerialCommand::G1,
+            SerialCommand::M0,
+            SerialCommand::M1 { tds_1: (540, 130) },
+            SerialCommand::M2,
+            SerialCommand::S0 { on: true },
+            SerialCommand::S0 { on: false },
+            SerialCommand::S1 { mode: PeristalticPumpMode::On },
+            SerialCommand::S1 { mode: PeristalticPumpMode::Rev },
+            SerialCommand::S1 { mode: PeristalticPumpMode::Off },
+            SerialCommand::S2 { mode: BronchusMode::Fill },
+            SerialCommand::S2 { mode: BronchusMode::Empty },
+            SerialCommand::V0,
+            SerialCommand::V1 { on: false },
+        ]
+    }
+
+    #[test]
+    fn parse_captured_output() {
+        let results: Vec<SerialCommandResult> = CAPTURED.iter().map(|line| parse_all(line).remove(0).0).collect();
+        assert_eq!(results[0], SerialCommandResult::G0 { tds_1: Some(512.0), ph_1: Some(498.0) });
+        assert_eq!(results[1], SerialCommandResult::G1 {
+            tds_1: Some(513.05),
+            ph_1: Some(6.79),
+            t_1: Some(21.5),
+            status: Some(Status::TDS_CONNECTED | Status::PH_CONNECTED | Status::TEMPERATURE_CONNECTED | Status::OSMOS_SWITCH_CLOSED | Status::BRONCHUS_STANDBY_SAMPLING | Status::BREATHING),
+        });
+        assert_eq!(results[4], SerialCommandResult::M1 { tds_1: Some((538, 127)) });
+        assert_eq!(results[10], SerialCommandResult::S1 { mode: Some(PeristalticPumpMode::Rev) });
+        assert_eq!(results[11], SerialCommandResult::Error { command: Some("S0".to_string()), error: BoardError::Busy });
+        assert_eq!(results[15], SerialCommandResult::Error { command: None, error: BoardError::Overflow });
+        assert_eq!(results[16], SerialCommandResult::Error { command: None, error: BoardError::Calibration });
+        assert_eq!(results[17], SerialCommandResult::Error { command: Some("X9".to_string()), error: BoardError::Unknown });
+        assert_eq!(results[18], SerialCommandResult::Error { command: None, error: BoardError::EmptyCommand });
+        assert_eq!(results[19], SerialCommandResult::V0 { version: Some(2) });
+        assert_eq!(results[21], SerialCommandResult::Error { command: None, error: BoardError::Checksum });
+    }
+
+    #[test]
+    fn results_round_trip() {
+        for line in CAPTURED {
+            let results = parse_all(line);
+            assert_eq!(results.len(), 1, "{:?}", line);
+            let (result, success) = &results[0];
+            assert_eq!(*success, line.starts_with("OK"), "{:?}", line);
+            assert_eq!(format!("{}\r\n", result), *line);
+        }
+    }
+
+    #[test]
+    fn commands_round_trip() {
+        for cmd in commands() {
+            assert_eq!(cmd.to_string().parse::<SerialCommand>().unwrap(), cmd);
+            assert_eq!(cmd.to_string().to_lowercase().parse::<SerialCommand>().unwrap(), cmd);
+        }
+        assert!("S0 MAYBE".parse::<SerialCommand>().is_err());
+        assert!("M1 TDS1 12".parse::<SerialCommand>().is_err());
+    }
+
+    #[test]
+    fn virtual_board_answers_every_command() {
+        let mut board = VirtualBoard::default();
+        board.run_until(Duration::from_secs(12));
+        board.take_output();
+        for cmd in commands() {
+            board.receive(format!("{}\n", cmd).as_bytes());
+            board.run_until(board.elapsed() + Duration::from_secs(3));
+            let results = parse_all(&board.take_output());
+            let answered = results.iter().any(|(result, _)| cmd.is_answered_by(result));
+            assert!(answered || cmd.implicit_reply().is_some(), "{} got {:?}", cmd, results);
+        }
+    }
+
+    #[test]
+    fn virtual_board_negotiates_framing() {
+        let mut board = VirtualBoard::default();
+        let mut encoder = FrameEncoder::default();
+        let mut decoder = FrameDecoder::default();
+        let mut stats = FrameStats::default();
+        board.run_until(Duration::from_secs(12));
+        board.take_output();
+        board.receive(b"V0\n");
+        board.receive(format!("{}\n", encoder.encode("V1 ON")).as_bytes());
+        board.run_until(board.elapsed() + Duration::from_secs(1));
+        assert_eq!(board.take_output(), "OK V0 2\r\nOK V1 ON\r\n");
+
+        let frame = encoder.encode("G0");
+        board.receive(format!("{}\n{}\n", frame, frame).as_bytes());
+        board.receive(format!("{}\n", encoder.encode("M2").replace("M2", "M0")).as_bytes());
+        board.receive(b"M2\n");
+        board.run_until(board.elapsed() + Duration::from_secs(1));
+        let output = board.take_output();
+        let payloads: Vec<&str> = output.lines().filter_map(|line| decoder.accept(line, &mut stats)).collect();
+        assert_eq!(payloads.len(), 3, "{:?}", output);
+        assert!(payloads[0].starts_with("OK G0"));
+        assert_eq!(payloads[1..], ["ERR CRC", "ERR CRC"]);
+        assert_eq!(stats, FrameStats { received: 3, ..FrameStats::default() });
+
+        board.receive(b"V0\n");
+        board.run_until(board.elapsed() + Duration::from_secs(1));
+        assert_eq!(board.take_output(), "OK V0 2\r\n");
+    }
+}
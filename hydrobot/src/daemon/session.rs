@@ -0,0 +1,196 @@
+//! Serial traffic sessions.
+use super::{framing::FrameDecoder, FrameStats, ResponseParser, SerialCommandResult};
+use serialport::{SerialPort, posix::TTYPort};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::thread;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    /// Host to board
+    Tx,
+    /// Board to host
+    Rx,
+    /// Link event (open, disconnection, reconnection...)
+    Event,
+}
+
+impl Direction {
+    fn symbol(self) -> &'static str {
+        match self {
+            Direction::Tx => ">",
+            Direction::Rx => "<",
+            Direction::Event => "#",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub at: Duration,
+    pub direction: Direction,
+    pub line: String,
+}
+
+/// Append the link traffic to a session file, shared by the read loop and the handle
+#[derive(Clone)]
+pub struct SessionRecorder {
+    file: Arc<Mutex<BufWriter<File>>>,
+    started: Instant,
+}
+
+impl SessionRecorder {
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).write(true).truncate(true).open(path)?;
+        let recorder = Self {
+            file: Arc::new(Mutex::new(BufWriter::new(file))),
+            started: Instant::now(),
+        };
+        recorder.record(Direction::Event, &format!("session started {}", chrono::Local::now().to_rfc3339()));
+        Ok(recorder)
+    }
+
+    /// Entries are flushed right away so a session survives a crash of the host
+    pub fn record(&self, direction: Direction, line: &str) {
+        let at = self.started.elapsed().as_millis();
+        let mut file = self.file.lock().unwrap();
+        let res = writeln!(file, "{}\t{}\t{}", at, direction.symbol(), line.trim_end()).and_then(|_| file.flush());
+        if let Err(e) = res {
+            warn!("Failed to record the serial session: {}", e);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Session {
+    pub entries: Vec<Entry>,
+}
+
+impl Session {
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut entries = Vec::new();
+        for (idx, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+            let line = line?;
+            if line.is_empty() {
+                continue;
+            }
+            let entry = Self::parse_entry(&line).ok_or_else(|| {
+                io::Error::new(ErrorKind::InvalidData, format!("Invalid session entry line {}: {:?}", idx + 1, line))
+            })?;
+            entries.push(entry);
+        }
+        Ok(Self { entries })
+    }
+
+    fn parse_entry(line: &str) -> Option<Entry> {
+        let mut parts = line.splitn(3, '\t');
+        let at = Duration::from_millis(parts.next()?.parse().ok()?);
+        let direction = match parts.next()? {
+            ">" => Direction::Tx,
+            "<" => Direction::Rx,
+            "#" => Direction::Event,
+            _ => return None,
+        };
+        Some(Entry { at, direction, line: parts.next().unwrap_or_default().to_string() })
+    }
+
+    /// Board results in the order the daemon decoded them, to feed `SchedulerActor` without a link
+    pub fn board_results(&self) -> Vec<(Duration, SerialCommandResult, bool)> {
+        let mut parser = ResponseParser::default();
+        let mut decoder: Option<FrameDecoder> = None;
+        let mut stats = FrameStats::default();
+        let mut results = Vec::new();
+        for entry in self.entries.iter().filter(|entry| entry.direction == Direction::Rx) {
+            let payload = match decoder.as_mut() {
+                Some(decoder) => match decoder.accept(&entry.line, &mut stats) {
+                    Some(payload) => payload,
+                    None => continue,
+                },
+                None => entry.line.as_str(),
+            };
+            if let Some((result, success)) = parser.parse(payload) {
+                match result {
+                    SerialCommandResult::V0 { .. } | SerialCommandResult::V1 { on: Some(false) } => decoder = None,
+                    SerialCommandResult::V1 { on: Some(true) } => decoder = Some(FrameDecoder::default()),
+                    _ => {},
+                }
+                results.push((entry.at, result, success));
+            }
+        }
+        results
+    }
+}
+
+/// Play the board side of a session on a new pseudo-terminal and return the host side of it.
+/// Board lines are written at their recorded time divided by `speed`, host commands are only logged.
+pub fn replay(session: Session, speed: f64) -> serialport::Result<Box<dyn SerialPort>> {
+    let (mut master, slave) = TTYPort::pair()?;
+    master.set_timeout(Duration::from_millis(10))?;
+    info!("Replaying {} entries on {}", session.entries.len(), slave.name().unwrap_or_default());
+    thread::spawn(move || {
+        let started = Instant::now();
+        let mut buffer = [0u8; 64];
+        let mut entries = session.entries.into_iter().filter(|entry| entry.direction == Direction::Rx).peekable();
+        loop {
+            if let Some(entry) = entries.peek() {
+                if started.elapsed() >= entry.at.div_f64(speed) {
+                    if master.write_all(format!("{}\r\n", entry.line).as_bytes()).is_err() {
+                        warn!("Replay disconnected !");
+                        break;
+                    }
+                    if entries.next().is_some() && entries.peek().is_none() {
+                        info!("Replay finished");
+                    }
+                    continue;
+                }
+            }
+            // The port stays open once the session is over so the daemon doesn't try to reconnect
+            match master.read(&mut buffer) {
+                Ok(len) => debug!("Replay ignores {:?}", String::from_utf8_lossy(&buffer[..len])),
+                Err(e) if e.kind() == ErrorKind::TimedOut => {},
+                Err(e) => {
+                    warn!("Replay disconnected: {:?}", e);
+                    break;
+                },
+            }
+        }
+    });
+    Ok(Box::new(slave))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::daemon::framing;
+    use crate::store::testing::*;
+
+    #[test]
+    fn record_and_load() {
+        let path = temporary_path("session");
+        let recorder = SessionRecorder::create(&path).unwrap();
+        recorder.record(Direction::Tx, "V0\n");
+        recorder.record(Direction::Rx, "OK V0 2\r\n");
+        recorder.record(Direction::Tx, &framing::encode(0, "V1 ON"));
+        recorder.record(Direction::Rx, "OK V1 ON\r\n");
+        recorder.record(Direction::Rx, &framing::encode(0, "OK S0 OFF"));
+        recorder.record(Direction::Rx, &framing::encode(0, "OK S0 OFF"));
+        recorder.record(Direction::Rx, &framing::encode(1, "OK G1 STATUS 18573"));
+        let session = Session::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(session.entries.len(), 8);
+        assert_eq!(session.entries[0].direction, Direction::Event);
+        assert_eq!(session.entries[1].line, "V0");
+        assert_eq!(session.entries[2].line, "OK V0 2");
+        let results: Vec<SerialCommandResult> = session.board_results().into_iter().map(|(_, result, _)| result).collect();
+        assert_eq!(results, vec![
+            SerialCommandResult::V0 { version: Some(2) },
+            SerialCommandResult::V1 { on: Some(true) },
+            SerialCommandResult::S0 { on: Some(false) },
+            SerialCommandResult::G1 { tds_1: None, ph_1: None, t_1: None, status: crate::daemon::Status::from_bits(18573) },
+        ]);
+    }
+}
@@ -0,0 +1,202 @@
+//! Typed view of the `STATUS` word reported by `G1`.
+use super::{Status, PeristalticPumpMode};
+use std::{fmt, fmt::{Formatter, Display}};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ValveState {
+    Opening,
+    Opened,
+    Closing,
+    Closed,
+    /// No valve bit set, the board didn't report yet
+    Unknown,
+}
+
+/// Breathing cycle of the bronchus: sampling, emptying, filling then standby while full
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BronchusPhase {
+    Sampling,
+    Emptying,
+    Filling,
+    StandbyFull,
+    Unknown,
+}
+
+impl Display for BronchusPhase {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            BronchusPhase::Sampling => write!(f, "sampling"),
+            BronchusPhase::Emptying => write!(f, "emptying"),
+            BronchusPhase::Filling => write!(f, "filling"),
+            BronchusPhase::StandbyFull => write!(f, "standby full"),
+            BronchusPhase::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Sensor {
+    Tds,
+    Ph,
+    Temperature,
+}
+
+impl Display for Sensor {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Sensor::Tds => write!(f, "TDS probe"),
+            Sensor::Ph => write!(f, "PH probe"),
+            Sensor::Temperature => write!(f, "temperature probe"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DeviceState {
+    pub valve: ValveState,
+    pub pump: PeristalticPumpMode,
+    pub bronchus: BronchusPhase,
+    pub breathing: bool,
+    pub tds_connected: bool,
+    pub ph_connected: bool,
+    pub temperature_connected: bool,
+}
+
+impl Default for DeviceState {
+    fn default() -> Self {
+        Status::NONE.into()
+    }
+}
+
+impl From<Status> for DeviceState {
+    fn from(status: Status) -> Self {
+        let valve = if status.contains(Status::OSMOS_SWITCH_OPENING) {
+            ValveState::Opening
+        } else if status.contains(Status::OSMOS_SWITCH_CLOSING) {
+            ValveState::Closing
+        } else if status.contains(Status::OSMOS_SWITCH_OPENED) {
+            ValveState::Opened
+        } else if status.contains(Status::OSMOS_SWITCH_CLOSED) {
+            ValveState::Closed
+        } else {
+            ValveState::Unknown
+        };
+        let pump = if status.contains(Status::PERISTALIC_PUMP_ON) {
+            PeristalticPumpMode::On
+        } else if status.contains(Status::PERISTALIC_PUMP_REV) {
+            PeristalticPumpMode::Rev
+        } else {
+            PeristalticPumpMode::Off
+        };
+        let bronchus = if status.contains(Status::BRONCHUS_STANDBY_SAMPLING) {
+            BronchusPhase::Sampling
+        } else if status.contains(Status::BRONCHUS_WAIT_EMPTY) {
+            BronchusPhase::Emptying
+        } else if status.contains(Status::BRONCHUS_WAIT_FULL) {
+            BronchusPhase::Filling
+        } else if status.contains(Status::BRONCHUS_STANDBY_FULL) {
+            BronchusPhase::StandbyFull
+        } else {
+            BronchusPhase::Unknown
+        };
+        Self {
+            valve,
+            pump,
+            bronchus,
+            breathing: status.contains(Status::BREATHING),
+            tds_connected: status.contains(Status::TDS_CONNECTED),
+            ph_connected: status.contains(Status::PH_CONNECTED),
+            temperature_connected: status.contains(Status::TEMPERATURE_CONNECTED),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DeviceEvent {
+    Valve(ValveState),
+    Pump(PeristalticPumpMode),
+    Bronchus(BronchusPhase),
+    Breathing(bool),
+    SensorConnected(Sensor),
+    SensorDisconnected(Sensor),
+}
+
+impl Display for DeviceEvent {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            DeviceEvent::Valve(ValveState::Opening) => write!(f, "valve started opening"),
+            DeviceEvent::Valve(ValveState::Opened) => write!(f, "valve finished opening"),
+            DeviceEvent::Valve(ValveState::Closing) => write!(f, "valve started closing"),
+            DeviceEvent::Valve(ValveState::Closed) => write!(f, "valve finished closing"),
+            DeviceEvent::Valve(ValveState::Unknown) => write!(f, "valve state lost"),
+            DeviceEvent::Pump(PeristalticPumpMode::On) => write!(f, "peristaltic pump running forward"),
+            DeviceEvent::Pump(PeristalticPumpMode::Rev) => write!(f, "peristaltic pump running reverse"),
+            DeviceEvent::Pump(PeristalticPumpMode::Off) => write!(f, "peristaltic pump stopped"),
+            DeviceEvent::Bronchus(phase) => write!(f, "bronchus entered {}", phase),
+            DeviceEvent::Breathing(true) => write!(f, "breathing started"),
+            DeviceEvent::Breathing(false) => write!(f, "breathing stopped"),
+            DeviceEvent::SensorConnected(sensor) => write!(f, "{} connected", sensor),
+            DeviceEvent::SensorDisconnected(sensor) => write!(f, "{} disconnected", sensor),
+        }
+    }
+}
+
+impl DeviceState {
+    /// Events leading from `self` to `next`
+    pub fn transitions(&self, next: &DeviceState) -> Vec<DeviceEvent> {
+        let mut events = Vec::new();
+        if self.valve != next.valve {
+            events.push(DeviceEvent::Valve(next.valve));
+        }
+        if self.pump != next.pump {
+            events.push(DeviceEvent::Pump(next.pump));
+        }
+        if self.breathing != next.breathing {
+            events.push(DeviceEvent::Breathing(next.breathing));
+        }
+        if self.bronchus != next.bronchus {
+            events.push(DeviceEvent::Bronchus(next.bronchus));
+        }
+        let sensors = [
+            (Sensor::Tds, self.tds_connected, next.tds_connected),
+            (Sensor::Ph, self.ph_connected, next.ph_connected),
+            (Sensor::Temperature, self.temperature_connected, next.temperature_connected),
+        ];
+        for (sensor, was, is) in sensors.iter() {
+            match (was, is) {
+                (false, true) => events.push(DeviceEvent::SensorConnected(*sensor)),
+                (true, false) => events.push(DeviceEvent::SensorDisconnected(*sensor)),
+                _ => {},
+            }
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_and_diff_status() {
+        let boot = DeviceState::from(Status::from_bits(18501).unwrap());
+        assert_eq!(boot.valve, ValveState::Closing);
+        assert_eq!(boot.bronchus, BronchusPhase::Sampling);
+        assert_eq!(boot.pump, PeristalticPumpMode::Off);
+        assert!(boot.breathing && boot.tds_connected && boot.ph_connected && !boot.temperature_connected);
+
+        let ready = DeviceState::from(Status::from_bits(18573).unwrap());
+        assert_eq!(boot.transitions(&ready), vec![
+            DeviceEvent::Valve(ValveState::Closed),
+            DeviceEvent::SensorConnected(Sensor::Temperature),
+        ]);
+        assert_eq!(DeviceEvent::Valve(ValveState::Closed).to_string(), "valve finished closing");
+        assert!(ready.transitions(&ready).is_empty());
+
+        let emptying = DeviceState::from((Status::from_bits(18573).unwrap() - Status::BRONCHUS_STANDBY_SAMPLING) | Status::BRONCHUS_WAIT_EMPTY | Status::PERISTALIC_PUMP_REV);
+        assert_eq!(ready.transitions(&emptying), vec![
+            DeviceEvent::Pump(PeristalticPumpMode::Rev),
+            DeviceEvent::Bronchus(BronchusPhase::Emptying),
+        ]);
+    }
+}
@@ -0,0 +1,357 @@
+//! Byte streams the daemon can talk to the board through.
+#![allow(non_local_definitions)]
+use serialport::{SerialPort, SerialPortSettings, DataBits, Parity, StopBits, FlowControl};
+use std::collections::VecDeque;
+use std::io::{self, Read, Write, ErrorKind};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex, Condvar};
+use std::time::{Duration, Instant};
+use std::{fmt, fmt::{Formatter, Display}};
+
+pub trait Transport: Read + Write + Send {
+    /// Second handle on the same stream, the daemon reads from one and writes to the other
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>>;
+    /// Human readable name of the remote end
+    fn name(&self) -> String;
+    /// Maximum time a read blocks before failing with `TimedOut`
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()>;
+}
+
+impl Transport for Box<dyn SerialPort> {
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(SerialPort::try_clone(self.as_ref())?))
+    }
+
+    fn name(&self) -> String {
+        SerialPort::name(self.as_ref()).unwrap_or_default()
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        Ok(SerialPort::set_timeout(self.as_mut(), timeout)?)
+    }
+}
+
+impl Transport for TcpStream {
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(TcpStream::try_clone(self)?))
+    }
+
+    fn name(&self) -> String {
+        self.peer_addr().map(|addr| format!("tcp://{}", addr)).unwrap_or_default()
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.set_read_timeout(Some(timeout))
+    }
+}
+
+/// Connect to a serial bridge, `timeout` is used for the connection and the reads
+pub fn connect_tcp(addr: &str, timeout: Duration) -> io::Result<Box<dyn Transport>> {
+    let mut last_error = io::Error::new(ErrorKind::NotFound, format!("No address found for {}", addr));
+    for addr in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(mut stream) => {
+                stream.set_nodelay(true)?;
+                Transport::set_timeout(&mut stream, timeout)?;
+                return Ok(Box::new(stream));
+            },
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+/// One direction of a memory transport
+#[derive(Default)]
+struct Pipe {
+    state: Mutex<PipeState>,
+    readable: Condvar,
+}
+
+#[derive(Default)]
+struct PipeState {
+    buffer: VecDeque<u8>,
+    closed: bool,
+}
+
+/// Close both pipes once every clone of an endpoint is dropped
+struct Closer(Arc<Pipe>, Arc<Pipe>);
+
+impl Drop for Closer {
+    fn drop(&mut self) {
+        for pipe in [&self.0, &self.1].iter() {
+            pipe.state.lock().unwrap().closed = true;
+            pipe.readable.notify_all();
+        }
+    }
+}
+
+/// Endpoint of an in-memory link created by `memory_pair`
+#[derive(Clone)]
+pub struct MemoryTransport {
+    name: String,
+    rx: Arc<Pipe>,
+    tx: Arc<Pipe>,
+    timeout: Duration,
+    _closer: Arc<Closer>,
+}
+
+/// Two connected endpoints, what is written on one is read on the other
+pub fn memory_pair(name: &str) -> (MemoryTransport, MemoryTransport) {
+    let (a, b) = (Arc::new(Pipe::default()), Arc::new(Pipe::default()));
+    let endpoint = |rx: &Arc<Pipe>, tx: &Arc<Pipe>| MemoryTransport {
+        name: format!("memory://{}", name),
+        rx: rx.clone(),
+        tx: tx.clone(),
+        timeout: Duration::from_secs(1),
+        _closer: Arc::new(Closer(rx.clone(), tx.clone())),
+    };
+    (endpoint(&a, &b), endpoint(&b, &a))
+}
+
+impl Read for MemoryTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let deadline = Instant::now() + self.timeout;
+        let mut state = self.rx.state.lock().unwrap();
+        while state.buffer.is_empty() {
+            if state.closed {
+                return Ok(0);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(io::Error::new(ErrorKind::TimedOut, "Operation timed out"));
+            }
+            state = self.rx.readable.wait_timeout(state, deadline - now).unwrap().0;
+        }
+        let len = buf.len().min(state.buffer.len());
+        for (dst, src) in buf.iter_mut().zip(state.buffer.drain(..len)) {
+            *dst = src;
+        }
+        Ok(len)
+    }
+}
+
+impl Write for MemoryTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.tx.state.lock().unwrap();
+        if state.closed {
+            return Err(io::Error::new(ErrorKind::BrokenPipe, "Memory transport closed"));
+        }
+        state.buffer.extend(buf);
+        self.tx.readable.notify_all();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(self.clone()))
+    }
+
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.timeout = timeout;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Endpoint {
+    /// Serial port path
+    Serial(String),
+    /// `host:port` of a serial to TCP bridge
+    Tcp(String),
+}
+
+impl Display for Endpoint {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Endpoint::Serial(path) => write!(f, "serial://{}", path),
+            Endpoint::Tcp(addr) => write!(f, "tcp://{}", addr),
+        }
+    }
+}
+
+/// Board location given on the command line: `serial:///dev/ttyUSB0?baud=9600`, `tcp://host:port` or a bare path.
+/// The query overrides the serial settings, it accepts `baud`, `data_bits`, `parity`, `stop_bits`, `flow_control` and `timeout` (seconds).
+#[derive(Debug, Clone, PartialEq)]
+pub struct PortUrl {
+    pub endpoint: Endpoint,
+    pub baud_rate: Option<u32>,
+    pub data_bits: Option<DataBits>,
+    pub parity: Option<Parity>,
+    pub stop_bits: Option<StopBits>,
+    pub flow_control: Option<FlowControl>,
+    pub timeout: Option<Duration>,
+}
+
+#[derive(Debug, Fail)]
+#[fail(display = "Invalid port URL `{}`: {}", _0, _1)]
+pub struct PortUrlError(String, String);
+
+impl std::str::FromStr for PortUrl {
+    type Err = PortUrlError;
+
+    fn from_str(val: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason: &str| PortUrlError(val.to_string(), reason.to_string());
+        let (location, query) = match val.find('?') {
+            Some(idx) => (&val[..idx], Some(&val[idx + 1..])),
+            None => (val, None),
+        };
+        let endpoint = if let Some(addr) = location.strip_prefix("tcp://") {
+            if !addr.contains(':') {
+                return Err(invalid("missing TCP port"));
+            }
+            Endpoint::Tcp(addr.to_string())
+        } else if let Some(path) = location.strip_prefix("serial://") {
+            Endpoint::Serial(path.to_string())
+        } else if location.contains("://") {
+            return Err(invalid("unsupported scheme"));
+        } else {
+            Endpoint::Serial(location.to_string())
+        };
+        if matches!(&endpoint, Endpoint::Serial(path) if path.is_empty()) {
+            return Err(invalid("missing serial port path"));
+        }
+        let mut url = PortUrl {
+            endpoint,
+            baud_rate: None,
+            data_bits: None,
+            parity: None,
+            stop_bits: None,
+            flow_control: None,
+            timeout: None,
+        };
+        for param in query.unwrap_or_default().split('&').filter(|e| !e.is_empty()) {
+            let mut parts = param.splitn(2, '=');
+            let (key, value) = (parts.next().unwrap_or_default(), parts.next().unwrap_or_default());
+            let bad_value = || invalid(&format!("bad value for `{}`", key));
+            match key {
+                "baud" => url.baud_rate = Some(value.parse().map_err(|_| bad_value())?),
+                "data_bits" => url.data_bits = Some(parse_data_bits(value).ok_or_else(bad_value)?),
+                "parity" => url.parity = Some(parse_parity(value).ok_or_else(bad_value)?),
+                "stop_bits" => url.stop_bits = Some(parse_stop_bits(value).ok_or_else(bad_value)?),
+                "flow_control" => url.flow_control = Some(parse_flow_control(value).ok_or_else(bad_value)?),
+                "timeout" => url.timeout = Some(Duration::from_secs(value.parse().map_err(|_| bad_value())?)),
+                _ => return Err(invalid(&format!("unknown parameter `{}`", key))),
+            }
+        }
+        Ok(url)
+    }
+}
+
+impl PortUrl {
+    pub fn apply(&self, settings: &mut SerialPortSettings) {
+        if let Some(baud_rate) = self.baud_rate { settings.baud_rate = baud_rate; }
+        if let Some(data_bits) = self.data_bits { settings.data_bits = data_bits; }
+        if let Some(parity) = self.parity { settings.parity = parity; }
+        if let Some(stop_bits) = self.stop_bits { settings.stop_bits = stop_bits; }
+        if let Some(flow_control) = self.flow_control { settings.flow_control = flow_control; }
+        if let Some(timeout) = self.timeout { settings.timeout = timeout; }
+    }
+}
+
+pub fn parse_data_bits(val: &str) -> Option<DataBits> {
+    match val {
+        "5" => Some(DataBits::Five),
+        "6" => Some(DataBits::Six),
+        "7" => Some(DataBits::Seven),
+        "8" => Some(DataBits::Eight),
+        _ => None,
+    }
+}
+
+pub fn parse_parity(val: &str) -> Option<Parity> {
+    match val {
+        "none" => Some(Parity::None),
+        "odd" => Some(Parity::Odd),
+        "even" => Some(Parity::Even),
+        _ => None,
+    }
+}
+
+pub fn parse_stop_bits(val: &str) -> Option<StopBits> {
+    match val {
+        "1" => Some(StopBits::One),
+        "2" => Some(StopBits::Two),
+        _ => None,
+    }
+}
+
+pub fn parse_flow_control(val: &str) -> Option<FlowControl> {
+    match val {
+        "none" => Some(FlowControl::None),
+        "software" => Some(FlowControl::Software),
+        "hardware" => Some(FlowControl::Hardware),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::daemon::PortConfig;
+    use crate::simulator::{self, VirtualBoard};
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+
+    #[test]
+    fn parse_urls() {
+        let url: PortUrl = "serial:///dev/ttyUSB0?baud=115200&parity=even&timeout=3".parse().unwrap();
+        assert_eq!(url.endpoint, Endpoint::Serial("/dev/ttyUSB0".to_string()));
+        assert_eq!(url.baud_rate, Some(115200));
+        assert_eq!(url.parity, Some(Parity::Even));
+        assert_eq!(url.timeout, Some(Duration::from_secs(3)));
+        assert_eq!("/dev/ttyACM0".parse::<PortUrl>().unwrap().endpoint, Endpoint::Serial("/dev/ttyACM0".to_string()));
+        assert_eq!("tcp://growroom:2000".parse::<PortUrl>().unwrap().endpoint, Endpoint::Tcp("growroom:2000".to_string()));
+        assert!("tcp://growroom".parse::<PortUrl>().is_err());
+        assert!("udp://growroom:2000".parse::<PortUrl>().is_err());
+        assert!("serial:///dev/ttyUSB0?baud=fast".parse::<PortUrl>().is_err());
+        assert!("serial:///dev/ttyUSB0?speed=9600".parse::<PortUrl>().is_err());
+    }
+
+    #[test]
+    fn memory_pair_is_duplex() {
+        let (mut host, board) = memory_pair("test");
+        let mut reader = BufReader::new(Transport::try_clone(&board).unwrap());
+        host.write_all(b"G1\n").unwrap();
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "G1\n");
+        let mut host_reader = host.clone();
+        host_reader.set_timeout(Duration::from_millis(10)).unwrap();
+        assert_eq!(host_reader.read(&mut [0; 8]).unwrap_err().kind(), ErrorKind::TimedOut);
+        drop(board);
+        drop(reader);
+        assert_eq!(host_reader.read(&mut [0; 8]).unwrap(), 0);
+        assert!(host.write_all(b"G1\n").is_err());
+    }
+
+    #[test]
+    fn virtual_board_over_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            simulator::serve(VirtualBoard::default(), Box::new(stream));
+        });
+        let config = PortConfig { endpoint: Some(Endpoint::Tcp(addr.to_string())), ..PortConfig::default() };
+        let mut port = config.open().unwrap();
+        assert_eq!(port.name(), format!("tcp://{}", addr));
+        let mut reader = BufReader::new(port.try_clone().unwrap());
+        port.write_all(b"M2\n").unwrap();
+        let mut line = String::new();
+        while !line.starts_with("OK M2") {
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+        }
+        assert_eq!(line, "OK M2  TDS1 538 127\r\n");
+    }
+}
@@ -0,0 +1,549 @@
+//! `hydrobot export`: write the stored history as CSV or JSON Lines.
+use crate::store::*;
+use chrono::{DateTime, FixedOffset, Local, NaiveDate, NaiveDateTime, Offset, SecondsFormat, TimeZone, Utc};
+use std::io::{self, Write};
+use std::ops::Range;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Csv,
+    Jsonl,
+}
+
+impl std::str::FromStr for Format {
+    type Err = String;
+
+    fn from_str(val: &str) -> Result<Self, Self::Err> {
+        match val {
+            "csv" => Ok(Format::Csv),
+            "jsonl" => Ok(Format::Jsonl),
+            _ => Err(format!("Unknown format `{}`", val)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Timezone {
+    Local,
+    Utc,
+    Fixed(FixedOffset),
+}
+
+impl std::str::FromStr for Timezone {
+    type Err = String;
+
+    fn from_str(val: &str) -> Result<Self, Self::Err> {
+        match val {
+            "local" => Ok(Timezone::Local),
+            "utc" | "UTC" | "Z" => Ok(Timezone::Utc),
+            _ => parse_offset(val).map(Timezone::Fixed).ok_or_else(|| format!("Invalid timezone `{}`, expected local, utc or an offset such as +02:00", val)),
+        }
+    }
+}
+
+/// `+02:00`, `-0530` or `+2`
+fn parse_offset(val: &str) -> Option<FixedOffset> {
+    let sign = match val.chars().next()? {
+        '+' => 1,
+        '-' => -1,
+        _ => return None,
+    };
+    let digits = val[1..].replace(':', "");
+    let (hours, minutes) = match digits.len() {
+        1 | 2 => (digits.parse::<i32>().ok()?, 0),
+        4 => (digits[..2].parse::<i32>().ok()?, digits[2..].parse::<i32>().ok()?),
+        _ => return None,
+    };
+    if minutes >= 60 {
+        return None;
+    }
+    FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60))
+}
+
+impl Timezone {
+    fn offset_at(&self, when: SystemTime) -> FixedOffset {
+        let utc = DateTime::<Utc>::from(when).naive_utc();
+        match self {
+            Timezone::Local => Local.offset_from_utc_datetime(&utc).fix(),
+            Timezone::Utc => FixedOffset::east(0),
+            Timezone::Fixed(offset) => *offset,
+        }
+    }
+
+    /// Midnight of the day of `when` in this timezone
+    pub fn day_start(&self, when: SystemTime) -> SystemTime {
+        let offset = self.offset_at(when).local_minus_utc() as i64 * 1000;
+        let local = timestamp_ms(when) as i64 + offset;
+        UNIX_EPOCH + Duration::from_millis((local - local.rem_euclid(86_400_000) - offset).max(0) as u64)
+    }
+
+    pub fn format(&self, when: SystemTime) -> String {
+        DateTime::<Utc>::from(when).with_timezone(&self.offset_at(when)).to_rfc3339_opts(SecondsFormat::Millis, true)
+    }
+
+    fn resolve_local(&self, local: &NaiveDateTime) -> Option<SystemTime> {
+        let utc = match self {
+            Timezone::Local => {
+                // The libc lookup shifts a skipped time instead of failing
+                let time = Local.from_local_datetime(local).earliest().filter(|time| time.naive_local() == *local)?;
+                time.with_timezone(&Utc)
+            },
+            Timezone::Utc => Utc.from_utc_datetime(local),
+            Timezone::Fixed(offset) => offset.from_local_datetime(local).single()?.with_timezone(&Utc),
+        };
+        Some(utc.into())
+    }
+}
+
+/// Bound of the exported range
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimeSpec {
+    Now,
+    /// That long before now
+    Ago(Duration),
+    Absolute(DateTime<FixedOffset>),
+    /// In the export timezone
+    Naive(NaiveDateTime),
+}
+
+impl std::str::FromStr for TimeSpec {
+    type Err = String;
+
+    fn from_str(val: &str) -> Result<Self, Self::Err> {
+        if val == "now" {
+            Ok(TimeSpec::Now)
+        } else if let Ok(time) = DateTime::parse_from_rfc3339(val) {
+            Ok(TimeSpec::Absolute(time))
+        } else if let Ok(time) = NaiveDateTime::parse_from_str(val, "%Y-%m-%dT%H:%M:%S").or_else(|_| NaiveDateTime::parse_from_str(val, "%Y-%m-%dT%H:%M")) {
+            Ok(TimeSpec::Naive(time))
+        } else if let Ok(date) = NaiveDate::parse_from_str(val, "%Y-%m-%d") {
+            Ok(TimeSpec::Naive(date.and_hms(0, 0, 0)))
+        } else {
+            parse_interval(val).map(TimeSpec::Ago).map_err(|_| format!("Invalid time `{}`", val))
+        }
+    }
+}
+
+impl TimeSpec {
+    /// Fails on a local time skipped by a DST change
+    pub fn resolve(&self, timezone: Timezone, now: SystemTime) -> Result<SystemTime, String> {
+        Ok(match self {
+            TimeSpec::Now => now,
+            TimeSpec::Ago(duration) => now.checked_sub(*duration).unwrap_or(UNIX_EPOCH),
+            TimeSpec::Absolute(time) => time.with_timezone(&Utc).into(),
+            TimeSpec::Naive(time) => timezone.resolve_local(time).ok_or_else(|| format!("No such local time `{}`, skipped by a DST change", time))?,
+        })
+    }
+}
+
+/// Range between two bounds resolved now
+pub fn resolve_range(from: TimeSpec, to: TimeSpec, timezone: Timezone) -> Result<Range<SystemTime>, String> {
+    let now = SystemTime::now();
+    Ok(from.resolve(timezone, now)?..to.resolve(timezone, now)?)
+}
+
+const UNITS: [(&str, u64); 5] = [("d", 86_400_000), ("h", 3_600_000), ("m", 60_000), ("s", 1_000), ("ms", 1)];
+
+/// `90s`, `15m`, `1h`, `1d`...
+pub fn parse_interval(val: &str) -> Result<Duration, String> {
+    let split = val.find(|c: char| !c.is_ascii_digit()).unwrap_or(val.len());
+    let count: u64 = val[..split].parse().map_err(|_| format!("Invalid interval `{}`", val))?;
+    let unit = UNITS.iter().find(|(unit, _)| *unit == &val[split..]).ok_or_else(|| format!("Invalid interval unit in `{}`, expected d, h, m, s or ms", val))?;
+    if count == 0 {
+        return Err(format!("Invalid interval `{}`", val));
+    }
+    count.checked_mul(unit.1).map(Duration::from_millis).ok_or_else(|| format!("Interval `{}` too long", val))
+}
+
+fn format_interval(interval: Duration) -> String {
+    let ms = interval.as_millis() as u64;
+    let (unit, size) = UNITS.iter().find(|(_, size)| ms / size * size == ms).unwrap_or(&UNITS[4]);
+    format!("{}{}", ms / size, unit)
+}
+
+#[derive(Debug, Clone)]
+pub struct Export {
+    pub range: Range<SystemTime>,
+    pub metrics: Vec<Metric>,
+    /// Merge the stored buckets into buckets of that size, aligned on the timezone midnight
+    pub interval: Option<Duration>,
+    pub timezone: Timezone,
+    pub format: Format,
+}
+
+/// Merge `buckets` into `interval` ones, buckets coarser than `interval` are kept as is
+fn resample(buckets: Vec<Bucket>, interval: Duration, timezone: Timezone) -> Vec<Bucket> {
+    let interval_ms = interval.as_millis() as i64;
+    let mut resampled = Vec::new();
+    let mut pending: Vec<Bucket> = Vec::new();
+    let mut current = None;
+    for bucket in buckets {
+        let offset = timezone.offset_at(bucket.start).local_minus_utc() as i64 * 1000;
+        let local = timestamp_ms(bucket.start) as i64 + offset;
+        let start = local - local.rem_euclid(interval_ms) - offset;
+        if current != Some(start) && !pending.is_empty() {
+            let start = UNIX_EPOCH + Duration::from_millis(current.unwrap_or_default().max(0) as u64);
+            resampled.push(Bucket::merge(start, pending[0].resolution, &pending));
+            pending.clear();
+        }
+        current = Some(start);
+        pending.push(bucket);
+    }
+    if let Some(start) = current {
+        let start = UNIX_EPOCH + Duration::from_millis(start.max(0) as u64);
+        resampled.push(Bucket::merge(start, pending[0].resolution, &pending));
+    }
+    resampled
+}
+
+fn json_number(val: f64) -> String {
+    if val.is_finite() { val.to_string() } else { "null".to_string() }
+}
+
+/// Write the rows of `export` to `out`, returns the number of rows
+pub fn export<W: Write>(store: &Store, export: &Export, out: &mut W) -> io::Result<usize> {
+    let mut rows = 0;
+    if export.format == Format::Csv {
+        writeln!(out, "time,sensor,resolution,count,min,mean,max")?;
+    }
+    for metric in export.metrics.iter() {
+        let mut buckets = store.history(*metric, export.range.clone());
+        if let Some(interval) = export.interval {
+            buckets = resample(buckets, interval, export.timezone);
+        }
+        for bucket in buckets {
+            let time = export.timezone.format(bucket.start);
+            let resolution = match export.interval {
+                Some(interval) if bucket.resolution.bucket() < interval => format_interval(interval),
+                _ => bucket.resolution.to_string(),
+            };
+            match export.format {
+                Format::Csv => writeln!(out, "{},{},{},{},{},{},{}", time, metric, resolution, bucket.count, bucket.min, bucket.mean, bucket.max)?,
+                Format::Jsonl => writeln!(
+                    out,
+                    r#"{{"time":"{}","sensor":"{}","resolution":"{}","count":{},"min":{},"mean":{},"max":{}}}"#,
+                    time, metric, resolution, bucket.count, json_number(bucket.min), json_number(bucket.mean), json_number(bucket.max),
+                )?,
+            }
+            rows += 1;
+        }
+    }
+    out.flush()?;
+    Ok(rows)
+}
+
+/// How `export_doses` groups the dosing ledger
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DoseGrouping {
+    /// One row per dose
+    None,
+    /// Totals per day in the export timezone
+    Day,
+    /// Totals per grow cycle
+    Cycle,
+}
+
+impl std::str::FromStr for DoseGrouping {
+    type Err = String;
+
+    fn from_str(val: &str) -> Result<Self, Self::Err> {
+        match val {
+            "none" => Ok(DoseGrouping::None),
+            "day" => Ok(DoseGrouping::Day),
+            "cycle" => Ok(DoseGrouping::Cycle),
+            _ => Err(format!("Unknown grouping `{}`", val)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DoseExport {
+    pub range: Range<SystemTime>,
+    pub grouping: DoseGrouping,
+    pub timezone: Timezone,
+    pub format: Format,
+}
+
+fn json_option(val: Option<f64>) -> String {
+    val.map_or("null".to_string(), json_number)
+}
+
+/// Write the dosing ledger of `export.range` to `out`, returns the number of rows. Doses are
+/// `time,doser,trigger,duration,volume,before,after` rows, totals `time,period,doser,count,duration,volume`
+/// ones where the period is `1d` or the grow cycle name. Durations are in seconds and volumes in mL.
+pub fn export_doses<W: Write>(store: &Store, export: &DoseExport, out: &mut W) -> io::Result<usize> {
+    let mut totals: Vec<(SystemTime, String, DoseTotal)> = Vec::new();
+    match export.grouping {
+        DoseGrouping::None => {
+            if export.format == Format::Csv {
+                writeln!(out, "time,doser,trigger,duration,volume,before,after")?;
+            }
+            let mut rows = 0;
+            for dose in store.doses(export.range.clone()) {
+                let time = export.timezone.format(dose.at);
+                let duration = dose.duration.as_secs_f64();
+                match export.format {
+                    Format::Csv => {
+                        let option = |val: Option<f64>| val.map_or(String::new(), |val| val.to_string());
+                        writeln!(out, "{},{},{},{},{},{},{}", time, dose.doser, dose.trigger, duration, dose.volume, option(dose.before), option(dose.after))?
+                    },
+                    Format::Jsonl => writeln!(
+                        out,
+                        r#"{{"time":"{}","doser":"{}","trigger":"{}","duration":{},"volume":{},"before":{},"after":{}}}"#,
+                        time, dose.doser, dose.trigger, duration, json_number(dose.volume), json_option(dose.before), json_option(dose.after),
+                    )?,
+                }
+                rows += 1;
+            }
+            out.flush()?;
+            return Ok(rows);
+        },
+        DoseGrouping::Day => {
+            for dose in store.doses(export.range.clone()) {
+                let day = export.timezone.day_start(dose.at);
+                if totals.last().map(|(start, _, _)| *start) != Some(day) {
+                    totals.extend(Doser::ALL.iter().map(|doser| (day, "1d".to_string(), DoseTotal::new(*doser))));
+                }
+                let len = totals.len();
+                totals[len - Doser::ALL.len() + dose.doser as usize].2.add(&dose);
+            }
+        },
+        DoseGrouping::Cycle => {
+            for cycle in store.grow_cycles() {
+                let start = cycle.start.max(export.range.start);
+                let end = cycle.end.map_or(export.range.end, |end| end.min(export.range.end));
+                if start < end {
+                    totals.extend(store.dose_totals(start..end).into_iter().map(|total| (cycle.start, cycle.name.clone(), total)));
+                }
+            }
+        },
+    }
+    if export.format == Format::Csv {
+        writeln!(out, "time,period,doser,count,duration,volume")?;
+    }
+    for (start, period, total) in totals.iter() {
+        let time = export.timezone.format(*start);
+        let duration = total.duration.as_secs_f64();
+        match export.format {
+            Format::Csv => writeln!(out, "{},{},{},{},{},{}", time, period, total.doser, total.count, duration, total.volume)?,
+            Format::Jsonl => writeln!(
+                out,
+                r#"{{"time":"{}","period":"{}","doser":"{}","count":{},"duration":{},"volume":{}}}"#,
+                time, period, total.doser, total.count, duration, json_number(total.volume),
+            )?,
+        }
+    }
+    out.flush()?;
+    Ok(totals.len())
+}
+
+#[derive(Debug, Clone)]
+pub struct EventExport {
+    pub range: Range<SystemTime>,
+    /// Minimum level exported
+    pub level: LogLevel,
+    /// All kinds when empty
+    pub kinds: Vec<EventKind>,
+    pub timezone: Timezone,
+    pub format: Format,
+}
+
+/// Quoted when it holds a separator, a quote or a line break
+fn csv_field(val: &str) -> String {
+    if val.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", val.replace('"', "\"\""))
+    } else {
+        val.to_string()
+    }
+}
+
+fn json_string(val: &str) -> String {
+    let mut json = String::with_capacity(val.len() + 2);
+    json.push('"');
+    for c in val.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+/// Write the journal of `export.range` as `time,level,kind,message` rows to `out`, returns the number of rows
+pub fn export_events<W: Write>(store: &Store, export: &EventExport, out: &mut W) -> io::Result<usize> {
+    if export.format == Format::Csv {
+        writeln!(out, "time,level,kind,message")?;
+    }
+    let mut rows = 0;
+    let events = store.events(export.range.clone()).filter(|event| event.level >= export.level && (export.kinds.is_empty() || export.kinds.contains(&event.kind)));
+    for event in events {
+        let time = export.timezone.format(event.at);
+        match export.format {
+            Format::Csv => writeln!(out, "{},{},{},{}", time, event.level, event.kind, csv_field(&event.message))?,
+            Format::Jsonl => writeln!(
+                out,
+                r#"{{"time":"{}","level":"{}","kind":"{}","message":{}}}"#,
+                time, event.level, event.kind, json_string(&event.message),
+            )?,
+        }
+        rows += 1;
+    }
+    out.flush()?;
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::testing::*;
+
+    fn at(ms: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn parse_options() {
+        let paris = Timezone::Fixed(FixedOffset::east(3600));
+        assert_eq!("+01:00".parse::<Timezone>(), Ok(paris));
+        assert_eq!("-0530".parse::<Timezone>(), Ok(Timezone::Fixed(FixedOffset::west(5 * 3600 + 30 * 60))));
+        assert!("Europe/Paris".parse::<Timezone>().is_err());
+        assert_eq!(parse_interval("15m"), Ok(Duration::from_secs(900)));
+        assert_eq!(parse_interval("250ms"), Ok(Duration::from_millis(250)));
+        assert!(parse_interval("0h").is_err() && parse_interval("1w").is_err());
+        assert!(parse_interval("999999999999999d").is_err());
+        assert_eq!(format_interval(Duration::from_secs(7200)), "2h");
+
+        let now = at(1_600_000_000_000);
+        let time = |val: &str| val.parse::<TimeSpec>().unwrap().resolve(paris, now).unwrap();
+        assert_eq!(time("now"), now);
+        assert_eq!(time("2d"), at(1_600_000_000_000 - 2 * 86_400_000));
+        assert_eq!(time("2020-09-13"), at(1_599_951_600_000));
+        assert_eq!(time("2020-09-13T14:26"), time("2020-09-13T13:26:00Z"));
+        assert!("yesterday".parse::<TimeSpec>().is_err());
+    }
+
+    #[test]
+    fn export_csv_and_jsonl() {
+        let (store, path) = temporary_store("export");
+        // 23:59:50 then 00:00:10 UTC, the same day at UTC+01:00
+        for (idx, ms) in [1_600_041_590_000u64, 1_600_041_610_000].iter().enumerate() {
+            store.insert_metric(Metric::Tds1, at(*ms), 500.0 + idx as f64 * 10.0);
+            store.insert_metric(Metric::Ph1, at(*ms), 6.5);
+        }
+        let mut export = Export {
+            range: at(0)..at(1_700_000_000_000),
+            metrics: vec![Metric::Tds1],
+            interval: None,
+            timezone: Timezone::Utc,
+            format: Format::Csv,
+        };
+        let mut csv = Vec::new();
+        assert_eq!(super::export(&store, &export, &mut csv).unwrap(), 2);
+
+        export.metrics.push(Metric::Ph1);
+        export.interval = Some(Duration::from_secs(86_400));
+        export.timezone = Timezone::Fixed(FixedOffset::east(3600));
+        export.format = Format::Jsonl;
+        let mut jsonl = Vec::new();
+        assert_eq!(super::export(&store, &export, &mut jsonl).unwrap(), 2);
+        drop(store);
+        std::fs::remove_dir_all(&path).unwrap();
+
+        assert_eq!(String::from_utf8(csv).unwrap(), "time,sensor,resolution,count,min,mean,max\n\
+            2020-09-13T23:59:50.000Z,tds_1,raw,1,500,500,500\n\
+            2020-09-14T00:00:10.000Z,tds_1,raw,1,510,510,510\n");
+        assert_eq!(String::from_utf8(jsonl).unwrap(), "\
+            {\"time\":\"2020-09-14T00:00:00.000+01:00\",\"sensor\":\"tds_1\",\"resolution\":\"1d\",\"count\":2,\"min\":500,\"mean\":505,\"max\":510}\n\
+            {\"time\":\"2020-09-14T00:00:00.000+01:00\",\"sensor\":\"ph_1\",\"resolution\":\"1d\",\"count\":2,\"min\":6.5,\"mean\":6.5,\"max\":6.5}\n");
+    }
+
+    #[test]
+    fn export_doses_grouped() {
+        let (store, path) = temporary_store("export-doses");
+        let dose = |ms: u64, doser: Doser, volume: f64| Dose {
+            at: at(ms),
+            doser,
+            trigger: DoseTrigger::TdsMonitor,
+            duration: Duration::from_secs(6),
+            volume,
+            before: Some(700.0),
+            after: None,
+        };
+        // 23:59:50 then 00:00:10 UTC, the same day at UTC+01:00
+        store.record_dose(&dose(1_600_041_590_000, Doser::Osmoseur, 50.0));
+        store.record_dose(&dose(1_600_041_610_000, Doser::Osmoseur, 25.0));
+        store.record_dose(&dose(1_600_041_610_000, Doser::PhDown, 2.0));
+        store.start_grow_cycle("lettuce", at(1_600_000_000_000)).unwrap();
+        store.start_grow_cycle("tomato", at(1_600_041_600_000)).unwrap();
+        let mut export = DoseExport {
+            range: at(0)..at(1_700_000_000_000),
+            grouping: DoseGrouping::None,
+            timezone: Timezone::Utc,
+            format: Format::Csv,
+        };
+        let mut doses = Vec::new();
+        assert_eq!(export_doses(&store, &export, &mut doses).unwrap(), 3);
+        export.grouping = DoseGrouping::Day;
+        let mut days = Vec::new();
+        assert_eq!(export_doses(&store, &export, &mut days).unwrap(), 4);
+        export.timezone = Timezone::Fixed(FixedOffset::east(3600));
+        export.grouping = DoseGrouping::Cycle;
+        export.format = Format::Jsonl;
+        let mut cycles = Vec::new();
+        assert_eq!(export_doses(&store, &export, &mut cycles).unwrap(), 4);
+        drop(store);
+        std::fs::remove_dir_all(&path).unwrap();
+
+        assert_eq!(String::from_utf8(doses).unwrap().lines().take(2).collect::<Vec<_>>(), vec![
+            "time,doser,trigger,duration,volume,before,after",
+            "2020-09-13T23:59:50.000Z,osmoseur,tds_monitor,6,50,700,",
+        ]);
+        assert_eq!(String::from_utf8(days).unwrap(), "time,period,doser,count,duration,volume\n\
+            2020-09-13T00:00:00.000Z,1d,osmoseur,1,6,50\n\
+            2020-09-13T00:00:00.000Z,1d,ph_down,0,0,0\n\
+            2020-09-14T00:00:00.000Z,1d,osmoseur,1,6,25\n\
+            2020-09-14T00:00:00.000Z,1d,ph_down,1,6,2\n");
+        assert_eq!(String::from_utf8(cycles).unwrap().lines().nth(2).unwrap(),
+            "{\"time\":\"2020-09-14T01:00:00.000+01:00\",\"period\":\"tomato\",\"doser\":\"osmoseur\",\"count\":1,\"duration\":6,\"volume\":25}");
+    }
+
+    #[test]
+    fn export_events_escaped() {
+        let (store, path) = temporary_store("export-events");
+        let event = |ms: u64, kind: EventKind, level: LogLevel, message: &str| Event { at: at(ms), kind, level, message: message.to_string() };
+        store.insert_event(&event(1_600_041_590_000, EventKind::Log, LogLevel::Info, "Scheduler started"));
+        store.insert_event(&event(1_600_041_600_000, EventKind::Action, LogLevel::Warn, "Valve \"S0\" busy, retrying"));
+        store.insert_event(&event(1_600_041_610_000, EventKind::Device, LogLevel::Error, "Board lost\nreconnecting"));
+        let mut export = EventExport {
+            range: at(0)..at(1_700_000_000_000),
+            level: LogLevel::Warn,
+            kinds: Vec::new(),
+            timezone: Timezone::Utc,
+            format: Format::Csv,
+        };
+        let mut csv = Vec::new();
+        assert_eq!(export_events(&store, &export, &mut csv).unwrap(), 2);
+
+        export.level = LogLevel::Info;
+        export.kinds = vec![EventKind::Action, EventKind::Device];
+        export.format = Format::Jsonl;
+        let mut jsonl = Vec::new();
+        assert_eq!(export_events(&store, &export, &mut jsonl).unwrap(), 2);
+        drop(store);
+        std::fs::remove_dir_all(&path).unwrap();
+
+        assert_eq!(String::from_utf8(csv).unwrap(), "time,level,kind,message\n\
+            2020-09-14T00:00:00.000Z,warn,action,\"Valve \"\"S0\"\" busy, retrying\"\n\
+            2020-09-14T00:00:10.000Z,error,device,\"Board lost\nreconnecting\"\n");
+        assert_eq!(String::from_utf8(jsonl).unwrap(), "\
+            {\"time\":\"2020-09-14T00:00:00.000Z\",\"level\":\"warn\",\"kind\":\"action\",\"message\":\"Valve \\\"S0\\\" busy, retrying\"}\n\
+            {\"time\":\"2020-09-14T00:00:10.000Z\",\"level\":\"error\",\"kind\":\"device\",\"message\":\"Board lost\\nreconnecting\"}\n");
+    }
+}
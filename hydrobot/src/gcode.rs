@@ -0,0 +1,214 @@
+//! Runs the maintenance scripts of the `gcode/` directory (`hydrobot run <file.gcode>`).
+#![allow(non_local_definitions)]
+use actix::prelude::*;
+use crate::daemon::*;
+use crate::scheduler::SchedulerRequest;
+use std::io::Write;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Statement {
+    Command(SerialCommand),
+    Dwell(Duration),
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Script {
+    /// Statements with their line number
+    pub statements: Vec<(usize, Statement)>,
+}
+
+#[derive(Debug, Fail)]
+pub enum ScriptError {
+    #[fail(display = "line {}: {}", _0, _1)]
+    Parse(usize, String),
+    #[fail(display = "line {}: `{}` failed: {}", _0, _1, _2)]
+    Command(usize, SerialCommand, SerialError),
+    #[fail(display = "The board doesn't answer: {}", _0)]
+    NotReady(SerialError),
+    #[fail(display = "{}", _0)]
+    Io(std::io::Error),
+}
+
+impl From<std::io::Error> for ScriptError {
+    fn from(e: std::io::Error) -> Self {
+        ScriptError::Io(e)
+    }
+}
+
+fn strip_comments(line: &str) -> String {
+    let line = line.split(';').next().unwrap_or_default();
+    let mut stripped = String::with_capacity(line.len());
+    let mut depth = 0;
+    for c in line.chars() {
+        match c {
+            '(' => depth += 1,
+            ')' if depth > 0 => depth -= 1,
+            _ if depth == 0 => stripped.push(c),
+            _ => {},
+        }
+    }
+    stripped
+}
+
+fn parse_dwell(args: &[&str]) -> Option<Duration> {
+    match args {
+        [arg] if arg.starts_with('P') => Some(Duration::from_millis(arg[1..].parse().ok()?)),
+        [arg] if arg.starts_with('S') => Some(Duration::from_secs_f64(arg[1..].parse().ok()?)),
+        _ => None,
+    }
+}
+
+impl std::str::FromStr for Script {
+    type Err = ScriptError;
+
+    fn from_str(val: &str) -> Result<Self, Self::Err> {
+        let mut statements = Vec::new();
+        for (idx, line) in val.lines().enumerate() {
+            let line = strip_comments(line).trim().to_uppercase();
+            if line.is_empty() {
+                continue;
+            }
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            let statement = if parts[0] == "G4" {
+                let dwell = parse_dwell(&parts[1..]).ok_or_else(|| ScriptError::Parse(idx + 1, format!("Invalid dwell: `{}`", line)))?;
+                Statement::Dwell(dwell)
+            } else {
+                Statement::Command(line.parse().map_err(|e: ParseCommandError| ScriptError::Parse(idx + 1, e.to_string()))?)
+            };
+            statements.push((idx + 1, statement));
+        }
+        Ok(Script { statements })
+    }
+}
+
+impl Script {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ScriptError> {
+        std::fs::read_to_string(path)?.parse()
+    }
+}
+
+/// Poll the board until it answers, it reboots when the port is opened
+pub async fn wait_ready(handle: &mut SerialDaemonHandle, attempts: u32) -> Result<(), ScriptError> {
+    let mut last_error = SerialError::Disconnected;
+    for _ in 0..attempts {
+        match handle.request(SerialCommand::G1).await {
+            Ok(_) => return Ok(()),
+            Err(e) => last_error = e,
+        }
+    }
+    Err(ScriptError::NotReady(last_error))
+}
+
+/// Execute the script and write the transcript to `out`, stops at the first failure unless `keep_going`.
+/// Returns the number of failed commands.
+pub async fn run<W: Write>(script: &Script, handle: &mut SerialDaemonHandle, keep_going: bool, out: &mut W) -> Result<usize, ScriptError> {
+    let mut failed = 0;
+    for (line, statement) in script.statements.iter() {
+        match statement {
+            Statement::Dwell(duration) => {
+                writeln!(out, "{:>4} . dwell {} ms", line, duration.as_millis())?;
+                actix_rt::time::delay_for(*duration).await;
+            },
+            Statement::Command(command) => {
+                writeln!(out, "{:>4} > {}", line, command)?;
+                let started = Instant::now();
+                let reply = handle.request(*command).await;
+                let elapsed = started.elapsed().as_millis();
+                match reply {
+                    Ok(result) => writeln!(out, "{:>4} < {} ({} ms)", line, result, elapsed)?,
+                    Err(error) => {
+                        writeln!(out, "{:>4} ! {} ({} ms)", line, error, elapsed)?;
+                        if !keep_going {
+                            return Err(ScriptError::Command(*line, *command, error));
+                        }
+                        failed += 1;
+                    },
+                }
+            },
+        }
+    }
+    Ok(failed)
+}
+
+/// Stands for the scheduler while a script runs, prints what the board sends by itself
+pub struct Transcript;
+
+impl Actor for Transcript {
+    type Context = Context<Self>;
+}
+
+impl Handler<SchedulerRequest> for Transcript {
+    type Result = ();
+
+    fn handle(&mut self, msg: SchedulerRequest, _ctx: &mut Self::Context) -> Self::Result {
+        match msg {
+            SchedulerRequest::Serial { result, .. } => println!("     ~ {}", result),
+            SchedulerRequest::Link { state } => println!("     # link {}", state),
+            _ => {},
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::daemon::transport::{memory_pair, Transport};
+    use crate::simulator::{self, VirtualBoard};
+
+    #[test]
+    fn parse_script() {
+        let script: Script = "; calibration reset\nM0\n\n(wait for the EEPROM) G4 P250\nm1 tds1 540 130 ; new probe\nG4 S1.5\nm2".parse().unwrap();
+        assert_eq!(script.statements, vec![
+            (2, Statement::Command(SerialCommand::M0)),
+            (4, Statement::Dwell(Duration::from_millis(250))),
+            (5, Statement::Command(SerialCommand::M1 { tds_1: (540, 130) })),
+            (6, Statement::Dwell(Duration::from_millis(1500))),
+            (7, Statement::Command(SerialCommand::M2)),
+        ]);
+        assert!(matches!("M0\nS0 MAYBE".parse::<Script>(), Err(ScriptError::Parse(2, _))));
+        assert!(matches!("G4 X1".parse::<Script>(), Err(ScriptError::Parse(1, _))));
+    }
+
+    #[actix_rt::test]
+    async fn run_against_the_virtual_board() {
+        let (host, board) = memory_pair("board");
+        std::thread::spawn(move || simulator::serve(VirtualBoard::default(), Box::new(board)));
+        let config = PortConfig { framing: false, ..PortConfig::default() };
+        let mut handle = SerialDaemon::new(Box::new(host), config, None, Transcript.start().recipient());
+        wait_ready(&mut handle, 3).await.unwrap();
+
+        // The valve is still closing after the boot
+        let script: Script = "S1 ON\nG4 P10\nS1 OFF\nS0 ON\nM2".parse().unwrap();
+        let mut out = Vec::new();
+        match run(&script, &mut handle, false, &mut out).await {
+            Err(ScriptError::Command(4, SerialCommand::S0 { on: true }, SerialError::Board(_))) => {},
+            res => panic!("{:?}: {}", res, String::from_utf8_lossy(&out)),
+        }
+        let transcript = String::from_utf8(out).unwrap();
+        assert!(transcript.contains("   3 < OK S1 OFF"), "{}", transcript);
+        assert!(transcript.contains("   4 ! Board replied `ERR S0 BUSY`"), "{}", transcript);
+        assert!(!transcript.contains("M2"), "{}", transcript);
+
+        let script: Script = include_str!("../../gcode/reset.gcode").parse().unwrap();
+        let mut out = Vec::new();
+        assert_eq!(run(&script, &mut handle, false, &mut out).await.unwrap(), 0);
+        let transcript = String::from_utf8(out).unwrap();
+        assert!(transcript.starts_with("   1 > M0\n   1 < OK M0 ("), "{}", transcript);
+    }
+
+    #[actix_rt::test]
+    async fn dwell_longer_than_the_read_timeout() {
+        let (mut host, board) = memory_pair("board-dwell");
+        host.set_timeout(Duration::from_millis(200)).unwrap();
+        std::thread::spawn(move || simulator::serve(VirtualBoard::default(), Box::new(board)));
+        let config = PortConfig { framing: false, ..PortConfig::default() };
+        let mut handle = SerialDaemon::new(Box::new(host), config, None, Transcript.start().recipient());
+        wait_ready(&mut handle, 3).await.unwrap();
+
+        let script: Script = "G4 S1\nS1 ON\nG4 P500\nS1 OFF".parse().unwrap();
+        let mut out = Vec::new();
+        assert_eq!(run(&script, &mut handle, false, &mut out).await.unwrap(), 0, "{}", String::from_utf8_lossy(&out));
+    }
+}
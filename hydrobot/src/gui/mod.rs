@@ -1,8 +1,8 @@
 
-use crate::daemon::Status;
+use crate::daemon::{DeviceState, LinkState};
 use actix::prelude::*;
 use std::{
-    collections::{VecDeque},
+    collections::{HashMap, VecDeque},
     error::Error, io,io::Stdout};
 use termion::{raw::RawTerminal, event::Key, input::MouseTerminal, raw::IntoRawMode, screen::AlternateScreen};
 use tui::{
@@ -12,8 +12,9 @@ use tui::{
     Frame,
 };
 use std::time::SystemTime;
+use futures::channel::oneshot;
 use crate::scheduler::*;
-use crate::store::Store;
+use crate::store::{Store, Dose, Doser, Event, EventKind, Lockout, LogLevel, Setting, SettingCategory, SettingValue, SETTINGS};
 use termion::input::TermRead;
 
 mod widgets;
@@ -25,22 +26,6 @@ const MAX_TDS_SAMPLES: usize = 256;
 const MAX_PH_SAMPLES: usize = 256;
 const MAX_LOG: usize = 256;
 
-pub enum LogLevel {
-    Info,
-    Error,
-    Warn,
-}
-
-impl std::fmt::Display for LogLevel {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", match self {
-            LogLevel::Info => "info",
-            LogLevel::Warn => "warn",
-            LogLevel::Error => "error",
-        })
-    }
-}
-
 #[derive(Message)]
 #[rtype(Result = "()")]
 pub enum GuiEvent {
@@ -50,7 +35,26 @@ pub enum GuiEvent {
     TdsSensore(f64, AnalyticStatus),
     PhSensore(f64, AnalyticStatus),
     TemperatureSensore(f64),
-    Status(Status),
+    Device(DeviceState),
+    Link(LinkState),
+    /// A setting was stored
+    Setting(Setting, SettingValue),
+    /// A profile was applied or saved
+    Profile(Option<String>),
+    /// A dose was added to the ledger
+    Dose(Dose),
+    /// Progress of the hardware tasks, empty once they are all done
+    Tasks(Vec<TaskProgress>),
+    /// Automation was locked out, or resumed
+    Lockout(Option<Lockout>),
+    /// The shutdown is over, restore the terminal
+    Quit,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SettingsPage {
+    Category(SettingCategory),
+    Profiles,
 }
 
 type Term = Terminal<TermionBackend<AlternateScreen<MouseTerminal<RawTerminal<Stdout>>>>>;
@@ -59,16 +63,25 @@ type Fram<'a> = Frame<'a, TermionBackend<AlternateScreen<MouseTerminal<RawTermin
 pub struct GuiActor {
     widgets: Vec<Box<dyn SelectableWidget>>,
     current_selection: usize,
-    terminal: Term,
+    /// Dropped on quit to restore the terminal
+    terminal: Option<Term>,
     app: App,
+    /// Asks `main` for the shutdown
+    quit: Option<oneshot::Sender<()>>,
 }
 
 pub struct App {
-    selected_setting_categorie: SettingCategorie,
+    selected_settings_page: SettingsPage,
     focused: bool,
     scheduler: Addr<SchedulerActor>,
-    status: Status,
+    device: DeviceState,
+    link: Option<LinkState>,
     store: Store,
+    settings: HashMap<Setting, SettingValue>,
+    profiles: Vec<String>,
+    active_profile: Option<String>,
+    /// mL added today and during the current grow cycle
+    dose_totals: HashMap<Doser, (f64, f64)>,
     tds: f64,
     tds_status: AnalyticStatus,
     ph_status: AnalyticStatus,
@@ -79,6 +92,8 @@ pub struct App {
     temperature_buffer_trunc: Vec<(f64, f64)>,
     logs: VecDeque<(SystemTime, String, LogLevel)>,
     queries: VecDeque<(SystemTime, String)>,
+    tasks: Vec<TaskProgress>,
+    lockout: Option<Lockout>,
 }
 
 pub trait SelectableWidget {
@@ -89,6 +104,15 @@ pub trait SelectableWidget {
     }
 }
 impl App {
+    fn refresh_dose_totals(&mut self) {
+        let now = SystemTime::now();
+        let today = crate::export::Timezone::Local.day_start(now);
+        let cycle = self.store.current_grow_cycle().map(|cycle| cycle.start).unwrap_or(std::time::UNIX_EPOCH);
+        let until = now + std::time::Duration::from_secs(1);
+        for (daily, total) in self.store.dose_totals(today..until).iter().zip(self.store.dose_totals(cycle..until)) {
+            self.dose_totals.insert(total.doser, (daily.volume, total.volume));
+        }
+    }
 
     fn draw(&mut self, terminal: &mut Term, widgets: &[Box<dyn SelectableWidget>]) -> Result<(), Box<dyn Error>>  {
         terminal.draw(|f| {
@@ -157,7 +181,7 @@ impl GuiActor {
         self.widgets[self.current_selection].select();
     }
 
-    pub fn new(scheduler: Addr<SchedulerActor>, store: Store) -> Self{
+    pub fn new(scheduler: Addr<SchedulerActor>, store: Store, quit: oneshot::Sender<()>) -> Self{
         // Terminal initialization
         let stdout = io::stdout().into_raw_mode().expect("Failed to init get stdout raw");
         let stdout = MouseTerminal::from(stdout);
@@ -175,23 +199,32 @@ impl GuiActor {
                 Box::from(QueryWidget::new()),
             ],
             current_selection: 2,
-            terminal,
+            terminal: Some(terminal),
+            quit: Some(quit),
             app: App {
                 temperature: 0.0,
-                selected_setting_categorie: SettingCategorie::General,
+                selected_settings_page: SettingsPage::Category(SettingCategory::General),
                 focused: false,
                 scheduler,
-                status: Status::NONE,
+                device: DeviceState::default(),
+                link: None,
                 tds: 0.0,
                 tds_status: AnalyticStatus::Undefined,
                 ph_status: AnalyticStatus::Undefined,
                 ph: 0.0,
+                // Back-filled from the journal
+                logs: store.last_events(MAX_LOG, |event| event.kind != EventKind::Query).into_iter().map(|event: Event| (event.at, event.message, event.level)).collect(),
+                queries: store.last_events(MAX_LOG, |event| event.kind == EventKind::Query).into_iter().map(|event| (event.at, event.message)).collect(),
+                settings: SETTINGS.iter().map(|spec| (spec.setting, store.get(spec.setting))).collect(),
+                profiles: store.profiles(),
+                active_profile: store.active_profile(),
+                lockout: store.lockout(),
                 store: store,
-                logs: VecDeque::new(),
-                queries: VecDeque::new(),
+                dose_totals: HashMap::new(),
                 tds_buffer_trunc: Vec::with_capacity(MAX_TDS_SAMPLES),
                 temperature_buffer_trunc: Vec::with_capacity(MAX_TEMPERATURE_SAMPLES),
                 ph_buffer_trunc: Vec::with_capacity(MAX_PH_SAMPLES),
+                tasks: Vec::new(),
             }
         }
     }
@@ -209,8 +242,31 @@ impl Handler<GuiEvent> for GuiActor {
                 }
                 self.app.temperature = temperature;
             }
-            GuiEvent::Status(status) => {
-                self.app.status = status;
+            GuiEvent::Device(device) => {
+                self.app.device = device;
+            },
+            GuiEvent::Link(state) => {
+                self.app.link = Some(state);
+            },
+            GuiEvent::Setting(setting, value) => {
+                self.app.settings.insert(setting, value);
+            },
+            GuiEvent::Tasks(tasks) => {
+                self.app.tasks = tasks;
+            },
+            GuiEvent::Lockout(lockout) => {
+                self.app.lockout = lockout;
+            },
+            GuiEvent::Quit => {
+                self.terminal.take();
+                ctx.stop();
+            },
+            GuiEvent::Dose(_) => {
+                self.app.refresh_dose_totals();
+            },
+            GuiEvent::Profile(name) => {
+                self.app.profiles = self.app.store.profiles();
+                self.app.active_profile = name;
             },
             GuiEvent::TdsSensore(tds, status) => {
                 self.app.tds_buffer_trunc.push((std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs() as f64, tds));
@@ -242,10 +298,17 @@ impl Handler<GuiEvent> for GuiActor {
                 }
             }
             GuiEvent::Key(key) => match key {
-                Key::Char('q') => {
-                    ctx.stop();
-                    System::current().stop();
+                // 'q' may be part of a name being typed
+                Key::Char('q') | Key::Ctrl('c') if key == Key::Ctrl('c') || !self.app.focused => {
+                    // Raw mode swallows the SIGINT of Ctrl-C, `main` stops the hardware first, then sends `Quit`
+                    if let Some(quit) = self.quit.take() {
+                        self.app.logs.push_back((SystemTime::now(), "Shutting down ...".to_string(), LogLevel::Warn));
+                        let _ = quit.send(());
+                    }
                 }
+                Key::Ctrl('a') if self.app.lockout.is_some() => {
+                    self.app.scheduler.do_send(SchedulerRequest::AcknowledgeLockout);
+                },
                 Key::Left if !self.app.focused => {
                     self.select_prev();
                 },
@@ -262,8 +325,13 @@ impl Actor for  GuiActor {
     type Context = Context<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
+        self.app.refresh_dose_totals();
+        // Today's totals start over at midnight
+        ctx.run_interval(std::time::Duration::from_secs(60), |actor: &mut Self, _| actor.app.refresh_dose_totals());
         ctx.run_interval(std::time::Duration::from_millis(200), |actor: &mut Self, _| {
-            let _ = actor.app.draw(&mut actor.terminal, &actor.widgets);
+            if let Some(terminal) = actor.terminal.as_mut() {
+                let _ = actor.app.draw(terminal, &actor.widgets);
+            }
         });
         let addr = ctx.address();
         std::thread::spawn(move || {
@@ -5,12 +5,13 @@ use tui::{
     style::{Color, Style},
     widgets::{Block, Borders},
     widgets::{List, ListItem },
+    text::Span,
 };
 use super::super::*;
 
 pub struct ControlerWidget {
     selected:bool,
-    sub: Vec<(&'static str, bool, SettingCategorie)>,
+    sub: Vec<(&'static str, bool, SettingsPage)>,
     sub_selection: usize,
 }
 
@@ -19,17 +20,14 @@ impl ControlerWidget {
         Self{
             sub_selection: 0,
             selected: true,
-            sub: vec![
-                ("General", true, SettingCategorie::General),
-                ("EC Monitoring", false, SettingCategorie::EcMonitor),
-                ("PH Monitoring", false, SettingCategorie::PhMonitor),
-            ],
+            sub: SettingCategory::ALL.iter().enumerate().map(|(idx, category)| (category.label(), idx == 0, SettingsPage::Category(*category)))
+                .chain(std::iter::once(("Profiles", false, SettingsPage::Profiles))).collect(),
         }
     }
 }
 
 impl SelectableWidget for ControlerWidget {
-    fn render(&self, _app: &App, frame: &mut Fram, area: Rect) {
+    fn render(&self, app: &App, frame: &mut Fram, area: Rect) {
         let items: Vec<_> = self.sub.iter().map(|(name, selected, _)| {
             if *selected {
                 ListItem::new(format!(">> {}", name)).style( Style::default().fg(Color::Black).bg(Color::White))
@@ -37,8 +35,13 @@ impl SelectableWidget for ControlerWidget {
                 ListItem::new(name.to_string()).style( Style::default().fg(Color::White).bg(Color::Black))
             }
         }).collect();
+        let title = match app.link.as_ref() {
+            Some(LinkState::Connected(_)) => Span::raw("Job"),
+            Some(state) => Span::styled(format!("Job - board {}", state), Style::default().fg(Color::Red)),
+            None => Span::styled("Job - board not connected", Style::default().fg(Color::Red)),
+        };
         let items = List::new(items)
-            .block(Block::default().borders(Borders::ALL).title("Job").border_style(Style::default().fg(if self.selected {Color::White} else {Color::DarkGray}))
+            .block(Block::default().borders(Borders::ALL).title(title).border_style(Style::default().fg(if self.selected {Color::White} else {Color::DarkGray}))
             .style(Style::default().bg(Color::Black)));
         frame.render_widget(items, area);
     }
@@ -53,7 +56,7 @@ impl SelectableWidget for ControlerWidget {
                     self.sub_selection = 0;
                 }
                 self.sub[self.sub_selection].1 = true;
-                app.selected_setting_categorie = self.sub[self.sub_selection].2;
+                app.selected_settings_page = self.sub[self.sub_selection].2;
             },
             Key::Up => {
                 self.sub[self.sub_selection].1 = false;
@@ -63,7 +66,7 @@ impl SelectableWidget for ControlerWidget {
                     self.sub_selection = self.sub.len() - 1;
                 }
                 self.sub[self.sub_selection].1 = true;
-                app.selected_setting_categorie = self.sub[self.sub_selection].2;
+                app.selected_settings_page = self.sub[self.sub_selection].2;
             },
             _ => {},
         }
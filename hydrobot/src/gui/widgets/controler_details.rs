@@ -6,51 +6,9 @@ use tui::{
     widgets::{Block, Borders},
     widgets::{List, ListItem },
 };
-use std::time::Duration;
 use std::collections::HashMap;
 use super::super::*;
-
-#[derive(Debug, Clone, Copy)]
-pub enum ParamKind {
-    Boolean(bool),
-    Float(f64),
-    Int(i64),
-    Duration(Duration),
-}
-
-impl ParamKind {
-    pub fn float_mut(&mut self) -> &mut f64 {
-        if let ParamKind::Float(f) = self {
-            f
-        } else {
-            panic!("float_mut called on a non f64 value !")
-        }
-    }
-
-    pub fn float(self) -> f64 {
-        if let ParamKind::Float(f) = self {
-            f
-        } else {
-            panic!("float_mut called on a non f64 value !")
-        }
-    }
-
-    pub fn duration(self) -> Duration {
-        if let ParamKind::Duration(f) = self {
-            f
-        } else {
-            panic!("duration called on a non Duration value !")
-        }
-    }
-
-    pub fn bool(self) -> bool {
-        if let ParamKind::Boolean(f) = self {
-            f
-        } else {
-            panic!("unwrap_bool called on a non bool value !")
-        }
-    }
-}
+use crate::store::*;
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum ParamStatus {
@@ -69,165 +27,186 @@ impl ParamStatus {
     }
 }
 
+type ValueRef = Box<dyn Fn(&App) -> SettingValue>;
+type ApplyRef = Box<dyn FnMut(&mut SettingValue, &App)>;
+
 pub struct ParamWidget {
     status: ParamStatus,
     name: String,
-    can_edit: bool,
+    /// Registry entry, read only values have none
+    setting: Option<Setting>,
     postfix: Option<String>,
-    prefix: Option<String>,
-    kind: ParamKind,
-    apply_ref: Option<Box<dyn (FnMut(&mut ParamKind, &App))>>,
-    apply_val: Option<Box<dyn (FnMut(&ParamKind, &mut App))>>,
+    kind: SettingValue,
+    apply_ref: Option<ApplyRef>,
+    /// Live value of the read only rows
+    value_ref: Option<ValueRef>,
 }
 
 impl ParamWidget {
-    fn new<T: ToString>(name: T,  kind: ParamKind) -> Self {
+    fn new<T: ToString>(name: T,  kind: SettingValue) -> Self {
         Self {
             name: name.to_string(),
             kind: kind,
-            can_edit: false,
+            setting: None,
             postfix: None,
-            prefix: None,
             status: ParamStatus::None,
             apply_ref: None,
-            apply_val: None,
+            value_ref: None,
         }
     }
 
-    fn apply_ref(mut self, f: Box<dyn (FnMut(&mut ParamKind, &App))>) -> Self {
+    fn from_setting(spec: &SettingSpec, store: &Store) -> Self {
+        let mut widget = Self::new(spec.label, store.get(spec.setting)).postfix(spec.unit);
+        widget.setting = Some(spec.setting);
+        widget
+    }
+
+    fn apply_ref(mut self, f: ApplyRef) -> Self {
         self.apply_ref = Some(f);
         self
     }
 
-    fn apply_val(mut self, f: Box<dyn (FnMut(&ParamKind, &mut App))>) -> Self {
-        self.apply_val = Some(f);
+    fn value_ref(mut self, f: ValueRef) -> Self {
+        self.value_ref = Some(f);
         self
     }
 
-    fn can_edit(mut self, edit: bool) -> Self {
-        self.can_edit = edit;
-        self
+    fn can_edit(&self) -> bool {
+        self.setting.is_some()
     }
 
     fn postfix<T: ToString>(mut self, postfix: Option<T>) -> Self {
         self.postfix = postfix.map(|e| e.to_string());
         self
     }
-
-    fn prefix<T: ToString>(mut self, prefix: Option<T>) -> Self {
-        self.prefix = prefix.map(|e| e.to_string());
-        self
-    }
-}
-
-#[derive(Debug,Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
-pub enum SettingCategorie {
-    General,
-    EcMonitor,
-    PhMonitor,
 }
 
 pub struct ControlerDetailsWidget {
     selected:bool,
-    widgets: HashMap<SettingCategorie, Vec<ParamWidget>>,
+    widgets: HashMap<SettingCategory, Vec<ParamWidget>>,
+    profile_selection: Option<usize>,
+    /// Name typed for a new profile
+    new_profile: Option<String>,
 }
 
 impl ControlerDetailsWidget {
 
     pub fn new(store: &Store) -> Self {
         let mut widgets = HashMap::new();
-        widgets.insert(SettingCategorie::General, vec![
-            ParamWidget::new("EC Compensation", ParamKind::Boolean(store.get_tds_monitoring()))
-                .can_edit(true)
-                .apply_val(Box::from(|kind: &ParamKind, app: &mut App| {
-                    app.scheduler.do_send(SchedulerRequest::SetEcMonitorEnabled { enabled: kind.bool() });
-                }))
-            ,
-            ParamWidget::new("PH Compensation", ParamKind::Boolean(store.get_ph_monitoring()))
-                .can_edit(true)
-                .apply_val(Box::from(|kind: &ParamKind, app: &mut App| {
-                    app.scheduler.do_send(SchedulerRequest::SetPhMonitorEnabled { enabled: kind.bool() });
-                }))
-        ]);
-        widgets.insert(SettingCategorie::EcMonitor, vec![
-            ParamWidget::new("Threshold", ParamKind::Float(store.get_tds_1_thresh()))
-                .postfix(Some("PPM"))
-                .can_edit(true)
-                .apply_ref(Box::from(|kind: &mut ParamKind, app: &App| { *kind.float_mut() = app.tds; }))
-                .apply_val(Box::from(|kind: &ParamKind, app: &mut App| {
-                    app.scheduler.do_send(SchedulerRequest::SetTdsThresh { thresh: kind.float() });
-                })
-            ),
-            ParamWidget::new("Osmoseur pulse duration", ParamKind::Duration(store.get_osmoseur_pulse_duration()))
-                .can_edit(true)
-                .apply_val(Box::from(|kind: &ParamKind, app: &mut App| {
-                   app.scheduler.do_send(SchedulerRequest::SetOsmoseurPulseDuration { duration: kind.duration() });
-                })
-            ),
-            ParamWidget::new("Osmoseur pulse interval", ParamKind::Duration(store.get_osmoseur_pulse_min_interval()))
-                .can_edit(true)
-                .apply_val(Box::from(|kind: &ParamKind, app: &mut App| {
-                   app.scheduler.do_send(SchedulerRequest::SetOsmoseurPulseMinInterval { interval: kind.duration() });
-                })
-            ),
-            ParamWidget::new("Total water added", ParamKind::Int(0)).postfix(Some("ML")),
-        ]);
-        widgets.insert(SettingCategorie::PhMonitor, vec![
-            ParamWidget::new("Threshold", ParamKind::Float(store.get_ph_1_thresh()))
-                .prefix(Some("PH"))
-                .can_edit(true)
-                .apply_ref(Box::from(|kind: &mut ParamKind, app: &App| { *kind.float_mut() = app.ph; }))
-                .apply_val(Box::from(|kind: &ParamKind, app: &mut App| {
-                        app.scheduler.do_send(SchedulerRequest::SetPhThresh { thresh: kind.float() });
-                })
-            ),
-            ParamWidget::new("PH Down pulse duration", ParamKind::Duration(store.get_ph_pulse_duration()))
-                .can_edit(true)
-                .apply_val(Box::from(|kind: &ParamKind, app: &mut App| {
-                   app.scheduler.do_send(SchedulerRequest::SetPhPulseDuration { duration: kind.duration() });
-                })
-            ),
-            ParamWidget::new("PH Down pulse interval", ParamKind::Duration(store.get_ph_pulse_min_interval()))
-                .can_edit(true)
-                .apply_val(Box::from(|kind: &ParamKind, app: &mut App| {
-                   app.scheduler.do_send(SchedulerRequest::SetPhPulseMinInterval { interval: kind.duration() });
-                })
-            ),
-            ParamWidget::new("Total PH Down added", ParamKind::Int(0)).postfix(Some("ML")),
-        ]);
+        for category in SettingCategory::ALL.iter() {
+            let params: Vec<ParamWidget> = SETTINGS.iter().filter(|spec| spec.category == *category).map(|spec| {
+                let widget = ParamWidget::from_setting(spec, store);
+                // `r` picks the current reading as the threshold
+                match spec.setting {
+                    Setting::Tds1Thresh => widget.apply_ref(Box::from(|kind: &mut SettingValue, app: &App| { *kind = SettingValue::Float(app.tds); })),
+                    Setting::Ph1Thresh => widget.apply_ref(Box::from(|kind: &mut SettingValue, app: &App| { *kind = SettingValue::Float(app.ph); })),
+                    _ => widget,
+                }
+            }).collect();
+            widgets.insert(*category, params);
+        }
+        for (category, doser, name) in [(SettingCategory::EcMonitor, Doser::Osmoseur, "water"), (SettingCategory::PhMonitor, Doser::PhDown, "PH Down")].iter() {
+            let doser = *doser;
+            let volume = |val: f64| SettingValue::Int(val.round() as i64);
+            let params = widgets.get_mut(category).unwrap();
+            params.push(ParamWidget::new(format!("Total {} added today", name), SettingValue::Int(0)).postfix(Some("ML"))
+                .value_ref(Box::new(move |app: &App| volume(app.dose_totals.get(&doser).map_or(0.0, |totals| totals.0)))));
+            params.push(ParamWidget::new(format!("Total {} added this cycle", name), SettingValue::Int(0)).postfix(Some("ML"))
+                .value_ref(Box::new(move |app: &App| volume(app.dose_totals.get(&doser).map_or(0.0, |totals| totals.1)))));
+        }
         Self{
             widgets,
             selected: true,
+            profile_selection: None,
+            new_profile: None,
+        }
+    }
+
+    fn on_profiles_key(&mut self, key: Key, app: &mut App) {
+        if let Some(name) = self.new_profile.as_mut() {
+            match key {
+                Key::Char('\n') if !name.is_empty() => {
+                    app.scheduler.do_send(SchedulerRequest::SaveProfile { name: name.clone() });
+                    self.new_profile = None;
+                    app.focused = false;
+                },
+                Key::Char(c) if c.is_ascii_alphanumeric() || c == '-' || c == '_' => name.push(c),
+                Key::Backspace => {
+                    name.pop();
+                },
+                Key::Esc => {
+                    self.new_profile = None;
+                    app.focused = false;
+                },
+                _ => {},
+            }
+            return;
+        }
+        if key == Key::Char('n') {
+            self.new_profile = Some(String::new());
+            app.focused = true;
+            return;
+        }
+        let len = app.profiles.len();
+        if len == 0 {
+            return;
+        }
+        match (key, self.profile_selection.filter(|idx| *idx < len)) {
+            (Key::Down, Some(idx)) => self.profile_selection = Some((idx + 1) % len),
+            (Key::Up, Some(idx)) => self.profile_selection = Some((idx + len - 1) % len),
+            (Key::Up, None) | (Key::Down, None) => self.profile_selection = Some(0),
+            (Key::Insert, Some(idx)) => app.scheduler.do_send(SchedulerRequest::ApplyProfile { name: app.profiles[idx].clone() }),
+            (Key::Char('s'), Some(idx)) => app.scheduler.do_send(SchedulerRequest::SaveProfile { name: app.profiles[idx].clone() }),
+            _ => {},
         }
     }
 }
 
 impl SelectableWidget for ControlerDetailsWidget {
-    fn render(&self, _app: &App, frame: &mut Fram, area: Rect) {
-        let items: Vec<ListItem> = self.widgets[&_app.selected_setting_categorie].iter().map(|e| {
-            let item = ListItem::new(e.name.clone());
-            item
-        }).collect();
-
-        let values: Vec<ListItem> = self.widgets[&_app.selected_setting_categorie].iter().map(|e| {
-            let value = match e.kind {
-                ParamKind::Boolean(e) => format!("{}", e),
-                ParamKind::Duration(e) => format!("{:?}", e),
-                ParamKind::Float(e) => format!("{}", e),
-                ParamKind::Int(e) => format!("{}", e),
-            };
-            let value_str = match () {
-                _ if e.prefix.is_some() && e.postfix.is_some() => format!("{}{}{}",e.prefix.as_ref().unwrap(), value, e.postfix.as_ref().unwrap()),
-                _ if e.prefix.is_some() => format!("{}{}", e.prefix.as_ref().unwrap(), value),
-                _ if e.postfix.is_some() => format!("{}{}", value, e.postfix.as_ref().unwrap()),
-                _ => format!("{}", value),
-            };
-            match e.status {
-                ParamStatus::None => ListItem::new(value_str),
-                ParamStatus::Selected => ListItem::new(format!("{}", value_str)).style(Style::default().fg(if e.can_edit { Color::White} else { Color::DarkGray })),
-                ParamStatus::Editing => ListItem::new(format!("[{}]", value_str)).style(Style::default().fg(Color::White)),
-            }
-        }).collect();
+    fn render(&self, app: &App, frame: &mut Fram, area: Rect) {
+        let (items, values): (Vec<ListItem>, Vec<ListItem>) = match app.selected_settings_page {
+            SettingsPage::Category(category) => self.widgets[&category].iter().map(|e| {
+                // The scheduler may have changed the setting (ie: a profile was applied)
+                let kind = match (e.setting, e.value_ref.as_ref()) {
+                    (Some(setting), _) if !e.status.is_editing() => app.settings.get(&setting).copied().unwrap_or(e.kind),
+                    (None, Some(value_ref)) => value_ref(app),
+                    _ => e.kind,
+                };
+                let value_str = match e.postfix.as_ref() {
+                    Some(postfix) => format!("{} {}", kind, postfix),
+                    None => kind.to_string(),
+                };
+                let value = match e.status {
+                    ParamStatus::None => ListItem::new(value_str),
+                    ParamStatus::Selected => ListItem::new(value_str).style(Style::default().fg(if e.can_edit() { Color::White} else { Color::DarkGray })),
+                    ParamStatus::Editing => ListItem::new(format!("[{}]", value_str)).style(Style::default().fg(Color::White)),
+                };
+                (ListItem::new(e.name.clone()), value)
+            }).unzip(),
+            SettingsPage::Profiles => {
+                let (mut items, mut values): (Vec<ListItem>, Vec<ListItem>) = app.profiles.iter().enumerate().map(|(idx, name)| {
+                    let status = if app.active_profile.as_ref() == Some(name) { "active" } else { "" };
+                    let item = ListItem::new(name.clone());
+                    if self.profile_selection == Some(idx) && self.new_profile.is_none() {
+                        (item.style(Style::default().fg(Color::Black).bg(Color::White)), ListItem::new(format!("{} (insert: apply, s: save current settings, n: save as)", status)))
+                    } else {
+                        (item, ListItem::new(status))
+                    }
+                }).unzip();
+                match self.new_profile.as_ref() {
+                    Some(name) => {
+                        items.push(ListItem::new("New profile").style(Style::default().fg(Color::Black).bg(Color::White)));
+                        values.push(ListItem::new(format!("[{}] (enter: save current settings, esc: cancel)", name)).style(Style::default().fg(Color::White)));
+                    },
+                    None if app.profiles.is_empty() => {
+                        items.push(ListItem::new("No profile"));
+                        values.push(ListItem::new("n: save the current settings as one"));
+                    },
+                    None => {},
+                }
+                (items, values)
+            },
+        };
 
         let control_column = Layout::default()
         .direction(Direction::Horizontal)
@@ -248,19 +227,26 @@ impl SelectableWidget for ControlerDetailsWidget {
     }
 
     fn on_key(&mut self, key: Key, app: &mut App) {
-        let current_list = self.widgets.get_mut(&app.selected_setting_categorie).unwrap();
+        let category = match app.selected_settings_page {
+            SettingsPage::Category(category) => category,
+            SettingsPage::Profiles => return self.on_profiles_key(key, app),
+        };
+        let current_list = self.widgets.get_mut(&category).unwrap();
         let current_selection = current_list.iter_mut().enumerate().find(|(_, e)| e.status != ParamStatus::None);
         match (key, current_selection) {
-            (Key::Insert, Some((_, selection))) if selection.can_edit => selection.status = match selection.status {
+            (Key::Insert, Some((_, selection))) if selection.can_edit() => selection.status = match selection.status {
                 ParamStatus::Editing => {
                     app.focused = false;
-                    if let Some(apply) = selection.apply_val.as_mut() {
-                        apply(&mut selection.kind, app);
+                    if let Some(setting) = selection.setting {
+                        app.scheduler.do_send(SchedulerRequest::SetSetting { setting, value: selection.kind });
                     }
                     ParamStatus::Selected
                 },
                 _ => {
                     app.focused = true;
+                    if let Some(value) = selection.setting.and_then(|setting| app.settings.get(&setting)) {
+                        selection.kind = *value;
+                    }
                     ParamStatus::Editing
                 },
             },
@@ -280,17 +266,11 @@ impl SelectableWidget for ControlerDetailsWidget {
                     current_list.last_mut().unwrap().status = ParamStatus::Selected;
                 }
             },
-            (Key::Down, Some((_idx, selection))) => match selection.kind {
-                ParamKind::Boolean(ref mut value) => *value = !*value,
-                ParamKind::Float(ref mut value) => *value = *value - 1.0,
-                ParamKind::Duration(ref mut value) => *value = Duration::from_secs(value.as_secs() - 1),
-                _ => {},
+            (Key::Down, Some((_idx, selection))) => if let Some(setting) = selection.setting {
+                selection.kind = setting.spec().step(selection.kind, -1);
             },
-            (Key::Up, Some((_idx, selection))) => match selection.kind {
-                ParamKind::Boolean(ref mut value) => *value = !*value,
-                ParamKind::Float(ref mut value) => *value = *value + 1.0,
-                ParamKind::Duration(ref mut value) => *value = Duration::from_secs(value.as_secs() + 1),
-                _ => {},
+            (Key::Up, Some((_idx, selection))) => if let Some(setting) = selection.setting {
+                selection.kind = setting.spec().step(selection.kind, 1);
             },
             (Key::Char('r'), Some((_idx, selection))) if selection.status.is_editing() && selection.apply_ref.is_some() => selection.apply_ref.as_mut().unwrap()(&mut selection.kind, app),
             (Key::Up, None) => current_list[0].status = ParamStatus::Selected,
@@ -22,7 +22,12 @@ impl FeedBackWidget {
 
 impl SelectableWidget for FeedBackWidget {
     fn render(&self, app: &App, frame: &mut Fram, area: Rect) {
-        let items: Vec<ListItem> = app.logs
+        // Kept on top until acknowledged
+        let lockout = app.lockout.iter().map(|lockout| {
+            let datetime: DateTime<Utc> = DateTime::from(lockout.at);
+            ListItem::new(format!("[{}] LOCKED OUT: {} (Ctrl-A to acknowledge)", datetime.format("%d/%m %T"), lockout.reason)).style(Style::default().fg(Color::White).bg(Color::Red))
+        });
+        let items: Vec<ListItem> = lockout.chain(app.logs
             .iter()
             .rev()
             .map(|(time, msg, level)| {
@@ -32,7 +37,7 @@ impl SelectableWidget for FeedBackWidget {
                     LogLevel::Warn => Style::default().bg(Color::Yellow),
                     LogLevel::Info => Style::default(),
                 })
-            })
+            }))
             .collect();
         let items = List::new(items)
             .block(Block::default().borders(Borders::ALL).border_style(Style::default().fg(if self.selected {Color::White} else {Color::DarkGray})).title("Feedback"));
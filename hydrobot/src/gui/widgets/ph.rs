@@ -36,7 +36,7 @@ impl SelectableWidget for PhWidget {
             AnalyticStatus::Stabilizing(_,_) => "PH ⏳",
             _ => "PH ?"
         };
-        let x_labels = if app.status.contains(Status::PH_CONNECTED) {
+        let x_labels = if app.device.ph_connected {
             vec![
                 Span::raw("Current : "),
                 Span::styled(
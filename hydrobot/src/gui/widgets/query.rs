@@ -1,3 +1,4 @@
+use termion::event::Key;
 use tui::{
     layout::{Rect},
     style::{Color, Style},
@@ -21,16 +22,25 @@ impl QueryWidget {
 impl SelectableWidget for QueryWidget {
     fn render(&self, app: &App, frame: &mut Fram, area: Rect) {
 
-        let items: Vec<ListItem> = app.queries
+        // Running tasks first
+        let tasks = app.tasks.iter().map(|task| {
+            let done = task.done.map(|done| format!(" {:.0}%", done * 100.0)).unwrap_or_default();
+            ListItem::new(format!("{} {}{}", task.name, task.state, done)).style(Style::default().fg(Color::Black).bg(Color::Yellow))
+        });
+        let items: Vec<ListItem> = tasks.chain(app.queries
             .iter()
             .rev()
             .map(|(date, msg)| {
                 let datetime: chrono::DateTime<chrono::Utc> = chrono::DateTime::from(*date);
                 ListItem::new(format!("[{}]{}", datetime.format("%d/%m %T"), msg)).style(Style::default().fg(Color::Black).bg(Color::White))
-            })
+            }))
             .collect();
+        let title = match app.tasks.first() {
+            Some(task) => format!("Queries (Del cancels {})", task.name),
+            None => "Queries".to_string(),
+        };
         let items = List::new(items)
-            .block(Block::default().borders(Borders::ALL).border_style(Style::default().fg(if self.selected {Color::White} else {Color::DarkGray})).title("Queries"));
+            .block(Block::default().borders(Borders::ALL).border_style(Style::default().fg(if self.selected {Color::White} else {Color::DarkGray})).title(title));
         frame.render_widget(items, area);
     }
 
@@ -41,4 +51,10 @@ impl SelectableWidget for QueryWidget {
     fn deselect(&mut self) {
         self.selected = false;
     }
+
+    fn on_key(&mut self, key: Key, app: &mut App) {
+        if let (Key::Delete, Some(task)) = (key, app.tasks.first()) {
+            app.scheduler.do_send(SchedulerRequest::CancelTask { id: task.id });
+        }
+    }
 }
\ No newline at end of file
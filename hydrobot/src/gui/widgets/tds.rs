@@ -1,4 +1,3 @@
-use crate::daemon::Status;
 use tui::{
     layout::{Rect},
     style::{Color, Style, Modifier},
@@ -38,7 +37,7 @@ impl SelectableWidget for TdsWidget {
             AnalyticStatus::Stabilizing(_,_) => "PPM ⏳",
             _ => "PPM ?"
         };
-        let x_labels = if app.status.contains(Status::TDS_CONNECTED) {
+        let x_labels = if app.device.tds_connected {
             vec![
                 Span::raw("Current : "),
                 Span::styled(
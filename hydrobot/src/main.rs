@@ -2,14 +2,24 @@
 #[macro_use] extern crate failure;
 #[macro_use] extern crate bitflags;
 use actix::prelude::*;
-use serialport::{UsbPortInfo, SerialPortType};
+use serialport::{SerialPortType, DataBits, Parity, StopBits, FlowControl};
 use std::time::{Duration};
+use std::path::PathBuf;
+use futures::{channel::oneshot, FutureExt};
+use tokio::signal::unix::{signal, SignalKind};
 
 pub mod store;
 pub mod gui;
 pub mod daemon;
 pub mod scheduler;
+pub mod simulator;
+pub mod gcode;
+pub mod export;
+pub mod control;
 use daemon::*;
+use daemon::session::{self, Session, SessionRecorder};
+use daemon::transport;
+use control::{ControlRequest, ControlSocket};
 use gui::*;
 use store::*;
 use scheduler::*;
@@ -22,33 +32,779 @@ use clap::Clap;
 struct Opts {
     #[clap(short, long)]
     daemon: bool,
+    /// Directory holding the store, defaults to the current one if it has a `store`, otherwise to
+    /// `$XDG_DATA_HOME/hydrobot` (`~/.local/share/hydrobot`)
+    #[clap(long, env = "HYDROBOT_DATA_DIR")]
+    data_dir: Option<PathBuf>,
+    /// Open the store read-only, the subcommands writing to it fail
+    #[clap(long)]
+    read_only: bool,
+    /// Run against a virtual board exposed on a pseudo-terminal instead of the USB one
+    #[clap(long)]
+    simulate: bool,
+    /// Print the serial ports seen by the system and exit
+    #[clap(long)]
+    list_ports: bool,
+    /// Board location, skips the USB vid/pid lookup: a serial port path or an URL such as
+    /// `serial:///dev/ttyUSB0?baud=9600` or `tcp://host:port`, the URL query overrides the serial options
+    #[clap(short, long)]
+    port: Option<PortUrl>,
+    /// USB vendor id of the board serial bridge (decimal or 0x prefixed hex)
+    #[clap(long, default_value = "0x1a86", parse(try_from_str = parse_usb_id))]
+    vid: u16,
+    /// USB product id of the board serial bridge (decimal or 0x prefixed hex)
+    #[clap(long, default_value = "0x7523", parse(try_from_str = parse_usb_id))]
+    pid: u16,
+    /// Baud rate, must match `Serial.begin` in the firmware
+    #[clap(short, long, default_value = "9600")]
+    baud: u32,
+    #[clap(long, default_value = "8", possible_values = &["5", "6", "7", "8"])]
+    data_bits: u8,
+    #[clap(long, default_value = "none", possible_values = &["none", "odd", "even"])]
+    parity: String,
+    #[clap(long, default_value = "1", possible_values = &["1", "2"])]
+    stop_bits: u8,
+    #[clap(long, default_value = "none", possible_values = &["none", "software", "hardware"])]
+    flow_control: String,
+    /// Serial read timeout in seconds
+    #[clap(long, default_value = "10")]
+    timeout: u64,
+    /// Keep the plain text protocol even if the firmware supports framing
+    #[clap(long)]
+    no_framing: bool,
+    /// Record the serial traffic into a session file
+    #[clap(long)]
+    record: Option<PathBuf>,
+    /// Replay the board side of a session file instead of talking to a board
+    #[clap(long, conflicts_with = "simulate")]
+    replay: Option<PathBuf>,
+    /// Replay speed factor
+    #[clap(long, default_value = "1")]
+    replay_speed: f64,
+    #[clap(subcommand)]
+    command: Option<Command>,
 }
 
-#[actix_rt::main]
-async fn main() {
-    let opts: Opts = Opts::parse();
-    let store = Store::open(std::path::PathBuf::from("./store"));
-    let ports = serialport::available_ports().expect("Failed to get serial port list");
-    let arduino = ports.into_iter().find(|port| {
-        if let SerialPortType::UsbPort(UsbPortInfo { vid: 6790, pid: 29987, ..}) = port.port_type {
+#[derive(Clap)]
+enum Command {
+    /// Execute a G-code script (see the gcode/ directory) and print its transcript
+    Run(RunOpts),
+    /// Write the stored sensor history as CSV or JSON Lines, works while the daemon is running
+    Export(ExportOpts),
+    /// List the settings or change one, changes need the daemon to be stopped
+    Settings(SettingsOpts),
+    /// Manage the settings profiles, the running daemon applies the one used, the other changes need it to be stopped
+    Profile(ProfileOpts),
+    /// Print the journaled logs, queries, actions, device events and setting changes, or export them as CSV or JSON Lines
+    Journal(JournalOpts),
+    /// Export the dosing ledger or manage the grow cycles, new cycles need the daemon to be stopped
+    Dosing(DosingOpts),
+    /// Look for damaged entries in the store and repair it, needs the daemon to be stopped
+    Store(StoreOpts),
+    /// Show the safety lockout or acknowledge it, through the running daemon if any (or Ctrl-A in the GUI)
+    Lockout(LockoutOpts),
+}
+
+const RECOVERY_HELP: &str = "The store is damaged, the recovery options are:
+  hydrobot store check    list the corrupt entries
+  hydrobot store repair   remove the corrupt entries, their settings fall back to the defaults
+  hydrobot store reset    move the store aside and start with an empty one";
+
+#[derive(Clap)]
+struct StoreOpts {
+    #[clap(subcommand)]
+    action: StoreAction,
+}
+
+#[derive(Clap)]
+enum StoreAction {
+    /// List the entries that can't be read
+    Check,
+    /// Remove the entries that can't be read
+    Repair,
+    /// Move the store to `store.damaged-<timestamp>`, a new one is created on the next start
+    Reset,
+    /// Print the location of the store
+    Path,
+}
+
+#[derive(Clap)]
+struct LockoutOpts {
+    #[clap(subcommand)]
+    action: Option<LockoutAction>,
+}
+
+#[derive(Clap)]
+enum LockoutAction {
+    /// Resume automation, check the valve and the pump first
+    Ack,
+}
+
+#[derive(Clap)]
+struct SettingsOpts {
+    #[clap(subcommand)]
+    action: Option<SettingsAction>,
+}
+
+#[derive(Clap)]
+enum SettingsAction {
+    /// Print every setting with its value, bounds and description
+    List,
+    /// Print the value of a setting
+    Get { setting: Setting },
+    /// Validate and store a value, durations are in seconds unless suffixed (`4m`, `1h`)
+    Set { setting: Setting, value: String },
+}
+
+#[derive(Clap)]
+struct ProfileOpts {
+    #[clap(subcommand)]
+    action: Option<ProfileAction>,
+}
+
+#[derive(Clap)]
+enum ProfileAction {
+    /// Print the stored profiles, the active one is starred
+    List,
+    /// Save the current settings as a profile and make it the active one
+    Save { name: String },
+    /// Copy the values of a profile into the settings, the running daemon applies them right away
+    Use { name: String },
+    /// Remove a profile
+    Delete { name: String },
+    /// Write a profile as TOML, to stdout by default
+    Export { name: String, file: Option<PathBuf> },
+    /// Store the profile of a TOML file, replacing the one of the same name
+    Import { file: PathBuf },
+}
+
+#[derive(Clap)]
+struct RunOpts {
+    file: PathBuf,
+    /// Go on with the next command when one fails
+    #[clap(long)]
+    keep_going: bool,
+}
+
+#[derive(Clap)]
+struct ExportOpts {
+    /// Start of the range: `now`, an age such as `7d` or `12h`, a date (`2021-03-01`), a time
+    /// (`2021-03-01T08:00`) in the export timezone or an RFC 3339 time
+    #[clap(long, default_value = "7d")]
+    from: export::TimeSpec,
+    /// End of the range, same formats as `--from`
+    #[clap(long, default_value = "now")]
+    to: export::TimeSpec,
+    /// Sensors to export, the filtered ones by default
+    #[clap(long = "sensor", possible_values = &["tds_1", "ph_1", "t_1", "raw_tds_1", "raw_ph_1"])]
+    sensors: Vec<Metric>,
+    /// Resampling interval such as `15m`, `1h` or `1d`, aligned on the timezone midnight
+    #[clap(long, parse(try_from_str = export::parse_interval))]
+    interval: Option<Duration>,
+    /// `local`, `utc` or a fixed offset such as `+02:00`
+    #[clap(long, default_value = "local")]
+    timezone: export::Timezone,
+    #[clap(long, default_value = "csv", possible_values = &["csv", "jsonl"])]
+    format: export::Format,
+    /// Output file, the standard output by default
+    #[clap(short, long)]
+    output: Option<PathBuf>,
+}
+
+#[derive(Clap)]
+struct JournalOpts {
+    /// Start of the range, same formats as the export one
+    #[clap(long, default_value = "1d")]
+    from: export::TimeSpec,
+    #[clap(long, default_value = "now")]
+    to: export::TimeSpec,
+    /// Minimum level printed
+    #[clap(long, default_value = "info", possible_values = &["info", "warn", "error"])]
+    level: LogLevel,
+    /// Kinds of events printed, all by default
+    #[clap(long = "kind", possible_values = &["log", "query", "action", "device", "setting", "controller"])]
+    kinds: Vec<EventKind>,
+    /// `local`, `utc` or a fixed offset such as `+02:00`
+    #[clap(long, default_value = "local")]
+    timezone: export::Timezone,
+    /// Export as `time,level,kind,message` rows instead of printing them
+    #[clap(long, possible_values = &["csv", "jsonl"])]
+    format: Option<export::Format>,
+    /// Output file, the standard output by default
+    #[clap(short, long, requires = "format")]
+    output: Option<PathBuf>,
+}
+
+#[derive(Clap)]
+struct DosingOpts {
+    #[clap(subcommand)]
+    action: DosingAction,
+}
+
+#[derive(Clap)]
+enum DosingAction {
+    /// Write the doses, or their totals, as CSV or JSON Lines
+    Export(DosingExportOpts),
+    /// Print the grow cycles
+    Cycles,
+    /// Start a grow cycle now, the cycle totals start over
+    NewCycle { name: String },
+}
+
+#[derive(Clap)]
+struct DosingExportOpts {
+    /// Start of the range, same formats as the export one
+    #[clap(long, default_value = "30d")]
+    from: export::TimeSpec,
+    #[clap(long, default_value = "now")]
+    to: export::TimeSpec,
+    /// One row per dose or totals per day or grow cycle
+    #[clap(long, default_value = "none", possible_values = &["none", "day", "cycle"])]
+    group: export::DoseGrouping,
+    /// `local`, `utc` or a fixed offset such as `+02:00`
+    #[clap(long, default_value = "local")]
+    timezone: export::Timezone,
+    #[clap(long, default_value = "csv", possible_values = &["csv", "jsonl"])]
+    format: export::Format,
+    /// Output file, the standard output by default
+    #[clap(short, long)]
+    output: Option<PathBuf>,
+}
+
+fn parse_usb_id(val: &str) -> Result<u16, std::num::ParseIntError> {
+    if let Some(hex) = val.strip_prefix("0x").or_else(|| val.strip_prefix("0X")) {
+        u16::from_str_radix(hex, 16)
+    } else {
+        val.parse()
+    }
+}
+
+impl Opts {
+    fn port_config(&self) -> PortConfig {
+        let mut config = PortConfig {
+            vid: self.vid,
+            pid: self.pid,
+            framing: !self.no_framing,
+            ..PortConfig::default()
+        };
+        config.settings.baud_rate = self.baud;
+        config.settings.timeout = Duration::from_secs(self.timeout);
+        // Values are checked by clap
+        config.settings.data_bits = transport::parse_data_bits(&self.data_bits.to_string()).unwrap_or(DataBits::Eight);
+        config.settings.parity = transport::parse_parity(&self.parity).unwrap_or(Parity::None);
+        config.settings.stop_bits = transport::parse_stop_bits(&self.stop_bits.to_string()).unwrap_or(StopBits::One);
+        config.settings.flow_control = transport::parse_flow_control(&self.flow_control).unwrap_or(FlowControl::None);
+        if let Some(url) = self.port.as_ref() {
+            config.set_url(url);
+        }
+        config
+    }
+
+    fn store_location(&self) -> StoreLocation {
+        let data_dir = self.data_dir.clone().unwrap_or_else(store::default_data_dir);
+        StoreLocation { path: data_dir.join(store::STORE_DIR), read_only: self.read_only }
+    }
+}
+
+fn list_ports(config: &PortConfig) -> bool {
+    let ports = match serialport::available_ports() {
+        Ok(ports) => ports,
+        Err(e) => {
+            eprintln!("Failed to list the serial ports: {}", e);
+            return false;
+        },
+    };
+    if ports.is_empty() {
+        println!("No serial port found");
+    }
+    for port in ports {
+        let marker = if config.matches(&port.port_type) { "*" } else { " " };
+        match port.port_type {
+            SerialPortType::UsbPort(info) => println!(
+                "{} {} usb {:04x}:{:04x} {} {} {}",
+                marker,
+                port.port_name,
+                info.vid,
+                info.pid,
+                info.manufacturer.unwrap_or_default(),
+                info.product.unwrap_or_default(),
+                info.serial_number.unwrap_or_default(),
+            ),
+            SerialPortType::PciPort => println!("{} {} pci", marker, port.port_name),
+            SerialPortType::BluetoothPort => println!("{} {} bluetooth", marker, port.port_name),
+            SerialPortType::Unknown => println!("{} {} unknown", marker, port.port_name),
+        }
+    }
+    true
+}
+
+/// Run a script on the board, the scheduler and the GUI are not started
+async fn run_script(port: Box<dyn Transport>, config: PortConfig, recorder: Option<SessionRecorder>, opts: &RunOpts) -> bool {
+    let script = match gcode::Script::load(&opts.file) {
+        Ok(script) => script,
+        Err(e) => {
+            eprintln!("{}: {}", opts.file.display(), e);
+            return false;
+        },
+    };
+    let mut handle = SerialDaemon::new(port, config, recorder, gcode::Transcript.start().recipient());
+    println!("; {} on {}", opts.file.display(), handle.name());
+    let res = match gcode::wait_ready(&mut handle, 5).await {
+        Ok(()) => gcode::run(&script, &mut handle, opts.keep_going, &mut std::io::stdout()).await,
+        Err(e) => Err(e),
+    };
+    match res {
+        Ok(0) => true,
+        Ok(failed) => {
+            eprintln!("{} command(s) failed", failed);
+            false
+        },
+        Err(e) => {
+            eprintln!("{}", e);
+            false
+        },
+    }
+}
+
+struct StoreLocation {
+    path: PathBuf,
+    /// `--read-only`, applies to every open
+    read_only: bool,
+}
+
+impl StoreLocation {
+    /// Open the store, read-only when asked so the daemon may be running
+    fn open(&self, read_only: bool) -> Option<Store> {
+        let store = if read_only || self.read_only { Store::open_read_only(&self.path) } else { Store::open(&self.path) };
+        match store {
+            Ok(store) => Some(store),
+            Err(e) => {
+                eprintln!("Failed to open {}: {}", self.path.display(), e);
+                if e.is_damage() {
+                    eprintln!("{}", RECOVERY_HELP);
+                }
+                None
+            },
+        }
+    }
+}
+
+fn run_export(opts: &ExportOpts, location: &StoreLocation) -> bool {
+    let store = match location.open(true) {
+        Some(store) => store,
+        None => return false,
+    };
+    let range = match export::resolve_range(opts.from, opts.to, opts.timezone) {
+        Ok(range) => range,
+        Err(e) => {
+            eprintln!("{}", e);
+            return false;
+        },
+    };
+    let export = export::Export {
+        range,
+        metrics: if opts.sensors.is_empty() { vec![Metric::Tds1, Metric::Ph1, Metric::Temperature1] } else { opts.sensors.clone() },
+        interval: opts.interval,
+        timezone: opts.timezone,
+        format: opts.format,
+    };
+    let res = match opts.output.as_ref() {
+        Some(path) => std::fs::File::create(path).and_then(|file| export::export(&store, &export, &mut std::io::BufWriter::new(file))),
+        None => export::export(&store, &export, &mut std::io::stdout()),
+    };
+    match res {
+        Ok(rows) => {
+            info!("{} rows exported", rows);
             true
-        } else {
+        },
+        Err(e) => {
+            eprintln!("Export failed: {}", e);
             false
+        },
+    }
+}
+
+fn run_journal(opts: &JournalOpts, location: &StoreLocation) -> bool {
+    let store = match location.open(true) {
+        Some(store) => store,
+        None => return false,
+    };
+    let range = match export::resolve_range(opts.from, opts.to, opts.timezone) {
+        Ok(range) => range,
+        Err(e) => {
+            eprintln!("{}", e);
+            return false;
+        },
+    };
+    if let Some(format) = opts.format {
+        let export = export::EventExport {
+            range,
+            level: opts.level,
+            kinds: opts.kinds.clone(),
+            timezone: opts.timezone,
+            format,
+        };
+        let res = match opts.output.as_ref() {
+            Some(path) => std::fs::File::create(path).and_then(|file| export::export_events(&store, &export, &mut std::io::BufWriter::new(file))),
+            None => export::export_events(&store, &export, &mut std::io::stdout()),
+        };
+        return match res {
+            Ok(rows) => {
+                info!("{} rows exported", rows);
+                true
+            },
+            Err(e) => {
+                eprintln!("Export failed: {}", e);
+                false
+            },
+        };
+    }
+    let events = store.events(range).filter(|event| event.level >= opts.level && (opts.kinds.is_empty() || opts.kinds.contains(&event.kind)));
+    for event in events {
+        println!("{} {:5} {:7} {}", opts.timezone.format(event.at), event.level.to_string(), event.kind.to_string(), event.message);
+    }
+    true
+}
+
+fn run_dosing(opts: &DosingOpts, location: &StoreLocation) -> bool {
+    let store = match location.open(!matches!(opts.action, DosingAction::NewCycle { .. })) {
+        Some(store) => store,
+        None => return false,
+    };
+    match &opts.action {
+        DosingAction::Export(opts) => {
+            let range = match export::resolve_range(opts.from, opts.to, opts.timezone) {
+                Ok(range) => range,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return false;
+                },
+            };
+            let export = export::DoseExport {
+                range,
+                grouping: opts.group,
+                timezone: opts.timezone,
+                format: opts.format,
+            };
+            let res = match opts.output.as_ref() {
+                Some(path) => std::fs::File::create(path).and_then(|file| export::export_doses(&store, &export, &mut std::io::BufWriter::new(file))),
+                None => export::export_doses(&store, &export, &mut std::io::stdout()),
+            };
+            match res {
+                Ok(rows) => info!("{} rows exported", rows),
+                Err(e) => {
+                    eprintln!("Export failed: {}", e);
+                    return false;
+                },
+            }
+        },
+        DosingAction::Cycles => {
+            let timezone = export::Timezone::Local;
+            for cycle in store.grow_cycles() {
+                let end = cycle.end.map_or("now".to_string(), |end| timezone.format(end));
+                let totals: Vec<String> = store.dose_totals(cycle.start..cycle.end.unwrap_or_else(std::time::SystemTime::now))
+                    .iter().map(|total| format!("{} {:.0} ML", total.doser, total.volume)).collect();
+                println!("{} {} to {}: {}", cycle.name, timezone.format(cycle.start), end, totals.join(", "));
+            }
+        },
+        DosingAction::NewCycle { name } => match store.start_grow_cycle(name, std::time::SystemTime::now()) {
+            Ok(cycle) => println!("Grow cycle {} started", cycle.name),
+            Err(e) => {
+                eprintln!("Failed to start the grow cycle: {}", e);
+                return false;
+            },
+        },
+    }
+    true
+}
+
+fn run_store(opts: &StoreOpts, location: &StoreLocation) -> bool {
+    let store = match opts.action {
+        StoreAction::Path => {
+            println!("{}", location.path.display());
+            return true;
+        },
+        StoreAction::Reset if location.read_only => Err(StoreError::ReadOnly),
+        StoreAction::Check => Store::open_read_only(&location.path),
+        _ if location.read_only => Store::open_read_only(&location.path),
+        _ => Store::open(&location.path),
+    };
+    let result = match (&opts.action, store) {
+        (StoreAction::Reset, Err(e)) if !e.is_damage() => Err(e),
+        (StoreAction::Reset, store) => {
+            // Release the lock before moving the files
+            drop(store);
+            return match Store::move_aside(&location.path) {
+                Ok(path) => {
+                    println!("Store moved to {}", path.display());
+                    true
+                },
+                Err(e) => {
+                    eprintln!("Failed to move the store: {}", e);
+                    false
+                },
+            };
+        },
+        (_, Err(e)) => Err(e),
+        (StoreAction::Check, Ok(store)) => store.check(),
+        (StoreAction::Repair, Ok(store)) => store.repair(),
+        (StoreAction::Path, _) => unreachable!(),
+    };
+    match result {
+        Ok(entries) => {
+            for entry in entries.iter() {
+                println!("{} {:?}", entry.tree, entry.key.as_ref());
+            }
+            match opts.action {
+                StoreAction::Repair => println!("{} corrupt entries removed", entries.len()),
+                _ => println!("{} corrupt entries", entries.len()),
+            }
+            true
+        },
+        Err(e) => {
+            eprintln!("{}", e);
+            if e.is_damage() {
+                eprintln!("{}", RECOVERY_HELP);
+            }
+            false
+        },
+    }
+}
+
+/// Carry out `request` in the running daemon, `None` when there is none
+fn ask_daemon(location: &StoreLocation, request: &ControlRequest) -> Option<bool> {
+    match control::request(&location.path, request) {
+        Ok(Some(Ok(msg))) => println!("{}", msg),
+        Ok(Some(Err(msg))) => {
+            eprintln!("{}", msg);
+            return Some(false);
+        },
+        Ok(None) => return None,
+        Err(e) => {
+            eprintln!("The daemon didn't answer: {}", e);
+            return Some(false);
+        },
+    }
+    Some(true)
+}
+
+fn run_lockout(opts: &LockoutOpts, location: &StoreLocation) -> bool {
+    if let Some(LockoutAction::Ack) = opts.action {
+        // The daemon owns the store and keeps its own lockout
+        if let Some(success) = ask_daemon(location, &ControlRequest::AcknowledgeLockout) {
+            return success;
+        }
+    }
+    let store = match location.open(opts.action.is_none()) {
+        Some(store) => store,
+        None => return false,
+    };
+    match opts.action {
+        None => match store.lockout() {
+            Some(lockout) => println!("Locked out since {}: {}", export::Timezone::Local.format(lockout.at), lockout.reason),
+            None => println!("No lockout"),
+        },
+        Some(LockoutAction::Ack) => match store.clear_lockout() {
+            Ok(Some(lockout)) => {
+                store.record_event(EventKind::Action, LogLevel::Info, "Lockout acknowledged from the command line");
+                println!("Lockout acknowledged: {}", lockout.reason);
+            },
+            Ok(None) => println!("No lockout"),
+            Err(e) => {
+                eprintln!("{}", e);
+                return false;
+            },
+        },
+    }
+    true
+}
+
+fn run_settings(opts: &SettingsOpts, location: &StoreLocation) -> bool {
+    let store = match location.open(!matches!(opts.action, Some(SettingsAction::Set { .. }))) {
+        Some(store) => store,
+        None => return false,
+    };
+    match opts.action.as_ref().unwrap_or(&SettingsAction::List) {
+        SettingsAction::List => {
+            for category in SettingCategory::ALL.iter() {
+                println!("[{}]", category.label());
+                for spec in SETTINGS.iter().filter(|spec| spec.category == *category) {
+                    let bounds = match spec.kind() {
+                        SettingKind::Bool => String::new(),
+                        SettingKind::Choice(choices) => format!(" ({})", choices.join(", ")),
+                        _ => format!(" ({} to {})", spec.min, spec.max),
+                    };
+                    println!("{} = {}{}", spec.key, spec.display(store.get(spec.setting)), bounds);
+                    println!("    {}", spec.description);
+                }
+            }
+        },
+        SettingsAction::Get { setting } => println!("{}", store.get(*setting)),
+        SettingsAction::Set { setting, value } => {
+            match setting.spec().parse(value).map_err(StoreError::from).and_then(|value| store.set(*setting, value)) {
+                Ok(value) => println!("{} = {}", setting, setting.spec().display(value)),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return false;
+                },
+            }
+        },
+    }
+    true
+}
+
+fn run_profile(opts: &ProfileOpts, location: &StoreLocation) -> bool {
+    let action = opts.action.as_ref().unwrap_or(&ProfileAction::List);
+    if let ProfileAction::Use { name } = action {
+        // Applied by the running daemon, or on its next start
+        if let Some(success) = ask_daemon(location, &ControlRequest::ApplyProfile { name: name.clone() }) {
+            return success;
+        }
+    }
+    let store = match location.open(matches!(action, ProfileAction::List | ProfileAction::Export { .. })) {
+        Some(store) => store,
+        None => return false,
+    };
+    let result = match action {
+        ProfileAction::List => {
+            let active = store.active_profile();
+            for name in store.profiles() {
+                println!("{} {}", if active.as_ref() == Some(&name) { "*" } else { " " }, name);
+            }
+            Ok(())
+        },
+        ProfileAction::Save { name } => store.save_profile(name).map(|profile| println!("Saved {} settings in {}", profile.values.len(), name)),
+        ProfileAction::Use { name } => store.apply_profile(name).map(|profile| {
+            for (setting, value) in profile.values {
+                println!("{} = {}", setting, setting.spec().display(value));
+            }
+        }),
+        ProfileAction::Delete { name } => match store.delete_profile(name) {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(ProfileError::NotFound(name.clone())),
+            Err(e) => Err(e.into()),
+        },
+        ProfileAction::Export { name, file } => store.profile(name).and_then(|profile| {
+            let toml = profile.to_toml();
+            match file {
+                Some(file) => std::fs::write(file, toml).map_err(|e| ProfileError::Toml(format!("{}: {}", file.display(), e))),
+                None => {
+                    print!("{}", toml);
+                    Ok(())
+                },
+            }
+        }),
+        ProfileAction::Import { file } => std::fs::read_to_string(file)
+            .map_err(|e| ProfileError::Toml(format!("{}: {}", file.display(), e)))
+            .and_then(|toml| Profile::from_toml(&toml))
+            .and_then(|profile| store.put_profile(&profile).map(|_| println!("Imported {}", profile.name))),
+    };
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        return false;
+    }
+    true
+}
+
+/// Wait for Ctrl-C, SIGTERM or the GUI to quit, returns what asked for the shutdown
+async fn shutdown_requested(quit: oneshot::Receiver<()>) -> &'static str {
+    let mut terminate = signal(SignalKind::terminate()).expect("Failed to listen to SIGTERM");
+    let ctrl_c = tokio::signal::ctrl_c().fuse();
+    let terminate = terminate.recv().fuse();
+    // Dropped without sending in daemon mode
+    let quit = quit.then(|quit| async move {
+        if quit.is_err() {
+            futures::future::pending::<()>().await;
         }
-    });
-    if opts.daemon {
+    }).fuse();
+    futures::pin_mut!(ctrl_c, terminate, quit);
+    futures::select! {
+        _ = ctrl_c => "Ctrl-C received",
+        _ = terminate => "SIGTERM received",
+        _ = quit => "Quit from the GUI",
+    }
+}
+
+#[actix_rt::main]
+async fn main() {
+    let opts: Opts = Opts::parse();
+    let mut config = opts.port_config();
+    if opts.list_ports {
+        std::process::exit(if list_ports(&config) { 0 } else { 1 });
+    }
+    if opts.daemon || opts.command.is_some() {
         pretty_env_logger::init();
     }
-    if let Some(port) = arduino {
-        let mut port = serialport::open(&port.port_name).expect("Failed to open serial port !");
-        port.set_timeout(Duration::from_secs(10)).expect("Failed to set timeout");
+    let location = opts.store_location();
+    match opts.command.as_ref() {
+        Some(Command::Export(export)) => std::process::exit(if run_export(export, &location) { 0 } else { 1 }),
+        Some(Command::Settings(settings)) => std::process::exit(if run_settings(settings, &location) { 0 } else { 1 }),
+        Some(Command::Profile(profile)) => std::process::exit(if run_profile(profile, &location) { 0 } else { 1 }),
+        Some(Command::Journal(journal)) => std::process::exit(if run_journal(journal, &location) { 0 } else { 1 }),
+        Some(Command::Dosing(dosing)) => std::process::exit(if run_dosing(dosing, &location) { 0 } else { 1 }),
+        Some(Command::Store(store)) => std::process::exit(if run_store(store, &location) { 0 } else { 1 }),
+        Some(Command::Lockout(lockout)) => std::process::exit(if run_lockout(lockout, &location) { 0 } else { 1 }),
+        _ => {},
+    }
+    let port: Option<Box<dyn Transport>> = if opts.simulate {
+        let mut port = simulator::spawn(simulator::VirtualBoard::default()).expect("Failed to start the virtual board !");
+        port.set_timeout(config.settings.timeout).expect("Failed to set timeout");
+        config.endpoint = Some(Endpoint::Serial(Transport::name(&port)));
+        Some(Box::new(port))
+    } else if let Some(path) = opts.replay.as_ref() {
+        let session = Session::load(path).expect("Failed to load the session file !");
+        let mut port = session::replay(session, opts.replay_speed).expect("Failed to start the replay !");
+        port.set_timeout(config.settings.timeout).expect("Failed to set timeout");
+        config.endpoint = Some(Endpoint::Serial(Transport::name(&port)));
+        Some(Box::new(port))
+    } else {
+        match config.open() {
+            Ok(port) => Some(port),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => panic!("Failed to open {} : {}", config.endpoint.as_ref().map(|e| e.to_string()).unwrap_or_default(), e),
+        }
+    };
+    if let Some(port) = port {
+        let recorder = opts.record.as_ref().map(|path| SessionRecorder::create(path).expect("Failed to create the session file !"));
+        if let Some(Command::Run(run)) = opts.command.as_ref() {
+            let success = run_script(port, config, recorder, run).await;
+            System::current().stop();
+            std::process::exit(if success { 0 } else { 1 });
+        }
+        if location.read_only {
+            eprintln!("--read-only only applies to the subcommands, the scheduler writes to the store");
+            System::current().stop();
+            std::process::exit(1);
+        }
+        let store = match location.open(false) {
+            Some(store) => store,
+            None => {
+                System::current().stop();
+                std::process::exit(1);
+            },
+        };
+        store.spawn_compaction(Duration::from_secs(60));
         let scheduler = SchedulerActor::new(store.clone()).start();
-        let gui = if opts.daemon { None } else { Some(GuiActor::new(scheduler.clone(), store.clone()).start()) };
-        let daemon_handle = SerialDaemon::new(port, scheduler.clone());
-        scheduler.do_send(SchedulerRequest::Init { gui, handle: daemon_handle });
-        tokio::signal::ctrl_c().await.unwrap();
-        info!("Ctrl-C received, shutting down");
+        let control = ControlSocket::bind(&location.path, scheduler.clone())
+            .map_err(|e| warn!("No control socket, the commands can't reach the daemon: {}", e))
+            .ok();
+        let (quit, quit_requested) = oneshot::channel();
+        let gui = if opts.daemon { None } else { Some(GuiActor::new(scheduler.clone(), store.clone(), quit).start()) };
+        let daemon_handle = SerialDaemon::new(port, config, recorder, scheduler.clone().recipient());
+        scheduler.do_send(SchedulerRequest::Init { gui: gui.clone(), handle: daemon_handle });
+        info!("{}, shutting down", shutdown_requested(quit_requested).await);
+        // Bounded by the hardware timeout of the rest commands
+        let safe = scheduler.send(Shutdown).await.unwrap_or(false);
+        if let Some(gui) = gui {
+            let _ = gui.send(GuiEvent::Quit).await;
+        }
+        store.flush();
+        drop(control);
         System::current().stop();
+        if !safe {
+            error!("The board didn't confirm the valve and the pump are stopped, check them by hand");
+            std::process::exit(1);
+        }
     } else {
         error!("No board connected !");
     }
@@ -0,0 +1,166 @@
+//! Dosing controllers.
+use std::fmt::{self, Display, Formatter};
+use std::time::{Duration, SystemTime};
+
+/// Shorter pulses are skipped, the doser can't deliver them reliably
+pub const MIN_PULSE: Duration = Duration::from_secs(1);
+/// A gap in the stable readings doesn't count as one long error
+const MAX_STEP_MINUTES: f64 = 1.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControllerMode {
+    Fixed,
+    Proportional,
+    Pid,
+}
+
+impl ControllerMode {
+    /// From the index stored by the `*_controller` settings, see `store::CONTROLLER_MODES`
+    pub fn from_choice(idx: usize) -> Self {
+        match idx {
+            1 => ControllerMode::Proportional,
+            2 => ControllerMode::Pid,
+            _ => ControllerMode::Fixed,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Gains {
+    pub kp: f64,
+    pub ki: f64,
+    pub kd: f64,
+}
+
+/// A sized pulse and how it was computed, the terms are in seconds
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PulseDecision {
+    pub mode: ControllerMode,
+    pub error: f64,
+    pub p: f64,
+    pub i: f64,
+    pub d: f64,
+    pub duration: Duration,
+    /// Bounded by the longest pulse
+    pub saturated: bool,
+}
+
+impl Display for PulseDecision {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.mode {
+            ControllerMode::Fixed => write!(f, "error {:.2}, fixed pulse of {:.1}s", self.error, self.duration.as_secs_f64()),
+            _ => {
+                write!(f, "error {:.2}, P {:.1}s + I {:.1}s + D {:.1}s = {:.1}s", self.error, self.p, self.i, self.d, self.duration.as_secs_f64())?;
+                if self.saturated {
+                    write!(f, " (longest pulse)")?;
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+pub struct Controller {
+    pub mode: ControllerMode,
+    pub gains: Gains,
+    pub pulse_max: Duration,
+    /// Error accumulated over time, in units·minutes
+    integral: f64,
+    /// Previous error and when it was read
+    last: Option<(SystemTime, f64)>,
+}
+
+impl Controller {
+    pub fn new(mode: ControllerMode, gains: Gains, pulse_max: Duration) -> Self {
+        Self {
+            mode,
+            gains,
+            pulse_max,
+            integral: 0.0,
+            last: None,
+        }
+    }
+
+    /// Forget the accumulated error, when the mode or the threshold change
+    pub fn reset(&mut self) {
+        self.integral = 0.0;
+        self.last = None;
+    }
+
+    /// Feed a stable reading's error and size the pulse it calls for, `fixed` in the fixed mode
+    pub fn update(&mut self, error: f64, fixed: Duration, now: SystemTime) -> PulseDecision {
+        let step = self.last.map(|(at, _)| (now.duration_since(at).unwrap_or_default().as_secs_f64() / 60.0).min(MAX_STEP_MINUTES));
+        let slope = match (self.last, step) {
+            (Some((_, last)), Some(step)) if step > 0.0 => (error - last) / step,
+            _ => 0.0,
+        };
+        self.last = Some((now, error));
+        let max = self.pulse_max.as_secs_f64();
+        let Gains { kp, ki, kd } = self.gains;
+        let (p, d) = match self.mode {
+            ControllerMode::Fixed => return PulseDecision { mode: self.mode, error, p: 0.0, i: 0.0, d: 0.0, duration: fixed, saturated: false },
+            ControllerMode::Proportional => (kp * error, 0.0),
+            ControllerMode::Pid => (kp * error, kd * slope),
+        };
+        let i = if self.mode == ControllerMode::Pid && ki > 0.0 {
+            let output = p + ki * self.integral + d;
+            if !(output >= max && error > 0.0) {
+                self.integral += error * step.unwrap_or_default();
+            }
+            self.integral = self.integral.max(0.0).min(max / ki);
+            ki * self.integral
+        } else {
+            0.0
+        };
+        let output = p + i + d;
+        PulseDecision {
+            mode: self.mode,
+            error,
+            p,
+            i,
+            d,
+            duration: Duration::from_secs_f64(output.max(0.0).min(max)),
+            saturated: output > max,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pulse_sizing() {
+        let start = SystemTime::now();
+        let minute = |n: u64| start + Duration::from_secs(60 * n);
+        let fixed = Duration::from_secs(10);
+        let close = |a: f64, b: f64| (a - b).abs() < 1e-9;
+
+        let mut proportional = Controller::new(ControllerMode::Proportional, Gains { kp: 0.1, ki: 1.0, kd: 1.0 }, Duration::from_secs(30));
+        let small = proportional.update(120.0, fixed, minute(0));
+        let big = proportional.update(500.0, fixed, minute(1));
+        assert_eq!((small.duration, small.i, small.d), (Duration::from_secs(12), 0.0, 0.0));
+        assert_eq!((big.duration, big.saturated), (Duration::from_secs(30), true));
+
+        let mut pid = Controller::new(ControllerMode::Pid, Gains { kp: 0.1, ki: 0.1, kd: 0.0 }, Duration::from_secs(30));
+        // Held far over the threshold for an hour, the integral stops growing once the pulses are the longest
+        for n in 0..60 {
+            pid.update(200.0, fixed, minute(n));
+        }
+        assert!(close(pid.integral, 200.0));
+        // And unwinds as soon as the reading is back under the threshold
+        assert!(close(pid.update(-10.0, fixed, minute(60)).i, 19.0));
+        pid.reset();
+        let first = pid.update(50.0, fixed, minute(70));
+        let second = pid.update(50.0, fixed, minute(71));
+        assert_eq!((first.duration, first.i), (Duration::from_secs(5), 0.0));
+        assert!(close(second.i, 5.0));
+
+        let mut derivative = Controller::new(ControllerMode::Pid, Gains { kp: 0.0, ki: 0.0, kd: 1.0 }, Duration::from_secs(30));
+        derivative.update(10.0, fixed, minute(0));
+        assert!(close(derivative.update(14.0, fixed, minute(1)).d, 4.0));
+
+        let mut constant = Controller::new(ControllerMode::Fixed, Gains::default(), Duration::from_secs(30));
+        assert_eq!(constant.update(1.0, fixed, minute(0)).duration, fixed);
+    }
+}
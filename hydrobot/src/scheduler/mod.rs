@@ -1,3 +1,4 @@
+#![allow(non_local_definitions)]
 use actix::prelude::*;
 use crate::daemon::*;
 use crate::gui::*;
@@ -6,9 +7,19 @@ use std::collections::HashMap;
 use std::time::{SystemTime, Duration};
 use std::sync::{Arc, RwLock};
 mod utils;
+mod controller;
+mod runner;
 mod tasks;
-use tasks::*;
+mod watchdog;
+mod shutdown;
+#[cfg(test)]
+pub(crate) mod testing;
+pub use runner::*;
+pub use tasks::*;
+pub use watchdog::*;
+pub use shutdown::*;
 pub use utils::*;
+pub use controller::*;
 
 pub type SchedulerResult<T> =Result<T, SchedulerError>;
 
@@ -16,6 +27,8 @@ pub type SchedulerResult<T> =Result<T, SchedulerError>;
 pub enum SchedulerError {
     #[fail(display = "Board busy: {}", 0)]
     BoardBusy(&'static str),
+    #[fail(display = "Lockout kept, the {} is still not at rest", _0)]
+    NotAtRest(String),
 }
 
 #[derive(Message)]
@@ -29,55 +42,41 @@ pub enum SchedulerRequest {
         result: SerialCommandResult,
         success: bool,
     },
-    SetPhMonitorEnabled {
-        enabled: bool,
+    Link {
+        state: LinkState,
     },
-    SetEcMonitorEnabled {
-        enabled: bool,
+    /// Validated against the settings registry then stored
+    SetSetting {
+        setting: Setting,
+        value: SettingValue,
     },
-    SetTdsThresh {
-        thresh: f64,
+    /// Copy a stored profile into the settings and apply it
+    ApplyProfile {
+        name: String,
     },
-    SetOsmoseurPulseDuration {
-        duration: std::time::Duration,
+    /// Save the current settings as a profile
+    SaveProfile {
+        name: String,
     },
-    SetOsmoseurPulseMinInterval {
-        interval: std::time::Duration,
-    },
-    SetPhThresh {
-        thresh: f64,
-    },
-    SetPhPulseDuration {
-        duration: std::time::Duration,
-    },
-    SetPhPulseMinInterval {
-        interval: std::time::Duration,
+    CancelTask {
+        id: TaskId,
     },
-}
-
-#[derive(Debug)]
-pub struct HardwareError(&'static str);
-
-pub struct PumpHardwareLock {
-    locked: bool,
-    opened: Option<bool>,
-    poisoned: Option<HardwareError>,
-}
-
-impl PumpHardwareLock {
-    pub fn new() -> Self {
-        PumpHardwareLock {
-            locked: false,
-            opened: None,
-            poisoned: None,
-        }
-    }
+    /// Resume automation after a lockout
+    AcknowledgeLockout,
 }
 
 pub struct SchedulerActor {
-    osmoseur_pump: PumpHardwareLock,
-    status: Status,
+    tasks: TaskRunner,
+    watchdog: Watchdog,
+    /// Set when the hardware may be stuck, blocks the tasks
+    lockout: Option<Lockout>,
+    hardware_timeout: Duration,
+    /// Set once the shutdown started, no task runs anymore
+    shutting_down: bool,
+    device: DeviceState,
     handle: Option<SerialDaemonHandle>,
+    link_up: bool,
+    frame_stats: FrameStats,
     gui: Option<Addr<GuiActor>>,
     store: Store,
     tds_1_samples: SamplesAnalytic,
@@ -86,60 +85,224 @@ pub struct SchedulerActor {
     ph_monitor: PulseMonitor,
     ph_monitor_enabled: bool,
     ec_monitor_enabled: bool,
-    add_osmosed_water_task: Option<AddOsmoseurWaterTask>,
-}
-
-#[derive(Debug, Hash, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
-pub enum Task {
-    AddOsmoseurWater,
+    /// Ledger keys of the doses waiting for their `after` reading, with the metric and when it settles
+    pending_doses: Vec<(sled::IVec, Metric, SystemTime)>,
 }
 
 impl SchedulerActor {
     pub fn new(store: Store) -> Self {
         Self {
+            watchdog: Watchdog::default(),
+            lockout: store.lockout(),
+            hardware_timeout: store.get_hardware_timeout(),
+            shutting_down: false,
             ph_monitor_enabled: store.get_ph_monitoring(),
             ec_monitor_enabled: store.get_tds_monitoring(),
-            status: Status::NONE,
+            device: DeviceState::default(),
             handle: None,
+            link_up: false,
+            frame_stats: FrameStats::default(),
             gui: None,
-            tds_monitor: PulseMonitor::new(store.get_tds_1_thresh(), store.get_osmoseur_pulse_min_interval(), store.get_osmoseur_pulse_duration()),
+            tds_monitor: PulseMonitor::new(store.get_tds_1_thresh(), store.get_osmoseur_pulse_min_interval(), store.get_osmoseur_pulse_duration(), Controller::new(
+                ControllerMode::from_choice(store.get_osmoseur_controller()),
+                Gains { kp: store.get_osmoseur_kp(), ki: store.get_osmoseur_ki(), kd: store.get_osmoseur_kd() },
+                store.get_osmoseur_pulse_max(),
+            )),
             tds_1_samples: SamplesAnalytic::new(20, 4.0, Duration::from_secs(10)),
-            ph_monitor: PulseMonitor::new(store.get_ph_1_thresh(), store.get_ph_pulse_min_interval(), store.get_ph_pulse_duration()),
+            ph_monitor: PulseMonitor::new(store.get_ph_1_thresh(), store.get_ph_pulse_min_interval(), store.get_ph_pulse_duration(), Controller::new(
+                ControllerMode::from_choice(store.get_ph_controller()),
+                Gains { kp: store.get_ph_kp(), ki: store.get_ph_ki(), kd: store.get_ph_kd() },
+                store.get_ph_pulse_max(),
+            )),
             ph_1_samples: SamplesAnalytic::new(20, 0.1, Duration::from_secs(10)),
             store,
-            osmoseur_pump: PumpHardwareLock::new(),
-            add_osmosed_water_task: None,
+            tasks: TaskRunner::new(),
+            pending_doses: Vec::new(),
         }
     }
 
-    fn to_board(&mut self, req: SerialCommand) {
-        self.handle.as_mut().unwrap().send(req).expect("Serial port");
+    fn to_board(&mut self, req: SerialCommand) -> bool {
+        match self.handle.as_mut().map(|handle| handle.send(req)) {
+            Some(Ok(())) => true,
+            Some(Err(e)) => {
+                self.warn(format!("Failed to send command to the board: {}", e));
+                false
+            },
+            None => false,
+        }
     }
 
-    fn info<T: ToString>(&self, msg: T) {
-        if let Some(gui) = self.gui.as_ref() {
-            gui.do_send(GuiEvent::Log(SystemTime::now(), msg.to_string(), LogLevel::Info))
+    /// Send a command to the board and call `then` with its reply or a timeout error after `timeout`
+    fn request<F>(&mut self, req: SerialCommand, timeout: Duration, ctx: &mut Context<Self>, then: F)
+    where
+        F: FnOnce(&mut Self, SerialResult<SerialCommandResult>, &mut Context<Self>) + 'static
+    {
+        match self.handle.as_mut() {
+            Some(handle) => {
+                ctx.spawn(handle.request_with_timeout(req, timeout).into_actor(self).map(move |result, actor, ctx| then(actor, result, ctx)));
+            },
+            None => then(self, Err(SerialError::Disconnected), ctx),
         }
     }
 
-    fn query<T: ToString>(&self, msg: T) {
-        if let Some(gui) = self.gui.as_ref() {
-            gui.do_send(GuiEvent::Query(SystemTime::now(), msg.to_string()))
+    /// Drop the pending tasks and release the hardware while the board can't be reached.
+    /// The firmware closes the valve on boot so no task survives a board reset.
+    fn pause_automation(&mut self) {
+        // The doses delivered until the board went away are recorded by the tasks
+        self.cancel_tasks("the board link is down");
+        self.tasks.reset_locks();
+        self.watchdog.reset();
+        self.tds_monitor.resume();
+        self.ph_monitor.resume();
+        self.tds_1_samples.clear();
+        self.ph_1_samples.clear();
+        self.device = DeviceState::default();
+        self.to_gui(GuiEvent::Device(self.device));
+    }
+
+    /// Copy a stored profile into the settings, the running controllers pick them up right away
+    pub fn apply_profile(&mut self, name: &str) -> Result<Profile, ProfileError> {
+        match self.store.apply_profile(name) {
+            Ok(profile) => {
+                for (setting, value) in profile.values.iter() {
+                    self.apply_setting(*setting, *value);
+                    self.to_gui(GuiEvent::Setting(*setting, *value));
+                }
+                self.to_gui(GuiEvent::Profile(Some(name.to_string())));
+                self.journal(EventKind::Setting, LogLevel::Info, format!("Profile {} applied", name));
+                Ok(profile)
+            },
+            Err(e) => {
+                self.journal(EventKind::Setting, LogLevel::Warn, format!("Profile not applied: {}", e));
+                Err(e)
+            },
         }
     }
 
-    fn warn<T: ToString>(&self, msg: T) {
-        if let Some(gui) = self.gui.as_ref() {
-            gui.do_send(GuiEvent::Log(SystemTime::now(), msg.to_string(), LogLevel::Warn))
+    fn apply_setting(&mut self, setting: Setting, value: SettingValue) {
+        match setting {
+            Setting::TdsMonitoring => self.ec_monitor_enabled = value.as_bool(),
+            Setting::HardwareTimeout => self.hardware_timeout = value.as_duration(),
+            Setting::PhMonitoring => self.ph_monitor_enabled = value.as_bool(),
+            Setting::Tds1Thresh => {
+                self.tds_monitor.threshold = value.as_float();
+                self.tds_monitor.controller.reset();
+            },
+            Setting::OsmoseurPulseDuration => self.tds_monitor.pulse_duration = value.as_duration(),
+            Setting::OsmoseurPulseMinInterval => self.tds_monitor.pulse_minimum_interval = value.as_duration(),
+            Setting::OsmoseurController => {
+                self.tds_monitor.controller.mode = ControllerMode::from_choice(value.as_choice());
+                self.tds_monitor.controller.reset();
+            },
+            Setting::OsmoseurKp => self.tds_monitor.controller.gains.kp = value.as_float(),
+            Setting::OsmoseurKi => self.tds_monitor.controller.gains.ki = value.as_float(),
+            Setting::OsmoseurKd => self.tds_monitor.controller.gains.kd = value.as_float(),
+            Setting::OsmoseurPulseMax => self.tds_monitor.controller.pulse_max = value.as_duration(),
+            Setting::Ph1Thresh => {
+                self.ph_monitor.threshold = value.as_float();
+                self.ph_monitor.controller.reset();
+            },
+            Setting::PhPulseDuration => self.ph_monitor.pulse_duration = value.as_duration(),
+            Setting::PhPulseMinInterval => self.ph_monitor.pulse_minimum_interval = value.as_duration(),
+            Setting::PhController => {
+                self.ph_monitor.controller.mode = ControllerMode::from_choice(value.as_choice());
+                self.ph_monitor.controller.reset();
+            },
+            Setting::PhKp => self.ph_monitor.controller.gains.kp = value.as_float(),
+            Setting::PhKi => self.ph_monitor.controller.gains.ki = value.as_float(),
+            Setting::PhKd => self.ph_monitor.controller.gains.kd = value.as_float(),
+            Setting::PhPulseMax => self.ph_monitor.controller.pulse_max = value.as_duration(),
+            // Read when a dose is recorded
+            Setting::OsmoseurFlowRate | Setting::PhDownFlowRate => {},
+            // Read by the compaction job on each run
+            Setting::RawRetentionDays | Setting::MinuteRetentionDays | Setting::JournalRetentionDays => {},
         }
     }
 
-    fn error<T: ToString>(&self, msg: T) {
-        if let Some(gui) = self.gui.as_ref() {
-            gui.do_send(GuiEvent::Log(SystemTime::now(), msg.to_string(), LogLevel::Error))
+    /// Add a dose ending now to the ledger, its `after` reading is filled once the water is mixed if `settled`
+    fn record_dose(&mut self, doser: Doser, trigger: DoseTrigger, begin: SystemTime, before: Option<f64>, settled: bool) {
+        let duration = begin.elapsed().unwrap_or_default();
+        let dose = Dose { at: begin, doser, trigger, duration, volume: self.store.dose_volume(doser, duration), before, after: None };
+        if let (Some(key), Some(metric), true) = (self.store.record_dose(&dose), trigger.metric(), settled) {
+            let settle = match metric {
+                Metric::Ph1 => self.ph_monitor.pulse_minimum_interval,
+                _ => self.tds_monitor.pulse_minimum_interval,
+            };
+            self.pending_doses.push((key, metric, SystemTime::now() + settle));
+        }
+        self.journal(EventKind::Action, LogLevel::Info, format!("{:.0} ML added by the {} ({})", dose.volume, doser, trigger));
+        self.to_gui(GuiEvent::Dose(dose));
+    }
+
+    /// Fill the `after` reading of the settled doses correcting `metric`
+    fn complete_doses(&mut self, metric: Metric, value: f64) {
+        let now = SystemTime::now();
+        let store = &self.store;
+        self.pending_doses.retain(|(key, pending, ready_at)| {
+            if *pending == metric && *ready_at <= now {
+                store.set_dose_after(key, value);
+                return false;
+            }
+            true
+        });
+    }
+
+    /// Persist a sample unless its probe is unplugged, the board still reports a value then
+    fn record_sample(&self, metric: Metric, connected: bool, sample: Option<f64>) {
+        if let (true, Some(sample)) = (connected, sample) {
+            self.store.insert_metric(metric, SystemTime::now(), sample);
         }
     }
 
+    fn on_device_event(&mut self, event: DeviceEvent) {
+        match event {
+            DeviceEvent::SensorConnected(sensor) => {
+                self.journal(EventKind::Device, LogLevel::Info, format!("{} connected !", sensor));
+                match sensor {
+                    Sensor::Tds => self.tds_1_samples.clear(),
+                    Sensor::Ph => self.ph_1_samples.clear(),
+                    Sensor::Temperature => {},
+                }
+            },
+            DeviceEvent::SensorDisconnected(sensor) => self.journal(EventKind::Device, LogLevel::Warn, format!("{} disconnected !", sensor)),
+            DeviceEvent::Valve(ValveState::Opened) | DeviceEvent::Valve(ValveState::Closed) | DeviceEvent::Pump(_) => self.journal(EventKind::Action, LogLevel::Info, event),
+            _ => debug!("Device: {}", event),
+        }
+    }
+
+    /// Warn about the frames dropped since the last check
+    fn check_frame_stats(&mut self) {
+        let stats = match self.handle.as_ref() {
+            Some(handle) => handle.frame_stats(),
+            None => return,
+        };
+        let last = std::mem::replace(&mut self.frame_stats, stats);
+        if stats.corrupted > last.corrupted || stats.duplicated > last.duplicated || stats.lost > last.lost || stats.rejected > last.rejected {
+            self.warn(format!("Serial link errors: {}", stats));
+        }
+    }
+
+    /// Journal an event and show it in the GUI
+    fn journal<T: ToString>(&self, kind: EventKind, level: LogLevel, msg: T) {
+        let event = self.store.record_event(kind, level, msg);
+        match kind {
+            EventKind::Query => self.to_gui(GuiEvent::Query(event.at, event.message)),
+            _ => self.to_gui(GuiEvent::Log(event.at, event.message, level)),
+        }
+    }
+
+    fn info<T: ToString>(&self, msg: T) {
+        self.journal(EventKind::Log, LogLevel::Info, msg)
+    }
+
+    fn query<T: ToString>(&self, msg: T) {
+        self.journal(EventKind::Query, LogLevel::Info, msg)
+    }
+
+    fn warn<T: ToString>(&self, msg: T) {
+        self.journal(EventKind::Log, LogLevel::Warn, msg)
+    }
+
     fn to_gui(&self, req: GuiEvent) {
         if let Some(gui) = self.gui.as_ref() {
             gui.do_send(req);
@@ -151,89 +314,119 @@ impl Handler<SchedulerRequest> for SchedulerActor {
     type Result = ();
     fn handle(&mut self, msg: SchedulerRequest, ctx: &mut Self::Context) -> Self::Result {
         match msg {
-            SchedulerRequest::SetTdsThresh {  thresh } => {
-                self.info(format!("Tds threshold updated to {}", thresh));
-                self.store.set_tds_1_thresh(thresh);
-                self.tds_monitor.threshold = thresh;
-            }
-            SchedulerRequest::SetOsmoseurPulseDuration {  duration } => {
-                self.info(format!("Osmoseur pulse duration updated to {}", duration.as_secs()));
-                self.store.set_osmoseur_pulse_duration(duration);
-                self.tds_monitor.pulse_duration = duration;
-            }
-            SchedulerRequest::SetOsmoseurPulseMinInterval {  interval } => {
-                self.info(format!("Osmoseur pulse minimum interval updated to {}", interval.as_secs()));
-                self.store.set_osmoseur_pulse_min_interval(interval);
-                self.tds_monitor.pulse_minimum_interval = interval;
-            }
-            SchedulerRequest::SetPhThresh {  thresh } => {
-                self.info(format!("ph threshold updated to {}", thresh));
-                self.store.set_ph_1_thresh(thresh);
-                self.ph_monitor.threshold = thresh;
-            }
-            SchedulerRequest::SetPhPulseDuration {  duration } => {
-                self.info(format!("ph pulse duration updated to {}", duration.as_secs()));
-                self.store.set_ph_pulse_duration(duration);
-                self.ph_monitor.pulse_duration = duration;
-            }
-            SchedulerRequest::SetPhPulseMinInterval {  interval } => {
-                self.info(format!("ph pulse minimum interval updated to {}", interval.as_secs()));
-                self.store.set_ph_pulse_min_interval(interval);
-                self.ph_monitor.pulse_minimum_interval = interval;
-            }
-            SchedulerRequest::SetEcMonitorEnabled { enabled } => {
-                self.ec_monitor_enabled = enabled;
-                self.store.set_tds_monitoring(enabled);
+            SchedulerRequest::SetSetting { setting, value } => match self.store.set(setting, value) {
+                Ok(value) => {
+                    self.apply_setting(setting, value);
+                    self.to_gui(GuiEvent::Setting(setting, value));
+                    self.journal(EventKind::Setting, LogLevel::Info, format!("{} updated to {}", setting, setting.spec().display(value)));
+                },
+                Err(e) => self.journal(EventKind::Setting, LogLevel::Warn, format!("Setting rejected: {}", e)),
             },
-            SchedulerRequest::SetPhMonitorEnabled { enabled } => {
-                self.ph_monitor_enabled = enabled;
-                self.store.set_ph_monitoring(enabled);
+            SchedulerRequest::ApplyProfile { name } => {
+                let _ = self.apply_profile(&name);
+            },
+            SchedulerRequest::CancelTask { id } => {
+                self.cancel_task(id, "cancelled by the user");
+            },
+            SchedulerRequest::AcknowledgeLockout => {
+                let _ = self.acknowledge_lockout();
+            },
+            SchedulerRequest::SaveProfile { name } => match self.store.save_profile(&name) {
+                Ok(_) => {
+                    self.to_gui(GuiEvent::Profile(Some(name.clone())));
+                    self.journal(EventKind::Setting, LogLevel::Info, format!("Settings saved in the {} profile", name));
+                },
+                Err(e) => self.journal(EventKind::Setting, LogLevel::Warn, format!("Profile not saved: {}", e)),
             },
             SchedulerRequest::Init { handle , gui} => {
-                self.handle = Some(handle);
                 self.gui = gui;
+                self.to_gui(GuiEvent::Link(LinkState::Connected(handle.name().to_string())));
+                if let Some(lockout) = self.lockout.clone() {
+                    self.warn(format!("Automation is locked out since a previous run: {}", lockout.reason));
+                    self.to_gui(GuiEvent::Lockout(Some(lockout)));
+                }
+                self.handle = Some(handle);
+                self.link_up = true;
                 ctx.run_interval(Duration::from_secs(1), |actor: &mut Self, _| {
-                    actor.to_board(SerialCommand::G1);
+                    if actor.link_up {
+                        // G1 first, its status tells whether the raw values of G0 come from plugged probes
+                        actor.to_board(SerialCommand::G1);
+                        actor.to_board(SerialCommand::G0);
+                    }
+                    actor.check_frame_stats();
                 });
             },
-            SchedulerRequest::Serial { result, success } => {
-                // self.info(format!("Recv ({}) {:?}",if success {"OK"} else{"ERROR"}, &result));
+            SchedulerRequest::Link { state } => {
+                match &state {
+                    LinkState::Connected(port) => {
+                        self.journal(EventKind::Device, LogLevel::Info, format!("Board reconnected on {}, resuming automation", port));
+                        self.link_up = true;
+                        self.to_board(SerialCommand::S0 { on: false });
+                        self.to_board(SerialCommand::S1 { mode: PeristalticPumpMode::Off });
+                    },
+                    LinkState::Disconnected(reason) => {
+                        self.journal(EventKind::Device, LogLevel::Error, format!("Board disconnected: {}, automation paused", reason));
+                        self.link_up = false;
+                        self.pause_automation();
+                    },
+                    LinkState::Reconnecting { attempt, delay } => {
+                        self.warn(format!("Looking for the board in {}s (attempt {})", delay.as_secs(), attempt));
+                    },
+                }
+                self.to_gui(GuiEvent::Link(state));
+            },
+            SchedulerRequest::Serial { result, .. } => {
                 match result {
-                    SerialCommandResult::S0 { on } if success => { self.osmoseur_pump.opened = on; },
-                    SerialCommandResult::S0 { .. } => { self.osmoseur_pump.poisoned = Some(HardwareError("Osmoseur pump healted")); },
-                    SerialCommandResult::S1 { .. } => {
-                        // self.listeners.get_mut("S1").map(|m| m.replace(e));
+                    SerialCommandResult::S0 { on } => self.set_opened(Resource::OsmoseurValve, on),
+                    SerialCommandResult::S1 { mode } => self.set_opened(Resource::PeristalticPump, mode.map(|mode| mode != PeristalticPumpMode::Off)),
+                    SerialCommandResult::Error { command: Some(command), .. } if Resource::from_command(&command).is_some() => {
+                        if let Some(resource) = Resource::from_command(&command) {
+                            self.journal(EventKind::Action, LogLevel::Error, format!("The board rejected a {} command", resource));
+                            self.tasks.poison_resource(resource, HardwareError("Rejected by the board"));
+                        }
+                    },
+                    SerialCommandResult::Error { error: BoardError::Calibration, .. } => {
+                        self.warn("No calibration found on the board, defaults loaded");
+                    },
+                    SerialCommandResult::Error { .. } => {
+                        self.warn(format!("Board error: `{}`", result));
+                    },
+                    SerialCommandResult::M0 | SerialCommandResult::M1 { .. } | SerialCommandResult::M2 { .. } => {
+                        self.info(format!("Board calibration: `{}`", result));
+                    },
+                    SerialCommandResult::S2 { .. } => {},
+                    SerialCommandResult::V0 { .. } | SerialCommandResult::V1 { .. } => {},
+                    SerialCommandResult::G0 { tds_1, ph_1 } => {
+                        self.record_sample(Metric::RawTds1, self.device.tds_connected, tds_1);
+                        self.record_sample(Metric::RawPh1, self.device.ph_connected, ph_1);
                     },
-                    SerialCommandResult::G0 {..} => {},
                     SerialCommandResult::G1 { tds_1, ph_1, status, t_1 } => {
                         if let Some(status) = status {
-                            if status.contains(Status::TDS_CONNECTED) && !self.status.contains(Status::TDS_CONNECTED) {
-                                self.info("TDS probe connected !");
-                                self.tds_1_samples.clear();
-                            }
-                            else if !status.contains(Status::TDS_CONNECTED) && self.status.contains(Status::TDS_CONNECTED) {
-                                self.warn("TDS probe disconnected !");
-                            }
-                            if status.contains(Status::PH_CONNECTED) && !self.status.contains(Status::PH_CONNECTED) {
-                                self.info("PH probe donnected !");
-                                self.tds_1_samples.clear();
-                            }
-                            else if !status.contains(Status::PH_CONNECTED) && self.status.contains(Status::PH_CONNECTED) {
-                                self.warn("PH probe disconnected !");
+                            let device = DeviceState::from(status);
+                            let events = self.device.transitions(&device);
+                            self.device = device;
+                            for event in events {
+                                self.on_device_event(event);
                             }
-                            self.status = status;
-                            self.to_gui(GuiEvent::Status(status));
+                            self.to_gui(GuiEvent::Device(device));
                         }
-                        if self.status.contains(Status::TDS_CONNECTED) {
+                        self.record_sample(Metric::Tds1, self.device.tds_connected, tds_1);
+                        self.record_sample(Metric::Ph1, self.device.ph_connected, ph_1);
+                        self.record_sample(Metric::Temperature1, self.device.temperature_connected, t_1);
+                        let now = timestamp_ms(SystemTime::now());
+                        if self.device.tds_connected {
                             if let Some(sample) = tds_1 {
+                                self.tds_1_samples.sample(now, sample);
                                 self.to_gui(GuiEvent::TdsSensore(sample, self.tds_1_samples.status));
                                 if let AnalyticStatus::Stable(current) = self.tds_1_samples.status {
+                                    self.complete_doses(Metric::Tds1, current);
                                     if self.ec_monitor_enabled {
-                                        if let Some(duration) = self.tds_monitor.update(current) {
-                                            if self.add_osmosed_water_task.is_some() {
+                                        if let Some(decision) = self.tds_monitor.update(current) {
+                                            self.journal(EventKind::Controller, LogLevel::Info, format!("TDS at {:.0} PPM, {}", current, decision));
+                                            let duration = decision.duration;
+                                            if self.tasks.busy(Resource::OsmoseurValve) {
                                                 self.query("Can't lower TDS for now, the task is already pending !");
-                                            } else { 
-                                                self.add_osmosed_water_task = Some(AddOsmoseurWaterTask::new(duration));
+                                            } else if self.spawn_task(Box::new(AddOsmoseurWaterTask::new(duration, DoseTrigger::TdsMonitor, Some(current)))).is_some() {
                                                 self.query("Lowering TDS value (adding clean water)");
                                             }
                                         }
@@ -242,17 +435,20 @@ impl Handler<SchedulerRequest> for SchedulerActor {
                             }
                         }
                         
-                        if self.status.contains(Status::PH_CONNECTED) {
+                        if self.device.ph_connected {
                             if let Some(sample) = ph_1 {
+                                self.ph_1_samples.sample(now, sample);
                                 self.to_gui(GuiEvent::PhSensore(sample, self.ph_1_samples.status));
                                 if let AnalyticStatus::Stable(current) = self.ph_1_samples.status {
+                                    self.complete_doses(Metric::Ph1, current);
                                     if self.ph_monitor_enabled {
-                                        if let Some(duration) = self.tds_monitor.update(current) {
-                                            if self.add_osmosed_water_task.is_some() {
+                                        if let Some(decision) = self.ph_monitor.update(current) {
+                                            self.journal(EventKind::Controller, LogLevel::Info, format!("PH at {:.2}, {}", current, decision));
+                                            let duration = decision.duration;
+                                            if self.tasks.busy(Resource::PeristalticPump) {
                                                 self.query("Can't lower PH for now, the task is already pending !");
-                                            } else { 
-                                                self.add_osmosed_water_task = Some(AddOsmoseurWaterTask::new(duration));
-                                                self.query("Lowering PH value (adding PH Down water)");
+                                            } else if self.spawn_task(Box::new(AddPhDownTask::new(duration, DoseTrigger::PhMonitor, Some(current)))).is_some() {
+                                                self.query("Lowering PH value (adding PH Down)");
                                             }
                                         }
                                     }
@@ -277,10 +473,115 @@ impl Actor for SchedulerActor {
     type Context = Context<SchedulerActor>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
-        ctx.run_interval(std::time::Duration::from_millis(200), |actor, ctx|{
-            if let Some(task) = actor.add_osmosed_water_task.take() {
-                actor.update_add_osmosed_water_task(task, ctx);
+        ctx.run_interval(std::time::Duration::from_millis(200), |actor, ctx| {
+            if !actor.shutting_down {
+                actor.watch_hardware(ctx);
+                actor.run_tasks(ctx);
             }
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::testing::*;
+    use crate::simulator::VirtualBoard;
+    use std::time::UNIX_EPOCH;
+
+    #[actix_rt::test]
+    async fn poll_raw_samples() {
+        let bench = Bench::start("scheduler-raw", VirtualBoard::default(), |_| {});
+        let raw_ph = |actor: &mut SchedulerActor| actor.store.samples(Metric::RawPh1, UNIX_EPOCH..SystemTime::now()).next().is_some();
+        assert!(bench.until(Duration::from_secs(5), raw_ph).await);
+        assert!(bench.store.samples(Metric::RawTds1, UNIX_EPOCH..SystemTime::now()).next().is_some());
+        assert!(bench.sent().iter().any(|line| line == "G0"));
+    }
+
+    /// Board reading without the status, the simulated probes only report once calibrated
+    fn reading(tds_1: Option<f64>, ph_1: Option<f64>) -> SchedulerRequest {
+        SchedulerRequest::Serial { result: SerialCommandResult::G1 { tds_1, ph_1, t_1: None, status: None }, success: true }
+    }
+
+    #[actix_rt::test]
+    async fn tds_pulse_from_readings() {
+        let bench = Bench::start("scheduler-tds", VirtualBoard::default(), |actor| {
+            actor.tds_1_samples = SamplesAnalytic::new(3, 4.0, Duration::from_secs(0));
+            actor.ec_monitor_enabled = true;
+            actor.tds_monitor.threshold = 600.0;
+            actor.tds_monitor.controller = Controller::new(ControllerMode::Proportional, Gains { kp: 0.01, ..Gains::default() }, Duration::from_secs(30));
+        });
+        assert!(bench.until(Duration::from_secs(3), |actor| actor.device.tds_connected && actor.device.valve == ValveState::Closed).await);
+        for tds in [899.0, 901.0, 900.0, 900.0, 900.0].iter() {
+            bench.scheduler.do_send(reading(Some(*tds), None));
+        }
+        assert!(bench.until(Duration::from_secs(3), |actor| actor.tasks.owned(Resource::OsmoseurValve)).await);
+        let decisions = bench.store.last_events(10, |event| event.kind == EventKind::Controller);
+        assert_eq!(decisions.len(), 1);
+        assert_eq!(decisions[0].message, "TDS at 900 PPM, error 300.00, P 3.0s + I 0.0s + D 0.0s = 3.0s");
+        assert!(bench.until(Duration::from_secs(3), |actor| actor.tasks.lock(Resource::OsmoseurValve).and_then(|lock| lock.opened) == Some(true)).await);
+        assert!(bench.sent().iter().any(|line| line == "S0 ON"));
+    }
+
+    #[actix_rt::test]
+    async fn ph_down_from_readings() {
+        let bench = Bench::start("scheduler-ph", VirtualBoard::default(), |actor| {
+            actor.ph_1_samples = SamplesAnalytic::new(3, 0.1, Duration::from_secs(0));
+            actor.ph_monitor_enabled = true;
+            actor.ph_monitor.threshold = 6.0;
+            actor.ph_monitor.pulse_duration = Duration::from_secs(2);
+        });
+        assert!(bench.until(Duration::from_secs(3), |actor| actor.device.ph_connected).await);
+        for ph in [6.79, 6.81, 6.8, 6.8, 6.8].iter() {
+            bench.scheduler.do_send(reading(None, Some(*ph)));
+        }
+        let queued = |actor: &mut SchedulerActor| actor.tasks.progress().iter().any(|task| task.name == "PH Down");
+        assert!(bench.until(Duration::from_secs(3), queued).await);
+        assert!(bench.until(Duration::from_secs(3), |actor| actor.tasks.lock(Resource::PeristalticPump).and_then(|lock| lock.opened) == Some(true)).await);
+        assert!(bench.sent().iter().any(|line| line == "S1 ON"));
+    }
+
+    /// Field incident: the peristaltic pump kept running without a task, the board never obeyed `S1 OFF`
+    const PUMP_LEFT_ON: &str = "0\t#\tsession started 2026-09-02T06:12:44+02:00
+0\t#\tconnected on /dev/ttyUSB0
+250\t<\tOK G1 TDS1 521.40 PH1 6.79 T1 21.50 STATUS 18829
+500\t<\tOK G1 TDS1 521.38 PH1 6.77 T1 21.50 STATUS 18829
+750\t<\tOK G1 TDS1 521.41 PH1 6.75 T1 21.50 STATUS 18829
+1000\t<\tOK G1 TDS1 521.39 PH1 6.73 T1 21.50 STATUS 18829
+1250\t<\tOK G1 TDS1 521.40 PH1 6.71 T1 21.50 STATUS 18829
+1500\t<\tOK G1 TDS1 521.42 PH1 6.69 T1 21.56 STATUS 18829
+1750\t<\tOK G1 TDS1 521.40 PH1 6.67 T1 21.56 STATUS 18829
+2000\t<\tOK G1 TDS1 521.37 PH1 6.65 T1 21.56 STATUS 18829
+2250\t<\tOK G1 TDS1 521.39 PH1 6.63 T1 21.56 STATUS 18829
+2500\t<\tOK G1 TDS1 521.40 PH1 6.61 T1 21.56 STATUS 18829
+2750\t<\tOK G1 TDS1 521.41 PH1 6.59 T1 21.56 STATUS 18829
+3000\t<\tOK G1 TDS1 521.40 PH1 6.57 T1 21.56 STATUS 18829
+";
+
+    #[actix_rt::test]
+    async fn replay_pump_left_on() {
+        let path = crate::store::testing::temporary_path("pump-left-on");
+        std::fs::write(&path, PUMP_LEFT_ON).unwrap();
+        let session = crate::daemon::session::Session::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let bench = Bench::replay("scheduler-replay", session, |actor| actor.hardware_timeout = Duration::from_secs(1));
+        let failed = |actor: &mut SchedulerActor| !actor.store.last_events(1, |event| event.message.starts_with("Emergency stop of the peristaltic pump failed")).is_empty();
+        assert!(bench.until(Duration::from_secs(5), failed).await);
+        bench.scheduler.do_send(SchedulerRequest::AcknowledgeLockout);
+        let (device, locked, poisoned) = bench.inspect(|actor, _| (actor.device, actor.lockout.is_some(), actor.tasks.poisoned(Resource::PeristalticPump))).await;
+        let journal = bench.journal();
+
+        assert_eq!((device.pump, device.valve, device.tds_connected), (PeristalticPumpMode::On, ValveState::Closed, true));
+        assert!(locked && poisoned);
+        assert_eq!(bench.store.lockout().map(|lockout| lockout.reason), Some("Peristaltic pump running without a task".to_string()));
+        let position = |message: &str| journal.iter().position(|line| line.starts_with(message));
+        let steps = ["The peristaltic pump is stuck", "Automation locked out", "Emergency stop of the peristaltic pump (attempt 1/5)", "Emergency stop of the peristaltic pump failed", "Lockout kept, the peristaltic pump is still not at rest"];
+        let positions: Vec<Option<usize>> = steps.iter().map(|step| position(step)).collect();
+        assert!(positions.iter().all(Option::is_some), "{:?}", journal);
+        assert!(positions.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", journal);
+        assert!(bench.store.samples(Metric::Tds1, UNIX_EPOCH..SystemTime::now()).next().is_some());
+        let sent = bench.sent();
+        assert!(sent.iter().any(|line| line == "G1"));
+        assert_eq!(sent.iter().filter(|line| line.starts_with("S")).collect::<Vec<_>>(), vec!["S1 OFF"]);
+    }
+}
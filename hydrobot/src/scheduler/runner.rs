@@ -0,0 +1,647 @@
+//! Hardware task runner.
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+use std::time::Instant;
+use actix::Context as ActorContext;
+use super::*;
+
+pub type TaskId = u64;
+
+/// Steps of the valve stepper between closed and opened
+const VALVE_STEPS: u32 = 3800;
+/// Blocking time of a valve step, a pass of the firmware loop also steps the pump while it runs
+const VALVE_STEP: Duration = Duration::from_micros(300);
+const PUMP_STEP: Duration = Duration::from_micros(6000);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Resource {
+    /// Osmosis water valve (`S0`)
+    OsmoseurValve,
+    /// pH Down peristaltic pump (`S1`)
+    PeristalticPump,
+    /// Bronchus pumps (`S2`)
+    BronchusPumps,
+}
+
+impl Resource {
+    pub const ALL: [Resource; 3] = [Resource::OsmoseurValve, Resource::PeristalticPump, Resource::BronchusPumps];
+
+    /// Command putting the resource back to rest, the bronchus pumps cycle on their own
+    pub fn rest_command(self) -> Option<SerialCommand> {
+        match self {
+            Resource::OsmoseurValve => Some(SerialCommand::S0 { on: false }),
+            Resource::PeristalticPump => Some(SerialCommand::S1 { mode: PeristalticPumpMode::Off }),
+            Resource::BronchusPumps => None,
+        }
+    }
+
+    /// Resource driven by the `command` the board rejected
+    pub fn from_command(command: &str) -> Option<Resource> {
+        match command {
+            "S0" => Some(Resource::OsmoseurValve),
+            "S1" => Some(Resource::PeristalticPump),
+            "S2" => Some(Resource::BronchusPumps),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Resource {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", match self {
+            Resource::OsmoseurValve => "osmoseur valve",
+            Resource::PeristalticPump => "peristaltic pump",
+            Resource::BronchusPumps => "bronchus pumps",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TaskPriority {
+    Low,
+    Normal,
+    High,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskState {
+    /// Waiting for its resources
+    Queued,
+    Running,
+    /// Waiting for the board to answer a request
+    WaitReply,
+}
+
+impl Display for TaskState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", match self {
+            TaskState::Queued => "queued",
+            TaskState::Running => "running",
+            TaskState::WaitReply => "waiting the board",
+        })
+    }
+}
+
+pub enum TaskStep {
+    /// Poll again on the next tick
+    Pending,
+    /// Send a command to the board, the reply goes to `HardwareTask::on_reply`
+    Request(SerialCommand),
+    Done,
+    /// Stop the task and put its resources back to rest
+    Failed,
+    /// The hardware may be stuck, the resources of the task stay locked
+    Poisoned(HardwareError),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum TaskEnd {
+    Done,
+    Failed,
+    Cancelled,
+    Poisoned(HardwareError),
+}
+
+pub trait HardwareTask {
+    fn name(&self) -> &'static str;
+    /// Locked for the whole run of the task
+    fn resources(&self) -> &'static [Resource];
+    fn priority(&self) -> TaskPriority {
+        TaskPriority::Normal
+    }
+    /// Called once the resources are locked then on every tick, except while a request is pending
+    fn poll(&mut self, actor: &mut SchedulerActor) -> TaskStep;
+    fn on_reply(&mut self, actor: &mut SchedulerActor, result: SerialResult<SerialCommandResult>) -> TaskStep;
+    /// Called once when the task leaves the runner, the resources are already released
+    fn finish(&mut self, _actor: &mut SchedulerActor, _end: TaskEnd) {
+    }
+    /// Between 0 and 1
+    fn progress(&self) -> Option<f64> {
+        None
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TaskProgress {
+    pub id: TaskId,
+    pub name: &'static str,
+    pub state: TaskState,
+    pub done: Option<f64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HardwareError(pub &'static str);
+
+impl Display for HardwareError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct PumpHardwareLock {
+    owner: Option<TaskId>,
+    pub opened: Option<bool>,
+    poisoned: Option<HardwareError>,
+}
+
+impl PumpHardwareLock {
+    pub fn poisoned(&self) -> Option<HardwareError> {
+        self.poisoned
+    }
+}
+
+struct TaskEntry {
+    id: TaskId,
+    name: &'static str,
+    priority: TaskPriority,
+    resources: &'static [Resource],
+    state: TaskState,
+    /// Command waiting for its reply
+    request: Option<SerialCommand>,
+    /// Taken out while one of its hooks runs
+    task: Option<Box<dyn HardwareTask>>,
+}
+
+pub struct TaskRunner {
+    next_id: TaskId,
+    /// Highest priority first, then by creation
+    entries: Vec<TaskEntry>,
+    locks: HashMap<Resource, PumpHardwareLock>,
+    /// Released by a failed or cancelled task, put back to rest on the next tick
+    resting: Vec<Resource>,
+    idle_reported: bool,
+}
+
+impl Default for TaskRunner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TaskRunner {
+    pub fn new() -> Self {
+        Self {
+            next_id: 0,
+            entries: Vec::new(),
+            locks: Resource::ALL.iter().map(|resource| (*resource, PumpHardwareLock::default())).collect(),
+            resting: Vec::new(),
+            idle_reported: true,
+        }
+    }
+
+    fn push(&mut self, task: Box<dyn HardwareTask>) -> TaskId {
+        self.next_id += 1;
+        let entry = TaskEntry {
+            id: self.next_id,
+            name: task.name(),
+            priority: task.priority(),
+            resources: task.resources(),
+            state: TaskState::Queued,
+            request: None,
+            task: Some(task),
+        };
+        let idx = self.entries.iter().position(|other| other.priority < entry.priority).unwrap_or(self.entries.len());
+        self.entries.insert(idx, entry);
+        self.next_id
+    }
+
+    fn entry(&self, id: TaskId) -> Option<&TaskEntry> {
+        self.entries.iter().find(|entry| entry.id == id)
+    }
+
+    fn take(&mut self, id: TaskId) -> Option<Box<dyn HardwareTask>> {
+        self.entries.iter_mut().find(|entry| entry.id == id).and_then(|entry| entry.task.take())
+    }
+
+    fn put_back(&mut self, id: TaskId, task: Box<dyn HardwareTask>, state: TaskState, request: Option<SerialCommand>) {
+        if let Some(entry) = self.entries.iter_mut().find(|entry| entry.id == id) {
+            entry.task = Some(task);
+            entry.state = state;
+            entry.request = request;
+        }
+    }
+
+    fn remove(&mut self, id: TaskId) {
+        self.entries.retain(|entry| entry.id != id);
+    }
+
+    /// Lock the resources of a queued task if they are all free and not `reserved`. A task left waiting
+    /// reserves its resources, the queued tasks are tried by priority so the lower ones can't starve it.
+    fn try_start(&mut self, id: TaskId, reserved: &mut Vec<Resource>) -> bool {
+        let resources = match self.entry(id) {
+            Some(entry) => entry.resources,
+            None => return false,
+        };
+        if resources.iter().any(|resource| reserved.contains(resource) || matches!(self.locks.get(resource), Some(lock) if lock.owner.is_some() || lock.poisoned.is_some())) {
+            reserved.extend_from_slice(resources);
+            return false;
+        }
+        for resource in resources.iter() {
+            if let Some(lock) = self.locks.get_mut(resource) {
+                lock.owner = Some(id);
+                lock.opened = None;
+            }
+        }
+        if let Some(entry) = self.entries.iter_mut().find(|entry| entry.id == id) {
+            entry.state = TaskState::Running;
+        }
+        true
+    }
+
+    /// Unlock the resources held by `id`, returns them
+    fn release(&mut self, id: TaskId) -> Vec<Resource> {
+        let mut released = Vec::new();
+        for (resource, lock) in self.locks.iter_mut() {
+            if lock.owner == Some(id) && lock.poisoned.is_none() {
+                lock.owner = None;
+                released.push(*resource);
+            }
+        }
+        released
+    }
+
+    fn poison(&mut self, id: TaskId, error: HardwareError) {
+        for lock in self.locks.values_mut().filter(|lock| lock.owner == Some(id)) {
+            lock.poisoned = Some(error);
+        }
+    }
+
+    pub fn poison_resource(&mut self, resource: Resource, error: HardwareError) {
+        if let Some(lock) = self.locks.get_mut(&resource) {
+            lock.poisoned = Some(error);
+        }
+    }
+
+    pub fn owned(&self, resource: Resource) -> bool {
+        matches!(self.locks.get(&resource), Some(lock) if lock.owner.is_some())
+    }
+
+    pub fn poisoned(&self, resource: Resource) -> bool {
+        matches!(self.locks.get(&resource), Some(lock) if lock.poisoned.is_some())
+    }
+
+    /// Whether a task holds or waits for `resource`, or it is poisoned
+    pub fn busy(&self, resource: Resource) -> bool {
+        matches!(self.locks.get(&resource), Some(lock) if lock.owner.is_some() || lock.poisoned.is_some())
+            || self.entries.iter().any(|entry| entry.resources.contains(&resource))
+    }
+
+    pub fn lock(&self, resource: Resource) -> Option<&PumpHardwareLock> {
+        self.locks.get(&resource)
+    }
+
+    pub fn lock_mut(&mut self, resource: Resource) -> Option<&mut PumpHardwareLock> {
+        self.locks.get_mut(&resource)
+    }
+
+    /// Forget the locks and poisons, the board was reset
+    pub fn reset_locks(&mut self) {
+        for lock in self.locks.values_mut() {
+            *lock = PumpHardwareLock::default();
+        }
+    }
+
+    pub fn ids(&self) -> Vec<TaskId> {
+        self.entries.iter().map(|entry| entry.id).collect()
+    }
+
+    pub fn progress(&self) -> Vec<TaskProgress> {
+        self.entries.iter().map(|entry| TaskProgress {
+            id: entry.id,
+            name: entry.name,
+            state: entry.state,
+            done: entry.task.as_ref().and_then(|task| task.progress()),
+        }).collect()
+    }
+}
+
+impl SchedulerActor {
+    /// Queue `task`, it starts once its resources are free. Refused while automation is locked out or shutting down.
+    pub fn spawn_task(&mut self, task: Box<dyn HardwareTask>) -> Option<TaskId> {
+        if self.shutting_down {
+            return None;
+        }
+        if self.lockout.is_some() {
+            self.query(format!("{} not started, automation is locked out", task.name()));
+            return None;
+        }
+        let id = self.tasks.push(task);
+        self.report_tasks();
+        Some(id)
+    }
+
+    /// Start the queued tasks whose resources are free and poll the running ones
+    pub fn run_tasks(&mut self, ctx: &mut ActorContext<SchedulerActor>) {
+        for resource in std::mem::take(&mut self.tasks.resting) {
+            self.rest_resource(resource, ctx);
+        }
+        let mut reserved = Vec::new();
+        for id in self.tasks.ids() {
+            match self.tasks.entry(id).map(|entry| entry.state) {
+                Some(TaskState::Queued) if self.tasks.try_start(id, &mut reserved) => {},
+                Some(TaskState::Running) => {},
+                _ => continue,
+            }
+            if let Some(mut task) = self.tasks.take(id) {
+                let step = task.poll(self);
+                self.step_task(id, task, step, ctx);
+            }
+        }
+        self.report_tasks();
+    }
+
+    fn step_task(&mut self, id: TaskId, task: Box<dyn HardwareTask>, step: TaskStep, ctx: &mut ActorContext<SchedulerActor>) {
+        match step {
+            TaskStep::Pending => self.tasks.put_back(id, task, TaskState::Running, None),
+            TaskStep::Request(req) => {
+                self.tasks.put_back(id, task, TaskState::WaitReply, Some(req));
+                let timeout = self.reply_timeout(req);
+                self.request(req, timeout, ctx, move |actor, result, ctx| actor.on_task_reply(id, result, ctx));
+            },
+            TaskStep::Done => self.end_task(id, task, TaskEnd::Done),
+            TaskStep::Failed => self.end_task(id, task, TaskEnd::Failed),
+            TaskStep::Poisoned(error) => self.end_task(id, task, TaskEnd::Poisoned(error)),
+        }
+    }
+
+    fn on_task_reply(&mut self, id: TaskId, result: SerialResult<SerialCommandResult>, ctx: &mut ActorContext<SchedulerActor>) {
+        // The task may have been cancelled meanwhile
+        if self.tasks.entry(id).map(|entry| entry.state) != Some(TaskState::WaitReply) {
+            return;
+        }
+        if let Some(mut task) = self.tasks.take(id) {
+            let step = task.on_reply(self, result);
+            self.step_task(id, task, step, ctx);
+        }
+    }
+
+    fn end_task(&mut self, id: TaskId, mut task: Box<dyn HardwareTask>, end: TaskEnd) {
+        if let TaskEnd::Poisoned(error) = end {
+            self.journal(EventKind::Action, LogLevel::Error, format!("{} task left the hardware locked: {}", task.name(), error));
+            self.tasks.poison(id, error);
+        }
+        // Its reply must not be taken for the one of the rest command, ie: `ERR S0 BUSY` while the valve opens
+        if let Some(request) = self.tasks.entry(id).and_then(|entry| entry.request) {
+            if let Some(handle) = self.handle.as_mut() {
+                handle.forget(request);
+            }
+        }
+        let released = self.tasks.release(id);
+        if let TaskEnd::Failed | TaskEnd::Cancelled = end {
+            // The shutdown puts everything back to rest by itself
+            if self.link_up && !self.shutting_down {
+                self.tasks.resting.extend(released.iter().filter(|resource| resource.rest_command().is_some()));
+            }
+        }
+        self.tasks.remove(id);
+        task.finish(self, end);
+    }
+
+    /// Longest time `resource` takes to reach the state it was asked
+    pub fn stroke(&self, resource: Resource) -> Duration {
+        match resource {
+            Resource::OsmoseurValve if self.device.pump != PeristalticPumpMode::Off => (VALVE_STEP + PUMP_STEP) * VALVE_STEPS,
+            Resource::OsmoseurValve => VALVE_STEP * VALVE_STEPS,
+            _ => Duration::from_secs(0),
+        }
+    }
+
+    /// The board only replies to a valve move once the valve stopped
+    pub fn reply_timeout(&self, command: SerialCommand) -> Duration {
+        match Resource::from_command(command.name()) {
+            Some(resource) => self.stroke(resource) + self.hardware_timeout,
+            None => self.hardware_timeout,
+        }
+    }
+
+    /// Send the rest command of `resource` until the board confirms it, a moving valve answers busy meanwhile
+    fn rest_resource(&mut self, resource: Resource, ctx: &mut ActorContext<SchedulerActor>) {
+        let command = match resource.rest_command() {
+            Some(command) => command,
+            None => return,
+        };
+        let deadline = Instant::now() + self.stroke(resource) + self.hardware_timeout;
+        ctx.spawn(self.rest(command, deadline).map(move |result, actor, _| match result {
+            Ok(_) => actor.set_opened(resource, Some(false)),
+            // The watchdog locks out if it stays that way
+            Err(e) => actor.journal(EventKind::Action, LogLevel::Error, format!("The {} didn't confirm its stop: {}", resource, e)),
+        }));
+    }
+
+    /// Last state reported by the board for `resource`
+    pub fn set_opened(&mut self, resource: Resource, opened: Option<bool>) {
+        if let Some(lock) = self.tasks.lock_mut(resource) {
+            lock.opened = opened;
+        }
+    }
+
+    /// Stop a task, returns whether it was there
+    pub fn cancel_task(&mut self, id: TaskId, reason: &str) -> bool {
+        let task = match self.tasks.entry(id) {
+            Some(_) => self.tasks.take(id),
+            None => return false,
+        };
+        match task {
+            Some(task) => {
+                self.warn(format!("{} task cancelled: {}", task.name(), reason));
+                self.end_task(id, task, TaskEnd::Cancelled);
+                self.report_tasks();
+                true
+            },
+            // One of its hooks is running, can't happen from the actor's own messages
+            None => false,
+        }
+    }
+
+    pub fn cancel_tasks(&mut self, reason: &str) {
+        for id in self.tasks.ids() {
+            self.cancel_task(id, reason);
+        }
+    }
+
+    /// Send the progress of the tasks to the GUI while there are some
+    fn report_tasks(&mut self) {
+        let progress = self.tasks.progress();
+        if progress.is_empty() && self.tasks.idle_reported {
+            return;
+        }
+        self.tasks.idle_reported = progress.is_empty();
+        self.to_gui(GuiEvent::Tasks(progress));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::testing::*;
+    use crate::simulator::VirtualBoard;
+    use std::collections::VecDeque;
+    use std::sync::Mutex;
+
+    struct Dummy(TaskPriority, &'static [Resource]);
+
+    impl HardwareTask for Dummy {
+        fn name(&self) -> &'static str {
+            "Dummy"
+        }
+
+        fn resources(&self) -> &'static [Resource] {
+            self.1
+        }
+
+        fn priority(&self) -> TaskPriority {
+            self.0
+        }
+
+        fn poll(&mut self, _actor: &mut SchedulerActor) -> TaskStep {
+            TaskStep::Pending
+        }
+
+        fn on_reply(&mut self, _actor: &mut SchedulerActor, _result: SerialResult<SerialCommandResult>) -> TaskStep {
+            TaskStep::Done
+        }
+    }
+
+    /// Plays its steps in order, one per poll or reply, and logs its hooks
+    struct Script {
+        steps: VecDeque<TaskStep>,
+        log: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Script {
+        fn new(steps: Vec<TaskStep>) -> (Self, Arc<Mutex<Vec<String>>>) {
+            let log = Arc::new(Mutex::new(Vec::new()));
+            (Script { steps: steps.into(), log: log.clone() }, log)
+        }
+
+        fn next(&mut self) -> TaskStep {
+            self.steps.pop_front().unwrap_or(TaskStep::Pending)
+        }
+    }
+
+    impl HardwareTask for Script {
+        fn name(&self) -> &'static str {
+            "Script"
+        }
+
+        fn resources(&self) -> &'static [Resource] {
+            &[Resource::PeristalticPump]
+        }
+
+        fn poll(&mut self, _actor: &mut SchedulerActor) -> TaskStep {
+            self.log.lock().unwrap().push("poll".to_string());
+            self.next()
+        }
+
+        fn on_reply(&mut self, _actor: &mut SchedulerActor, result: SerialResult<SerialCommandResult>) -> TaskStep {
+            self.log.lock().unwrap().push(match result {
+                Ok(result) => format!("reply {}", result),
+                Err(e) => format!("error {}", e),
+            });
+            self.next()
+        }
+
+        fn finish(&mut self, _actor: &mut SchedulerActor, end: TaskEnd) {
+            self.log.lock().unwrap().push(format!("finish {:?}", end));
+        }
+    }
+
+    fn pump(on: bool) -> TaskStep {
+        TaskStep::Request(SerialCommand::S1 { mode: if on { PeristalticPumpMode::On } else { PeristalticPumpMode::Off } })
+    }
+
+    #[test]
+    fn locks_and_priorities() {
+        let mut runner = TaskRunner::new();
+        let valve = runner.push(Box::new(Dummy(TaskPriority::Normal, &[Resource::OsmoseurValve])));
+        let both = runner.push(Box::new(Dummy(TaskPriority::Low, &[Resource::OsmoseurValve, Resource::PeristalticPump])));
+        let pump = runner.push(Box::new(Dummy(TaskPriority::High, &[Resource::PeristalticPump])));
+        assert_eq!(runner.ids(), vec![pump, valve, both]);
+        assert!(runner.busy(Resource::PeristalticPump));
+        assert!(!runner.busy(Resource::BronchusPumps));
+
+        assert!(runner.try_start(pump, &mut Vec::new()));
+        assert!(runner.try_start(valve, &mut Vec::new()));
+        // Both of its resources are taken
+        assert!(!runner.try_start(both, &mut Vec::new()));
+        assert_eq!(runner.release(pump), vec![Resource::PeristalticPump]);
+        runner.remove(pump);
+        assert!(!runner.try_start(both, &mut Vec::new()));
+
+        runner.poison(valve, HardwareError("Stuck"));
+        assert!(runner.release(valve).is_empty());
+        runner.remove(valve);
+        assert!(!runner.try_start(both, &mut Vec::new()));
+        runner.reset_locks();
+        assert!(runner.try_start(both, &mut Vec::new()));
+        let progress = runner.progress();
+        assert_eq!(progress.len(), 1);
+        assert_eq!((progress[0].id, progress[0].state), (both, TaskState::Running));
+    }
+
+    #[test]
+    fn no_starvation() {
+        let mut runner = TaskRunner::new();
+        let dosing = runner.push(Box::new(Dummy(TaskPriority::Low, &[Resource::PeristalticPump])));
+        assert!(runner.try_start(dosing, &mut Vec::new()));
+        let flush = runner.push(Box::new(Dummy(TaskPriority::High, &[Resource::OsmoseurValve, Resource::PeristalticPump])));
+        // Short water top-ups keep coming while the flush waits for the pump
+        for _ in 0..3 {
+            let water = runner.push(Box::new(Dummy(TaskPriority::Normal, &[Resource::OsmoseurValve])));
+            let mut reserved = Vec::new();
+            assert!(!runner.try_start(flush, &mut reserved));
+            assert!(!runner.try_start(water, &mut reserved));
+            assert!(!runner.owned(Resource::OsmoseurValve));
+        }
+        runner.release(dosing);
+        runner.remove(dosing);
+        assert!(runner.try_start(flush, &mut Vec::new()));
+    }
+
+    #[actix_rt::test]
+    async fn poll_and_reply() {
+        let bench = Bench::start("runner", VirtualBoard::default(), |_| {});
+        let (task, log) = Script::new(vec![pump(true), TaskStep::Pending, pump(false), TaskStep::Done]);
+        let id = bench.inspect(move |actor, _| actor.spawn_task(Box::new(task))).await.unwrap();
+        assert!(bench.until(Duration::from_secs(3), move |actor| actor.tasks.entry(id).is_none()).await);
+        assert_eq!(*log.lock().unwrap(), vec!["poll", "reply OK S1 ON", "poll", "reply OK S1 OFF", "finish Done"]);
+        assert!(!bench.inspect(|actor, _| actor.tasks.owned(Resource::PeristalticPump)).await);
+    }
+
+    #[actix_rt::test]
+    async fn release_on_failure_and_cancellation() {
+        let bench = Bench::start("runner-end", VirtualBoard::default(), |_| {});
+        let (failing, failed) = Script::new(vec![pump(true), TaskStep::Failed]);
+        let id = bench.inspect(move |actor, _| actor.spawn_task(Box::new(failing))).await.unwrap();
+        assert!(bench.until(Duration::from_secs(3), move |actor| actor.tasks.entry(id).is_none()).await);
+        assert_eq!(failed.lock().unwrap().last().map(String::as_str), Some("finish Failed"));
+
+        let (running, cancelled) = Script::new(vec![pump(true)]);
+        let id = bench.inspect(move |actor, _| actor.spawn_task(Box::new(running))).await.unwrap();
+        assert!(bench.until(Duration::from_secs(3), move |actor| actor.tasks.entry(id).map(|entry| entry.state) == Some(TaskState::Running)).await);
+        assert!(bench.inspect(move |actor, _| actor.cancel_task(id, "test")).await);
+        assert_eq!(cancelled.lock().unwrap().last().map(String::as_str), Some("finish Cancelled"));
+        assert!(!bench.inspect(|actor, _| actor.tasks.owned(Resource::PeristalticPump)).await);
+
+        // Both put the pump back to rest on the next tick
+        assert!(bench.until(Duration::from_secs(1), |actor| actor.tasks.resting.is_empty()).await);
+        assert!(bench.until(Duration::from_secs(1), |actor| actor.tasks.lock(Resource::PeristalticPump).and_then(|lock| lock.opened) == Some(false)).await);
+        assert_eq!(bench.sent().iter().filter(|line| line.starts_with("S1")).collect::<Vec<_>>(), vec!["S1 ON", "S1 OFF", "S1 ON", "S1 OFF"]);
+    }
+
+    #[actix_rt::test]
+    async fn cancel_while_the_valve_opens() {
+        let bench = Bench::start("runner-cancel", VirtualBoard::default(), |_| {});
+        assert!(bench.valve_closed().await);
+        let task = tasks::AddOsmoseurWaterTask::new(Duration::from_secs(30), DoseTrigger::Manual, None);
+        let id = bench.inspect(move |actor, _| actor.spawn_task(Box::new(task))).await.unwrap();
+        assert!(bench.until(Duration::from_secs(3), |actor| actor.device.valve == ValveState::Opening).await);
+        assert!(bench.inspect(move |actor, _| actor.cancel_task(id, "test")).await);
+
+        // Refused while the valve still opens, then retried until it closes
+        assert!(bench.until(Duration::from_secs(5), |actor| actor.device.valve == ValveState::Closed && !actor.tasks.busy(Resource::OsmoseurValve)).await);
+        assert!(bench.sent().iter().filter(|line| *line == "S0 OFF").count() >= 2);
+        assert!(bench.journal().iter().any(|line| line.ends_with("added by the osmoseur (manual)")));
+    }
+}
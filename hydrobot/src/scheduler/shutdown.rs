@@ -0,0 +1,117 @@
+//! Fail-safe shutdown.
+use std::time::Instant;
+use actix::fut;
+use super::*;
+
+/// Delay before sending the rest command again while the valve is still moving
+const BUSY_RETRY: Duration = Duration::from_millis(500);
+/// The pump first, the valve moves slower while it runs
+const REST_ORDER: [Resource; 3] = [Resource::PeristalticPump, Resource::OsmoseurValve, Resource::BronchusPumps];
+
+/// Replies whether the board confirmed every resource at rest
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub struct Shutdown;
+
+impl SchedulerActor {
+    /// Send a rest command, again while the board replies busy
+    pub(super) fn rest(&mut self, command: SerialCommand, deadline: Instant) -> ResponseActFuture<Self, SerialResult<SerialCommandResult>> {
+        let left = deadline.saturating_duration_since(Instant::now());
+        let request = match self.handle.as_mut() {
+            Some(handle) => handle.request_with_timeout(command, left),
+            None => return Box::pin(fut::ready(Err(SerialError::Disconnected))),
+        };
+        Box::pin(request.into_actor(self).then(move |result, actor, _| -> ResponseActFuture<Self, _> {
+            match result {
+                Err(SerialError::Board(SerialCommandResult::Error { error: BoardError::Busy, .. })) if Instant::now() + BUSY_RETRY < deadline => {
+                    Box::pin(actix_rt::time::delay_for(BUSY_RETRY).into_actor(actor).then(move |_, actor, _| actor.rest(command, deadline)))
+                },
+                result => Box::pin(fut::ready(result)),
+            }
+        }))
+    }
+
+    /// Put `resources` back to rest one after the other, each within the hardware timeout, resolves with whether they all are
+    fn rest_all(&mut self, resources: &'static [Resource], safe: bool) -> ResponseActFuture<Self, bool> {
+        let (resource, resources) = match resources.split_first() {
+            Some((resource, resources)) => (*resource, resources),
+            None => return Box::pin(fut::ready(safe)),
+        };
+        let command = match resource.rest_command() {
+            Some(command) => command,
+            None => return self.rest_all(resources, safe),
+        };
+        let deadline = Instant::now() + self.hardware_timeout;
+        Box::pin(self.rest(command, deadline).then(move |result, actor, _| {
+            let at_rest = match result {
+                Ok(_) => {
+                    actor.set_opened(resource, Some(false));
+                    actor.journal(EventKind::Action, LogLevel::Info, format!("The {} is at rest", resource));
+                    true
+                },
+                Err(e) => {
+                    actor.journal(EventKind::Action, LogLevel::Error, format!("The {} didn't confirm its stop: {}, check it by hand", resource, e));
+                    false
+                },
+            };
+            actor.rest_all(resources, safe && at_rest)
+        }))
+    }
+}
+
+impl Handler<Shutdown> for SchedulerActor {
+    type Result = ResponseActFuture<Self, bool>;
+
+    fn handle(&mut self, _: Shutdown, _ctx: &mut Self::Context) -> Self::Result {
+        self.shutting_down = true;
+        self.cancel_tasks("shutting down");
+        if !self.link_up {
+            self.journal(EventKind::Action, LogLevel::Error, "Shutting down without the board, check the valve and the pump by hand");
+            self.store.flush();
+            return Box::pin(fut::ready(false));
+        }
+        self.info("Shutting down, waiting for the valve and the pump to stop ...");
+        Box::pin(self.rest_all(&REST_ORDER, true).map(|safe, actor, _| {
+            actor.store.flush();
+            safe
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::testing::*;
+    use crate::simulator::VirtualBoard;
+
+    #[actix_rt::test]
+    async fn stop_the_valve_and_the_pump() {
+        let bench = Bench::start("shutdown", VirtualBoard::default(), |_| {});
+        let dose = |actor: &mut SchedulerActor, _: &mut Context<SchedulerActor>| actor.spawn_task(Box::new(AddPhDownTask::new(Duration::from_secs(10), DoseTrigger::Manual, None)));
+        assert!(bench.inspect(dose).await.is_some());
+        assert!(bench.until(Duration::from_secs(1), |actor| actor.tasks.lock(Resource::PeristalticPump).and_then(|lock| lock.opened) == Some(true)).await);
+        // The valve is still closing since the board booted
+        assert!(bench.scheduler.send(Shutdown).await.unwrap());
+        let journal = bench.journal();
+        assert!(journal.iter().any(|line| line == "PH Down task cancelled: shutting down"));
+        assert!(journal.iter().any(|line| line == "The osmoseur valve is at rest"));
+        assert!(journal.iter().any(|line| line == "The peristaltic pump is at rest"));
+        let sent = bench.sent();
+        assert!(sent.iter().filter(|line| *line == "S0 OFF").count() >= 2, "{:?}", sent);
+        assert!(sent.iter().any(|line| line == "S1 OFF"));
+        assert!(bench.inspect(dose).await.is_none());
+    }
+
+    #[actix_rt::test]
+    async fn give_up_at_the_deadline() {
+        let bench = Bench::start("shutdown-deadline", VirtualBoard::default(), |actor| actor.hardware_timeout = Duration::from_secs(1));
+        assert!(bench.until(Duration::from_secs(3), |actor| actor.device.valve == ValveState::Closed).await);
+        bench.stall("S1 OFF", usize::MAX);
+        let started = Instant::now();
+        assert!(!bench.scheduler.send(Shutdown).await.unwrap());
+        assert!(started.elapsed() < Duration::from_secs(2));
+        let journal = bench.journal();
+        assert!(journal.iter().any(|line| line == "The osmoseur valve is at rest"));
+        assert!(journal.iter().any(|line| line.starts_with("The peristaltic pump didn't confirm its stop")));
+    }
+}
@@ -1,72 +1,225 @@
-use std::sync::{Arc, RwLock};
 use std::time::Duration;
 use std::time::SystemTime;
-use std::future::Future;
-use std::task::{Context, Poll, Waker};
-use std::pin::Pin;
-use actix::{Context as ActorContext, ActorFuture};
 use super::*;
 
-#[derive(Clone, Copy, Debug)]
-enum AddOsmoseurWaterStatus {
-    WaitLock,
-    WaitOpen,
-    WaitClose,
-    WaitDuration,
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum DoseStatus {
+    Idle,
+    Starting,
+    Running,
+    Stopping,
 }
 
-pub struct AddOsmoseurWaterTask {
-    status: AddOsmoseurWaterStatus,
+/// Run a doser for a given duration, shared by the osmoseur valve and the pH Down pump tasks
+struct DoseRun {
+    status: DoseStatus,
     duration: Duration,
+    /// When the doser was asked to start, it may deliver before it confirms
+    asked: Option<SystemTime>,
     begin: Option<SystemTime>,
+    trigger: DoseTrigger,
+    /// Reading that triggered the task
+    before: Option<f64>,
 }
 
-impl AddOsmoseurWaterTask {
-    pub fn new(duration: Duration) -> Self {
+impl DoseRun {
+    fn new(duration: Duration, trigger: DoseTrigger, before: Option<f64>) -> Self {
         Self {
-            status: AddOsmoseurWaterStatus::WaitLock,
+            status: DoseStatus::Idle,
+            asked: None,
             begin: None,
-            duration: duration,
+            duration,
+            trigger,
+            before,
+        }
+    }
+
+    /// The operator goes first
+    fn priority(&self, automatic: TaskPriority) -> TaskPriority {
+        match self.trigger {
+            DoseTrigger::Manual => TaskPriority::High,
+            _ => automatic,
         }
     }
+
+    fn progress(&self) -> Option<f64> {
+        let elapsed = self.begin?.elapsed().unwrap_or_default();
+        Some((elapsed.as_secs_f64() / self.duration.as_secs_f64()).min(1.0))
+    }
+
+    /// Add what was delivered to the ledger, nothing if the doser never started
+    fn record(&self, actor: &mut SchedulerActor, doser: Doser, end: &TaskEnd) {
+        if let Some(begin) = self.begin.or(self.asked) {
+            // A doser that failed to stop may still be delivering, its `after` reading would mean nothing
+            let settled = !matches!(end, TaskEnd::Poisoned(_));
+            actor.record_dose(doser, self.trigger, begin, self.before, settled);
+        }
+    }
+}
+
+pub struct AddOsmoseurWaterTask {
+    run: DoseRun,
+}
+
+impl AddOsmoseurWaterTask {
+    pub fn new(duration: Duration, trigger: DoseTrigger, before: Option<f64>) -> Self {
+        Self { run: DoseRun::new(duration, trigger, before) }
+    }
 }
 
-impl SchedulerActor {
-    pub fn update_add_osmosed_water_task(&mut self, mut task: AddOsmoseurWaterTask, cx: &mut ActorContext<SchedulerActor>) {
-        match task.status {
-            AddOsmoseurWaterStatus::WaitLock if !self.osmoseur_pump.locked => {
-                self.osmoseur_pump.locked = true;
-                self.osmoseur_pump.opened = None;
-                self.handle.as_mut().unwrap().send(SerialCommand::S0{ on: true }).unwrap();
-                task.status = AddOsmoseurWaterStatus::WaitOpen;
+impl HardwareTask for AddOsmoseurWaterTask {
+    fn name(&self) -> &'static str {
+        "Osmoseur water"
+    }
+
+    fn resources(&self) -> &'static [Resource] {
+        &[Resource::OsmoseurValve]
+    }
+
+    fn priority(&self) -> TaskPriority {
+        self.run.priority(TaskPriority::Normal)
+    }
+
+    fn poll(&mut self, actor: &mut SchedulerActor) -> TaskStep {
+        match self.run.status {
+            DoseStatus::Idle => {
+                actor.info("Wait osmoseur valve to be opened ...");
+                self.run.status = DoseStatus::Starting;
+                self.run.asked = Some(SystemTime::now());
+                TaskStep::Request(SerialCommand::S0{ on: true })
+            },
+            DoseStatus::Running if self.run.begin.as_ref().unwrap().elapsed().unwrap_or_default() >= self.run.duration => {
+                actor.info("Wait osmoseur valve to be closed ...");
+                self.run.status = DoseStatus::Stopping;
+                TaskStep::Request(SerialCommand::S0{ on: false })
+            },
+            _ => TaskStep::Pending,
+        }
+    }
+
+    fn on_reply(&mut self, actor: &mut SchedulerActor, result: SerialResult<SerialCommandResult>) -> TaskStep {
+        match (self.run.status, result) {
+            (DoseStatus::Starting, Ok(_)) => {
+                actor.journal(EventKind::Action, LogLevel::Info, "Osmoseur valve opened !");
+                actor.set_opened(Resource::OsmoseurValve, Some(true));
+                self.run.begin.replace(SystemTime::now());
+                self.run.status = DoseStatus::Running;
+                TaskStep::Pending
             },
-            AddOsmoseurWaterStatus::WaitOpen => {
-                match self.osmoseur_pump.opened.as_ref() {
-                    Some(true) => {
-                        self.info("Osmoseur valve opened !");
-                        task.begin.replace(SystemTime::now());
-                        task.status = AddOsmoseurWaterStatus::WaitDuration;
-                    },
-                    Some(false) => {
-                        self.error("Failed to open valve !");
-                        return;
-                    },
-                    _ => self.info("Wait osmoseur valve to be opened ..."),
+            (DoseStatus::Starting, Err(e)) => {
+                if let SerialError::Board(_) = e {
+                    // Refused, nothing moved
+                    self.run.asked = None;
                 }
-            }
-            AddOsmoseurWaterStatus::WaitDuration if task.begin.as_ref().unwrap().elapsed().unwrap() >= task.duration => {
-                self.handle.as_mut().unwrap().send(SerialCommand::S0{on: false}).expect("Board disconnected !");
-                self.info("Wait osmoseur valve to be closed ...");
-                task.status = AddOsmoseurWaterStatus::WaitClose;
+                actor.journal(EventKind::Action, LogLevel::Error, format!("Failed to open valve: {}", e));
+                TaskStep::Failed
+            },
+            (_, Ok(_)) => {
+                actor.journal(EventKind::Action, LogLevel::Info, "Osmoseur valve closed !");
+                actor.set_opened(Resource::OsmoseurValve, Some(false));
+                TaskStep::Done
             },
-            AddOsmoseurWaterStatus::WaitClose if !self.osmoseur_pump.opened.unwrap_or_default() => {
-                self.info("Osmoseur valve closed !");
-                self.tds_monitor.resume();
-                self.osmoseur_pump.locked = false;
-                return;
+            (_, Err(e)) => {
+                // Keep the valve locked, we can't tell if water is still flowing
+                actor.journal(EventKind::Action, LogLevel::Error, format!("Failed to close valve: {}", e));
+                TaskStep::Poisoned(HardwareError("Osmoseur valve failed to close"))
             },
-            _ => {},
         }
-        self.add_osmosed_water_task = Some(task);
     }
-}
\ No newline at end of file
+
+    fn finish(&mut self, actor: &mut SchedulerActor, end: TaskEnd) {
+        self.run.record(actor, Doser::Osmoseur, &end);
+        if let TaskEnd::Poisoned(_) = end {
+            return;
+        }
+        actor.tds_monitor.resume();
+    }
+
+    fn progress(&self) -> Option<f64> {
+        self.run.progress()
+    }
+}
+
+pub struct AddPhDownTask {
+    run: DoseRun,
+}
+
+impl AddPhDownTask {
+    pub fn new(duration: Duration, trigger: DoseTrigger, before: Option<f64>) -> Self {
+        Self { run: DoseRun::new(duration, trigger, before) }
+    }
+}
+
+impl HardwareTask for AddPhDownTask {
+    fn name(&self) -> &'static str {
+        "PH Down"
+    }
+
+    fn resources(&self) -> &'static [Resource] {
+        &[Resource::PeristalticPump]
+    }
+
+    /// The dilution moves the pH too, the water top-up goes first
+    fn priority(&self) -> TaskPriority {
+        self.run.priority(TaskPriority::Low)
+    }
+
+    fn poll(&mut self, actor: &mut SchedulerActor) -> TaskStep {
+        match self.run.status {
+            DoseStatus::Idle => {
+                actor.info("Wait PH Down pump to be started ...");
+                self.run.status = DoseStatus::Starting;
+                self.run.asked = Some(SystemTime::now());
+                TaskStep::Request(SerialCommand::S1{ mode: PeristalticPumpMode::On })
+            },
+            DoseStatus::Running if self.run.begin.as_ref().unwrap().elapsed().unwrap_or_default() >= self.run.duration => {
+                actor.info("Wait PH Down pump to be stopped ...");
+                self.run.status = DoseStatus::Stopping;
+                TaskStep::Request(SerialCommand::S1{ mode: PeristalticPumpMode::Off })
+            },
+            _ => TaskStep::Pending,
+        }
+    }
+
+    fn on_reply(&mut self, actor: &mut SchedulerActor, result: SerialResult<SerialCommandResult>) -> TaskStep {
+        match (self.run.status, result) {
+            (DoseStatus::Starting, Ok(_)) => {
+                actor.journal(EventKind::Action, LogLevel::Info, "PH Down pump started !");
+                actor.set_opened(Resource::PeristalticPump, Some(true));
+                self.run.begin.replace(SystemTime::now());
+                self.run.status = DoseStatus::Running;
+                TaskStep::Pending
+            },
+            (DoseStatus::Starting, Err(e)) => {
+                if let SerialError::Board(_) = e {
+                    // Refused, nothing moved
+                    self.run.asked = None;
+                }
+                actor.journal(EventKind::Action, LogLevel::Error, format!("Failed to start the PH Down pump: {}", e));
+                TaskStep::Failed
+            },
+            (_, Ok(_)) => {
+                actor.journal(EventKind::Action, LogLevel::Info, "PH Down pump stopped !");
+                actor.set_opened(Resource::PeristalticPump, Some(false));
+                TaskStep::Done
+            },
+            (_, Err(e)) => {
+                // Keep the pump locked, we can't tell if it is still running
+                actor.journal(EventKind::Action, LogLevel::Error, format!("Failed to stop the PH Down pump: {}", e));
+                TaskStep::Poisoned(HardwareError("PH Down pump failed to stop"))
+            },
+        }
+    }
+
+    fn finish(&mut self, actor: &mut SchedulerActor, end: TaskEnd) {
+        self.run.record(actor, Doser::PhDown, &end);
+        if let TaskEnd::Poisoned(_) = end {
+            return;
+        }
+        actor.ph_monitor.resume();
+    }
+
+    fn progress(&self) -> Option<f64> {
+        self.run.progress()
+    }
+}
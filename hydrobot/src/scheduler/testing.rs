@@ -0,0 +1,183 @@
+//! Scheduler wired to a virtual board through a memory link, for the actor tests.
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Instant, UNIX_EPOCH};
+use crate::daemon::{framing, session::{self, Direction, Session, SessionRecorder}, transport::{memory_pair, MemoryTransport, Transport}};
+use crate::simulator::{self, VirtualBoard};
+use crate::store::testing::{temporary_path, temporary_store};
+use super::*;
+
+/// Run a closure on the actor, replies what it returns
+pub struct Inspect<F>(pub F);
+
+impl<F, R> Message for Inspect<F>
+where
+    F: FnOnce(&mut SchedulerActor, &mut Context<SchedulerActor>) -> R,
+    R: 'static,
+{
+    type Result = R;
+}
+
+impl<F, R> Handler<Inspect<F>> for SchedulerActor
+where
+    F: FnOnce(&mut SchedulerActor, &mut Context<SchedulerActor>) -> R,
+    R: 'static,
+{
+    type Result = MessageResult<Inspect<F>>;
+
+    fn handle(&mut self, msg: Inspect<F>, ctx: &mut Self::Context) -> Self::Result {
+        MessageResult((msg.0)(self, ctx))
+    }
+}
+
+/// Commands the board never reads, with how many more times
+type Stalls = Arc<Mutex<Vec<(&'static str, usize)>>>;
+
+pub struct Bench {
+    pub scheduler: Addr<SchedulerActor>,
+    pub store: Store,
+    path: PathBuf,
+    /// Traffic of the link
+    session: PathBuf,
+    stalls: Stalls,
+    stop: Arc<AtomicBool>,
+}
+
+impl Bench {
+    /// Start a scheduler on a fresh store talking to `board`, `setup` runs before the actor starts
+    pub fn start<F: FnOnce(&mut SchedulerActor)>(name: &str, board: VirtualBoard, setup: F) -> Self {
+        let (host, to_host) = memory_pair("host");
+        let (to_board, board_side) = memory_pair("board");
+        let stalls: Stalls = Arc::default();
+        let stop = Arc::new(AtomicBool::new(false));
+        thread::spawn(move || simulator::serve(board, Box::new(board_side)));
+        forward_replies(to_board.clone(), to_host.clone(), stop.clone());
+        forward_commands(to_host, to_board, stalls.clone(), stop.clone());
+        Self::connect(name, Box::new(host), PortConfig::default(), stalls, stop, setup)
+    }
+
+    /// Start a scheduler on a fresh store reading the board lines of `session`, in real time
+    pub fn replay<F: FnOnce(&mut SchedulerActor)>(name: &str, session: Session, setup: F) -> Self {
+        let mut port = session::replay(session, 1.0).unwrap();
+        port.set_timeout(Duration::from_secs(10)).unwrap();
+        let config = PortConfig { framing: false, ..PortConfig::default() };
+        Self::connect(name, Box::new(port), config, Arc::default(), Arc::default(), setup)
+    }
+
+    fn connect<F: FnOnce(&mut SchedulerActor)>(name: &str, port: Box<dyn Transport>, config: PortConfig, stalls: Stalls, stop: Arc<AtomicBool>, setup: F) -> Self {
+        let (store, path) = temporary_store(name);
+        let session = temporary_path(&format!("{}-session", name));
+        let mut actor = SchedulerActor::new(store.clone());
+        setup(&mut actor);
+        let scheduler = actor.start();
+        let recorder = SessionRecorder::create(&session).unwrap();
+        let handle = SerialDaemon::new(port, config, Some(recorder), scheduler.clone().recipient());
+        scheduler.do_send(SchedulerRequest::Init { handle, gui: None });
+        Self { scheduler, store, path, session, stalls, stop }
+    }
+
+    /// Drop the next `times` commands starting with `command` before the board reads them, as a board that stopped answering
+    pub fn stall(&self, command: &'static str, times: usize) {
+        self.stalls.lock().unwrap().push((command, times));
+    }
+
+    /// Commands the host sent, stalled ones included, unframed
+    pub fn sent(&self) -> Vec<String> {
+        let session = Session::load(&self.session).unwrap();
+        session.entries.into_iter().filter(|entry| entry.direction == Direction::Tx).map(|entry| match framing::decode(&entry.line) {
+            Ok((_, payload)) => payload.to_string(),
+            Err(_) => entry.line,
+        }).collect()
+    }
+
+    pub fn journal(&self) -> Vec<String> {
+        self.store.events(UNIX_EPOCH..SystemTime::now() + Duration::from_secs(60)).map(|event| event.message).collect()
+    }
+
+    pub async fn inspect<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut SchedulerActor, &mut Context<SchedulerActor>) -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        self.scheduler.send(Inspect(f)).await.unwrap()
+    }
+
+    /// The board closes the valve when it boots
+    pub async fn valve_closed(&self) -> bool {
+        self.until(Duration::from_secs(3), |actor| actor.device.valve == ValveState::Closed).await
+    }
+
+    /// Poll the actor until `f` holds, returns false on timeout
+    pub async fn until<F>(&self, timeout: Duration, f: F) -> bool
+    where
+        F: Fn(&mut SchedulerActor) -> bool + Clone + Send + 'static,
+    {
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            let f = f.clone();
+            if self.inspect(move |actor, _| f(actor)).await {
+                return true;
+            }
+            actix_rt::time::delay_for(Duration::from_millis(50)).await;
+        }
+        false
+    }
+}
+
+impl Drop for Bench {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        let _ = std::fs::remove_dir_all(&self.path);
+        let _ = std::fs::remove_file(&self.session);
+    }
+}
+
+fn forward_replies(mut from: MemoryTransport, mut to: MemoryTransport, stop: Arc<AtomicBool>) {
+    thread::spawn(move || {
+        let mut buffer = [0u8; 256];
+        while !stop.load(Ordering::Relaxed) {
+            match from.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(len) => if to.write_all(&buffer[..len]).is_err() {
+                    break;
+                },
+                Err(e) if e.kind() == ErrorKind::TimedOut => {},
+                Err(_) => break,
+            }
+        }
+    });
+}
+
+fn forward_commands(from: MemoryTransport, mut to: MemoryTransport, stalls: Stalls, stop: Arc<AtomicBool>) {
+    thread::spawn(move || {
+        let mut reader = BufReader::new(from);
+        let mut line = String::new();
+        while !stop.load(Ordering::Relaxed) {
+            match reader.read_line(&mut line) {
+                Ok(0) => break,
+                Ok(_) => {},
+                // A partial line stays in `line` until the rest comes
+                Err(e) if e.kind() == ErrorKind::TimedOut => continue,
+                Err(_) => break,
+            }
+            let raw = std::mem::take(&mut line);
+            let payload = framing::decode(raw.trim_end()).map(|(_, payload)| payload).unwrap_or_else(|_| raw.trim_end());
+            let stalled = stalls.lock().unwrap().iter_mut().any(|(command, times)| {
+                let hit = *times > 0 && payload.starts_with(*command);
+                if hit {
+                    *times -= 1;
+                }
+                hit
+            });
+            if stalled {
+                continue;
+            }
+            if to.write_all(raw.as_bytes()).is_err() {
+                break;
+            }
+        }
+    });
+}
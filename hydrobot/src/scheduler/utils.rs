@@ -2,6 +2,7 @@
 use actix::prelude::*;
 use std::time::{SystemTime, Duration};
 use std::collections::VecDeque;
+use super::controller::*;
 
 pub struct PulseMonitor {
     pub threshold: f64,
@@ -9,17 +10,19 @@ pub struct PulseMonitor {
     pub last_pulse: SystemTime,
     pub pulse_minimum_interval: Duration,
     pub suspend: bool,
+    pub controller: Controller,
 }
 
 
 impl PulseMonitor {
-    pub fn new(threshold: f64, pulse_minimum_interval: Duration, pulse_duration: Duration) -> Self {
+    pub fn new(threshold: f64, pulse_minimum_interval: Duration, pulse_duration: Duration, controller: Controller) -> Self {
         Self {
             suspend: false,
             threshold,
             pulse_duration,
             last_pulse: std::time::UNIX_EPOCH,
             pulse_minimum_interval,
+            controller,
         }
     }
 
@@ -27,13 +30,24 @@ impl PulseMonitor {
         self.suspend = false;
     }
 
-    pub fn update(&mut self, current: f64) -> Option<Duration> {
+    pub fn update(&mut self, current: f64) -> Option<PulseDecision> {
         if self.suspend {
-            None
-        } else if current > self.threshold && self.last_pulse.elapsed().unwrap_or_default() > self.pulse_minimum_interval {
-            self.last_pulse = SystemTime::now();
+            return None;
+        }
+        // No pulse can follow, stepping the controller on every reading would only feed noise to its derivative
+        if self.last_pulse.elapsed().unwrap_or_default() <= self.pulse_minimum_interval {
+            return None;
+        }
+        let now = SystemTime::now();
+        let decision = self.controller.update(current - self.threshold, self.pulse_duration, now);
+        if current > self.threshold {
+            if decision.duration < MIN_PULSE {
+                debug!("Pulse skipped, {}", decision);
+                return None;
+            }
+            self.last_pulse = now;
             self.suspend = true;
-            Some(self.pulse_duration)
+            Some(decision)
         } else {
             None
         }
@@ -96,7 +110,7 @@ impl SamplesAnalytic {
         let current = if let AnalyticStatus::Stabilizing(e, _) |  AnalyticStatus::Stable(e) | AnalyticStatus::Uprising(e) | AnalyticStatus::Downrising(e) = self.status {
             e
         } else {
-            min + max / 2.0
+            (min + max) / 2.0
         };
         let uprising_delta = max - current;
         let downrising_delta = current - min;
@@ -0,0 +1,293 @@
+//! Hardware watchdog.
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+use actix::Context as ActorContext;
+use super::*;
+
+const EMERGENCY_RETRY: Duration = Duration::from_secs(5);
+const EMERGENCY_ATTEMPTS: u32 = 5;
+
+#[derive(Debug, Default)]
+struct EmergencyRest {
+    attempts: u32,
+    last: Option<SystemTime>,
+    pending: bool,
+    /// Confirmed by the board, or given up
+    over: bool,
+}
+
+#[derive(Debug)]
+pub struct Watchdog {
+    /// Since when the board reports a resource moving or active without a task driving it
+    unexpected: HashMap<Resource, SystemTime>,
+    /// Last state of the valve, a new move gets its own stroke
+    valve: Option<ValveState>,
+    emergency: HashMap<Resource, EmergencyRest>,
+    /// Delay between two emergency stops of a resource
+    retry: Duration,
+    attempts: u32,
+}
+
+impl Default for Watchdog {
+    fn default() -> Self {
+        Self {
+            unexpected: HashMap::new(),
+            valve: None,
+            emergency: HashMap::new(),
+            retry: EMERGENCY_RETRY,
+            attempts: EMERGENCY_ATTEMPTS,
+        }
+    }
+}
+
+impl Watchdog {
+    pub fn reset(&mut self) {
+        self.unexpected.clear();
+        self.emergency.clear();
+    }
+}
+
+impl SchedulerActor {
+    /// Whether the board reports `resource` in a state no task should leave it in
+    fn unexpected_state(&self, resource: Resource) -> bool {
+        match resource {
+            // Moving is given its stroke on top of the timeout, whoever asked for it
+            Resource::OsmoseurValve => match self.device.valve {
+                ValveState::Opening | ValveState::Closing => true,
+                ValveState::Opened => !self.tasks.owned(resource),
+                _ => false,
+            },
+            Resource::PeristalticPump => self.device.pump != PeristalticPumpMode::Off && !self.tasks.owned(resource),
+            // Cycled by the firmware
+            Resource::BronchusPumps => false,
+        }
+    }
+
+    fn at_rest(&self, resource: Resource) -> bool {
+        match resource {
+            Resource::OsmoseurValve => self.device.valve == ValveState::Closed,
+            Resource::PeristalticPump => self.device.pump == PeristalticPumpMode::Off,
+            Resource::BronchusPumps => true,
+        }
+    }
+
+    /// Run on every scheduler tick
+    pub fn watch_hardware(&mut self, ctx: &mut ActorContext<SchedulerActor>) {
+        if !self.link_up {
+            return;
+        }
+        let now = SystemTime::now();
+        if self.watchdog.valve.replace(self.device.valve) != Some(self.device.valve) {
+            self.watchdog.unexpected.remove(&Resource::OsmoseurValve);
+        }
+        for resource in Resource::ALL.iter().copied() {
+            if !self.unexpected_state(resource) {
+                self.watchdog.unexpected.remove(&resource);
+                continue;
+            }
+            let since = *self.watchdog.unexpected.entry(resource).or_insert(now);
+            let limit = match self.device.valve {
+                ValveState::Opening | ValveState::Closing if resource == Resource::OsmoseurValve => self.stroke(resource) + self.hardware_timeout,
+                _ => self.hardware_timeout,
+            };
+            if now.duration_since(since).unwrap_or_default() >= limit && !self.tasks.poisoned(resource) {
+                self.journal(EventKind::Action, LogLevel::Error, format!("The {} is stuck", resource));
+                self.tasks.poison_resource(resource, HardwareError(match resource {
+                    Resource::OsmoseurValve => "Osmoseur valve stuck",
+                    Resource::PeristalticPump => "Peristaltic pump running without a task",
+                    Resource::BronchusPumps => "Bronchus pumps stuck",
+                }));
+            }
+        }
+        let poisoned: Vec<(Resource, HardwareError)> = Resource::ALL.iter().filter_map(|resource| {
+            self.tasks.lock(*resource).and_then(|lock| lock.poisoned()).map(|error| (*resource, error))
+        }).collect();
+        if let Some((_, error)) = poisoned.first() {
+            if self.lockout.is_none() {
+                self.lock_out(error.to_string());
+            }
+        }
+        for (resource, _) in poisoned {
+            self.emergency_rest(resource, now, ctx);
+        }
+    }
+
+    fn emergency_rest(&mut self, resource: Resource, now: SystemTime, ctx: &mut ActorContext<SchedulerActor>) {
+        let command = match resource.rest_command() {
+            Some(command) => command,
+            None => return,
+        };
+        let at_rest = self.at_rest(resource);
+        let (retry, attempts) = (self.watchdog.retry, self.watchdog.attempts);
+        let emergency = self.watchdog.emergency.entry(resource).or_default();
+        if emergency.over || emergency.pending || matches!(emergency.last, Some(last) if now.duration_since(last).unwrap_or_default() < retry) {
+            return;
+        }
+        if at_rest && emergency.attempts > 0 {
+            emergency.over = true;
+            return;
+        }
+        if emergency.attempts >= attempts {
+            emergency.over = true;
+            self.journal(EventKind::Action, LogLevel::Error, format!("The {} didn't come back to rest after {} attempts, check it by hand", resource, attempts));
+            return;
+        }
+        emergency.attempts += 1;
+        emergency.last = Some(now);
+        emergency.pending = true;
+        let attempt = emergency.attempts;
+        self.journal(EventKind::Action, LogLevel::Warn, format!("Emergency stop of the {} (attempt {}/{})", resource, attempt, attempts));
+        let timeout = self.reply_timeout(command);
+        self.request(command, timeout, ctx, move |actor, result, _| {
+            let emergency = match actor.watchdog.emergency.get_mut(&resource) {
+                Some(emergency) => emergency,
+                // Acknowledged meanwhile
+                None => return,
+            };
+            emergency.pending = false;
+            match result {
+                Ok(_) => {
+                    emergency.over = true;
+                    actor.set_opened(resource, Some(false));
+                    actor.journal(EventKind::Action, LogLevel::Warn, format!("The {} confirmed the emergency stop", resource));
+                },
+                Err(e) => actor.journal(EventKind::Action, LogLevel::Error, format!("Emergency stop of the {} failed: {}", resource, e)),
+            }
+        });
+    }
+
+    /// Stop automation until an operator acknowledges it, kept across restarts
+    pub fn lock_out(&mut self, reason: String) {
+        let lockout = Lockout::new(reason);
+        if let Err(e) = self.store.set_lockout(&lockout) {
+            self.warn(format!("Failed to store the lockout: {}", e));
+        }
+        self.journal(EventKind::Action, LogLevel::Error, format!("Automation locked out: {}", lockout.reason));
+        self.lockout = Some(lockout.clone());
+        self.cancel_tasks("automation is locked out");
+        self.to_gui(GuiEvent::Lockout(Some(lockout)));
+    }
+
+    /// Refused while the board still reports the hardware out of its rest state
+    pub fn acknowledge_lockout(&mut self) -> SchedulerResult<Option<Lockout>> {
+        let lockout = match self.lockout.clone() {
+            Some(lockout) => lockout,
+            None => return Ok(None),
+        };
+        // A poisoned resource is still owned by the task that left it
+        let stuck: Vec<String> = Resource::ALL.iter()
+            .filter(|resource| self.unexpected_state(**resource) || (self.tasks.poisoned(**resource) && !self.at_rest(**resource)))
+            .map(|resource| resource.to_string())
+            .collect();
+        if !stuck.is_empty() {
+            let e = SchedulerError::NotAtRest(stuck.join(" and the "));
+            self.warn(e.to_string());
+            return Err(e);
+        }
+        if let Err(e) = self.store.clear_lockout() {
+            self.warn(format!("Failed to clear the stored lockout: {}", e));
+        }
+        self.lockout = None;
+        self.tasks.reset_locks();
+        self.watchdog.reset();
+        self.tds_monitor.resume();
+        self.ph_monitor.resume();
+        self.journal(EventKind::Action, LogLevel::Info, "Lockout acknowledged, automation resumed");
+        self.to_gui(GuiEvent::Lockout(None));
+        Ok(Some(lockout))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::testing::*;
+    use crate::simulator::VirtualBoard;
+
+    /// Once the valve closed on boot
+    async fn bench(name: &str, attempts: u32) -> Bench {
+        let bench = Bench::start(name, VirtualBoard::default(), move |actor| {
+            actor.hardware_timeout = Duration::from_secs(1);
+            actor.watchdog.retry = Duration::from_millis(200);
+            actor.watchdog.attempts = attempts;
+        });
+        assert!(bench.until(Duration::from_secs(3), |actor| actor.device.valve == ValveState::Closed).await);
+        bench
+    }
+
+    fn ph_down(actor: &mut SchedulerActor) -> Option<TaskId> {
+        actor.spawn_task(Box::new(AddPhDownTask::new(Duration::from_millis(300), DoseTrigger::Manual, None)))
+    }
+
+    /// Whether the journal holds `steps` in this order
+    fn in_order(journal: &[String], steps: &[&str]) -> bool {
+        let mut lines = journal.iter();
+        steps.iter().all(|step| lines.any(|line| line.starts_with(step)))
+    }
+
+    #[actix_rt::test]
+    async fn emergency_stop_after_a_stalled_reply() {
+        let bench = bench("watchdog-stop", 5).await;
+        // The stop of the task and the first emergency stop never reach the board
+        bench.stall("S1 OFF", 2);
+        assert!(bench.inspect(|actor, _| ph_down(actor)).await.is_some());
+        let confirmed = |actor: &mut SchedulerActor| actor.watchdog.emergency.get(&Resource::PeristalticPump).map_or(false, |emergency| emergency.over);
+        assert!(bench.until(Duration::from_secs(6), confirmed).await);
+        let journal = bench.journal();
+        assert!(in_order(&journal, &[
+            "PH Down pump started !",
+            "Failed to stop the PH Down pump",
+            "Automation locked out: PH Down pump failed to stop",
+            "Emergency stop of the peristaltic pump (attempt 1/5)",
+            "Emergency stop of the peristaltic pump failed",
+            "Emergency stop of the peristaltic pump (attempt 2/5)",
+            "The peristaltic pump confirmed the emergency stop",
+        ]), "{:?}", journal);
+        assert_eq!(bench.store.lockout().map(|lockout| lockout.reason), Some("PH Down pump failed to stop".to_string()));
+        // Recorded even though the pump may still run
+        assert!(journal.iter().any(|line| line.ends_with("added by the ph_down (manual)")), "{:?}", journal);
+
+        assert!(bench.inspect(|actor, _| ph_down(actor)).await.is_none());
+        assert!(bench.until(Duration::from_secs(3), |actor| actor.device.pump == PeristalticPumpMode::Off).await);
+        bench.scheduler.do_send(SchedulerRequest::AcknowledgeLockout);
+        let (locked, task) = bench.inspect(|actor, _| (actor.lockout.is_some(), ph_down(actor))).await;
+        assert!(!locked && task.is_some());
+        assert_eq!(bench.store.lockout(), None);
+    }
+
+    #[actix_rt::test]
+    async fn give_up_and_keep_the_lockout() {
+        let bench = bench("watchdog-give-up", 2).await;
+        bench.stall("S1 OFF", 3);
+        assert!(bench.inspect(|actor, _| ph_down(actor)).await.is_some());
+        let given_up = |actor: &mut SchedulerActor| actor.watchdog.emergency.get(&Resource::PeristalticPump).map_or(false, |emergency| emergency.over);
+        assert!(bench.until(Duration::from_secs(8), given_up).await);
+        assert!(bench.journal().iter().any(|line| line == "The peristaltic pump didn't come back to rest after 2 attempts, check it by hand"));
+
+        // The pump still runs on the board
+        bench.scheduler.do_send(SchedulerRequest::AcknowledgeLockout);
+        assert!(bench.inspect(|actor, _| actor.lockout.is_some()).await);
+        assert!(bench.journal().iter().any(|line| line == "Lockout kept, the peristaltic pump is still not at rest"));
+        assert!(bench.inspect(|actor, _| ph_down(actor)).await.is_none());
+
+        // Stopped by hand
+        bench.inspect(|actor, _| actor.to_board(SerialCommand::S1 { mode: PeristalticPumpMode::Off })).await;
+        assert!(bench.until(Duration::from_secs(3), |actor| actor.device.pump == PeristalticPumpMode::Off).await);
+        bench.scheduler.do_send(SchedulerRequest::AcknowledgeLockout);
+        assert!(!bench.inspect(|actor, _| actor.lockout.is_some()).await);
+        assert_eq!(bench.store.lockout(), None);
+    }
+
+    #[actix_rt::test]
+    async fn valve_moves_within_its_stroke() {
+        let bench = bench("watchdog-stroke", 5).await;
+        bench.inspect(|actor, _| actor.hardware_timeout = Duration::from_millis(200)).await;
+        let water = |actor: &mut SchedulerActor, _: &mut ActorContext<SchedulerActor>| actor.spawn_task(Box::new(AddOsmoseurWaterTask::new(Duration::from_millis(300), DoseTrigger::Manual, None)));
+        assert!(bench.inspect(water).await.is_some());
+        assert!(bench.until(Duration::from_secs(3), |actor| actor.device.valve == ValveState::Opening).await);
+        // Opening then closing take longer than the timeout each
+        assert!(bench.until(Duration::from_secs(6), |actor| !actor.tasks.busy(Resource::OsmoseurValve)).await, "{:?}", bench.journal());
+        assert!(bench.valve_closed().await);
+        assert!(bench.inspect(|actor, _| actor.lockout.is_none()).await, "{:?}", bench.journal());
+    }
+}
@@ -0,0 +1,680 @@
+//! Virtual Mega2560 speaking the same serial protocol as the firmware (`src/main.cpp`).
+use crate::daemon::{Status, framing, transport::Transport};
+use serialport::{SerialPort, posix::TTYPort};
+use std::io::{Read, Write, ErrorKind};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use std::thread;
+
+const TDS_SAMPLE_INTERVAL: u64 = 100;
+const PH_SAMPLE_INTERVAL: u64 = 100;
+const TDS_1_FILTER_CALIBRATION_DURATION: u64 = 10000;
+const PH_1_FILTER_CALIBRATION_DURATION: u64 = 10000;
+const TEMPERATURE_1_CALIBRATION_DURATION: u64 = 2000;
+const TDS_FILTER_WEIGHT: i64 = 5;
+const PH_FILTER_WEIGHT: i64 = 2;
+const TEMP_RESOLUTION: u32 = 12;
+
+const WATER_VALVE_DELTA: i32 = 1900;
+/// Stepper::step blocking time for 200 steps/rev at 1000 RPM
+const WATER_VALVE_STEP_US: u64 = 300;
+/// Stepper::step blocking time for 200 steps/rev at 50 RPM
+const PPUMP_1_STEP_US: u64 = 6000;
+/// Duration of a loop pass when no stepper is blocking it
+const LOOP_US: u64 = 1000;
+
+const BRONCHUS_FILL_DURATION: u64 = 21000;
+const BRONCHUS_EMPTY_DURATION: u64 = 20000;
+const BRONCHUS_STANDBY_FULL_DURATION: u64 = 40000;
+const BRONCHUS_STANDBY_SAMPLING_DURATION: u64 = 120000;
+
+const COMMAND_BUFFER_SIZE: usize = 64;
+const PROTOCOL_VERSION: u32 = 2;
+
+const RES2: f64 = 857.39;
+const ECREF: f64 = 255.86;
+const VREF: f64 = 5.0;
+
+const M_OSMOS_SWITCH_BUSY: Status = Status::from_bits_truncate(Status::OSMOS_SWITCH_OPENING.bits() | Status::OSMOS_SWITCH_CLOSING.bits());
+const M_STEPPER_RUNNING: Status = Status::from_bits_truncate(M_OSMOS_SWITCH_BUSY.bits() | Status::PERISTALIC_PUMP_ON.bits() | Status::PERISTALIC_PUMP_REV.bits());
+
+/// Port of MegunoLink `ExponentialFilter<long>`
+struct ExponentialFilter {
+    weight_new: i64,
+    current: i64,
+}
+
+impl ExponentialFilter {
+    fn new(weight_new: i64, initial: i64) -> Self {
+        Self { weight_new, current: initial }
+    }
+
+    fn filter(&mut self, new: i64) {
+        self.current = (100 * self.weight_new * new + (100 - self.weight_new) * self.current + 50) / 100;
+    }
+
+    fn current(&self) -> i64 {
+        (self.current + 50) / 100
+    }
+
+    fn set_current(&mut self, new: i64) {
+        self.current = new * 100;
+    }
+}
+
+/// Physical model of the tank the probes are dipped in
+#[derive(Debug, Clone)]
+pub struct WaterModel {
+    /// Total dissolved solids (PPM)
+    pub tds: f64,
+    pub ph: f64,
+    /// Water temperature (°C)
+    pub temperature: f64,
+    /// TDS increase per second caused by evaporation
+    pub tds_drift: f64,
+    /// pH increase per second caused by the nutrients
+    pub ph_drift: f64,
+    /// TDS decrease per second while the osmosis valve is opened
+    pub valve_flow_effect: f64,
+    /// pH decrease per second while the peristaltic pump pushes pH Down
+    pub ph_down_effect: f64,
+    pub tds_connected: bool,
+    pub ph_connected: bool,
+    pub temperature_connected: bool,
+    noise_seed: u64,
+}
+
+impl Default for WaterModel {
+    fn default() -> Self {
+        Self {
+            tds: 520.0,
+            ph: 6.8,
+            temperature: 21.5,
+            tds_drift: 0.01,
+            ph_drift: 0.0002,
+            valve_flow_effect: 2.0,
+            ph_down_effect: 0.01,
+            tds_connected: true,
+            ph_connected: true,
+            temperature_connected: true,
+            noise_seed: 0x2545_F491_4F6C_DD1D,
+        }
+    }
+}
+
+impl WaterModel {
+    fn update(&mut self, elapsed: f64, valve_opened: bool, ph_down: bool, ph_up: bool) {
+        self.tds = (self.tds + self.tds_drift * elapsed).max(0.0);
+        self.ph += self.ph_drift * elapsed;
+        if valve_opened {
+            self.tds = (self.tds - self.valve_flow_effect * elapsed).max(0.0);
+        }
+        if ph_down {
+            self.ph -= self.ph_down_effect * elapsed;
+        }
+        if ph_up {
+            self.ph += self.ph_down_effect * elapsed;
+        }
+        self.ph = self.ph.max(0.0).min(14.0);
+    }
+
+    /// xorshift noise in [-1, 1]
+    fn noise(&mut self) -> f64 {
+        self.noise_seed ^= self.noise_seed << 13;
+        self.noise_seed ^= self.noise_seed >> 7;
+        self.noise_seed ^= self.noise_seed << 17;
+        (self.noise_seed % 2001) as f64 / 1000.0 - 1.0
+    }
+
+    /// Value returned by `analogRead(TDS_1_PIN)`
+    fn tds_analog(&mut self) -> i64 {
+        if !self.tds_connected {
+            return 0;
+        }
+        let target = self.tds + self.noise();
+        let (mut low, mut high) = (1, 1023);
+        while low < high {
+            let mid = (low + high) / 2;
+            if read_ec(mid as f64, 25.0) < target { low = mid + 1 } else { high = mid }
+        }
+        low
+    }
+
+    /// Value returned by `1023 - analogRead(PH_1_PIN)`
+    fn ph_analog(&mut self) -> i64 {
+        if !self.ph_connected {
+            return 0;
+        }
+        let ph = self.ph + self.noise() * 0.02;
+        ((ph * 100.0 * 1024.0 / 1400.0).round() as i64).max(1).min(1023)
+    }
+}
+
+fn read_ec(analog: f64, temperature: f64) -> f64 {
+    let voltage = analog * VREF / 1024.0;
+    let compensation_coefficient = 1.0 + 0.02 * (temperature - 25.0);
+    let compensation_voltage = voltage / compensation_coefficient;
+    (133.42 * compensation_voltage.powi(3) - ECREF * compensation_voltage.powi(2) + RES2 * compensation_voltage) * 0.5
+}
+
+fn read_ph(ph: i64, _temperature: f64) -> f64 {
+    ph as f64 / 100.0
+}
+
+/// Arduino `map()`
+fn map(x: i64, in_min: i64, in_max: i64, out_min: i64, out_max: i64) -> i64 {
+    (x - in_min) * (out_max - out_min) / (in_max - in_min) + out_min
+}
+
+/// Case insensitive prefix comparison behaving like `strncasecmp(token, expected, n) == 0`
+fn strncasecmp(token: &str, expected: &str, n: usize) -> bool {
+    let token: Vec<u8> = token.bytes().take(n).map(|b| b.to_ascii_uppercase()).collect();
+    let expected: Vec<u8> = expected.bytes().take(n).map(|b| b.to_ascii_uppercase()).collect();
+    token == expected
+}
+
+pub struct VirtualBoard {
+    pub water: WaterModel,
+    status: Status,
+    /// Board clock in microseconds
+    clock: u64,
+    uptime: u64,
+    breath_step: u64,
+    water_valve_current_angle: i32,
+    tds_1_map: [i64; 2],
+    tds_1_filter: ExponentialFilter,
+    tds_1_raw: i64,
+    ph_1_filter: ExponentialFilter,
+    ph_1_raw: i64,
+    temp_1_raw: f64,
+    last_tds_update: u64,
+    last_ph_update: u64,
+    last_temp_update: u64,
+    temp_conversion_duration: u64,
+    command_buffer: Vec<u8>,
+    rx: VecDeque<u8>,
+    tx: String,
+    /// Line being printed, framed once complete
+    line: String,
+    framed: bool,
+    seq: u8,
+    last_host_seq: Option<u8>,
+}
+
+impl Default for VirtualBoard {
+    fn default() -> Self {
+        Self::new(WaterModel::default())
+    }
+}
+
+impl VirtualBoard {
+    pub fn new(water: WaterModel) -> Self {
+        let mut board = Self {
+            water,
+            status: Status::BREATHING | Status::OSMOS_SWITCH_CLOSING | Status::BRONCHUS_STANDBY_SAMPLING,
+            clock: 0,
+            uptime: 0,
+            breath_step: 0,
+            water_valve_current_angle: -WATER_VALVE_DELTA,
+            tds_1_map: [0, 0],
+            tds_1_filter: ExponentialFilter::new(TDS_FILTER_WEIGHT, 1),
+            tds_1_raw: 0,
+            ph_1_filter: ExponentialFilter::new(PH_FILTER_WEIGHT, 600),
+            ph_1_raw: 0,
+            temp_1_raw: 0.0,
+            last_tds_update: 0,
+            last_ph_update: 0,
+            last_temp_update: 0,
+            temp_conversion_duration: 750 / (1 << (12 - TEMP_RESOLUTION)),
+            command_buffer: Vec::with_capacity(COMMAND_BUFFER_SIZE),
+            rx: VecDeque::new(),
+            tx: String::new(),
+            line: String::new(),
+            framed: false,
+            seq: 0,
+            last_host_seq: None,
+        };
+        board.set_default();
+        board
+    }
+
+    pub fn status(&self) -> Status {
+        self.status
+    }
+
+    /// Board uptime
+    pub fn elapsed(&self) -> Duration {
+        Duration::from_micros(self.clock)
+    }
+
+    fn millis(&self) -> u64 {
+        self.clock / 1000
+    }
+
+    /// Push bytes on the board RX line
+    pub fn receive(&mut self, bytes: &[u8]) {
+        self.rx.extend(bytes);
+    }
+
+    /// Drain what the board wrote on its TX line
+    pub fn take_output(&mut self) -> String {
+        std::mem::take(&mut self.tx)
+    }
+
+    fn print(&mut self, msg: &str) {
+        self.line.push_str(msg);
+    }
+
+    fn println(&mut self, msg: &str) {
+        self.line.push_str(msg);
+        let line = std::mem::take(&mut self.line);
+        if self.framed {
+            self.tx.push_str(&framing::encode(self.seq, &line));
+            self.seq = self.seq.wrapping_add(1);
+        } else {
+            self.tx.push_str(&line);
+        }
+        self.tx.push_str("\r\n");
+    }
+
+    /// Payload of a host line, `None` when it must be dropped
+    fn unframe(&mut self, line: String) -> Option<String> {
+        if !line.contains('*') {
+            // Unframed lines are only allowed in framed mode to reset the link with `V0`
+            if self.framed && !strncasecmp(&line, "V0", 2) {
+                self.println("ERR CRC");
+                return None;
+            }
+            return Some(line);
+        }
+        match framing::decode(&line) {
+            Ok((seq, _)) if self.last_host_seq == Some(seq) => None,
+            Ok((seq, payload)) => {
+                self.last_host_seq = Some(seq);
+                Some(payload.to_string())
+            },
+            Err(_) => {
+                self.println("ERR CRC");
+                None
+            },
+        }
+    }
+
+    fn set_default(&mut self) {
+        self.tds_1_map = [538, 127];
+    }
+
+    fn echo_tds_cal(&mut self) {
+        let msg = format!(" TDS1 {} {}", self.tds_1_map[0], self.tds_1_map[1]);
+        self.println(&msg);
+    }
+
+    /// Run the firmware main loop until the board clock reaches `until`
+    pub fn run_until(&mut self, until: Duration) {
+        let until = until.as_micros() as u64;
+        while self.clock < until {
+            self.step();
+        }
+    }
+
+    /// One pass of the firmware `loop()`
+    pub fn step(&mut self) {
+        let begin = self.clock;
+        let mut pass_duration = 0;
+        // Fill the command buffer
+        while let Some(rd) = self.rx.pop_front() {
+            if self.command_buffer.len() + 1 >= COMMAND_BUFFER_SIZE {
+                self.println("ERR OVERFLOW");
+                self.command_buffer.clear();
+                self.rx.push_front(rd);
+            } else {
+                self.command_buffer.push(rd);
+                if rd == b'\n' { break; }
+            }
+        }
+        if self.command_buffer.last() == Some(&b'\n') {
+            self.command_buffer.pop();
+            let line = String::from_utf8_lossy(&self.command_buffer).to_string();
+            self.command_buffer.clear();
+            if let Some(line) = self.unframe(line) {
+                self.exec(&line);
+            }
+        }
+        let millis = self.millis();
+        // Water valve status/stepper update
+        if self.status.contains(Status::OSMOS_SWITCH_CLOSING) {
+            if self.water_valve_current_angle < WATER_VALVE_DELTA {
+                self.water_valve_current_angle += 1;
+                pass_duration += WATER_VALVE_STEP_US;
+            } else {
+                self.println("OK S0 OFF");
+                self.status.insert(Status::OSMOS_SWITCH_CLOSED);
+                self.status.remove(Status::OSMOS_SWITCH_CLOSING);
+            }
+        } else if self.status.contains(Status::OSMOS_SWITCH_OPENING) {
+            if self.water_valve_current_angle > -WATER_VALVE_DELTA {
+                self.water_valve_current_angle -= 1;
+                pass_duration += WATER_VALVE_STEP_US;
+            } else {
+                self.println("OK S0 ON");
+                self.status.insert(Status::OSMOS_SWITCH_OPENED);
+                self.status.remove(Status::OSMOS_SWITCH_OPENING);
+            }
+        }
+        // Peristatic pump stepper update
+        if self.status.intersects(Status::PERISTALIC_PUMP_ON | Status::PERISTALIC_PUMP_REV) {
+            pass_duration += PPUMP_1_STEP_US;
+        }
+        // Tds update
+        if millis - self.last_tds_update >= TDS_SAMPLE_INTERVAL {
+            self.last_tds_update = millis;
+            self.tds_1_raw = self.water.tds_analog();
+            if millis - self.uptime > TDS_1_FILTER_CALIBRATION_DURATION {
+                self.tds_1_filter.filter(self.tds_1_raw);
+            } else {
+                self.tds_1_filter.set_current(self.tds_1_raw);
+            }
+            self.status.set(Status::TDS_CONNECTED, self.tds_1_raw != 0);
+        }
+        // Ph update
+        if millis - self.last_ph_update >= PH_SAMPLE_INTERVAL && self.status.contains(Status::BRONCHUS_STANDBY_SAMPLING) {
+            self.last_ph_update = millis;
+            self.ph_1_raw = self.water.ph_analog();
+            let ph = map(self.ph_1_raw, 0, 1024, 0, 1400);
+            if millis - self.uptime > PH_1_FILTER_CALIBRATION_DURATION {
+                self.ph_1_filter.filter(ph);
+            } else {
+                self.ph_1_filter.set_current(ph);
+            }
+            self.status.set(Status::PH_CONNECTED, self.ph_1_raw != 0);
+        }
+        // Temp update
+        if !self.status.intersects(M_STEPPER_RUNNING) && millis - self.last_temp_update > self.temp_conversion_duration && self.water.temperature_connected {
+            self.temp_1_raw = (self.water.temperature * 16.0).round() / 16.0;
+            self.last_temp_update = millis;
+            self.status.insert(Status::TEMPERATURE_CONNECTED);
+        }
+        // Breath update
+        if self.status.contains(Status::BREATHING) {
+            let since = millis - self.breath_step;
+            if self.status.contains(Status::BRONCHUS_STANDBY_FULL) && since >= BRONCHUS_STANDBY_FULL_DURATION {
+                self.status.remove(Status::BRONCHUS_STANDBY_FULL);
+                self.status.insert(Status::BRONCHUS_STANDBY_SAMPLING);
+                self.breath_step = millis;
+            } else if self.status.contains(Status::BRONCHUS_STANDBY_SAMPLING) && since >= BRONCHUS_STANDBY_SAMPLING_DURATION {
+                self.status.remove(Status::BRONCHUS_STANDBY_SAMPLING);
+                self.status.insert(Status::BRONCHUS_WAIT_EMPTY);
+                self.breath_step = millis;
+            } else if self.status.contains(Status::BRONCHUS_WAIT_EMPTY) && since >= BRONCHUS_EMPTY_DURATION {
+                self.status.remove(Status::BRONCHUS_WAIT_EMPTY);
+                self.status.insert(Status::BRONCHUS_WAIT_FULL);
+                self.breath_step = millis;
+            } else if self.status.contains(Status::BRONCHUS_WAIT_FULL) && since >= BRONCHUS_FILL_DURATION {
+                self.status.remove(Status::BRONCHUS_WAIT_FULL);
+                self.status.insert(Status::BRONCHUS_STANDBY_FULL);
+                self.breath_step = millis;
+            }
+        } else {
+            self.status.remove(Status::BRONCHUS_WAIT_FULL | Status::BRONCHUS_WAIT_EMPTY | Status::BRONCHUS_STANDBY_SAMPLING);
+            self.status.insert(Status::BRONCHUS_STANDBY_FULL);
+        }
+        self.clock = begin + if pass_duration > 0 { pass_duration } else { LOOP_US };
+        let elapsed = (self.clock - begin) as f64 / 1_000_000.0;
+        let valve_opened = self.status.intersects(Status::OSMOS_SWITCH_OPENED | Status::OSMOS_SWITCH_OPENING | Status::OSMOS_SWITCH_CLOSING);
+        let ph_down = self.status.contains(Status::PERISTALIC_PUMP_ON);
+        let ph_up = self.status.contains(Status::PERISTALIC_PUMP_REV);
+        self.water.update(elapsed, valve_opened, ph_down, ph_up);
+    }
+
+    fn exec(&mut self, line: &str) {
+        let mut tokens = line.split(' ').filter(|e| !e.is_empty());
+        let command = match tokens.next() {
+            Some(command) => command,
+            None => return self.println("PROCESS ERROR EMPTY COMMAND"),
+        };
+        match () {
+            _ if strncasecmp(command, "M0", 2) => {
+                self.set_default();
+                self.println("OK M0");
+            },
+            _ if strncasecmp(command, "M1", 2) => {
+                while let Some(token) = tokens.next() {
+                    let low = tokens.next().and_then(|e| e.parse().ok()).unwrap_or(0);
+                    let high = tokens.next().and_then(|e| e.parse().ok()).unwrap_or(0);
+                    if strncasecmp(token, "TDS1", 4) {
+                        self.tds_1_map = [low, high];
+                    }
+                }
+                self.print("OK M1 ");
+                self.echo_tds_cal();
+            },
+            _ if strncasecmp(command, "M2", 2) => {
+                self.print("OK M2 ");
+                self.echo_tds_cal();
+            },
+            _ if strncasecmp(command, "S0", 2) => match tokens.next() {
+                Some(_) if self.status.intersects(M_OSMOS_SWITCH_BUSY) => self.println("ERR S0 BUSY"),
+                Some(arg) if strncasecmp(arg, "ON", 2) => {
+                    self.status.insert(Status::OSMOS_SWITCH_OPENING);
+                    self.status.remove(Status::OSMOS_SWITCH_OPENED | Status::OSMOS_SWITCH_CLOSED);
+                },
+                Some(arg) if strncasecmp(arg, "OFF", 3) => {
+                    self.status.insert(Status::OSMOS_SWITCH_CLOSING);
+                    self.status.remove(Status::OSMOS_SWITCH_OPENED | Status::OSMOS_SWITCH_CLOSED);
+                },
+                Some(_) => self.println("ERR S0 BAD_REQUEST"),
+                None => {},
+            },
+            _ if strncasecmp(command, "S1", 2) => match tokens.next() {
+                Some(arg) if strncasecmp(arg, "ON", 2) => {
+                    self.status.insert(Status::PERISTALIC_PUMP_ON);
+                    self.status.remove(Status::PERISTALIC_PUMP_REV);
+                    self.println("OK S1 ON");
+                },
+                Some(arg) if strncasecmp(arg, "OFF", 3) => {
+                    self.status.remove(Status::PERISTALIC_PUMP_ON | Status::PERISTALIC_PUMP_REV);
+                    self.println("OK S1 OFF");
+                },
+                Some(arg) if strncasecmp(arg, "REV", 3) => {
+                    self.status.insert(Status::PERISTALIC_PUMP_REV);
+                    self.status.remove(Status::PERISTALIC_PUMP_ON);
+                    self.println("OK S1 REV");
+                },
+                Some(_) => self.println("ERR S1 BAD_REQUEST"),
+                None => {},
+            },
+            _ if strncasecmp(command, "S2", 2) => match tokens.next() {
+                Some(arg) if strncasecmp(arg, "FILL", 2) || strncasecmp(arg, "EMPTY", 3) => {},
+                Some(_) => self.println("ERR S2 BAD_REQUEST"),
+                None => {},
+            },
+            _ if strncasecmp(command, "G0", 2) => {
+                let msg = format!("OK G0 TDS1 {} PH1 {}", self.tds_1_raw, self.ph_1_raw);
+                self.println(&msg);
+            },
+            _ if strncasecmp(command, "G1", 2) => {
+                let millis = self.millis();
+                let mut msg = String::from("OK G1");
+                if self.status.contains(Status::TDS_CONNECTED) && millis - self.uptime > TDS_1_FILTER_CALIBRATION_DURATION {
+                    msg.push_str(&format!(" TDS1 {:.2}", read_ec(self.tds_1_filter.current() as f64, 25.0)));
+                }
+                if self.status.contains(Status::PH_CONNECTED) && millis - self.uptime > PH_1_FILTER_CALIBRATION_DURATION {
+                    msg.push_str(&format!(" PH1 {:.2}", read_ph(self.ph_1_filter.current(), 25.0)));
+                }
+                if self.status.contains(Status::TEMPERATURE_CONNECTED) && millis - self.uptime > TEMPERATURE_1_CALIBRATION_DURATION {
+                    msg.push_str(&format!(" T1 {:.2}", self.temp_1_raw));
+                }
+                msg.push_str(&format!(" STATUS {}", self.status.bits()));
+                self.println(&msg);
+            },
+            _ if strncasecmp(command, "V0", 2) => {
+                // Always answered in plain text so a new host session can reset the link
+                self.framed = false;
+                self.last_host_seq = None;
+                let msg = format!("OK V0 {}", PROTOCOL_VERSION);
+                self.println(&msg);
+            },
+            _ if strncasecmp(command, "V1", 2) => match tokens.next() {
+                Some(arg) if strncasecmp(arg, "ON", 2) => {
+                    self.println("OK V1 ON");
+                    self.framed = true;
+                },
+                Some(arg) if strncasecmp(arg, "OFF", 3) => {
+                    self.println("OK V1 OFF");
+                    self.framed = false;
+                },
+                Some(_) => self.println("ERR V1 BAD_REQUEST"),
+                None => {},
+            },
+            _ => {
+                self.println("ERR UNKNOW");
+                self.println(command);
+            },
+        }
+    }
+}
+
+/// Start a virtual board on a new pseudo-terminal and return the host side of it
+pub fn spawn(board: VirtualBoard) -> serialport::Result<Box<dyn SerialPort>> {
+    let (mut master, slave) = TTYPort::pair()?;
+    master.set_timeout(Duration::from_millis(10))?;
+    info!("Virtual board listening on {}", slave.name().unwrap_or_default());
+    let master: Box<dyn SerialPort> = Box::new(master);
+    thread::spawn(move || serve(board, Box::new(master)));
+    Ok(Box::new(slave))
+}
+
+/// Run the board against the host connected on `transport` until the link breaks
+pub fn serve(mut board: VirtualBoard, mut transport: Box<dyn Transport>) {
+    if let Err(e) = transport.set_timeout(Duration::from_millis(10)) {
+        return warn!("Virtual board failed to set its timeout: {}", e);
+    }
+    let started = Instant::now();
+    let mut buffer = [0u8; 64];
+    loop {
+        match transport.read(&mut buffer) {
+            Ok(0) => {
+                warn!("Virtual board disconnected !");
+                break;
+            },
+            Ok(len) => board.receive(&buffer[..len]),
+            Err(e) if e.kind() == ErrorKind::TimedOut || e.kind() == ErrorKind::WouldBlock => {},
+            Err(e) => {
+                warn!("Virtual board disconnected: {:?}", e);
+                break;
+            },
+        }
+        board.run_until(started.elapsed());
+        let output = board.take_output();
+        if !output.is_empty() && transport.write_all(output.as_bytes()).is_err() {
+            warn!("Virtual board disconnected !");
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Send a command and run one pass, returns what the board replied right away
+    fn send(board: &mut VirtualBoard, line: &str) -> String {
+        board.receive(format!("{}\n", line).as_bytes());
+        board.step();
+        board.take_output().trim_end().to_string()
+    }
+
+    /// Step until `status` is set, returns how long it took on the board clock
+    fn wait_for(board: &mut VirtualBoard, status: Status) -> Duration {
+        let begin = board.elapsed();
+        while !board.status().contains(status) {
+            board.step();
+        }
+        board.elapsed() - begin
+    }
+
+    fn booted() -> VirtualBoard {
+        let mut board = VirtualBoard::default();
+        wait_for(&mut board, Status::OSMOS_SWITCH_CLOSED);
+        board.take_output();
+        board
+    }
+
+    #[test]
+    fn valve_stroke() {
+        let mut board = VirtualBoard::default();
+        // 3800 steps of 300µs, then the pass confirming it
+        let boot = wait_for(&mut board, Status::OSMOS_SWITCH_CLOSED);
+        assert_eq!(boot, Duration::from_micros(3800 * 300 + LOOP_US));
+        assert_eq!(board.take_output(), "OK S0 OFF\r\n");
+
+        // The pump steps on every pass too
+        assert_eq!(send(&mut board, "S1 ON"), "OK S1 ON");
+        assert_eq!(send(&mut board, "S0 ON"), "");
+        assert!(board.status().contains(Status::OSMOS_SWITCH_OPENING));
+        assert_eq!(send(&mut board, "S0 OFF"), "ERR S0 BUSY");
+        let stroke = wait_for(&mut board, Status::OSMOS_SWITCH_OPENED);
+        assert!(stroke > Duration::from_secs(23) && stroke < Duration::from_secs(25), "{:?}", stroke);
+        assert_eq!(board.take_output(), "OK S0 ON\r\n");
+        assert!(!board.status().intersects(Status::OSMOS_SWITCH_CLOSED | M_OSMOS_SWITCH_BUSY));
+    }
+
+    #[test]
+    fn breathing_cycle() {
+        let mut board = VirtualBoard::default();
+        assert!(board.status().contains(Status::BREATHING | Status::BRONCHUS_STANDBY_SAMPLING));
+        let mut phase = |status: Status| Duration::from_secs(wait_for(&mut board, status).as_secs_f64().round() as u64);
+        assert_eq!(phase(Status::BRONCHUS_WAIT_EMPTY), Duration::from_millis(BRONCHUS_STANDBY_SAMPLING_DURATION));
+        assert_eq!(phase(Status::BRONCHUS_WAIT_FULL), Duration::from_millis(BRONCHUS_EMPTY_DURATION));
+        assert_eq!(phase(Status::BRONCHUS_STANDBY_FULL), Duration::from_millis(BRONCHUS_FILL_DURATION));
+        assert_eq!(phase(Status::BRONCHUS_STANDBY_SAMPLING), Duration::from_millis(BRONCHUS_STANDBY_FULL_DURATION));
+        // One phase at a time
+        assert!(!board.status().intersects(Status::BRONCHUS_STANDBY_FULL | Status::BRONCHUS_WAIT_EMPTY | Status::BRONCHUS_WAIT_FULL));
+    }
+
+    #[test]
+    fn replies() {
+        let mut board = booted();
+        for (command, reply) in [
+            ("M0", "OK M0"),
+            ("m1 tds1 540 130", "OK M1  TDS1 540 130"),
+            ("M2", "OK M2  TDS1 540 130"),
+            // Already closed, confirmed on the same pass
+            ("S0 OFF", "OK S0 OFF"),
+            ("S0 HALF", "ERR S0 BAD_REQUEST"),
+            ("S1 REV", "OK S1 REV"),
+            ("S1 OFF", "OK S1 OFF"),
+            ("S1 FAST", "ERR S1 BAD_REQUEST"),
+            ("S2 FILL", ""),
+            ("S2 DRAIN", "ERR S2 BAD_REQUEST"),
+            ("V0", "OK V0 2"),
+            ("V1 OFF", "OK V1 OFF"),
+            ("V1 MAYBE", "ERR V1 BAD_REQUEST"),
+            ("X9", "ERR UNKNOW\r\nX9"),
+            ("", "PROCESS ERROR EMPTY COMMAND"),
+        ].iter() {
+            assert_eq!(send(&mut board, command), *reply, "{}", command);
+        }
+
+        assert!(send(&mut board, "G0").starts_with("OK G0 TDS1 "));
+        // The readings show once the probes calibrated
+        board.run_until(Duration::from_secs(11));
+        let g1 = send(&mut board, "G1");
+        assert!(g1.starts_with("OK G1 TDS1 ") && g1.contains(" PH1 ") && g1.contains(" T1 "), "{}", g1);
+        assert!(g1.ends_with(&format!(" STATUS {}", board.status().bits())), "{}", g1);
+    }
+
+    #[test]
+    fn framed_replies() {
+        let mut board = booted();
+        assert_eq!(send(&mut board, "V1 ON"), "OK V1 ON");
+        let payload = |reply: String| framing::decode(&reply).map(|(_, payload)| payload.to_string()).unwrap();
+        assert_eq!(payload(send(&mut board, "M0")), "ERR CRC");
+        assert_eq!(payload(send(&mut board, &framing::encode(0, "M0"))), "OK M0");
+        // A repeated frame is dropped
+        assert_eq!(send(&mut board, &framing::encode(0, "M0")), "");
+        assert_eq!(payload(send(&mut board, "M0*0000")), "ERR CRC");
+        assert_eq!(send(&mut board, "V0"), "OK V0 2");
+        assert_eq!(send(&mut board, "M0"), "OK M0");
+    }
+}
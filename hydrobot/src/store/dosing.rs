@@ -0,0 +1,272 @@
+//! Dosing ledger.
+use super::{timestamp_ms, Metric, Setting, Store, StoreResult};
+use std::fmt::{self, Display, Formatter};
+use std::ops::Range;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const DOSE_LEN: usize = 34;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Doser {
+    /// Osmosed water valve (`S0`)
+    Osmoseur,
+    /// pH Down peristaltic pump (`S1`)
+    PhDown,
+}
+
+impl Doser {
+    pub const ALL: [Doser; 2] = [Doser::Osmoseur, Doser::PhDown];
+
+    /// Estimated flow rate setting, in mL/min
+    pub fn flow_rate_setting(self) -> Setting {
+        match self {
+            Doser::Osmoseur => Setting::OsmoseurFlowRate,
+            Doser::PhDown => Setting::PhDownFlowRate,
+        }
+    }
+}
+
+impl Display for Doser {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", match self {
+            Doser::Osmoseur => "osmoseur",
+            Doser::PhDown => "ph_down",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DoseTrigger {
+    TdsMonitor,
+    PhMonitor,
+    Manual,
+}
+
+impl DoseTrigger {
+    const ALL: [DoseTrigger; 3] = [DoseTrigger::TdsMonitor, DoseTrigger::PhMonitor, DoseTrigger::Manual];
+
+    /// Sensor the dose is meant to correct
+    pub fn metric(self) -> Option<Metric> {
+        match self {
+            DoseTrigger::TdsMonitor => Some(Metric::Tds1),
+            DoseTrigger::PhMonitor => Some(Metric::Ph1),
+            DoseTrigger::Manual => None,
+        }
+    }
+}
+
+impl Display for DoseTrigger {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", match self {
+            DoseTrigger::TdsMonitor => "tds_monitor",
+            DoseTrigger::PhMonitor => "ph_monitor",
+            DoseTrigger::Manual => "manual",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Dose {
+    pub at: SystemTime,
+    pub doser: Doser,
+    pub trigger: DoseTrigger,
+    pub duration: Duration,
+    /// Estimated from the flow rate setting when the dose was recorded, in mL
+    pub volume: f64,
+    pub before: Option<f64>,
+    /// First stable reading once the dose is done
+    pub after: Option<f64>,
+}
+
+impl Dose {
+    fn encode(&self) -> Vec<u8> {
+        let mut buff = Vec::with_capacity(DOSE_LEN);
+        buff.push(self.doser as u8);
+        buff.push(self.trigger as u8);
+        buff.extend_from_slice(&(self.duration.as_millis() as u64).to_be_bytes());
+        for val in [Some(self.volume), self.before, self.after].iter() {
+            buff.extend_from_slice(&val.unwrap_or(f64::NAN).to_be_bytes());
+        }
+        buff
+    }
+
+    pub(super) fn decode(key: &[u8], val: &[u8]) -> Option<Dose> {
+        if key.len() < 8 || val.len() != DOSE_LEN {
+            return None;
+        }
+        let read = |offset: usize| {
+            let mut buff = [0u8; 8];
+            buff.copy_from_slice(&val[offset..offset + 8]);
+            buff
+        };
+        let optional = |val: f64| if val.is_nan() { None } else { Some(val) };
+        let mut at = [0u8; 8];
+        at.copy_from_slice(&key[..8]);
+        Some(Dose {
+            at: UNIX_EPOCH + Duration::from_millis(u64::from_be_bytes(at)),
+            doser: *Doser::ALL.get(val[0] as usize)?,
+            trigger: *DoseTrigger::ALL.get(val[1] as usize)?,
+            duration: Duration::from_millis(u64::from_be_bytes(read(2))),
+            volume: f64::from_be_bytes(read(10)),
+            before: optional(f64::from_be_bytes(read(18))),
+            after: optional(f64::from_be_bytes(read(26))),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DoseTotal {
+    pub doser: Doser,
+    pub count: u64,
+    pub duration: Duration,
+    /// mL
+    pub volume: f64,
+}
+
+impl DoseTotal {
+    pub fn new(doser: Doser) -> Self {
+        Self { doser, count: 0, duration: Duration::from_secs(0), volume: 0.0 }
+    }
+
+    pub fn add(&mut self, dose: &Dose) {
+        self.count += 1;
+        self.duration += dose.duration;
+        self.volume += dose.volume;
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GrowCycle {
+    pub name: String,
+    pub start: SystemTime,
+    /// Start of the next cycle, none for the current one
+    pub end: Option<SystemTime>,
+}
+
+fn time_key(at: SystemTime) -> [u8; 8] {
+    timestamp_ms(at).to_be_bytes()
+}
+
+impl Store {
+    /// Estimated volume delivered by `doser` in `duration`, in mL
+    pub fn dose_volume(&self, doser: Doser, duration: Duration) -> f64 {
+        self.get(doser.flow_rate_setting()).as_float() * duration.as_secs_f64() / 60.0
+    }
+
+    /// Append `dose` to the ledger, returns its key to fill in the `after` reading later
+    pub fn record_dose(&self, dose: &Dose) -> Option<sled::IVec> {
+        let id = self.db.generate_id().unwrap_or(0);
+        let mut key = time_key(dose.at).to_vec();
+        key.extend_from_slice(&id.to_be_bytes());
+        match self.dosing_tree.insert(key.as_slice(), dose.encode()) {
+            Ok(_) => Some(key.into()),
+            Err(e) => {
+                warn!("Failed to record the {} dose: {}", dose.doser, e);
+                None
+            },
+        }
+    }
+
+    pub fn set_dose_after(&self, key: &[u8], after: f64) {
+        let updated = self.dosing_tree.fetch_and_update(key, |val| {
+            let mut dose = Dose::decode(key, val?)?;
+            dose.after = Some(after);
+            Some(dose.encode())
+        });
+        if let Err(e) = updated {
+            warn!("Failed to update a dose: {}", e);
+        }
+    }
+
+    /// Doses started in `range`, in chronological order
+    pub fn doses(&self, range: Range<SystemTime>) -> impl DoubleEndedIterator<Item = Dose> {
+        self.dosing_tree.range(time_key(range.start)..time_key(range.end)).filter_map(|entry| match entry {
+            Ok((key, val)) => Dose::decode(&key, &val).or_else(|| {
+                warn!("Skipping a corrupt dose");
+                None
+            }),
+            Err(e) => {
+                warn!("Failed to read the dosing ledger: {}", e);
+                None
+            },
+        })
+    }
+
+    /// Totals of the doses started in `range`, one per doser
+    pub fn dose_totals(&self, range: Range<SystemTime>) -> Vec<DoseTotal> {
+        let mut totals: Vec<DoseTotal> = Doser::ALL.iter().map(|doser| DoseTotal::new(*doser)).collect();
+        for dose in self.doses(range) {
+            totals[dose.doser as usize].add(&dose);
+        }
+        totals
+    }
+
+    pub fn start_grow_cycle(&self, name: &str, at: SystemTime) -> StoreResult<GrowCycle> {
+        self.writable()?;
+        self.grow_cycles_tree.insert(time_key(at), name.as_bytes())?;
+        self.grow_cycles_tree.flush()?;
+        Ok(GrowCycle { name: name.to_string(), start: at, end: None })
+    }
+
+    /// Every grow cycle, oldest first
+    pub fn grow_cycles(&self) -> Vec<GrowCycle> {
+        let starts: Vec<(SystemTime, String)> = self.grow_cycles_tree.iter().filter_map(Result::ok).filter(|(key, _)| key.len() == 8).map(|(key, name)| {
+            let mut ms = [0u8; 8];
+            ms.copy_from_slice(&key);
+            (UNIX_EPOCH + Duration::from_millis(u64::from_be_bytes(ms)), String::from_utf8_lossy(&name).to_string())
+        }).collect();
+        starts.iter().enumerate().map(|(idx, (start, name))| GrowCycle {
+            name: name.clone(),
+            start: *start,
+            end: starts.get(idx + 1).map(|(end, _)| *end),
+        }).collect()
+    }
+
+    pub fn current_grow_cycle(&self) -> Option<GrowCycle> {
+        self.grow_cycles().pop()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::testing::*;
+
+    #[test]
+    fn ledger_totals_and_cycles() {
+        let (store, path) = temporary_store("dosing");
+        let at = |secs: u64| UNIX_EPOCH + Duration::from_secs(1_600_000_000 + secs);
+        store.set(Setting::OsmoseurFlowRate, super::super::SettingValue::Float(600.0)).unwrap();
+        let water = Dose {
+            at: at(0),
+            doser: Doser::Osmoseur,
+            trigger: DoseTrigger::TdsMonitor,
+            duration: Duration::from_secs(10),
+            volume: store.dose_volume(Doser::Osmoseur, Duration::from_secs(10)),
+            before: Some(700.0),
+            after: None,
+        };
+        let key = store.record_dose(&water).unwrap();
+        store.set_dose_after(&key, 640.0);
+        store.record_dose(&Dose { at: at(60), doser: Doser::PhDown, trigger: DoseTrigger::PhMonitor, duration: Duration::from_secs(2), volume: 1.5, before: None, after: None }).unwrap();
+        store.record_dose(&Dose { at: at(7200), ..water.clone() }).unwrap();
+        store.start_grow_cycle("lettuce", at(0)).unwrap();
+        store.start_grow_cycle("tomato", at(3600)).unwrap();
+
+        let doses: Vec<Dose> = store.doses(at(0)..at(3600)).collect();
+        let totals = store.dose_totals(at(0)..at(10_000));
+        let cycles = store.grow_cycles();
+        let current = store.current_grow_cycle().unwrap();
+        drop(store);
+        std::fs::remove_dir_all(&path).unwrap();
+
+        assert_eq!(doses.len(), 2);
+        assert_eq!((doses[0].volume, doses[0].before, doses[0].after), (100.0, Some(700.0), Some(640.0)));
+        assert_eq!((doses[1].doser, doses[1].trigger, doses[1].before), (Doser::PhDown, DoseTrigger::PhMonitor, None));
+        assert_eq!((totals[0].count, totals[0].duration, totals[0].volume), (2, Duration::from_secs(20), 200.0));
+        assert_eq!((totals[1].doser, totals[1].volume), (Doser::PhDown, 1.5));
+        assert_eq!(cycles.len(), 2);
+        assert_eq!((cycles[0].name.as_str(), cycles[0].end), ("lettuce", Some(at(3600))));
+        assert_eq!((current.name.as_str(), current.start, current.end), ("tomato", at(3600), None));
+    }
+}
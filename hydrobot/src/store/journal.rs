@@ -0,0 +1,201 @@
+//! Event journal.
+use super::{timestamp_ms, Setting, Store, StoreResult};
+use std::fmt::{self, Display, Formatter};
+use std::ops::Range;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Info,
+    Warn,
+    Error,
+}
+
+impl Display for LogLevel {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", match self {
+            LogLevel::Info => "info",
+            LogLevel::Warn => "warn",
+            LogLevel::Error => "error",
+        })
+    }
+}
+
+impl std::str::FromStr for LogLevel {
+    type Err = String;
+
+    fn from_str(val: &str) -> Result<Self, Self::Err> {
+        match val {
+            "info" => Ok(LogLevel::Info),
+            "warn" => Ok(LogLevel::Warn),
+            "error" => Ok(LogLevel::Error),
+            _ => Err(format!("Unknown level `{}`, expected info, warn or error", val)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    /// Scheduler feedback
+    Log,
+    /// Requests of the monitors (ie: lowering the TDS)
+    Query,
+    /// Dosing, a valve or a pump was driven
+    Action,
+    /// Probe and board connections
+    Device,
+    /// Setting or profile change
+    Setting,
+    /// Pulses sized by the dosing controllers, with their terms
+    Controller,
+}
+
+impl EventKind {
+    pub const ALL: [EventKind; 6] = [EventKind::Log, EventKind::Query, EventKind::Action, EventKind::Device, EventKind::Setting, EventKind::Controller];
+
+    fn name(self) -> &'static str {
+        match self {
+            EventKind::Log => "log",
+            EventKind::Query => "query",
+            EventKind::Action => "action",
+            EventKind::Device => "device",
+            EventKind::Setting => "setting",
+            EventKind::Controller => "controller",
+        }
+    }
+}
+
+impl Display for EventKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl std::str::FromStr for EventKind {
+    type Err = String;
+
+    fn from_str(val: &str) -> Result<Self, Self::Err> {
+        EventKind::ALL.iter().copied().find(|kind| kind.name() == val)
+            .ok_or_else(|| format!("Unknown event kind `{}`, expected log, query, action, device, setting or controller", val))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    pub at: SystemTime,
+    pub kind: EventKind,
+    pub level: LogLevel,
+    pub message: String,
+}
+
+impl Event {
+    fn encode(&self) -> Vec<u8> {
+        let mut buff = vec![self.kind as u8, self.level as u8];
+        buff.extend_from_slice(self.message.as_bytes());
+        buff
+    }
+
+    pub(super) fn decode(key: &[u8], val: &[u8]) -> Option<Event> {
+        let mut ms = [0u8; 8];
+        ms.copy_from_slice(key.get(..8)?);
+        Some(Event {
+            at: UNIX_EPOCH + Duration::from_millis(u64::from_be_bytes(ms)),
+            kind: *EventKind::ALL.get(*val.first()? as usize)?,
+            level: *[LogLevel::Info, LogLevel::Warn, LogLevel::Error].get(*val.get(1)? as usize)?,
+            message: String::from_utf8_lossy(&val[2..]).to_string(),
+        })
+    }
+}
+
+fn range_key(at: SystemTime) -> [u8; 8] {
+    timestamp_ms(at).to_be_bytes()
+}
+
+impl Store {
+    pub fn get_journal_retention_days(&self) -> u64 {
+        self.get(Setting::JournalRetentionDays).as_int() as u64
+    }
+
+    /// Append an event stamped now and return it
+    pub fn record_event<T: ToString>(&self, kind: EventKind, level: LogLevel, message: T) -> Event {
+        let event = Event { at: SystemTime::now(), kind, level, message: message.to_string() };
+        self.insert_event(&event);
+        event
+    }
+
+    pub fn insert_event(&self, event: &Event) {
+        let id = self.db.generate_id().unwrap_or(0);
+        let mut key = range_key(event.at).to_vec();
+        key.extend_from_slice(&id.to_be_bytes());
+        if let Err(e) = self.journal_tree.insert(key, event.encode()) {
+            warn!("Failed to journal `{}`: {}", event.message, e);
+        }
+    }
+
+    /// Events of `range` in chronological order
+    pub fn events(&self, range: Range<SystemTime>) -> impl DoubleEndedIterator<Item = Event> {
+        self.journal_tree.range(range_key(range.start)..range_key(range.end)).filter_map(|entry| match entry {
+            Ok((key, val)) => Event::decode(&key, &val).or_else(|| {
+                warn!("Skipping a corrupt journal entry");
+                None
+            }),
+            Err(e) => {
+                warn!("Failed to read the journal: {}", e);
+                None
+            },
+        })
+    }
+
+    /// The `count` most recent events matching `filter`, oldest first
+    pub fn last_events<F: Fn(&Event) -> bool>(&self, count: usize, filter: F) -> Vec<Event> {
+        let mut events: Vec<Event> = self.events(UNIX_EPOCH..SystemTime::now() + Duration::from_secs(1)).rev().filter(|event| filter(event)).take(count).collect();
+        events.reverse();
+        events
+    }
+
+    /// Remove the events older than `limit`, returns how many were
+    pub fn prune_events(&self, limit: SystemTime) -> StoreResult<usize> {
+        let mut batch = sled::Batch::default();
+        let mut count = 0;
+        for key in self.journal_tree.range(..range_key(limit)).keys() {
+            batch.remove(key?);
+            count += 1;
+        }
+        self.journal_tree.apply_batch(batch)?;
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::testing::*;
+
+    #[test]
+    fn journal_query_and_prune() {
+        let (store, path) = temporary_store("journal");
+        let now = SystemTime::now();
+        let old = Event { at: now - Duration::from_secs(3600), kind: EventKind::Action, level: LogLevel::Info, message: "Osmoseur valve opened !".to_string() };
+        store.insert_event(&old);
+        // Same millisecond, both are kept
+        let connected = store.record_event(EventKind::Device, LogLevel::Info, "Tds connected !");
+        let rejected = store.record_event(EventKind::Setting, LogLevel::Warn, "Setting rejected: ünicode");
+        let all: Vec<Event> = store.events(UNIX_EPOCH..now + Duration::from_secs(60)).collect();
+        let warnings = store.last_events(10, |event| event.level >= LogLevel::Warn);
+        let last = store.last_events(1, |_| true);
+        let pruned = store.prune_events(now - Duration::from_secs(60)).unwrap();
+        let left = store.events(UNIX_EPOCH..now + Duration::from_secs(60)).count();
+        drop(store);
+        std::fs::remove_dir_all(&path).unwrap();
+
+        assert_eq!(all.len(), 3);
+        assert_eq!(all[0].at, UNIX_EPOCH + Duration::from_millis(timestamp_ms(old.at)));
+        assert_eq!((all[0].kind, all[0].message.as_str()), (EventKind::Action, "Osmoseur valve opened !"));
+        assert_eq!(all[1].message, connected.message);
+        assert_eq!(warnings.iter().map(|event| event.message.clone()).collect::<Vec<_>>(), vec![rejected.message.clone()]);
+        assert_eq!(last[0].kind, EventKind::Setting);
+        assert_eq!((pruned, left), (1, 2));
+        assert_eq!("device".parse::<EventKind>(), Ok(EventKind::Device));
+        assert!("warning".parse::<LogLevel>().is_err());
+    }
+}
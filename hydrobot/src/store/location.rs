@@ -0,0 +1,105 @@
+//! Where the store lives and who owns it.
+use super::{StoreError, StoreResult};
+use fs2::FileExt;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+pub const STORE_DIR: &str = "store";
+const LOCK_FILE: &str = "hydrobot.lock";
+
+/// `$XDG_DATA_HOME/hydrobot`, relative values are ignored as the spec requires
+fn xdg_data_dir(xdg_data_home: Option<PathBuf>, home: Option<PathBuf>) -> Option<PathBuf> {
+    match xdg_data_home.filter(|dir| dir.is_absolute()) {
+        Some(dir) => Some(dir.join("hydrobot")),
+        None => home.filter(|dir| dir.is_absolute()).map(|dir| dir.join(".local/share/hydrobot")),
+    }
+}
+
+/// Data directory used when none is given
+pub fn default_data_dir() -> PathBuf {
+    if Path::new(STORE_DIR).join("db").exists() {
+        return PathBuf::from(".");
+    }
+    xdg_data_dir(std::env::var_os("XDG_DATA_HOME").map(PathBuf::from), std::env::var_os("HOME").map(PathBuf::from))
+        .unwrap_or_else(|| PathBuf::from("."))
+}
+
+/// Exclusive ownership of a store, dropping it releases the lock
+#[derive(Debug)]
+pub struct StoreLock {
+    _file: File,
+}
+
+impl StoreLock {
+    pub fn acquire<T: AsRef<Path>>(store: T) -> StoreResult<StoreLock> {
+        let store = store.as_ref();
+        std::fs::create_dir_all(store).map_err(sled::Error::Io)?;
+        let path = store.join(LOCK_FILE);
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path).map_err(sled::Error::Io)?;
+        if file.try_lock_exclusive().is_err() {
+            let mut pid = String::new();
+            let _ = file.read_to_string(&mut pid);
+            return Err(StoreError::Locked(match pid.trim() {
+                "" => format!("another process ({})", path.display()),
+                pid => format!("process {} ({})", pid, path.display()),
+            }));
+        }
+        let written = file.set_len(0)
+            .and_then(|_| file.seek(SeekFrom::Start(0)))
+            .and_then(|_| write!(file, "{}", std::process::id()))
+            .and_then(|_| file.sync_all());
+        if let Err(e) = written {
+            warn!("Failed to write the pid in {}: {}", path.display(), e);
+        }
+        Ok(StoreLock { _file: file })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::testing::*;
+
+    #[test]
+    fn data_dir_resolution() {
+        let home = Some(PathBuf::from("/home/grower"));
+        assert_eq!(xdg_data_dir(Some(PathBuf::from("/srv/data")), home.clone()), Some(PathBuf::from("/srv/data/hydrobot")));
+        assert_eq!(xdg_data_dir(Some(PathBuf::from("data")), home.clone()), Some(PathBuf::from("/home/grower/.local/share/hydrobot")));
+        assert_eq!(xdg_data_dir(None, home), Some(PathBuf::from("/home/grower/.local/share/hydrobot")));
+        assert_eq!(xdg_data_dir(None, None), None);
+    }
+
+    #[test]
+    fn lock_and_read_only() {
+        use super::super::{Setting, SettingValue, Store};
+        let (store, path) = temporary_store("lock");
+        store.set(Setting::Tds1Thresh, SettingValue::Float(650.0)).unwrap();
+        store.flush();
+        let second = Store::open(&path).err();
+        let read_only = Store::open_read_only(&path).unwrap();
+        // Each reader has its own snapshot
+        let other = Store::open_read_only(&path).unwrap();
+        let snapshots = || std::fs::read_dir(std::env::temp_dir()).unwrap()
+            .filter(|entry| entry.as_ref().unwrap().file_name().to_string_lossy().starts_with(&format!("hydrobot-snapshot-{}-", std::process::id())))
+            .count();
+        let taken = snapshots();
+        let thresh = read_only.get(Setting::Tds1Thresh);
+        let set = read_only.set(Setting::Tds1Thresh, SettingValue::Float(700.0)).err();
+        drop(read_only);
+        let other_thresh = other.get(Setting::Tds1Thresh);
+        drop(other);
+        let left = snapshots();
+        drop(store);
+        // Released on drop
+        let reopened = Store::open(&path).is_ok();
+        std::fs::remove_dir_all(&path).unwrap();
+
+        let pid = std::process::id().to_string();
+        assert!(matches!(second, Some(StoreError::Locked(ref owner)) if owner.contains(&pid)));
+        assert_eq!((thresh, other_thresh), (SettingValue::Float(650.0), SettingValue::Float(650.0)));
+        assert_eq!((taken, left), (2, 0));
+        assert!(matches!(set, Some(StoreError::ReadOnly)));
+        assert!(reopened);
+    }
+}
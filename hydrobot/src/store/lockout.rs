@@ -0,0 +1,90 @@
+//! Safety lockout.
+use super::{timestamp_ms, Store, StoreResult};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const LOCKOUT_KEY: &str = "lockout";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lockout {
+    pub at: SystemTime,
+    pub reason: String,
+}
+
+impl Lockout {
+    pub fn new<T: ToString>(reason: T) -> Self {
+        Self { at: SystemTime::now(), reason: reason.to_string() }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buff = timestamp_ms(self.at).to_be_bytes().to_vec();
+        buff.extend_from_slice(self.reason.as_bytes());
+        buff
+    }
+
+    fn decode(val: &[u8]) -> Option<Lockout> {
+        let mut ms = [0u8; 8];
+        ms.copy_from_slice(val.get(..8)?);
+        Some(Lockout {
+            at: UNIX_EPOCH + Duration::from_millis(u64::from_be_bytes(ms)),
+            reason: String::from_utf8_lossy(&val[8..]).to_string(),
+        })
+    }
+}
+
+impl Store {
+    pub fn lockout(&self) -> Option<Lockout> {
+        match self.meta_tree.get(LOCKOUT_KEY) {
+            Ok(Some(val)) => Lockout::decode(&val).or_else(|| {
+                // Better safe than sorry
+                warn!("The stored lockout is corrupt");
+                Some(Lockout::new("Unknown, the stored lockout is corrupt"))
+            }),
+            Ok(None) => None,
+            Err(e) => {
+                warn!("Failed to read the lockout: {}", e);
+                None
+            },
+        }
+    }
+
+    pub fn set_lockout(&self, lockout: &Lockout) -> StoreResult<()> {
+        self.writable()?;
+        self.meta_tree.insert(LOCKOUT_KEY, lockout.encode())?;
+        self.meta_tree.flush()?;
+        Ok(())
+    }
+
+    /// Acknowledge the lockout, returns it
+    pub fn clear_lockout(&self) -> StoreResult<Option<Lockout>> {
+        self.writable()?;
+        let lockout = self.lockout();
+        self.meta_tree.remove(LOCKOUT_KEY)?;
+        self.meta_tree.flush()?;
+        Ok(lockout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::testing::*;
+
+    #[test]
+    fn lockout_survives_reopen() {
+        let (store, path) = temporary_store("lockout");
+        let empty = store.lockout();
+        store.set_lockout(&Lockout::new("Osmoseur valve failed to close")).unwrap();
+        drop(store);
+        let store = reopen(&path);
+        let kept = store.lockout();
+        let cleared = store.clear_lockout().unwrap();
+        let left = store.lockout();
+        drop(store);
+        std::fs::remove_dir_all(&path).unwrap();
+
+        assert_eq!(empty, None);
+        assert_eq!(kept.as_ref().map(|lockout| lockout.reason.as_str()), Some("Osmoseur valve failed to close"));
+        assert_eq!(cleared, kept);
+        assert_eq!(left, None);
+    }
+}
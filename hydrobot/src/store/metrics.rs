@@ -0,0 +1,289 @@
+//! Sensor time series.
+use super::Store;
+use sled::transaction::{ConflictableTransactionError, Transactional};
+use std::ops::Range;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Version of the key/value encoding of the metric trees, bumped when the layout changes
+const METRIC_FORMAT: u8 = 2;
+
+/// Time series persisted by the store, one sled tree each
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Metric {
+    /// Filtered samples reported by `G1`
+    Tds1,
+    Ph1,
+    Temperature1,
+    /// Raw samples reported by `G0`
+    RawTds1,
+    RawPh1,
+}
+
+impl Metric {
+    pub const ALL: [Metric; 5] = [Metric::Tds1, Metric::Ph1, Metric::Temperature1, Metric::RawTds1, Metric::RawPh1];
+
+    pub fn tree_name(self) -> &'static str {
+        match self {
+            Metric::Tds1 => "tds_1",
+            Metric::Ph1 => "ph_1",
+            Metric::Temperature1 => "t_1",
+            Metric::RawTds1 => "raw_tds_1",
+            Metric::RawPh1 => "raw_ph_1",
+        }
+    }
+}
+
+impl std::str::FromStr for Metric {
+    type Err = String;
+
+    fn from_str(val: &str) -> Result<Self, Self::Err> {
+        Metric::ALL.iter().copied().find(|metric| metric.tree_name() == val).ok_or_else(|| format!("Unknown sensor `{}`", val))
+    }
+}
+
+impl std::fmt::Display for Metric {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.tree_name())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sample {
+    pub at: SystemTime,
+    pub value: f64,
+}
+
+/// Statistics of the samples of one window
+#[derive(Debug, Clone, PartialEq)]
+pub struct Aggregate {
+    pub start: SystemTime,
+    pub count: usize,
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    /// In the order of the requested percentiles
+    pub percentiles: Vec<f64>,
+}
+
+/// Milliseconds since the unix epoch, the key of the metric trees
+pub fn timestamp_ms(when: SystemTime) -> u64 {
+    when.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+fn encode_key(when: SystemTime) -> [u8; 8] {
+    timestamp_ms(when).to_be_bytes()
+}
+
+pub(super) fn decode(key: &[u8], val: &[u8]) -> Option<Sample> {
+    let mut timestamp = [0u8; 8];
+    let mut value = [0u8; 8];
+    timestamp.copy_from_slice(key.get(..8)?);
+    value.copy_from_slice(val.get(..8)?);
+    Some(Sample {
+        at: UNIX_EPOCH + Duration::from_millis(u64::from_be_bytes(timestamp)),
+        value: f64::from_be_bytes(value),
+    })
+}
+
+/// Linear interpolation between the closest ranks, `sorted` must not be empty
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    let rank = (p.max(0.0).min(100.0) / 100.0) * (sorted.len() - 1) as f64;
+    let (low, high) = (rank.floor() as usize, rank.ceil() as usize);
+    sorted[low] + (sorted[high] - sorted[low]) * (rank - low as f64)
+}
+
+fn aggregate_window(start: SystemTime, mut values: Vec<f64>, percentiles: &[f64]) -> Aggregate {
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    Aggregate {
+        start,
+        count: values.len(),
+        min: values[0],
+        max: values[values.len() - 1],
+        mean: values.iter().sum::<f64>() / values.len() as f64,
+        percentiles: percentiles.iter().map(|p| percentile(&values, *p)).collect(),
+    }
+}
+
+impl Store {
+    pub fn metric_tree(&self, metric: Metric) -> &sled::Tree {
+        match metric {
+            Metric::Tds1 => &self.tds_1_tree,
+            Metric::Ph1 => &self.ph_1_tree,
+            Metric::Temperature1 => &self.t_1_tree,
+            Metric::RawTds1 => &self.raw_tds_1_tree,
+            Metric::RawPh1 => &self.raw_ph_1_tree,
+        }
+    }
+
+    pub fn insert_metric(&self, metric: Metric, when: SystemTime, sample: f64) {
+        if let Err(e) = self.metric_tree(metric).insert(encode_key(when), &sample.to_be_bytes()) {
+            warn!("Failed to store {} sample: {}", metric, e);
+        }
+    }
+
+    /// Samples taken in `range`, oldest first
+    pub fn samples(&self, metric: Metric, range: Range<SystemTime>) -> impl DoubleEndedIterator<Item = Sample> {
+        self.metric_tree(metric).range(encode_key(range.start)..encode_key(range.end)).filter_map(move |entry| match entry {
+            Ok((key, val)) => decode(&key, &val).or_else(|| {
+                warn!("Skipping a corrupt {} sample", metric);
+                None
+            }),
+            Err(e) => {
+                warn!("Failed to read {} samples: {}", metric, e);
+                None
+            },
+        })
+    }
+
+    /// The `count` most recent samples, oldest first
+    pub fn last_samples(&self, metric: Metric, count: usize) -> Vec<Sample> {
+        let mut samples: Vec<Sample> = self.samples(metric, UNIX_EPOCH..SystemTime::now() + Duration::from_secs(1)).rev().take(count).collect();
+        samples.reverse();
+        samples
+    }
+
+    /// Statistics of `range` split in windows of `window` aligned on the unix epoch, empty windows are skipped.
+    /// `percentiles` are in `0..=100`.
+    pub fn aggregate(&self, metric: Metric, range: Range<SystemTime>, window: Duration, percentiles: &[f64]) -> Vec<Aggregate> {
+        let window_ms = (window.as_millis() as u64).max(1);
+        let mut aggregates = Vec::new();
+        let mut current: Option<(u64, Vec<f64>)> = None;
+        for sample in self.samples(metric, range) {
+            let start = timestamp_ms(sample.at) / window_ms * window_ms;
+            match current.as_mut() {
+                Some((current_start, values)) if *current_start == start => values.push(sample.value),
+                _ => {
+                    if let Some((start, values)) = current.replace((start, vec![sample.value])) {
+                        aggregates.push(aggregate_window(UNIX_EPOCH + Duration::from_millis(start), values, percentiles));
+                    }
+                },
+            }
+        }
+        if let Some((start, values)) = current {
+            aggregates.push(aggregate_window(UNIX_EPOCH + Duration::from_millis(start), values, percentiles));
+        }
+        aggregates
+    }
+
+    /// Rewrite the metric trees written before `METRIC_FORMAT` 2: little-endian keys, in seconds for the
+    /// first `tds_1` tree, and little-endian values. Each tree is converted in one transaction with its marker.
+    pub(super) fn migrate_metric_keys(&self) {
+        for metric in Metric::ALL.iter() {
+            let marker = format!("{}.format", metric.tree_name());
+            if let Ok(Some(format)) = self.meta_tree.get(&marker) {
+                if format.as_ref() == [METRIC_FORMAT] {
+                    continue;
+                }
+            }
+            let tree = self.metric_tree(*metric);
+            let entries: Vec<(sled::IVec, sled::IVec)> = tree.iter().filter_map(Result::ok).collect();
+            let res = (tree, &self.meta_tree).transaction(|(tree, meta)| {
+                for (key, val) in entries.iter() {
+                    if key.len() != 8 || val.len() != 8 {
+                        continue;
+                    }
+                    let mut buff = [0u8; 8];
+                    buff.copy_from_slice(key);
+                    let mut timestamp = u64::from_le_bytes(buff);
+                    // Anything before 1973 in milliseconds was stored in seconds
+                    if timestamp < 100_000_000_000 {
+                        timestamp *= 1000;
+                    }
+                    buff.copy_from_slice(val);
+                    let value = f64::from_le_bytes(buff);
+                    tree.remove(key)?;
+                    tree.insert(&timestamp.to_be_bytes(), &value.to_be_bytes())?;
+                }
+                meta.insert(marker.as_str(), &[METRIC_FORMAT])?;
+                Ok::<_, ConflictableTransactionError<()>>(())
+            });
+            match res {
+                Ok(()) if !entries.is_empty() => info!("Migrated {} {} samples", entries.len(), metric),
+                Ok(()) => {},
+                Err(e) => error!("Failed to migrate the {} samples: {:?}", metric, e),
+            }
+        }
+        let _ = self.db.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::testing::*;
+
+    fn at(ms: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn metrics_survive_reopen() {
+        let (store, path) = temporary_store("store");
+        for (idx, metric) in Metric::ALL.iter().enumerate() {
+            store.insert_metric(*metric, at(1_600_000_000_123), idx as f64);
+        }
+        store.insert_metric(Metric::Tds1, at(1_600_000_000_124), 520.5);
+        store.flush();
+        drop(store);
+
+        let store = reopen(&path);
+        let counts: Vec<usize> = Metric::ALL.iter().map(|metric| store.metric_tree(*metric).len()).collect();
+        let ph: Vec<Sample> = store.samples(Metric::Ph1, at(0)..at(u64::MAX / 2)).collect();
+        drop(store);
+        std::fs::remove_dir_all(&path).unwrap();
+        assert_eq!(counts, vec![2, 1, 1, 1, 1]);
+        assert_eq!(ph, vec![Sample { at: at(1_600_000_000_123), value: 1.0 }]);
+    }
+
+    #[test]
+    fn query_ranges_and_windows() {
+        let (store, path) = temporary_store("query");
+        // 255 and 256 ms are out of order when the keys are little-endian
+        for (ms, value) in [(255, 1.0), (256, 2.0), (1_000, 3.0), (1_500, 5.0), (1_999, 4.0), (3_000, 10.0)].iter() {
+            store.insert_metric(Metric::Tds1, at(*ms), *value);
+        }
+        let values = |samples: Vec<Sample>| samples.iter().map(|s| s.value).collect::<Vec<f64>>();
+        let in_range = values(store.samples(Metric::Tds1, at(256)..at(3_000)).collect());
+        let last = values(store.last_samples(Metric::Tds1, 2));
+        let aggregates = store.aggregate(Metric::Tds1, at(0)..at(10_000), Duration::from_secs(1), &[50.0, 100.0]);
+        drop(store);
+        std::fs::remove_dir_all(&path).unwrap();
+
+        assert_eq!(in_range, vec![2.0, 3.0, 5.0, 4.0]);
+        assert_eq!(last, vec![4.0, 10.0]);
+        assert_eq!(aggregates, vec![
+            Aggregate { start: at(0), count: 2, min: 1.0, max: 2.0, mean: 1.5, percentiles: vec![1.5, 2.0] },
+            Aggregate { start: at(1_000), count: 3, min: 3.0, max: 5.0, mean: 4.0, percentiles: vec![4.0, 5.0] },
+            Aggregate { start: at(3_000), count: 1, min: 10.0, max: 10.0, mean: 10.0, percentiles: vec![10.0, 10.0] },
+        ]);
+    }
+
+    #[test]
+    fn migrate_little_endian_keys() {
+        let path = temporary_path("migrate");
+        {
+            let db = sled::open(&path).unwrap();
+            let tds = db.open_tree("tds_1").unwrap();
+            tds.insert(1_600_000_000u64.to_le_bytes(), &500.0f64.to_le_bytes()).unwrap();
+            tds.insert(1_600_000_000_500u64.to_le_bytes(), &510.0f64.to_le_bytes()).unwrap();
+            db.open_tree("ph_1").unwrap().insert(1_600_000_001_000u64.to_le_bytes(), &6.5f64.to_le_bytes()).unwrap();
+            db.flush().unwrap();
+        }
+        let store = reopen(&path);
+        let tds: Vec<Sample> = store.samples(Metric::Tds1, at(0)..at(u64::MAX / 2)).collect();
+        let ph = store.last_samples(Metric::Ph1, 10);
+        drop(store);
+        // Migrated trees are left alone on the next open
+        let store = reopen(&path);
+        let reopened = store.last_samples(Metric::Tds1, 10);
+        drop(store);
+        std::fs::remove_dir_all(&path).unwrap();
+
+        assert_eq!(tds, vec![
+            Sample { at: at(1_600_000_000_000), value: 500.0 },
+            Sample { at: at(1_600_000_000_500), value: 510.0 },
+        ]);
+        assert_eq!(ph, vec![Sample { at: at(1_600_000_001_000), value: 6.5 }]);
+        assert_eq!(reopened, tds);
+    }
+}
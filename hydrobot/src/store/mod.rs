@@ -0,0 +1,302 @@
+#![allow(non_local_definitions)]
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+mod location;
+mod metrics;
+mod retention;
+mod settings;
+mod profiles;
+mod journal;
+mod dosing;
+mod recovery;
+mod lockout;
+pub use location::*;
+pub use metrics::*;
+pub use retention::*;
+pub use settings::*;
+pub use profiles::*;
+pub use journal::*;
+pub use dosing::*;
+pub use recovery::*;
+pub use lockout::*;
+
+/// The daemon may write while the store is copied, a torn copy is taken again
+const SNAPSHOT_ATTEMPTS: u32 = 3;
+
+/// A new directory for each snapshot, the readers of a store don't share theirs
+fn snapshot_dir() -> std::io::Result<PathBuf> {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    loop {
+        let dir = std::env::temp_dir().join(format!("hydrobot-snapshot-{}-{}", std::process::id(), NEXT.fetch_add(1, Ordering::Relaxed)));
+        match std::fs::create_dir(&dir) {
+            // Left by a reader that crashed with the same pid
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            res => return res.map(|_| dir),
+        }
+    }
+}
+
+fn copy_dir(from: &Path, to: &Path) -> std::io::Result<()> {
+    std::fs::create_dir_all(to)?;
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            copy_dir(&entry.path(), &to.join(entry.file_name()))?;
+        } else if file_type.is_file() {
+            std::fs::copy(entry.path(), to.join(entry.file_name()))?;
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Fail)]
+pub enum StoreError {
+    #[fail(display = "Database error: {}", _0)]
+    Db(sled::Error),
+    /// sled found the files damaged, see `Store::move_aside`
+    #[fail(display = "Damaged database: {}", _0)]
+    Damaged(String),
+    #[fail(display = "Corrupt entry in the {} tree", _0)]
+    Corrupt(String),
+    #[fail(display = "{}", _0)]
+    Setting(SettingError),
+    #[fail(display = "The store is used by {}", _0)]
+    Locked(String),
+    #[fail(display = "The store is opened read-only")]
+    ReadOnly,
+}
+
+impl StoreError {
+    /// Whether `hydrobot store repair` or `reset` may help
+    pub fn is_damage(&self) -> bool {
+        matches!(self, StoreError::Damaged(_) | StoreError::Corrupt(_))
+    }
+}
+
+impl From<sled::Error> for StoreError {
+    fn from(e: sled::Error) -> Self {
+        match e {
+            sled::Error::Corruption { .. } => StoreError::Damaged(e.to_string()),
+            e => StoreError::Db(e),
+        }
+    }
+}
+
+impl From<SettingError> for StoreError {
+    fn from(e: SettingError) -> Self {
+        StoreError::Setting(e)
+    }
+}
+
+pub type StoreResult<T> = Result<T, StoreError>;
+
+#[derive(Clone)]
+pub struct Store {
+    pub tds_1_tree: sled::Tree,
+    pub ph_1_tree: sled::Tree,
+    pub t_1_tree: sled::Tree,
+    pub raw_tds_1_tree: sled::Tree,
+    pub raw_ph_1_tree: sled::Tree,
+    pub settings_tree: sled::Tree,
+    pub journal_tree: sled::Tree,
+    pub dosing_tree: sled::Tree,
+    pub grow_cycles_tree: sled::Tree,
+    meta_tree: sled::Tree,
+    rollup_trees: HashMap<(Metric, Resolution), sled::Tree>,
+    db: sled::Db,
+    read_only: bool,
+    _lock: Option<Arc<StoreLock>>,
+}
+
+impl Store {
+    /// Open the store for reading and writing, fails with `StoreError::Locked` if another process has it
+    pub fn open<T: AsRef<Path>>(path: T) -> StoreResult<Store> {
+        let lock = StoreLock::acquire(&path)?;
+        Self::from_db(sled::open(path)?, false, Some(lock))
+    }
+
+    /// Open the store for reading while another process may hold it: the database is then copied into a
+    /// temporary directory of its own, checked and taken again if torn, and removed once the store is dropped. The settings, profiles, grow cycles,
+    /// repair and compaction fail with `StoreError::ReadOnly`.
+    pub fn open_read_only<T: AsRef<Path>>(path: T) -> StoreResult<Store> {
+        let path = path.as_ref();
+        if !path.join("db").exists() {
+            return Err(sled::Error::Io(std::io::Error::new(std::io::ErrorKind::NotFound, format!("No store in {}", path.display()))).into());
+        }
+        match StoreLock::acquire(path) {
+            Ok(lock) => return Self::from_db(sled::open(path)?, true, Some(lock)),
+            Err(e) => debug!("Can't open {}, taking a snapshot: {}", path.display(), e),
+        }
+        let mut attempt = 1;
+        loop {
+            let copy = snapshot_dir().map_err(sled::Error::Io)?;
+            let snapshot = copy_dir(path, &copy).map_err(|e| StoreError::from(sled::Error::Io(e)))
+                .and_then(|_| Ok(sled::Config::new().path(&copy).temporary(true).open()?))
+                .and_then(|db| Self::from_db(db, true, None))
+                .and_then(|store| Ok((store.check()?.is_empty(), store)));
+            match snapshot {
+                Ok((true, store)) => return Ok(store),
+                // Damage that outlives the copies is the store's own, `store check` reports it
+                Ok((false, store)) if attempt == SNAPSHOT_ATTEMPTS => return Ok(store),
+                Err(e) if attempt == SNAPSHOT_ATTEMPTS => {
+                    let _ = std::fs::remove_dir_all(&copy);
+                    return Err(e);
+                },
+                Ok(_) => debug!("Corrupt entries in the snapshot of {}, copying it again", path.display()),
+                Err(e) => {
+                    debug!("Failed to open the snapshot of {}, copying it again: {}", path.display(), e);
+                    let _ = std::fs::remove_dir_all(&copy);
+                },
+            }
+            attempt += 1;
+        }
+    }
+
+    /// Rename a damaged store to `<path>.damaged-<unix seconds>` so a fresh one can be created, returns the new path
+    pub fn move_aside<T: AsRef<Path>>(path: T) -> std::io::Result<PathBuf> {
+        let path = path.as_ref();
+        let secs = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_secs();
+        let mut name = path.file_name().unwrap_or_else(|| "store".as_ref()).to_os_string();
+        name.push(format!(".damaged-{}", secs));
+        let target = path.with_file_name(name);
+        std::fs::rename(path, &target)?;
+        Ok(target)
+    }
+
+    fn from_db(db: sled::Db, read_only: bool, lock: Option<StoreLock>) -> StoreResult<Store> {
+        let mut rollup_trees = HashMap::new();
+        for metric in Metric::ALL.iter() {
+            for resolution in [Resolution::Minute, Resolution::Hour].iter() {
+                rollup_trees.insert((*metric, *resolution), db.open_tree(format!("{}{}", metric.tree_name(), resolution.suffix()))?);
+            }
+        }
+        let store = Self {
+            settings_tree: db.open_tree("settings")?,
+            tds_1_tree: db.open_tree(Metric::Tds1.tree_name())?,
+            ph_1_tree: db.open_tree(Metric::Ph1.tree_name())?,
+            t_1_tree: db.open_tree(Metric::Temperature1.tree_name())?,
+            raw_tds_1_tree: db.open_tree(Metric::RawTds1.tree_name())?,
+            raw_ph_1_tree: db.open_tree(Metric::RawPh1.tree_name())?,
+            journal_tree: db.open_tree("journal")?,
+            dosing_tree: db.open_tree("dosing")?,
+            grow_cycles_tree: db.open_tree("grow_cycles")?,
+            meta_tree: db.open_tree("meta")?,
+            rollup_trees,
+            db,
+            read_only,
+            _lock: lock.map(Arc::new),
+        };
+        store.migrate_metric_keys();
+        Ok(store)
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn writable(&self) -> StoreResult<()> {
+        if self.read_only {
+            Err(StoreError::ReadOnly)
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn get_tds_monitoring(&self) -> bool {
+        self.get(Setting::TdsMonitoring).as_bool()
+    }
+    pub fn get_ph_monitoring(&self) -> bool {
+        self.get(Setting::PhMonitoring).as_bool()
+    }
+    pub fn get_tds_1_thresh(&self) -> f64 {
+        self.get(Setting::Tds1Thresh).as_float()
+    }
+    pub fn get_osmoseur_pulse_duration(&self) -> Duration {
+        self.get(Setting::OsmoseurPulseDuration).as_duration()
+    }
+    pub fn get_osmoseur_pulse_min_interval(&self) -> Duration {
+        self.get(Setting::OsmoseurPulseMinInterval).as_duration()
+    }
+    pub fn get_osmoseur_controller(&self) -> usize {
+        self.get(Setting::OsmoseurController).as_choice()
+    }
+    pub fn get_osmoseur_kp(&self) -> f64 {
+        self.get(Setting::OsmoseurKp).as_float()
+    }
+    pub fn get_osmoseur_ki(&self) -> f64 {
+        self.get(Setting::OsmoseurKi).as_float()
+    }
+    pub fn get_osmoseur_kd(&self) -> f64 {
+        self.get(Setting::OsmoseurKd).as_float()
+    }
+    pub fn get_osmoseur_pulse_max(&self) -> Duration {
+        self.get(Setting::OsmoseurPulseMax).as_duration()
+    }
+    pub fn get_ph_1_thresh(&self) -> f64 {
+        self.get(Setting::Ph1Thresh).as_float()
+    }
+    pub fn get_ph_pulse_duration(&self) -> Duration {
+        self.get(Setting::PhPulseDuration).as_duration()
+    }
+    pub fn get_ph_pulse_min_interval(&self) -> Duration {
+        self.get(Setting::PhPulseMinInterval).as_duration()
+    }
+    pub fn get_ph_controller(&self) -> usize {
+        self.get(Setting::PhController).as_choice()
+    }
+    pub fn get_ph_kp(&self) -> f64 {
+        self.get(Setting::PhKp).as_float()
+    }
+    pub fn get_ph_ki(&self) -> f64 {
+        self.get(Setting::PhKi).as_float()
+    }
+    pub fn get_ph_kd(&self) -> f64 {
+        self.get(Setting::PhKd).as_float()
+    }
+    pub fn get_ph_pulse_max(&self) -> Duration {
+        self.get(Setting::PhPulseMax).as_duration()
+    }
+    pub fn get_hardware_timeout(&self) -> Duration {
+        self.get(Setting::HardwareTimeout).as_duration()
+    }
+
+    /// Sled flushes every 500ms by itself, this makes sure nothing is lost on shutdown
+    pub fn flush(&self) {
+        if let Err(e) = self.db.flush() {
+            warn!("Failed to flush the store: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod testing {
+    use super::*;
+
+    /// Path unique to the test and the process in the temp dir, removed first if a previous run left it
+    pub fn temporary_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("hydrobot-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    pub fn temporary_store(name: &str) -> (Store, PathBuf) {
+        let path = temporary_path(name);
+        (Store::open(&path).unwrap(), path)
+    }
+
+    /// Sled releases its lock once its flusher thread noticed the drop
+    pub fn reopen(path: &Path) -> Store {
+        for _ in 0..100 {
+            if let Ok(store) = Store::open(path) {
+                return store;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        Store::open(path).unwrap()
+    }
+}
@@ -0,0 +1,250 @@
+//! Named settings profiles (ie: one per crop).
+#![allow(non_local_definitions)]
+use super::*;
+use std::result::Result;
+
+const ACTIVE_PROFILE: &str = "active_profile";
+const PROFILE_PREFIX: &str = "profile/";
+
+#[derive(Debug, Fail)]
+pub enum ProfileError {
+    #[fail(display = "No profile named `{}`", _0)]
+    NotFound(String),
+    #[fail(display = "Invalid profile name `{}`, use letters, digits, `-` and `_`", _0)]
+    InvalidName(String),
+    #[fail(display = "Invalid profile file: {}", _0)]
+    Toml(String),
+    #[fail(display = "{}", _0)]
+    Setting(SettingError),
+    #[fail(display = "{}", _0)]
+    Store(StoreError),
+}
+
+impl From<SettingError> for ProfileError {
+    fn from(e: SettingError) -> Self {
+        ProfileError::Setting(e)
+    }
+}
+
+impl From<StoreError> for ProfileError {
+    fn from(e: StoreError) -> Self {
+        match e {
+            StoreError::Setting(e) => ProfileError::Setting(e),
+            e => ProfileError::Store(e),
+        }
+    }
+}
+
+impl From<sled::Error> for ProfileError {
+    fn from(e: sled::Error) -> Self {
+        ProfileError::Store(e.into())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Profile {
+    pub name: String,
+    /// In the registry order, settings missing from a profile are left untouched when it's applied
+    pub values: Vec<(Setting, SettingValue)>,
+}
+
+pub fn check_profile_name(name: &str) -> Result<(), ProfileError> {
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err(ProfileError::InvalidName(name.to_string()));
+    }
+    Ok(())
+}
+
+impl SettingSpec {
+    /// The safety limits and the storage belong to the installation, not to a crop
+    pub fn in_profiles(&self) -> bool {
+        !matches!(self.category, SettingCategory::Safety | SettingCategory::Storage)
+    }
+}
+
+fn to_toml(value: SettingValue) -> toml::Value {
+    match value {
+        SettingValue::Bool(val) => toml::Value::Boolean(val),
+        SettingValue::Float(val) => toml::Value::Float(val),
+        SettingValue::Int(val) => toml::Value::Integer(val),
+        SettingValue::Duration(val) => toml::Value::Integer(val.as_secs() as i64),
+        SettingValue::Choice(..) => toml::Value::String(value.to_string()),
+    }
+}
+
+fn from_toml(spec: &SettingSpec, value: &toml::Value) -> Result<SettingValue, SettingError> {
+    let value = match (spec.kind(), value) {
+        (SettingKind::Bool, toml::Value::Boolean(val)) => SettingValue::Bool(*val),
+        (SettingKind::Float, toml::Value::Float(val)) => SettingValue::Float(*val),
+        (SettingKind::Float, toml::Value::Integer(val)) => SettingValue::Float(*val as f64),
+        (SettingKind::Int, toml::Value::Integer(val)) => SettingValue::Int(*val),
+        (SettingKind::Duration, toml::Value::Integer(val)) if *val >= 0 => SettingValue::Duration(Duration::from_secs(*val as u64)),
+        (SettingKind::Duration, toml::Value::String(val)) | (SettingKind::Choice(_), toml::Value::String(val)) => return spec.parse(val),
+        _ => return Err(SettingError::Kind(spec.key, spec.kind())),
+    };
+    spec.validate(value)
+}
+
+impl Profile {
+    pub fn to_toml(&self) -> String {
+        let mut settings = toml::value::Table::new();
+        for (setting, value) in self.values.iter() {
+            settings.insert(setting.spec().key.to_string(), to_toml(*value));
+        }
+        let mut root = toml::value::Table::new();
+        root.insert("name".to_string(), toml::Value::String(self.name.clone()));
+        root.insert("settings".to_string(), toml::Value::Table(settings));
+        toml::Value::Table(root).to_string()
+    }
+
+    pub fn from_toml(val: &str) -> Result<Profile, ProfileError> {
+        let root: toml::Value = val.parse().map_err(|e: toml::de::Error| ProfileError::Toml(e.to_string()))?;
+        let name = root.get("name").and_then(|name| name.as_str()).ok_or_else(|| ProfileError::Toml("missing `name`".to_string()))?;
+        check_profile_name(name)?;
+        let table = root.get("settings").and_then(|settings| settings.as_table()).ok_or_else(|| ProfileError::Toml("missing `[settings]`".to_string()))?;
+        for key in table.keys() {
+            let setting: Setting = key.parse()?;
+            if !setting.spec().in_profiles() {
+                return Err(ProfileError::Toml(format!("`{}` can't be part of a profile", key)));
+            }
+        }
+        let mut values = Vec::new();
+        for spec in SETTINGS.iter() {
+            if let Some(value) = table.get(spec.key) {
+                values.push((spec.setting, from_toml(spec, value)?));
+            }
+        }
+        Ok(Profile { name: name.to_string(), values })
+    }
+}
+
+impl Store {
+    fn profile_key(name: &str, setting: Setting) -> String {
+        format!("{}{}/{}", PROFILE_PREFIX, name, setting.spec().key)
+    }
+
+    /// Names of the stored profiles, sorted
+    pub fn profiles(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.settings_tree.scan_prefix(PROFILE_PREFIX).keys().filter_map(Result::ok).filter_map(|key| {
+            let key = String::from_utf8_lossy(&key[PROFILE_PREFIX.len()..]).to_string();
+            key.split('/').next().map(|name| name.to_string())
+        }).collect();
+        names.sort();
+        names.dedup();
+        names
+    }
+
+    pub fn profile(&self, name: &str) -> Result<Profile, ProfileError> {
+        check_profile_name(name)?;
+        let mut values = Vec::new();
+        for spec in SETTINGS.iter().filter(|spec| spec.in_profiles()) {
+            if let Ok(Some(buff)) = self.settings_tree.get(Self::profile_key(name, spec.setting)) {
+                match SettingValue::decode(spec.kind(), &buff).map(|value| spec.validate(value)) {
+                    Some(Ok(value)) => values.push((spec.setting, value)),
+                    _ => warn!("Ignoring the invalid {} of the {} profile", spec.key, name),
+                }
+            }
+        }
+        if values.is_empty() {
+            return Err(ProfileError::NotFound(name.to_string()));
+        }
+        Ok(Profile { name: name.to_string(), values })
+    }
+
+    /// Store `profile`, replacing the one of the same name
+    pub fn put_profile(&self, profile: &Profile) -> Result<(), ProfileError> {
+        check_profile_name(&profile.name)?;
+        for (setting, value) in profile.values.iter() {
+            setting.spec().validate(*value)?;
+        }
+        self.delete_profile(&profile.name)?;
+        for (setting, value) in profile.values.iter() {
+            self.settings_tree.insert(Self::profile_key(&profile.name, *setting), value.encode())?;
+        }
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// Save the current settings as the `name` profile
+    pub fn save_profile(&self, name: &str) -> Result<Profile, ProfileError> {
+        let values = SETTINGS.iter().filter(|spec| spec.in_profiles()).map(|spec| (spec.setting, self.get(spec.setting))).collect();
+        let profile = Profile { name: name.to_string(), values };
+        self.put_profile(&profile)?;
+        self.settings_tree.insert(ACTIVE_PROFILE, name.as_bytes())?;
+        Ok(profile)
+    }
+
+    /// Returns whether the profile existed
+    pub fn delete_profile(&self, name: &str) -> StoreResult<bool> {
+        self.writable()?;
+        let keys: Vec<sled::IVec> = self.settings_tree.scan_prefix(format!("{}{}/", PROFILE_PREFIX, name)).keys().filter_map(Result::ok).collect();
+        for key in keys.iter() {
+            let _ = self.settings_tree.remove(key);
+        }
+        if self.active_profile().as_deref() == Some(name) {
+            let _ = self.settings_tree.remove(ACTIVE_PROFILE);
+        }
+        Ok(!keys.is_empty())
+    }
+
+    /// Copy the values of the profile into the settings, returns it so the caller can apply them
+    pub fn apply_profile(&self, name: &str) -> Result<Profile, ProfileError> {
+        let profile = self.profile(name)?;
+        for (setting, value) in profile.values.iter() {
+            self.set(*setting, *value)?;
+        }
+        self.settings_tree.insert(ACTIVE_PROFILE, name.as_bytes())?;
+        self.db.flush()?;
+        Ok(profile)
+    }
+
+    /// The last profile applied or saved
+    pub fn active_profile(&self) -> Option<String> {
+        let name = self.settings_tree.get(ACTIVE_PROFILE).ok()??;
+        Some(String::from_utf8_lossy(&name).to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::testing::*;
+
+    #[test]
+    fn profiles_roundtrip() {
+        let (store, path) = temporary_store("profiles");
+        store.set(Setting::Tds1Thresh, SettingValue::Float(560.0)).unwrap();
+        let lettuce = store.save_profile("lettuce").unwrap();
+        assert!(lettuce.values.iter().all(|(setting, _)| setting.spec().in_profiles()));
+
+        let tomato = Profile::from_toml("name = \"tomato\"\n[settings]\ntds_1_thresh = 1400\nph_1_thresh = 6.2\nph_pulse_min_interval = \"5m\"\n").unwrap();
+        store.put_profile(&tomato).unwrap();
+        assert_eq!(store.profiles(), vec!["lettuce", "tomato"]);
+        store.apply_profile("tomato").unwrap();
+        let applied = (store.get(Setting::Tds1Thresh), store.get(Setting::PhPulseMinInterval), store.active_profile());
+        store.apply_profile("lettuce").unwrap();
+        let back = store.get(Setting::Tds1Thresh);
+        let exported = Profile::from_toml(&store.profile("tomato").unwrap().to_toml()).unwrap();
+        assert!(store.delete_profile("tomato").unwrap());
+        let missing = store.apply_profile("tomato");
+        drop(store);
+        std::fs::remove_dir_all(&path).unwrap();
+
+        assert_eq!(applied, (SettingValue::Float(1400.0), SettingValue::Duration(Duration::from_secs(300)), Some("tomato".to_string())));
+        assert_eq!(back, SettingValue::Float(560.0));
+        assert_eq!(exported, tomato);
+        assert!(matches!(missing, Err(ProfileError::NotFound(_))));
+    }
+
+    #[test]
+    fn reject_invalid_profiles() {
+        let parse = |val: &str| Profile::from_toml(val);
+        assert!(matches!(parse("name = \"a b\"\n[settings]\n"), Err(ProfileError::InvalidName(_))));
+        assert!(matches!(parse("name = \"x\"\n[settings]\nph_1_thresh = 20.0\n"), Err(ProfileError::Setting(SettingError::OutOfRange(..)))));
+        assert!(matches!(parse("name = \"x\"\n[settings]\nph_1_thresh = true\n"), Err(ProfileError::Setting(SettingError::Kind(..)))));
+        assert!(matches!(parse("name = \"x\"\n[settings]\nfoo = 1\n"), Err(ProfileError::Setting(SettingError::Unknown(_)))));
+        assert!(matches!(parse("name = \"x\"\n[settings]\nraw_retention_days = 3\n"), Err(ProfileError::Toml(_))));
+        assert!(matches!(parse("name = \"x\"\n[settings]\nhardware_timeout = \"2m\"\n"), Err(ProfileError::Toml(_))));
+        assert!(matches!(parse("name = "), Err(ProfileError::Toml(_))));
+    }
+}
@@ -0,0 +1,107 @@
+//! Detection and removal of the entries a crash or a failing SD card left unreadable.
+use super::*;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CorruptEntry {
+    pub tree: String,
+    pub key: sled::IVec,
+}
+
+fn valid_setting(key: &[u8], val: &[u8]) -> bool {
+    let key = match std::str::from_utf8(key) {
+        Ok(key) => key,
+        Err(_) => return false,
+    };
+    // `profile/<name>/<setting key>`
+    let key = match key.strip_prefix("profile/") {
+        Some(key) => match key.split('/').nth(1) {
+            Some(key) => key,
+            None => return false,
+        },
+        None => key,
+    };
+    match key.parse::<Setting>() {
+        Ok(setting) => matches!(SettingValue::decode(setting.spec().kind(), val), Some(value) if setting.spec().validate(value).is_ok()),
+        // Profile name and keys of older versions
+        Err(_) => true,
+    }
+}
+
+impl Store {
+    /// Every entry that can't be decoded
+    pub fn check(&self) -> StoreResult<Vec<CorruptEntry>> {
+        let mut corrupt = Vec::new();
+        let mut scan = |tree: &sled::Tree, valid: &dyn Fn(&[u8], &[u8]) -> bool| -> StoreResult<()> {
+            for entry in tree.iter() {
+                let (key, val) = entry?;
+                if !valid(&key, &val) {
+                    corrupt.push(CorruptEntry { tree: String::from_utf8_lossy(&tree.name()).to_string(), key });
+                }
+            }
+            Ok(())
+        };
+        for metric in Metric::ALL.iter() {
+            scan(self.metric_tree(*metric), &|key, val| key.len() == 8 && val.len() == 8 && metrics::decode(key, val).is_some())?;
+            for resolution in [Resolution::Minute, Resolution::Hour].iter() {
+                let resolution = *resolution;
+                scan(self.resolution_tree(*metric, resolution), &|key, val| key.len() == 8 && val.len() == 32 && Bucket::decode(resolution, key, val).is_some())?;
+            }
+        }
+        scan(&self.journal_tree, &|key, val| key.len() == 16 && Event::decode(key, val).is_some())?;
+        scan(&self.dosing_tree, &|key, val| key.len() == 16 && Dose::decode(key, val).is_some())?;
+        scan(&self.grow_cycles_tree, &|key, val| key.len() == 8 && std::str::from_utf8(val).is_ok())?;
+        scan(&self.settings_tree, &valid_setting)?;
+        Ok(corrupt)
+    }
+
+    /// Remove the entries `check` reports, returns them. Settings fall back to their defaults.
+    pub fn repair(&self) -> StoreResult<Vec<CorruptEntry>> {
+        self.writable()?;
+        let corrupt = self.check()?;
+        for entry in corrupt.iter() {
+            self.db.open_tree(&entry.tree)?.remove(&entry.key)?;
+        }
+        self.db.flush()?;
+        Ok(corrupt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::testing::*;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    #[test]
+    fn check_and_repair() {
+        let (store, path) = temporary_store("recovery");
+        store.insert_metric(Metric::Tds1, SystemTime::now() - Duration::from_secs(1), 500.0);
+        store.set(Setting::Tds1Thresh, SettingValue::Float(600.0)).unwrap();
+        store.save_profile("lettuce").unwrap();
+        store.record_event(EventKind::Log, LogLevel::Info, "Started");
+        // Truncated values
+        store.tds_1_tree.insert(7u64.to_be_bytes(), &[1u8, 2][..]).unwrap();
+        store.settings_tree.insert("tds_1_thresh", &[0u8][..]).unwrap();
+        store.settings_tree.insert("profile/lettuce/ph_1_thresh", &[0u8; 3][..]).unwrap();
+        store.journal_tree.insert(&[0u8; 16][..], &[9u8][..]).unwrap();
+
+        let found = store.check().unwrap();
+        let samples = store.samples(Metric::Tds1, UNIX_EPOCH..SystemTime::now()).count();
+        let profile = store.profile("lettuce").unwrap();
+        let thresh = store.get(Setting::Tds1Thresh);
+        let repaired = store.repair().unwrap();
+        let left = store.check().unwrap();
+        drop(store);
+        std::fs::remove_dir_all(&path).unwrap();
+
+        let mut trees: Vec<&str> = found.iter().map(|entry| entry.tree.as_str()).collect();
+        trees.sort();
+        assert_eq!(trees, vec!["journal", "settings", "settings", "tds_1"]);
+        assert_eq!(samples, 1);
+        assert!(profile.values.iter().all(|(setting, _)| *setting != Setting::Ph1Thresh));
+        // The corrupt threshold was reset to the default by `get`
+        assert_eq!(thresh, Setting::Tds1Thresh.spec().default);
+        assert_eq!(repaired.len(), 3);
+        assert!(left.is_empty());
+    }
+}
@@ -0,0 +1,314 @@
+//! Downsampling and retention of the metric trees.
+use super::*;
+use sled::transaction::{ConflictableTransactionError, TransactionError, Transactional};
+use std::ops::Range;
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const DAY_MS: u64 = 24 * 3600 * 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Resolution {
+    Raw,
+    Minute,
+    Hour,
+}
+
+impl Resolution {
+    /// Finest first
+    pub const ALL: [Resolution; 3] = [Resolution::Raw, Resolution::Minute, Resolution::Hour];
+
+    pub fn bucket(self) -> Duration {
+        match self {
+            Resolution::Raw => Duration::from_millis(1),
+            Resolution::Minute => Duration::from_secs(60),
+            Resolution::Hour => Duration::from_secs(3600),
+        }
+    }
+
+    pub(super) fn suffix(self) -> &'static str {
+        match self {
+            Resolution::Raw => "",
+            Resolution::Minute => ".1m",
+            Resolution::Hour => ".1h",
+        }
+    }
+}
+
+impl std::fmt::Display for Resolution {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Resolution::Raw => write!(f, "raw"),
+            Resolution::Minute => write!(f, "1m"),
+            Resolution::Hour => write!(f, "1h"),
+        }
+    }
+}
+
+/// Samples of one bucket, a raw sample is a bucket of one
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bucket {
+    pub start: SystemTime,
+    pub resolution: Resolution,
+    pub count: u64,
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+}
+
+impl Bucket {
+    fn encode(&self) -> [u8; 32] {
+        let mut buff = [0u8; 32];
+        buff[..8].copy_from_slice(&self.count.to_be_bytes());
+        buff[8..16].copy_from_slice(&self.min.to_be_bytes());
+        buff[16..24].copy_from_slice(&self.max.to_be_bytes());
+        buff[24..].copy_from_slice(&self.mean.to_be_bytes());
+        buff
+    }
+
+    pub(super) fn decode(resolution: Resolution, key: &[u8], val: &[u8]) -> Option<Bucket> {
+        let field = |range: Range<usize>| {
+            let mut buff = [0u8; 8];
+            buff.copy_from_slice(val.get(range)?);
+            Some(buff)
+        };
+        let mut start = [0u8; 8];
+        start.copy_from_slice(key.get(..8)?);
+        Some(Bucket {
+            start: UNIX_EPOCH + Duration::from_millis(u64::from_be_bytes(start)),
+            resolution,
+            count: u64::from_be_bytes(field(0..8)?),
+            min: f64::from_be_bytes(field(8..16)?),
+            max: f64::from_be_bytes(field(16..24)?),
+            mean: f64::from_be_bytes(field(24..32)?),
+        })
+    }
+
+    /// Merge finer buckets into one starting at `start`, `buckets` must not be empty
+    pub fn merge(start: SystemTime, resolution: Resolution, buckets: &[Bucket]) -> Bucket {
+        let count: u64 = buckets.iter().map(|b| b.count).sum();
+        Bucket {
+            start,
+            resolution,
+            count,
+            min: buckets.iter().map(|b| b.min).fold(f64::INFINITY, f64::min),
+            max: buckets.iter().map(|b| b.max).fold(f64::NEG_INFINITY, f64::max),
+            mean: buckets.iter().map(|b| b.mean * b.count as f64).sum::<f64>() / count as f64,
+        }
+    }
+}
+
+impl From<Sample> for Bucket {
+    fn from(sample: Sample) -> Self {
+        Bucket { start: sample.at, resolution: Resolution::Raw, count: 1, min: sample.value, max: sample.value, mean: sample.value }
+    }
+}
+
+fn floor(when: SystemTime, bucket: Duration) -> u64 {
+    let bucket = bucket.as_millis() as u64;
+    timestamp_ms(when) / bucket * bucket
+}
+
+fn at(ms: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(ms)
+}
+
+impl Store {
+    pub fn get_raw_retention_days(&self) -> u64 {
+        self.get(Setting::RawRetentionDays).as_int() as u64
+    }
+    pub fn get_minute_retention_days(&self) -> u64 {
+        self.get(Setting::MinuteRetentionDays).as_int() as u64
+    }
+
+    pub fn resolution_tree(&self, metric: Metric, resolution: Resolution) -> &sled::Tree {
+        match resolution {
+            Resolution::Raw => self.metric_tree(metric),
+            // Every rollup tree is opened with the store
+            _ => &self.rollup_trees[&(metric, resolution)],
+        }
+    }
+
+    /// Buckets of one resolution starting in `range`, oldest first
+    pub fn buckets(&self, metric: Metric, resolution: Resolution, range: Range<SystemTime>) -> Vec<Bucket> {
+        if resolution == Resolution::Raw {
+            return self.samples(metric, range).map(Bucket::from).collect();
+        }
+        let range = timestamp_ms(range.start).to_be_bytes()..timestamp_ms(range.end).to_be_bytes();
+        self.resolution_tree(metric, resolution).range(range).filter_map(|entry| match entry {
+            Ok((key, val)) => Bucket::decode(resolution, &key, &val).or_else(|| {
+                warn!("Skipping a corrupt {} {} bucket", metric, resolution);
+                None
+            }),
+            Err(e) => {
+                warn!("Failed to read {} {} buckets: {}", metric, resolution, e);
+                None
+            },
+        }).collect()
+    }
+
+    /// History of `range` at the finest resolution still available for each part of it: the recent part
+    /// comes from the raw samples, older ones from the minute then hour buckets.
+    /// The first bucket of a coarser resolution may overlap the finer ones.
+    pub fn history(&self, metric: Metric, range: Range<SystemTime>) -> Vec<Bucket> {
+        let mut end = range.end;
+        let mut parts = Vec::new();
+        for resolution in Resolution::ALL.iter() {
+            if end <= range.start {
+                break;
+            }
+            let oldest = match self.oldest(metric, *resolution) {
+                Some(oldest) => at(oldest),
+                None => continue,
+            };
+            let from = oldest.max(range.start);
+            if from < end {
+                parts.push(self.buckets(metric, *resolution, from..end));
+                end = from;
+            }
+        }
+        parts.into_iter().rev().flatten().collect()
+    }
+
+    /// Start of the first entry of a resolution
+    fn oldest(&self, metric: Metric, resolution: Resolution) -> Option<u64> {
+        let (key, _) = self.resolution_tree(metric, resolution).first().ok()??;
+        let mut buff = [0u8; 8];
+        buff.copy_from_slice(key.get(..8)?);
+        Some(u64::from_be_bytes(buff))
+    }
+
+    fn watermark(&self, metric: Metric, resolution: Resolution) -> Option<u64> {
+        let val = self.meta_tree.get(format!("{}.rolled_up{}", metric.tree_name(), resolution.suffix())).ok()??;
+        let mut buff = [0u8; 8];
+        buff.copy_from_slice(val.get(..8)?);
+        Some(u64::from_be_bytes(buff))
+    }
+
+    /// Roll `from` up into `into` buckets up to the last complete one, returns the new watermark.
+    /// Everything before the watermark has been rolled up.
+    fn roll_up(&self, metric: Metric, from: Resolution, into: Resolution, until: SystemTime) -> StoreResult<Option<u64>> {
+        let start = match self.watermark(metric, into).or_else(|| self.oldest(metric, from)) {
+            Some(start) => floor(at(start), into.bucket()),
+            None => return Ok(None),
+        };
+        let end = floor(until, into.bucket());
+        if end <= start {
+            return Ok(Some(start));
+        }
+        let mut batch = sled::Batch::default();
+        let bucket_ms = into.bucket().as_millis() as u64;
+        let sources = self.buckets(metric, from, at(start)..at(end));
+        let mut idx = 0;
+        while idx < sources.len() {
+            let bucket_start = floor(sources[idx].start, into.bucket());
+            let len = sources[idx..].iter().take_while(|b| timestamp_ms(b.start) < bucket_start + bucket_ms).count();
+            let bucket = Bucket::merge(at(bucket_start), into, &sources[idx..idx + len]);
+            batch.insert(&bucket_start.to_be_bytes(), &bucket.encode());
+            idx += len;
+        }
+        let target = self.resolution_tree(metric, into);
+        (target, &self.meta_tree).transaction(|(target, meta)| {
+            target.apply_batch(&batch)?;
+            meta.insert(format!("{}.rolled_up{}", metric.tree_name(), into.suffix()).as_bytes(), &end.to_be_bytes())?;
+            Ok::<_, ConflictableTransactionError<()>>(())
+        }).map_err(|e| match e {
+            TransactionError::Storage(e) => StoreError::from(e),
+            TransactionError::Abort(()) => StoreError::Corrupt(metric.to_string()),
+        })?;
+        Ok(Some(end))
+    }
+
+    /// Remove the entries older than `before`, returns how many were removed
+    fn prune(&self, metric: Metric, resolution: Resolution, before: u64) -> StoreResult<usize> {
+        let tree = self.resolution_tree(metric, resolution);
+        let mut batch = sled::Batch::default();
+        let mut count = 0;
+        for key in tree.range(..before.to_be_bytes()).keys() {
+            batch.remove(key?);
+            count += 1;
+        }
+        tree.apply_batch(batch)?;
+        Ok(count)
+    }
+
+    /// Roll up then prune every metric, `now` is the reference for the retention
+    pub fn compact(&self, now: SystemTime) -> StoreResult<()> {
+        self.writable()?;
+        let raw_limit = timestamp_ms(now).saturating_sub(self.get_raw_retention_days() * DAY_MS);
+        let minute_limit = timestamp_ms(now).saturating_sub(self.get_minute_retention_days() * DAY_MS);
+        for metric in Metric::ALL.iter() {
+            let minutes = self.roll_up(*metric, Resolution::Raw, Resolution::Minute, now)?;
+            let hours = match minutes {
+                Some(minutes) => self.roll_up(*metric, Resolution::Minute, Resolution::Hour, at(minutes))?,
+                None => None,
+            };
+            // Nothing is pruned before being rolled up
+            let pruned = self.prune(*metric, Resolution::Raw, raw_limit.min(minutes.unwrap_or(0)))?
+                + self.prune(*metric, Resolution::Minute, minute_limit.min(hours.unwrap_or(0)))?;
+            if pruned > 0 {
+                debug!("Pruned {} {} entries", pruned, metric);
+            }
+        }
+        let pruned = self.prune_events(at(timestamp_ms(now).saturating_sub(self.get_journal_retention_days() * DAY_MS)))?;
+        if pruned > 0 {
+            debug!("Pruned {} journal events", pruned);
+        }
+        Ok(())
+    }
+
+    /// Run `compact` every `interval` on a thread of its own
+    pub fn spawn_compaction(&self, interval: Duration) -> thread::JoinHandle<()> {
+        let store = self.clone();
+        thread::spawn(move || loop {
+            if let Err(e) = store.compact(SystemTime::now()) {
+                error!("Metric store compaction failed: {}", e);
+            }
+            thread::sleep(interval);
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::testing::*;
+
+    #[test]
+    fn roll_up_prune_and_query() {
+        let (store, path) = temporary_store("retention");
+        store.set(Setting::RawRetentionDays, SettingValue::Int(1)).unwrap();
+        store.set(Setting::MinuteRetentionDays, SettingValue::Int(2)).unwrap();
+
+        // One sample every 20s for 3 days, starting on an hour boundary
+        let start = 1_600_002_000_000;
+        for idx in 0..(3 * 24 * 180) {
+            store.insert_metric(Metric::Tds1, at(start + idx * 20_000), (idx % 3) as f64);
+        }
+        let now = at(start + 3 * 24 * 3600 * 1000);
+        store.compact(now).unwrap();
+        // Running it again changes nothing
+        store.compact(now).unwrap();
+
+        let raw = store.buckets(Metric::Tds1, Resolution::Raw, at(0)..now);
+        let minutes = store.buckets(Metric::Tds1, Resolution::Minute, at(0)..now);
+        let hours = store.buckets(Metric::Tds1, Resolution::Hour, at(0)..now);
+        let history = store.history(Metric::Tds1, at(start)..now);
+        drop(store);
+        std::fs::remove_dir_all(&path).unwrap();
+
+        assert_eq!(raw.len(), 24 * 180);
+        assert_eq!(raw[0].start, at(start + 2 * 24 * 3600 * 1000));
+        assert_eq!(minutes.len(), 2 * 24 * 60);
+        assert_eq!(minutes[0].start, at(start + 24 * 3600 * 1000));
+        assert_eq!(minutes[0], Bucket { start: minutes[0].start, resolution: Resolution::Minute, count: 3, min: 0.0, max: 2.0, mean: 1.0 });
+        assert_eq!(hours.len(), 3 * 24);
+        assert_eq!(hours[0], Bucket { start: at(start), resolution: Resolution::Hour, count: 180, min: 0.0, max: 2.0, mean: 1.0 });
+
+        assert_eq!(history.len(), 24 + 24 * 60 + 24 * 180);
+        assert_eq!(history[0].resolution, Resolution::Hour);
+        assert_eq!(history[24].resolution, Resolution::Minute);
+        assert_eq!(history[24 + 24 * 60].resolution, Resolution::Raw);
+        assert!(history.windows(2).all(|pair| pair[0].start < pair[1].start));
+    }
+}
@@ -0,0 +1,652 @@
+//! Settings registry.
+#![allow(non_local_definitions)]
+use super::{Store, StoreResult};
+use std::fmt::{self, Display, Formatter};
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SettingCategory {
+    General,
+    EcMonitor,
+    PhMonitor,
+    /// Limits of the hardware watchdog
+    Safety,
+    Storage,
+}
+
+impl SettingCategory {
+    pub const ALL: [SettingCategory; 5] = [SettingCategory::General, SettingCategory::EcMonitor, SettingCategory::PhMonitor, SettingCategory::Safety, SettingCategory::Storage];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            SettingCategory::General => "General",
+            SettingCategory::EcMonitor => "EC Monitoring",
+            SettingCategory::PhMonitor => "PH Monitoring",
+            SettingCategory::Safety => "Safety",
+            SettingCategory::Storage => "Storage",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Setting {
+    TdsMonitoring,
+    PhMonitoring,
+    HardwareTimeout,
+    Tds1Thresh,
+    OsmoseurPulseDuration,
+    OsmoseurPulseMinInterval,
+    OsmoseurFlowRate,
+    OsmoseurController,
+    OsmoseurKp,
+    OsmoseurKi,
+    OsmoseurKd,
+    OsmoseurPulseMax,
+    Ph1Thresh,
+    PhPulseDuration,
+    PhPulseMinInterval,
+    PhDownFlowRate,
+    PhController,
+    PhKp,
+    PhKi,
+    PhKd,
+    PhPulseMax,
+    RawRetentionDays,
+    MinuteRetentionDays,
+    JournalRetentionDays,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SettingKind {
+    Bool,
+    Float,
+    Int,
+    /// Stored in seconds
+    Duration,
+    /// One of the names, stored as its index
+    Choice(&'static [&'static str]),
+}
+
+impl Display for SettingKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            SettingKind::Bool => write!(f, "boolean"),
+            SettingKind::Float => write!(f, "number"),
+            SettingKind::Int => write!(f, "integer"),
+            SettingKind::Duration => write!(f, "duration"),
+            SettingKind::Choice(choices) => write!(f, "one of {}", choices.join(", ")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SettingValue {
+    Bool(bool),
+    Float(f64),
+    Int(i64),
+    Duration(Duration),
+    /// Index in the names
+    Choice(usize, &'static [&'static str]),
+}
+
+impl SettingValue {
+    pub fn kind(&self) -> SettingKind {
+        match self {
+            SettingValue::Bool(_) => SettingKind::Bool,
+            SettingValue::Float(_) => SettingKind::Float,
+            SettingValue::Int(_) => SettingKind::Int,
+            SettingValue::Duration(_) => SettingKind::Duration,
+            SettingValue::Choice(_, choices) => SettingKind::Choice(choices),
+        }
+    }
+
+    /// Value compared to the bounds, `None` for booleans
+    fn number(&self) -> Option<f64> {
+        match self {
+            SettingValue::Bool(_) => None,
+            SettingValue::Float(val) => Some(*val),
+            SettingValue::Int(val) => Some(*val as f64),
+            SettingValue::Duration(val) => Some(val.as_secs_f64()),
+            SettingValue::Choice(idx, _) => Some(*idx as f64),
+        }
+    }
+
+    pub fn as_bool(self) -> bool {
+        if let SettingValue::Bool(val) = self {
+            val
+        } else {
+            panic!("as_bool called on a {} setting !", self.kind())
+        }
+    }
+
+    pub fn as_float(self) -> f64 {
+        if let SettingValue::Float(val) = self {
+            val
+        } else {
+            panic!("as_float called on a {} setting !", self.kind())
+        }
+    }
+
+    pub fn as_int(self) -> i64 {
+        if let SettingValue::Int(val) = self {
+            val
+        } else {
+            panic!("as_int called on a {} setting !", self.kind())
+        }
+    }
+
+    pub fn as_duration(self) -> Duration {
+        if let SettingValue::Duration(val) = self {
+            val
+        } else {
+            panic!("as_duration called on a {} setting !", self.kind())
+        }
+    }
+
+    pub fn as_choice(self) -> usize {
+        if let SettingValue::Choice(idx, _) = self {
+            idx
+        } else {
+            panic!("as_choice called on a {} setting !", self.kind())
+        }
+    }
+
+    pub(super) fn encode(&self) -> Vec<u8> {
+        match self {
+            SettingValue::Bool(val) => vec![*val as u8],
+            SettingValue::Float(val) => val.to_be_bytes().to_vec(),
+            SettingValue::Int(val) => val.to_be_bytes().to_vec(),
+            SettingValue::Duration(val) => val.as_secs().to_be_bytes().to_vec(),
+            SettingValue::Choice(idx, _) => (*idx as u64).to_be_bytes().to_vec(),
+        }
+    }
+
+    pub(super) fn decode(kind: SettingKind, buff: &[u8]) -> Option<SettingValue> {
+        let word = || {
+            let mut word = [0u8; 8];
+            word.copy_from_slice(buff.get(..8)?);
+            Some(word)
+        };
+        Some(match kind {
+            SettingKind::Bool => SettingValue::Bool(*buff.first()? == 1),
+            SettingKind::Float => SettingValue::Float(f64::from_be_bytes(word()?)),
+            SettingKind::Int => SettingValue::Int(i64::from_be_bytes(word()?)),
+            SettingKind::Duration => SettingValue::Duration(Duration::from_secs(u64::from_be_bytes(word()?))),
+            SettingKind::Choice(choices) => SettingValue::Choice(u64::from_be_bytes(word()?) as usize, choices),
+        })
+    }
+}
+
+impl Display for SettingValue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            SettingValue::Bool(val) => write!(f, "{}", val),
+            SettingValue::Float(val) => write!(f, "{}", val),
+            SettingValue::Int(val) => write!(f, "{}", val),
+            SettingValue::Duration(val) => write!(f, "{}s", val.as_secs()),
+            SettingValue::Choice(idx, choices) => write!(f, "{}", choices.get(*idx).unwrap_or(&"?")),
+        }
+    }
+}
+#[derive(Debug, Fail)]
+pub enum SettingError {
+    #[fail(display = "Unknown setting `{}`", _0)]
+    Unknown(String),
+    #[fail(display = "{} expects a {}", _0, _1)]
+    Kind(&'static str, SettingKind),
+    #[fail(display = "{} must be between {} and {}, got {}", _0, _1, _2, _3)]
+    OutOfRange(&'static str, f64, f64, SettingValue),
+    #[fail(display = "Invalid value `{}` for {}", _1, _0)]
+    Parse(&'static str, String),
+}
+
+pub struct SettingSpec {
+    pub setting: Setting,
+    /// Key in the `settings` tree, also its name on the command line
+    pub key: &'static str,
+    pub label: &'static str,
+    pub description: &'static str,
+    pub category: SettingCategory,
+    pub default: SettingValue,
+    /// Bounds of the numeric settings, in seconds for the durations
+    pub min: f64,
+    pub max: f64,
+    /// Increment used by the GUI
+    pub step: f64,
+    pub unit: Option<&'static str>,
+}
+
+const DAY_MAX: f64 = 86_400.0;
+
+/// Modes of the dosing controllers, see `scheduler::ControllerMode`
+pub const CONTROLLER_MODES: [&str; 3] = ["fixed", "proportional", "pid"];
+
+pub const SETTINGS: [SettingSpec; 24] = [
+    SettingSpec {
+        setting: Setting::TdsMonitoring,
+        key: "tds_monitoring",
+        label: "EC Compensation",
+        description: "Add osmosed water when the TDS goes over its threshold",
+        category: SettingCategory::General,
+        default: SettingValue::Bool(false),
+        min: 0.0, max: 0.0, step: 0.0,
+        unit: None,
+    },
+    SettingSpec {
+        setting: Setting::PhMonitoring,
+        key: "ph_monitoring",
+        label: "PH Compensation",
+        description: "Add PH Down when the pH goes over its threshold",
+        category: SettingCategory::General,
+        default: SettingValue::Bool(false),
+        min: 0.0, max: 0.0, step: 0.0,
+        unit: None,
+    },
+    SettingSpec {
+        setting: Setting::HardwareTimeout,
+        key: "hardware_timeout",
+        label: "Hardware timeout",
+        description: "Longest wait for the valve or the pump to obey before automation is locked out, on top of the valve stroke",
+        category: SettingCategory::Safety,
+        default: SettingValue::Duration(Duration::from_secs(15)),
+        // Above the stroke of the valve, its reply only comes once it stopped
+        min: 5.0, max: 120.0, step: 1.0,
+        unit: None,
+    },
+    SettingSpec {
+        setting: Setting::Tds1Thresh,
+        key: "tds_1_thresh",
+        label: "Threshold",
+        description: "TDS above which osmosed water is added",
+        category: SettingCategory::EcMonitor,
+        default: SettingValue::Float(500.0),
+        min: 0.0, max: 5000.0, step: 1.0,
+        unit: Some("PPM"),
+    },
+    SettingSpec {
+        setting: Setting::OsmoseurPulseDuration,
+        key: "osmoseur_pulse_duration",
+        label: "Osmoseur pulse duration",
+        description: "How long the osmoseur valve stays open per pulse",
+        category: SettingCategory::EcMonitor,
+        default: SettingValue::Duration(Duration::from_secs(10)),
+        min: 1.0, max: 600.0, step: 1.0,
+        unit: None,
+    },
+    SettingSpec {
+        setting: Setting::OsmoseurPulseMinInterval,
+        key: "osmoseur_pulse_min_interval",
+        label: "Osmoseur pulse interval",
+        description: "Minimum time between two osmoseur pulses, lets the water mix",
+        category: SettingCategory::EcMonitor,
+        default: SettingValue::Duration(Duration::from_secs(240)),
+        min: 1.0, max: DAY_MAX, step: 1.0,
+        unit: None,
+    },
+    SettingSpec {
+        setting: Setting::OsmoseurFlowRate,
+        key: "osmoseur_flow_rate",
+        label: "Osmoseur flow rate",
+        description: "Water delivered by the osmoseur valve, used to estimate the dosed volumes",
+        category: SettingCategory::EcMonitor,
+        default: SettingValue::Float(500.0),
+        min: 1.0, max: 20000.0, step: 10.0,
+        unit: Some("ML/min"),
+    },
+    SettingSpec {
+        setting: Setting::OsmoseurController,
+        key: "osmoseur_controller",
+        label: "Osmoseur controller",
+        description: "How the osmoseur pulses are sized: fixed duration, proportional or PID on the distance to the threshold",
+        category: SettingCategory::EcMonitor,
+        default: SettingValue::Choice(0, &CONTROLLER_MODES),
+        min: 0.0, max: 2.0, step: 1.0,
+        unit: None,
+    },
+    SettingSpec {
+        setting: Setting::OsmoseurKp,
+        key: "osmoseur_kp",
+        label: "Osmoseur Kp",
+        description: "Seconds of osmosed water per PPM over the threshold",
+        category: SettingCategory::EcMonitor,
+        default: SettingValue::Float(0.1),
+        min: 0.0, max: 10.0, step: 0.01,
+        unit: Some("s/PPM"),
+    },
+    SettingSpec {
+        setting: Setting::OsmoseurKi,
+        key: "osmoseur_ki",
+        label: "Osmoseur Ki",
+        description: "Seconds of osmosed water per PPM over the threshold for a minute, PID only",
+        category: SettingCategory::EcMonitor,
+        default: SettingValue::Float(0.0),
+        min: 0.0, max: 10.0, step: 0.01,
+        unit: Some("s/PPM.min"),
+    },
+    SettingSpec {
+        setting: Setting::OsmoseurKd,
+        key: "osmoseur_kd",
+        label: "Osmoseur Kd",
+        description: "Seconds of osmosed water per PPM/min the reading rises, PID only",
+        category: SettingCategory::EcMonitor,
+        default: SettingValue::Float(0.0),
+        min: 0.0, max: 10.0, step: 0.01,
+        unit: Some("s.min/PPM"),
+    },
+    SettingSpec {
+        setting: Setting::OsmoseurPulseMax,
+        key: "osmoseur_pulse_max",
+        label: "Osmoseur longest pulse",
+        description: "Upper bound of the pulses sized by the proportional and PID controllers",
+        category: SettingCategory::EcMonitor,
+        default: SettingValue::Duration(Duration::from_secs(60)),
+        min: 1.0, max: 600.0, step: 1.0,
+        unit: None,
+    },
+    SettingSpec {
+        setting: Setting::Ph1Thresh,
+        key: "ph_1_thresh",
+        label: "Threshold",
+        description: "pH above which PH Down is added",
+        category: SettingCategory::PhMonitor,
+        default: SettingValue::Float(7.0),
+        min: 0.0, max: 14.0, step: 0.1,
+        unit: Some("pH"),
+    },
+    SettingSpec {
+        setting: Setting::PhPulseDuration,
+        key: "ph_pulse_duration",
+        label: "PH Down pulse duration",
+        description: "How long the peristaltic pump runs per PH Down pulse",
+        category: SettingCategory::PhMonitor,
+        default: SettingValue::Duration(Duration::from_secs(10)),
+        min: 1.0, max: 600.0, step: 1.0,
+        unit: None,
+    },
+    SettingSpec {
+        setting: Setting::PhPulseMinInterval,
+        key: "ph_pulse_min_interval",
+        label: "PH Down pulse interval",
+        description: "Minimum time between two PH Down pulses, lets the water mix",
+        category: SettingCategory::PhMonitor,
+        default: SettingValue::Duration(Duration::from_secs(240)),
+        min: 1.0, max: DAY_MAX, step: 1.0,
+        unit: None,
+    },
+    SettingSpec {
+        setting: Setting::PhDownFlowRate,
+        key: "ph_down_flow_rate",
+        label: "PH Down flow rate",
+        description: "PH Down delivered by the peristaltic pump, used to estimate the dosed volumes",
+        category: SettingCategory::PhMonitor,
+        default: SettingValue::Float(50.0),
+        min: 0.1, max: 1000.0, step: 1.0,
+        unit: Some("ML/min"),
+    },
+    SettingSpec {
+        setting: Setting::PhController,
+        key: "ph_controller",
+        label: "PH Down controller",
+        description: "How the PH Down pulses are sized: fixed duration, proportional or PID on the distance to the threshold",
+        category: SettingCategory::PhMonitor,
+        default: SettingValue::Choice(0, &CONTROLLER_MODES),
+        min: 0.0, max: 2.0, step: 1.0,
+        unit: None,
+    },
+    SettingSpec {
+        setting: Setting::PhKp,
+        key: "ph_kp",
+        label: "PH Down Kp",
+        description: "Seconds of PH Down per pH over the threshold",
+        category: SettingCategory::PhMonitor,
+        default: SettingValue::Float(20.0),
+        min: 0.0, max: 600.0, step: 1.0,
+        unit: Some("s/pH"),
+    },
+    SettingSpec {
+        setting: Setting::PhKi,
+        key: "ph_ki",
+        label: "PH Down Ki",
+        description: "Seconds of PH Down per pH over the threshold for a minute, PID only",
+        category: SettingCategory::PhMonitor,
+        default: SettingValue::Float(0.0),
+        min: 0.0, max: 600.0, step: 1.0,
+        unit: Some("s/pH.min"),
+    },
+    SettingSpec {
+        setting: Setting::PhKd,
+        key: "ph_kd",
+        label: "PH Down Kd",
+        description: "Seconds of PH Down per pH/min the reading rises, PID only",
+        category: SettingCategory::PhMonitor,
+        default: SettingValue::Float(0.0),
+        min: 0.0, max: 600.0, step: 1.0,
+        unit: Some("s.min/pH"),
+    },
+    SettingSpec {
+        setting: Setting::PhPulseMax,
+        key: "ph_pulse_max",
+        label: "PH Down longest pulse",
+        description: "Upper bound of the pulses sized by the proportional and PID controllers",
+        category: SettingCategory::PhMonitor,
+        default: SettingValue::Duration(Duration::from_secs(60)),
+        min: 1.0, max: 600.0, step: 1.0,
+        unit: None,
+    },
+    SettingSpec {
+        setting: Setting::RawRetentionDays,
+        key: "raw_retention_days",
+        label: "Raw samples retention",
+        description: "Days the raw samples are kept before only their 1-minute rollups remain",
+        category: SettingCategory::Storage,
+        default: SettingValue::Int(7),
+        min: 1.0, max: 3650.0, step: 1.0,
+        unit: Some("days"),
+    },
+    SettingSpec {
+        setting: Setting::MinuteRetentionDays,
+        key: "minute_retention_days",
+        label: "1-minute rollups retention",
+        description: "Days the 1-minute rollups are kept before only the 1-hour ones remain",
+        category: SettingCategory::Storage,
+        default: SettingValue::Int(90),
+        min: 1.0, max: 3650.0, step: 1.0,
+        unit: Some("days"),
+    },
+    SettingSpec {
+        setting: Setting::JournalRetentionDays,
+        key: "journal_retention_days",
+        label: "Journal retention",
+        description: "Days the logs, queries, actions and setting changes are kept in the journal",
+        category: SettingCategory::Storage,
+        default: SettingValue::Int(90),
+        min: 1.0, max: 3650.0, step: 1.0,
+        unit: Some("days"),
+    },
+];
+
+impl Setting {
+    pub fn spec(self) -> &'static SettingSpec {
+        SETTINGS.iter().find(|spec| spec.setting == self).expect("Setting missing from the registry !")
+    }
+}
+
+impl std::str::FromStr for Setting {
+    type Err = SettingError;
+
+    fn from_str(val: &str) -> Result<Self, Self::Err> {
+        SETTINGS.iter().find(|spec| spec.key == val).map(|spec| spec.setting).ok_or_else(|| SettingError::Unknown(val.to_string()))
+    }
+}
+
+impl Display for Setting {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.spec().key)
+    }
+}
+
+impl SettingSpec {
+    pub fn kind(&self) -> SettingKind {
+        self.default.kind()
+    }
+
+    pub fn validate(&self, value: SettingValue) -> Result<SettingValue, SettingError> {
+        if value.kind() != self.kind() {
+            return Err(SettingError::Kind(self.key, self.kind()));
+        }
+        match value.number() {
+            Some(number) if !number.is_finite() || number < self.min || number > self.max => {
+                Err(SettingError::OutOfRange(self.key, self.min, self.max, value))
+            },
+            _ => Ok(value),
+        }
+    }
+
+    /// Parse a value typed by the user, durations are in seconds unless suffixed (`4m`, `1h`...)
+    pub fn parse(&self, val: &str) -> Result<SettingValue, SettingError> {
+        let error = || SettingError::Parse(self.key, val.to_string());
+        let value = match self.kind() {
+            SettingKind::Bool => match val {
+                "true" | "on" | "yes" | "1" => SettingValue::Bool(true),
+                "false" | "off" | "no" | "0" => SettingValue::Bool(false),
+                _ => return Err(error()),
+            },
+            SettingKind::Float => SettingValue::Float(val.parse().map_err(|_| error())?),
+            SettingKind::Int => SettingValue::Int(val.parse().map_err(|_| error())?),
+            SettingKind::Duration => match val.parse::<u64>() {
+                Ok(secs) => SettingValue::Duration(Duration::from_secs(secs)),
+                // Stored in whole seconds
+                Err(_) => match crate::export::parse_interval(val) {
+                    Ok(duration) if duration.subsec_nanos() == 0 => SettingValue::Duration(duration),
+                    _ => return Err(error()),
+                },
+            },
+            SettingKind::Choice(choices) => SettingValue::Choice(choices.iter().position(|choice| choice.eq_ignore_ascii_case(val)).ok_or_else(error)?, choices),
+        };
+        self.validate(value)
+    }
+
+    /// `value` moved by `steps` increments and kept within the bounds, booleans are toggled and choices cycled
+    pub fn step(&self, value: SettingValue, steps: i32) -> SettingValue {
+        let clamp = |val: f64| ((val / self.step).round() / self.step.recip()).max(self.min).min(self.max);
+        match value {
+            SettingValue::Bool(val) => SettingValue::Bool(!val),
+            SettingValue::Float(val) => SettingValue::Float(clamp(val + self.step * steps as f64)),
+            SettingValue::Int(val) => SettingValue::Int(clamp(val as f64 + self.step * steps as f64) as i64),
+            SettingValue::Duration(val) => SettingValue::Duration(Duration::from_secs(clamp(val.as_secs_f64() + self.step * steps as f64) as u64)),
+            SettingValue::Choice(idx, choices) => SettingValue::Choice((idx as i64 + steps as i64).rem_euclid(choices.len() as i64) as usize, choices),
+        }
+    }
+
+    /// The value followed by the unit
+    pub fn display(&self, value: SettingValue) -> String {
+        match self.unit {
+            Some(unit) => format!("{} {}", value, unit),
+            None => value.to_string(),
+        }
+    }
+}
+
+impl Store {
+    /// Stored value of `setting`, the default is stored on first use and replaces corrupt values.
+    /// Never fails: the default is used when the value can't be read.
+    pub fn get(&self, setting: Setting) -> SettingValue {
+        let spec = setting.spec();
+        let stored = match self.settings_tree.get(spec.key) {
+            Ok(Some(buff)) => match SettingValue::decode(spec.kind(), &buff) {
+                Some(value) => Some(value),
+                None => {
+                    warn!("Stored {} is corrupt, resetting it to the default", spec.key);
+                    None
+                },
+            },
+            Ok(None) => None,
+            Err(e) => {
+                warn!("Failed to read {} ({}), using the default", spec.key, e);
+                return spec.default;
+            },
+        };
+        match stored.map(|value| spec.validate(value)) {
+            Some(Ok(value)) => value,
+            Some(Err(e)) => {
+                warn!("Stored {} is invalid ({}), using the default", spec.key, e);
+                spec.default
+            },
+            None if self.read_only => spec.default,
+            None => {
+                if let Err(e) = self.put(setting, spec.default) {
+                    warn!("Failed to store the default {}: {}", spec.key, e);
+                }
+                spec.default
+            },
+        }
+    }
+
+    /// Validate then store `value`
+    pub fn set(&self, setting: Setting, value: SettingValue) -> StoreResult<SettingValue> {
+        let value = setting.spec().validate(value)?;
+        self.put(setting, value)?;
+        Ok(value)
+    }
+
+    fn put(&self, setting: Setting, value: SettingValue) -> StoreResult<()> {
+        self.writable()?;
+        self.settings_tree.insert(setting.spec().key, value.encode())?;
+        self.db.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::testing::*;
+
+    #[test]
+    fn registry_validation() {
+        for spec in SETTINGS.iter() {
+            assert_eq!(spec.setting.spec().key, spec.key);
+            assert_eq!(spec.key.parse::<Setting>().unwrap(), spec.setting);
+            spec.validate(spec.default).unwrap();
+        }
+        let ph = Setting::Ph1Thresh.spec();
+        assert!(matches!(ph.parse("-1"), Err(SettingError::OutOfRange("ph_1_thresh", ..))));
+        assert!(matches!(ph.parse("NaN"), Err(SettingError::OutOfRange(..))));
+        assert!(matches!(ph.validate(SettingValue::Bool(true)), Err(SettingError::Kind(..))));
+        assert_eq!(ph.step(SettingValue::Float(7.0), -3), SettingValue::Float(6.7));
+        assert_eq!(ph.step(SettingValue::Float(13.95), 1), SettingValue::Float(14.0));
+        assert_eq!(ph.display(SettingValue::Float(6.5)), "6.5 pH");
+
+        let interval = Setting::OsmoseurPulseMinInterval.spec();
+        assert_eq!(interval.parse("4m").unwrap(), SettingValue::Duration(Duration::from_secs(240)));
+        assert!(matches!(interval.parse("0"), Err(SettingError::OutOfRange(..))));
+        assert!(matches!(interval.parse("1500ms"), Err(SettingError::Parse(..))));
+        // The duration can't underflow anymore
+        assert_eq!(interval.step(SettingValue::Duration(Duration::from_secs(1)), -1), SettingValue::Duration(Duration::from_secs(1)));
+        assert!(matches!(Setting::TdsMonitoring.spec().parse("maybe"), Err(SettingError::Parse(..))));
+        assert!(matches!("tds".parse::<Setting>(), Err(SettingError::Unknown(_))));
+
+        let controller = Setting::PhController.spec();
+        let pid = controller.parse("PID").unwrap();
+        assert_eq!(pid, SettingValue::Choice(2, &CONTROLLER_MODES));
+        assert_eq!(controller.display(pid), "pid");
+        assert_eq!(controller.step(pid, 1), SettingValue::Choice(0, &CONTROLLER_MODES));
+        assert_eq!(SettingValue::decode(controller.kind(), &pid.encode()), Some(pid));
+        assert!(matches!(controller.parse("bang-bang"), Err(SettingError::Parse(..))));
+        assert!(matches!(controller.validate(SettingValue::Choice(3, &CONTROLLER_MODES)), Err(SettingError::OutOfRange(..))));
+    }
+
+    #[test]
+    fn store_settings() {
+        let (store, path) = temporary_store("settings");
+        assert_eq!(store.get(Setting::Tds1Thresh), SettingValue::Float(500.0));
+        store.set(Setting::Tds1Thresh, SettingValue::Float(650.0)).unwrap();
+        assert!(store.set(Setting::PhPulseDuration, SettingValue::Duration(Duration::from_secs(0))).is_err());
+        // Written by a previous version without validation
+        store.settings_tree.insert("ph_1_thresh", &(-2.0f64).to_be_bytes()).unwrap();
+        let values = (store.get(Setting::Tds1Thresh), store.get(Setting::PhPulseDuration), store.get(Setting::Ph1Thresh));
+        drop(store);
+        std::fs::remove_dir_all(&path).unwrap();
+        assert_eq!(values, (SettingValue::Float(650.0), SettingValue::Duration(Duration::from_secs(10)), SettingValue::Float(7.0)));
+    }
+}
@@ -43,10 +43,13 @@
 #define PPUMP_1_STEPPER_STEPS 200
 
 #define STATUS_UNKNOWN -1
-#define RES_OK(msg) Serial.println("OK " msg)
-#define RES_ERR(msg) Serial.println("ERR " msg)
+#define RES_OK(msg) out.println("OK " msg)
+#define RES_ERR(msg) out.println("ERR " msg)
 
 #define COMMAND_BUFFER_SIZE 64
+#define LINE_BUFFER_SIZE 96
+// Version reported by V0, framing (V1) is supported since version 2
+#define PROTOCOL_VERSION 2
 #define CMD_SEPARATOR " "
 
 #define HEALT_CHECK_INTERVAL 1000
@@ -70,6 +73,57 @@
 #define M_STEPPER_RUNNING ((M_OSMOS_SWITCH_BUSY | S_PERISTALIC_PUMP_ON | S_PERISTALIC_PUMP_REV ))
 #define M_OSMOS_SWITCH_BUSY ((S_OSMOS_SWITCH_OPENING | S_OSMOS_SWITCH_CLOSING))
 
+// CRC-16/CCITT-FALSE
+uint16_t crc16(const char *data, uint16_t crc = 0xFFFF) {
+  while (*data) {
+    crc ^= (uint16_t)(*data++) << 8;
+    for (int i = 0; i < 8; i++)
+      crc = crc & 0x8000 ? (crc << 1) ^ 0x1021 : crc << 1;
+  }
+  return crc;
+}
+
+// Buffer the replies line by line to send them as `<seq>:<payload>*<crc>` frames once V1 ON was received
+class LineWriter : public Print {
+public:
+  bool framed = false;
+
+  virtual size_t write(uint8_t c) {
+    if (c == '\r') return 1;
+    if (c == '\n') {
+      flush_line();
+    } else if (idx + 1 < LINE_BUFFER_SIZE) {
+      buffer[idx++] = c;
+    }
+    return 1;
+  }
+
+private:
+  char buffer[LINE_BUFFER_SIZE];
+  int idx = 0;
+  uint8_t seq = 0;
+
+  void flush_line() {
+    buffer[idx] = '\0';
+    idx = 0;
+    if (!framed) {
+      Serial.println(buffer);
+      return;
+    }
+    char header[5];
+    char footer[6];
+    snprintf(header, sizeof(header), "%u:", seq++);
+    snprintf(footer, sizeof(footer), "*%04X", crc16(buffer, crc16(header)));
+    Serial.print(header);
+    Serial.print(buffer);
+    Serial.println(footer);
+  }
+};
+
+LineWriter out;
+uint8_t last_host_seq;
+bool last_host_seq_valid = false;
+
 unsigned int status = S_BREATHING | S_OSMOS_SWITCH_CLOSING | S_BRONCHUS_STANDBY_SAMPLING;
 unsigned long int breath_step = millis();
 
@@ -121,11 +175,11 @@ inline void M0() {
 }
 
 void echo_tds_cal() {
-  Serial.print(" TDS1 ");
-  Serial.print(settings.tds_1_map[0]);
-  Serial.print(" ");
-  Serial.print(settings.tds_1_map[1]);
-  Serial.println();
+  out.print(" TDS1 ");
+  out.print(settings.tds_1_map[0]);
+  out.print(" ");
+  out.print(settings.tds_1_map[1]);
+  out.println();
 }
 
 // Set tds calibration value
@@ -143,24 +197,48 @@ inline void M1() {
     }
   }
   EEPROM.put(0, settings);
-  Serial.print("OK M1 ");
+  out.print("OK M1 ");
   echo_tds_cal();
 }
 
 // Read tds calibration values
 inline void M2() {
-  Serial.print("OK M2 ");
+  out.print("OK M2 ");
   echo_tds_cal();
 }
 
+// Protocol version, also resets the link to plain text lines
+inline void V0() {
+  out.framed = false;
+  last_host_seq_valid = false;
+  out.print("OK V0 ");
+  out.println(PROTOCOL_VERSION);
+}
+
+// Enable/disable framing, the reply is sent before switching
+inline void V1() {
+  char *command = strtok(NULL, CMD_SEPARATOR);
+  if (command != NULL) {
+    if (strncasecmp(command, "ON", 2) == 0) {
+      RES_OK("V1 ON");
+      out.framed = true;
+    } else if (strncasecmp(command, "OFF", 3) == 0) {
+      RES_OK("V1 OFF");
+      out.framed = false;
+    } else {
+      RES_ERR("V1 BAD_REQUEST");
+    }
+  }
+}
+
 // Read raw sensore values
 inline void G0() {
-  Serial.print("OK G0");
-  Serial.print(" TDS1 ");
-  Serial.print(tds_1_raw);
-  Serial.print(" PH1 ");
-  Serial.print(ph_1_raw);
-  Serial.println();
+  out.print("OK G0");
+  out.print(" TDS1 ");
+  out.print(tds_1_raw);
+  out.print(" PH1 ");
+  out.print(ph_1_raw);
+  out.println();
 }
 
 #define RES2 857.39 //TODO check that const match with our sensor
@@ -184,22 +262,22 @@ inline float read_ph(float ph, float temperature) {
 
 // Read filtred sensore values & status
 inline void G1() {
-  Serial.print("OK G1");
+  out.print("OK G1");
   if (status & S_TDS_CONNECTED && millis() - uptime > TDS_1_FILTER_CALIBRATION_DURATION) {
-    Serial.print(" TDS1 ");
-    Serial.print(read_ec(tds_1_filter.Current(), 25));
+    out.print(" TDS1 ");
+    out.print(read_ec(tds_1_filter.Current(), 25));
   }
   if (status & S_PH_CONNECTED && millis() - uptime > PH_1_FILTER_CALIBRATION_DURATION) {
-    Serial.print(" PH1 ");
-    Serial.print(read_ph(ph_1_filter.Current(), 25));
+    out.print(" PH1 ");
+    out.print(read_ph(ph_1_filter.Current(), 25));
   }
   if (status & S_TEMPERATURE_CONNECTED && millis() - uptime > TEMPERATURE_1_CALIBRATION_DURATION) {
-    Serial.print(" T1 ");
-    Serial.print(temp_1_raw);
+    out.print(" T1 ");
+    out.print(temp_1_raw);
   }
-  Serial.print(" STATUS ");
-  Serial.print(status);
-  Serial.println();
+  out.print(" STATUS ");
+  out.print(status);
+  out.println();
 }
 
 // Controle water valve status
@@ -257,6 +335,31 @@ inline void S2() {
 }
 
 
+// Payload of a host line, NULL when the frame is corrupted or duplicated
+char *unframe(char *line) {
+  char *star = strrchr(line, '*');
+  char *colon = strchr(line, ':');
+  if (star == NULL || colon == NULL || colon > star) {
+    // Unframed lines are only allowed in framed mode to reset the link with V0
+    if (out.framed && strncasecmp(line, "V0", 2) != 0) {
+      RES_ERR("CRC");
+      return NULL;
+    }
+    return line;
+  }
+  *star = '\0';
+  if (crc16(line) != (uint16_t)strtoul(star + 1, NULL, 16)) {
+    RES_ERR("CRC");
+    return NULL;
+  }
+  uint8_t seq = atoi(line);
+  if (last_host_seq_valid && seq == last_host_seq)
+    return NULL;
+  last_host_seq = seq;
+  last_host_seq_valid = true;
+  return colon + 1;
+}
+
 void setup() {
   Serial.begin(9600);
   pinMode(TDS_1_PIN, INPUT);
@@ -301,10 +404,12 @@ void loop() {
   // Check for command into the buffer
   if (command_buffer_idx > 0 && command_buffer[command_buffer_idx - 1] == '\n') {
     command_buffer[command_buffer_idx - 1] = '\0';
-    char *command = strtok(command_buffer, CMD_SEPARATOR);
+    char *line = unframe(command_buffer);
+    char *command = line == NULL ? NULL : strtok(line, CMD_SEPARATOR);
     // Load default settings
-    if (command == NULL)
-      Serial.println("PROCESS ERROR EMPTY COMMAND");
+    if (line == NULL);
+    else if (command == NULL)
+      out.println("PROCESS ERROR EMPTY COMMAND");
     else if (strncasecmp(command, "M0", 2) == 0)    M0();
     else if (strncasecmp(command, "M1", 2) == 0)    M1();
     else if (strncasecmp(command, "M2", 2) == 0)    M2();
@@ -313,10 +418,12 @@ void loop() {
     else if (strncasecmp(command, "S2", 2) == 0)    S2();
     else if (strncasecmp(command, "G0", 2) == 0)    G0();
     else if (strncasecmp(command, "G1", 2) == 0)    G1();
+    else if (strncasecmp(command, "V0", 2) == 0)    V0();
+    else if (strncasecmp(command, "V1", 2) == 0)    V1();
     else {
       command_buffer[command_buffer_idx] = '\0';
       RES_ERR("UNKNOW");
-      Serial.println(command_buffer);
+      out.println(command);
     }
     command_buffer_idx = 0;
   }